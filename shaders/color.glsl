@@ -0,0 +1,49 @@
+// Color encodings shared by the shaders. The values of the OUTPUT_* constants match
+// `OutputEncoding` in swapchain.rs
+
+const uint OUTPUT_LINEAR = 0u;
+const uint OUTPUT_SRGB = 1u;
+const uint OUTPUT_PQ = 2u;
+
+// Luminance of SDR white in HDR10 output, as recommended by ITU-R BT.2408
+const float SDR_WHITE_NITS = 203.0f;
+
+vec3 linearToSrgb(vec3 color)
+{
+  vec3 low = color * 12.92f;
+  vec3 high = 1.055f * pow(color, vec3(1.0f / 2.4f)) - 0.055f;
+  return mix(high, low, lessThanEqual(color, vec3(0.0031308f)));
+}
+
+// Converts linear BT.709 colors, with 1.0 as SDR white, to the ST.2084 (PQ) encoding of BT.2020
+vec3 linearToPq(vec3 color)
+{
+  // Columns of the BT.709 to BT.2020 primaries conversion
+  const mat3 bt709ToBt2020 = mat3(
+    0.6274f, 0.0691f, 0.0164f,
+    0.3293f, 0.9195f, 0.0880f,
+    0.0433f, 0.0114f, 0.8956f);
+  vec3 luminance = max(bt709ToBt2020 * color, vec3(0.0f)) * (SDR_WHITE_NITS / 10000.0f);
+
+  const float m1 = 0.1593017578125f;
+  const float m2 = 78.84375f;
+  const float c1 = 0.8359375f;
+  const float c2 = 18.8515625f;
+  const float c3 = 18.6875f;
+  vec3 p = pow(luminance, vec3(m1));
+  return pow((c1 + c2 * p) / (1.0f + c3 * p), vec3(m2));
+}
+
+// Encodes the linear output of the final pass for the color attachment
+vec4 encodeOutput(vec4 color, uint encoding)
+{
+  if (encoding == OUTPUT_SRGB)
+  {
+    return vec4(linearToSrgb(max(color.rgb, vec3(0.0f))), color.a);
+  }
+  if (encoding == OUTPUT_PQ)
+  {
+    return vec4(linearToPq(color.rgb), color.a);
+  }
+  return color;
+}
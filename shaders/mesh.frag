@@ -0,0 +1,24 @@
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+#include "color.glsl"
+
+layout (constant_id = 0) const uint outputEncoding = OUTPUT_LINEAR;
+
+layout (location = 0) in vec3 inNormal;
+layout (location = 1) in vec4 inColor;
+layout (location = 2) in vec2 inUv;
+
+layout (set = 1, binding = 0) uniform sampler2D baseColorTexture;
+
+layout (location = 0) out vec4 outFragColor;
+
+void main()
+{
+  vec4 color = inColor * texture(baseColorTexture, inUv);
+  // A fixed directional light in world space
+  const vec3 toLight = normalize(vec3(0.3f, 1.0f, 0.5f));
+  float diffuse = max(dot(normalize(inNormal), toLight), 0.0f);
+  vec4 lit = vec4(color.rgb * (0.2f + 0.8f * diffuse), color.a);
+  outFragColor = encodeOutput(lit, outputEncoding);
+}
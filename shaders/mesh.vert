@@ -0,0 +1,31 @@
+#version 450
+
+layout (location = 0) in vec3 inPosition;
+layout (location = 1) in vec3 inNormal;
+layout (location = 2) in vec2 inUv;
+layout (location = 3) in vec4 inTangent;
+layout (location = 4) in vec4 inColor;
+
+layout (set = 0, binding = 0) uniform FrameUniforms
+{
+  mat4 viewProjection;
+} frame;
+
+layout (push_constant) uniform PushConstants
+{
+  mat4 model;
+  mat3 normalMatrix;
+  vec4 baseColor;
+} pushConstants;
+
+layout (location = 0) out vec3 outNormal;
+layout (location = 1) out vec4 outColor;
+layout (location = 2) out vec2 outUv;
+
+void main()
+{
+  gl_Position = frame.viewProjection * pushConstants.model * vec4(inPosition, 1.0f);
+  outNormal = pushConstants.normalMatrix * inNormal;
+  outColor = inColor * pushConstants.baseColor;
+  outUv = inUv;
+}
@@ -1,8 +1,13 @@
 #version 450
+#extension GL_GOOGLE_include_directive : require
+
+#include "color.glsl"
+
+layout (constant_id = 0) const uint outputEncoding = OUTPUT_LINEAR;
 
 layout (location = 0) out vec4 outFragColor;
 
 void main()
 {
-    outFragColor = vec4(1.f, 0.f, 0.f, 1.0f);
-}
\ No newline at end of file
+    outFragColor = encodeOutput(vec4(1.f, 0.f, 0.f, 1.0f), outputEncoding);
+}
@@ -0,0 +1,1021 @@
+use ash::{vk, Device};
+use std::collections::{BTreeSet, HashMap};
+use std::ptr::NonNull;
+
+use crate::error::{RendererError, RendererResult};
+use crate::memory::find_memory_type_index;
+
+/// Smallest piece a buddy block is split into
+const MIN_BUDDY_SIZE: vk::DeviceSize = 256;
+
+/// Where the memory of a resource should live, depending on who accesses it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryLocation {
+    /// Only accessed by the GPU
+    GpuOnly,
+    /// Written by the CPU and read by the GPU, e.g. staging and uniform buffers. Persistently
+    /// mapped.
+    CpuToGpu,
+    /// Written by the GPU and read back by the CPU. Persistently mapped and cached if possible.
+    GpuToCpu,
+}
+
+impl MemoryLocation {
+    /// The property flags a memory type must have, and the ones that are preferred on top
+    fn flags(self) -> (vk::MemoryPropertyFlags, vk::MemoryPropertyFlags) {
+        let host = vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
+        match self {
+            MemoryLocation::GpuOnly => (
+                vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                vk::MemoryPropertyFlags::empty(),
+            ),
+            MemoryLocation::CpuToGpu => (host, vk::MemoryPropertyFlags::empty()),
+            MemoryLocation::GpuToCpu => (host, vk::MemoryPropertyFlags::HOST_CACHED),
+        }
+    }
+}
+
+/// How allocations are placed within the memory blocks of a pool
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AllocationStrategy {
+    /// Power-of-two buddy allocation. Allocations can be freed in any order and neighboring free
+    /// space is merged again, at the cost of rounding sizes up to a power of two.
+    Buddy,
+    /// Allocations are placed one after the other. A block only becomes reusable once all of its
+    /// allocations are freed, so this suits resources that are freed together.
+    Linear,
+}
+
+pub struct AllocationDesc<'a> {
+    /// Shows up in leak reports
+    pub name: &'a str,
+    pub location: MemoryLocation,
+    pub strategy: AllocationStrategy,
+    /// Give the resource its own `VkDeviceMemory` even if it is small and the driver doesn't ask
+    /// for it
+    pub dedicated: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AllocatorConfig {
+    /// Size of the `VkDeviceMemory` blocks that are sub-allocated. Rounded down to a power of two
+    /// and limited to an eighth of the memory heap.
+    pub block_size: vk::DeviceSize,
+}
+
+impl Default for AllocatorConfig {
+    fn default() -> Self {
+        AllocatorConfig {
+            block_size: 64 << 20,
+        }
+    }
+}
+
+/// Memory usage, either in total or for a single memory heap
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AllocatorStats {
+    /// Live `vkAllocateMemory` allocations, i.e. blocks plus dedicated allocations
+    pub device_memory_count: u32,
+    pub block_count: u32,
+    pub dedicated_count: u32,
+    /// Live allocations handed out by the allocator, including dedicated ones
+    pub allocation_count: u32,
+    /// Bytes allocated from the driver
+    pub reserved_bytes: vk::DeviceSize,
+    /// Bytes used by allocations, including the padding for alignment and buddy sizes
+    pub used_bytes: vk::DeviceSize,
+}
+
+impl AllocatorStats {
+    fn add(&mut self, other: &AllocatorStats) {
+        self.device_memory_count += other.device_memory_count;
+        self.block_count += other.block_count;
+        self.dedicated_count += other.dedicated_count;
+        self.allocation_count += other.allocation_count;
+        self.reserved_bytes += other.reserved_bytes;
+        self.used_bytes += other.used_bytes;
+    }
+}
+
+/// Resources are kept in separate pools depending on whether they are linear (buffers) or not
+/// (optimal-tiling images), so that they never share a `bufferImageGranularity` page.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct PoolKey {
+    memory_type_index: u32,
+    linear: bool,
+    strategy: AllocationStrategy,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Placement {
+    Dedicated,
+    Block {
+        pool: PoolKey,
+        block: usize,
+        /// Only used by buddy blocks
+        order: u32,
+    },
+}
+
+/// A piece of device memory that a resource is bound to. Must be returned through
+/// `Allocator::free` exactly once.
+#[derive(Debug)]
+pub struct Allocation {
+    memory: vk::DeviceMemory,
+    offset: vk::DeviceSize,
+    size: vk::DeviceSize,
+    mapped: Option<NonNull<u8>>,
+    memory_type_index: u32,
+    placement: Placement,
+}
+
+impl Allocation {
+    pub fn memory(&self) -> vk::DeviceMemory {
+        self.memory
+    }
+
+    pub fn offset(&self) -> vk::DeviceSize {
+        self.offset
+    }
+
+    /// The requested size. The allocator may have reserved more.
+    pub fn size(&self) -> vk::DeviceSize {
+        self.size
+    }
+
+    /// Pointer to the start of the allocation for host-visible memory locations
+    pub fn mapped_ptr(&self) -> Option<NonNull<u8>> {
+        self.mapped
+    }
+
+    /// The allocation's memory for host-visible memory locations. Memory locations are always
+    /// host-coherent, so writes don't need to be flushed.
+    ///
+    /// # Safety
+    /// The GPU must not access the memory while the slice is in use.
+    pub unsafe fn mapped_slice_mut(&mut self) -> Option<&mut [u8]> {
+        self.mapped
+            .map(|ptr| std::slice::from_raw_parts_mut(ptr.as_ptr(), self.size as usize))
+    }
+}
+
+struct Buddy {
+    /// Free offsets for each order, where order `n` has a size of `MIN_BUDDY_SIZE << n`
+    free: Vec<BTreeSet<vk::DeviceSize>>,
+}
+
+impl Buddy {
+    fn new(block_size: vk::DeviceSize) -> Self {
+        let max_order = (block_size / MIN_BUDDY_SIZE).trailing_zeros() as usize;
+        let mut free = vec![BTreeSet::new(); max_order + 1];
+        free[max_order].insert(0);
+        Buddy { free }
+    }
+
+    fn max_order(&self) -> u32 {
+        self.free.len() as u32 - 1
+    }
+
+    fn order_size(order: u32) -> vk::DeviceSize {
+        MIN_BUDDY_SIZE << order
+    }
+
+    /// Buddies are aligned to their size, so a size that covers the alignment is enough
+    fn allocate(
+        &mut self,
+        size: vk::DeviceSize,
+        alignment: vk::DeviceSize,
+    ) -> Option<(vk::DeviceSize, u32)> {
+        let size = size.max(alignment).max(MIN_BUDDY_SIZE).next_power_of_two();
+        let order = (size / MIN_BUDDY_SIZE).trailing_zeros();
+        if order > self.max_order() {
+            return None;
+        }
+        let mut current =
+            (order..=self.max_order()).find(|o| !self.free[*o as usize].is_empty())?;
+        let offset = *self.free[current as usize].iter().next().unwrap();
+        self.free[current as usize].remove(&offset);
+        // Split until the size fits, keeping the lower half
+        while current > order {
+            current -= 1;
+            self.free[current as usize].insert(offset + Self::order_size(current));
+        }
+        Some((offset, order))
+    }
+
+    fn free(&mut self, mut offset: vk::DeviceSize, mut order: u32) {
+        while order < self.max_order() {
+            let buddy = offset ^ Self::order_size(order);
+            if !self.free[order as usize].remove(&buddy) {
+                break;
+            }
+            offset = offset.min(buddy);
+            order += 1;
+        }
+        self.free[order as usize].insert(offset);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.free[self.max_order() as usize].contains(&0)
+    }
+}
+
+enum BlockAllocator {
+    Buddy(Buddy),
+    Linear { offset: vk::DeviceSize, live: u32 },
+}
+
+struct MemoryBlock {
+    memory: vk::DeviceMemory,
+    size: vk::DeviceSize,
+    /// Null if the memory isn't host visible
+    mapped: *mut u8,
+    allocator: BlockAllocator,
+}
+
+impl MemoryBlock {
+    fn allocate(
+        &mut self,
+        size: vk::DeviceSize,
+        alignment: vk::DeviceSize,
+    ) -> Option<(vk::DeviceSize, u32, vk::DeviceSize)> {
+        let (offset, order, used) = match &mut self.allocator {
+            BlockAllocator::Buddy(buddy) => {
+                let (offset, order) = buddy.allocate(size, alignment)?;
+                (offset, order, Buddy::order_size(order))
+            }
+            BlockAllocator::Linear {
+                offset: next_offset,
+                live,
+            } => {
+                let offset = align_up(*next_offset, alignment);
+                if offset + size > self.size {
+                    return None;
+                }
+                let used = offset + size - *next_offset;
+                *next_offset = offset + size;
+                *live += 1;
+                (offset, 0, used)
+            }
+        };
+        Some((offset, order, used))
+    }
+
+    /// Returns the number of bytes that became unused
+    fn free(&mut self, offset: vk::DeviceSize, order: u32) -> vk::DeviceSize {
+        match &mut self.allocator {
+            BlockAllocator::Buddy(buddy) => {
+                buddy.free(offset, order);
+                Buddy::order_size(order)
+            }
+            BlockAllocator::Linear {
+                offset: next_offset,
+                live,
+            } => {
+                *live -= 1;
+                if *live == 0 {
+                    // Everything is free again, including the alignment padding
+                    let used = *next_offset;
+                    *next_offset = 0;
+                    used
+                } else {
+                    // The space is only reclaimed once the whole block is free
+                    0
+                }
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match &self.allocator {
+            BlockAllocator::Buddy(buddy) => buddy.is_empty(),
+            BlockAllocator::Linear { live, .. } => *live == 0,
+        }
+    }
+}
+
+struct Pool {
+    block_size: vk::DeviceSize,
+    /// Freed blocks leave a `None` so that the indices of the other blocks stay valid. New
+    /// blocks take the first free slot.
+    blocks: Vec<Option<MemoryBlock>>,
+    /// The most recently created block, which linear pools allocate from
+    newest: Option<usize>,
+}
+
+impl Pool {
+    /// Adds a block in the first free slot and makes it the newest one. Returns its index.
+    fn insert(&mut self, block: MemoryBlock) -> usize {
+        let index = match self.blocks.iter().position(Option::is_none) {
+            Some(index) => index,
+            None => {
+                self.blocks.push(None);
+                self.blocks.len() - 1
+            }
+        };
+        self.blocks[index] = Some(block);
+        self.newest = Some(index);
+        index
+    }
+}
+
+/// Large resources would waste most of a block, so they get their own memory as well
+fn wants_dedicated(
+    desc: &AllocationDesc,
+    prefers_dedicated: bool,
+    size: vk::DeviceSize,
+    block_size: vk::DeviceSize,
+) -> bool {
+    desc.dedicated || prefers_dedicated || size > block_size / 2
+}
+
+fn align_up(value: vk::DeviceSize, alignment: vk::DeviceSize) -> vk::DeviceSize {
+    (value + alignment - 1) & !(alignment - 1)
+}
+
+#[derive(Clone, Copy)]
+enum Resource {
+    Buffer(vk::Buffer),
+    Image(vk::Image),
+}
+
+/// Sub-allocates buffer and image memory from large blocks per memory type, so that the renderer
+/// stays far below `maxMemoryAllocationCount` and doesn't pay for a driver allocation per
+/// resource. Resources that are large or that the driver wants to have their own memory get
+/// dedicated allocations instead.
+///
+/// Host-visible blocks are persistently mapped.
+pub struct Allocator {
+    memory_properties: vk::PhysicalDeviceMemoryProperties,
+    buffer_image_granularity: vk::DeviceSize,
+    max_memory_allocation_count: u32,
+    config: AllocatorConfig,
+    pools: HashMap<PoolKey, Pool>,
+    /// Indexed by memory type
+    stats: Vec<AllocatorStats>,
+}
+
+impl Allocator {
+    pub fn new(
+        memory_properties: vk::PhysicalDeviceMemoryProperties,
+        limits: &vk::PhysicalDeviceLimits,
+        config: AllocatorConfig,
+    ) -> Self {
+        Allocator {
+            memory_properties,
+            buffer_image_granularity: limits.buffer_image_granularity,
+            max_memory_allocation_count: limits.max_memory_allocation_count,
+            config,
+            pools: HashMap::new(),
+            stats: vec![AllocatorStats::default(); memory_properties.memory_type_count as usize],
+        }
+    }
+
+    pub fn memory_properties(&self) -> &vk::PhysicalDeviceMemoryProperties {
+        &self.memory_properties
+    }
+
+    /// Totals over all memory types
+    pub fn stats(&self) -> AllocatorStats {
+        let mut total = AllocatorStats::default();
+        for stats in &self.stats {
+            total.add(stats);
+        }
+        total
+    }
+
+    /// Usage of each memory heap, indexed like `VkPhysicalDeviceMemoryProperties::memoryHeaps`
+    pub fn heap_stats(&self) -> Vec<AllocatorStats> {
+        let mut heaps =
+            vec![AllocatorStats::default(); self.memory_properties.memory_heap_count as usize];
+        for (memory_type, stats) in self.memory_properties.memory_types.iter().zip(&self.stats) {
+            heaps[memory_type.heap_index as usize].add(stats);
+        }
+        heaps
+    }
+
+    /// Allocates memory for `buffer` and binds it.
+    pub fn allocate_buffer(
+        &mut self,
+        device: &Device,
+        buffer: vk::Buffer,
+        desc: &AllocationDesc,
+    ) -> RendererResult<Allocation> {
+        let info = vk::BufferMemoryRequirementsInfo2::builder().buffer(buffer);
+        let mut dedicated_requirements = vk::MemoryDedicatedRequirements::default();
+        let mut requirements =
+            vk::MemoryRequirements2::builder().push_next(&mut dedicated_requirements);
+        unsafe { device.get_buffer_memory_requirements2(&info, &mut requirements) };
+        let requirements = requirements.memory_requirements;
+
+        let allocation = self.allocate(
+            device,
+            &requirements,
+            Resource::Buffer(buffer),
+            desc,
+            dedicated_requirements.prefers_dedicated_allocation == vk::TRUE,
+        )?;
+        if let Err(err) =
+            unsafe { device.bind_buffer_memory(buffer, allocation.memory, allocation.offset) }
+        {
+            unsafe { self.free(device, &allocation) };
+            return Err(err.into());
+        }
+        Ok(allocation)
+    }
+
+    /// Allocates memory for `image` and binds it. The image must use optimal tiling.
+    pub fn allocate_image(
+        &mut self,
+        device: &Device,
+        image: vk::Image,
+        desc: &AllocationDesc,
+    ) -> RendererResult<Allocation> {
+        let info = vk::ImageMemoryRequirementsInfo2::builder().image(image);
+        let mut dedicated_requirements = vk::MemoryDedicatedRequirements::default();
+        let mut requirements =
+            vk::MemoryRequirements2::builder().push_next(&mut dedicated_requirements);
+        unsafe { device.get_image_memory_requirements2(&info, &mut requirements) };
+        let requirements = requirements.memory_requirements;
+
+        let allocation = self.allocate(
+            device,
+            &requirements,
+            Resource::Image(image),
+            desc,
+            dedicated_requirements.prefers_dedicated_allocation == vk::TRUE,
+        )?;
+        if let Err(err) =
+            unsafe { device.bind_image_memory(image, allocation.memory, allocation.offset) }
+        {
+            unsafe { self.free(device, &allocation) };
+            return Err(err.into());
+        }
+        Ok(allocation)
+    }
+
+    fn memory_type_index(
+        &self,
+        requirements: &vk::MemoryRequirements,
+        desc: &AllocationDesc,
+    ) -> RendererResult<u32> {
+        let (required, preferred) = desc.location.flags();
+        find_memory_type_index(
+            &self.memory_properties,
+            requirements.memory_type_bits,
+            required | preferred,
+        )
+        .or_else(|| {
+            find_memory_type_index(
+                &self.memory_properties,
+                requirements.memory_type_bits,
+                required,
+            )
+        })
+        .ok_or_else(|| {
+            RendererError::Unsupported(format!(
+                "Can't find a {:?} memory type for {}",
+                desc.location, desc.name
+            ))
+        })
+    }
+
+    fn block_size(&self, memory_type_index: u32) -> vk::DeviceSize {
+        let heap_index = self.memory_properties.memory_types[memory_type_index as usize].heap_index;
+        let heap_size = self.memory_properties.memory_heaps[heap_index as usize].size;
+        let block_size = self
+            .config
+            .block_size
+            .min(heap_size / 8)
+            .max(MIN_BUDDY_SIZE);
+        // Round down to a power of two for the buddy allocator
+        1 << (63 - block_size.leading_zeros())
+    }
+
+    fn device_memory_count(&self) -> u32 {
+        self.stats().device_memory_count
+    }
+
+    fn allocate_device_memory(
+        &mut self,
+        device: &Device,
+        size: vk::DeviceSize,
+        memory_type_index: u32,
+        dedicated_resource: Option<Resource>,
+    ) -> RendererResult<(vk::DeviceMemory, *mut u8)> {
+        if self.device_memory_count() >= self.max_memory_allocation_count {
+            return Err(vk::Result::ERROR_TOO_MANY_OBJECTS.into());
+        }
+
+        let mut dedicated_info = vk::MemoryDedicatedAllocateInfo::builder();
+        match dedicated_resource {
+            Some(Resource::Buffer(buffer)) => dedicated_info = dedicated_info.buffer(buffer),
+            Some(Resource::Image(image)) => dedicated_info = dedicated_info.image(image),
+            None => {}
+        }
+        let mut allocate_info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(size)
+            .memory_type_index(memory_type_index);
+        if dedicated_resource.is_some() {
+            allocate_info = allocate_info.push_next(&mut dedicated_info);
+        }
+        let memory = unsafe { device.allocate_memory(&allocate_info, None)? };
+
+        let host_visible = self.memory_properties.memory_types[memory_type_index as usize]
+            .property_flags
+            .contains(vk::MemoryPropertyFlags::HOST_VISIBLE);
+        let mapped = if host_visible {
+            match unsafe {
+                device.map_memory(memory, 0, vk::WHOLE_SIZE, vk::MemoryMapFlags::empty())
+            } {
+                Ok(mapped) => mapped as *mut u8,
+                Err(err) => {
+                    unsafe { device.free_memory(memory, None) };
+                    return Err(err.into());
+                }
+            }
+        } else {
+            std::ptr::null_mut()
+        };
+
+        let stats = &mut self.stats[memory_type_index as usize];
+        stats.device_memory_count += 1;
+        stats.reserved_bytes += size;
+        Ok((memory, mapped))
+    }
+
+    fn allocate(
+        &mut self,
+        device: &Device,
+        requirements: &vk::MemoryRequirements,
+        resource: Resource,
+        desc: &AllocationDesc,
+        prefers_dedicated: bool,
+    ) -> RendererResult<Allocation> {
+        let memory_type_index = self.memory_type_index(requirements, desc)?;
+        let block_size = self.block_size(memory_type_index);
+
+        if wants_dedicated(desc, prefers_dedicated, requirements.size, block_size) {
+            match self.allocate_dedicated(device, requirements, resource, memory_type_index) {
+                Ok(allocation) => return Ok(allocation),
+                // Sub-allocation doesn't need a new `VkDeviceMemory` if a block has space
+                Err(RendererError::Vulkan(vk::Result::ERROR_TOO_MANY_OBJECTS))
+                    if requirements.size <= block_size => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        let pool_key = PoolKey {
+            memory_type_index,
+            // Without a granularity requirement, buffers and images can share blocks
+            linear: matches!(resource, Resource::Buffer(_)) || self.buffer_image_granularity <= 1,
+            strategy: desc.strategy,
+        };
+        let alignment = requirements.alignment.max(1);
+        self.allocate_from_pool(device, pool_key, block_size, requirements.size, alignment)
+            .map_err(|err| {
+                log::debug!("Failed to allocate memory for {}: {}", desc.name, err);
+                err
+            })
+    }
+
+    fn allocate_dedicated(
+        &mut self,
+        device: &Device,
+        requirements: &vk::MemoryRequirements,
+        resource: Resource,
+        memory_type_index: u32,
+    ) -> RendererResult<Allocation> {
+        let (memory, mapped) = self.allocate_device_memory(
+            device,
+            requirements.size,
+            memory_type_index,
+            Some(resource),
+        )?;
+        let stats = &mut self.stats[memory_type_index as usize];
+        stats.dedicated_count += 1;
+        stats.allocation_count += 1;
+        stats.used_bytes += requirements.size;
+        Ok(Allocation {
+            memory,
+            offset: 0,
+            size: requirements.size,
+            mapped: NonNull::new(mapped),
+            memory_type_index,
+            placement: Placement::Dedicated,
+        })
+    }
+
+    fn allocate_from_pool(
+        &mut self,
+        device: &Device,
+        pool_key: PoolKey,
+        block_size: vk::DeviceSize,
+        size: vk::DeviceSize,
+        alignment: vk::DeviceSize,
+    ) -> RendererResult<Allocation> {
+        if let Some(allocation) = self.allocate_from_blocks(pool_key, size, alignment) {
+            return Ok(allocation);
+        }
+
+        let pool = self.pools.entry(pool_key).or_insert_with(|| Pool {
+            block_size,
+            blocks: Vec::new(),
+            newest: None,
+        });
+        let block_size = pool.block_size;
+        let (memory, mapped) =
+            self.allocate_device_memory(device, block_size, pool_key.memory_type_index, None)?;
+        let mut block = MemoryBlock {
+            memory,
+            size: block_size,
+            mapped,
+            allocator: match pool_key.strategy {
+                AllocationStrategy::Buddy => BlockAllocator::Buddy(Buddy::new(block_size)),
+                AllocationStrategy::Linear => BlockAllocator::Linear { offset: 0, live: 0 },
+            },
+        };
+        let placement = block
+            .allocate(size, alignment)
+            .expect("Allocations that don't fit into a block are dedicated");
+        self.stats[pool_key.memory_type_index as usize].block_count += 1;
+
+        let pool = self.pools.get_mut(&pool_key).unwrap();
+        let index = pool.insert(block);
+        Ok(self.finish_block_allocation(pool_key, index, memory, mapped, size, placement))
+    }
+
+    /// Allocates from the existing blocks of a pool, `None` if a new block is needed
+    fn allocate_from_blocks(
+        &mut self,
+        pool_key: PoolKey,
+        size: vk::DeviceSize,
+        alignment: vk::DeviceSize,
+    ) -> Option<Allocation> {
+        let pool = self.pools.get_mut(&pool_key)?;
+        // Linear pools only ever allocate from their newest block, older ones drain over time
+        let candidates: Vec<usize> = match pool_key.strategy {
+            AllocationStrategy::Buddy => (0..pool.blocks.len()).collect(),
+            AllocationStrategy::Linear => pool.newest.into_iter().collect(),
+        };
+        for index in candidates {
+            if let Some(block) = &mut pool.blocks[index] {
+                if let Some(placement) = block.allocate(size, alignment) {
+                    let mapped = block.mapped;
+                    let memory = block.memory;
+                    return Some(self.finish_block_allocation(
+                        pool_key, index, memory, mapped, size, placement,
+                    ));
+                }
+            }
+        }
+        None
+    }
+
+    fn finish_block_allocation(
+        &mut self,
+        pool: PoolKey,
+        block: usize,
+        memory: vk::DeviceMemory,
+        mapped: *mut u8,
+        size: vk::DeviceSize,
+        (offset, order, used): (vk::DeviceSize, u32, vk::DeviceSize),
+    ) -> Allocation {
+        let stats = &mut self.stats[pool.memory_type_index as usize];
+        stats.allocation_count += 1;
+        stats.used_bytes += used;
+        Allocation {
+            memory,
+            offset,
+            size,
+            mapped: NonNull::new(mapped).map(|mapped| unsafe {
+                NonNull::new_unchecked(mapped.as_ptr().add(offset as usize))
+            }),
+            memory_type_index: pool.memory_type_index,
+            placement: Placement::Block { pool, block, order },
+        }
+    }
+
+    /// Returns the memory of a resource. Empty blocks are released, except for the last one of
+    /// each pool to avoid reallocating it over and over.
+    ///
+    /// # Safety
+    /// The resource bound to the allocation must have been destroyed, or at least no longer be
+    /// in use by the GPU.
+    pub unsafe fn free(&mut self, device: &Device, allocation: &Allocation) {
+        if let Some(memory) = self.release(allocation) {
+            device.free_memory(memory, None);
+        }
+    }
+
+    /// Updates the blocks and stats for a freed allocation. Returns the `VkDeviceMemory` that
+    /// isn't needed anymore, if any.
+    fn release(&mut self, allocation: &Allocation) -> Option<vk::DeviceMemory> {
+        let stats = &mut self.stats[allocation.memory_type_index as usize];
+        stats.allocation_count -= 1;
+        match allocation.placement {
+            Placement::Dedicated => {
+                stats.dedicated_count -= 1;
+                stats.device_memory_count -= 1;
+                stats.used_bytes -= allocation.size;
+                stats.reserved_bytes -= allocation.size;
+                Some(allocation.memory)
+            }
+            Placement::Block { pool, block, order } => {
+                let pool = self
+                    .pools
+                    .get_mut(&pool)
+                    .expect("Allocations come from existing pools");
+                let live_blocks = pool.blocks.iter().filter(|block| block.is_some()).count();
+                let memory_block = pool.blocks[block]
+                    .as_mut()
+                    .expect("Blocks aren't freed while they have allocations");
+                stats.used_bytes -= memory_block.free(allocation.offset, order);
+                if memory_block.is_empty() && live_blocks > 1 {
+                    stats.block_count -= 1;
+                    stats.device_memory_count -= 1;
+                    stats.reserved_bytes -= memory_block.size;
+                    let memory = memory_block.memory;
+                    pool.blocks[block] = None;
+                    if pool.newest == Some(block) {
+                        pool.newest = None;
+                    }
+                    Some(memory)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Releases all blocks. Allocations that were never freed are reported as leaks.
+    ///
+    /// # Safety
+    /// No memory from this allocator may be in use anymore.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        let stats = self.stats();
+        if stats.allocation_count > 0 {
+            log::warn!(
+                "{} allocation(s) with {} bytes were not freed",
+                stats.allocation_count,
+                stats.used_bytes
+            );
+        }
+        for (_, pool) in self.pools.drain() {
+            for block in pool.blocks.into_iter().flatten() {
+                device.free_memory(block.memory, None);
+            }
+        }
+        for stats in &mut self.stats {
+            *stats = AllocatorStats::default();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK_SIZE: vk::DeviceSize = 1 << 20;
+
+    fn buddy_block(size: vk::DeviceSize) -> MemoryBlock {
+        MemoryBlock {
+            memory: vk::DeviceMemory::null(),
+            size,
+            mapped: std::ptr::null_mut(),
+            allocator: BlockAllocator::Buddy(Buddy::new(size)),
+        }
+    }
+
+    fn linear_block(size: vk::DeviceSize) -> MemoryBlock {
+        MemoryBlock {
+            memory: vk::DeviceMemory::null(),
+            size,
+            mapped: std::ptr::null_mut(),
+            allocator: BlockAllocator::Linear { offset: 0, live: 0 },
+        }
+    }
+
+    fn allocator(heap_size: vk::DeviceSize) -> Allocator {
+        let mut memory_properties = vk::PhysicalDeviceMemoryProperties {
+            memory_type_count: 1,
+            memory_heap_count: 1,
+            ..Default::default()
+        };
+        memory_properties.memory_types[0].property_flags = vk::MemoryPropertyFlags::DEVICE_LOCAL;
+        memory_properties.memory_heaps[0].size = heap_size;
+        let limits = vk::PhysicalDeviceLimits {
+            buffer_image_granularity: 1,
+            max_memory_allocation_count: 4096,
+            ..Default::default()
+        };
+        Allocator::new(
+            memory_properties,
+            &limits,
+            AllocatorConfig {
+                block_size: BLOCK_SIZE,
+            },
+        )
+    }
+
+    /// Adds a pool with `blocks` as if they had been allocated from the driver
+    fn add_pool(
+        allocator: &mut Allocator,
+        strategy: AllocationStrategy,
+        blocks: Vec<MemoryBlock>,
+    ) -> PoolKey {
+        let key = PoolKey {
+            memory_type_index: 0,
+            linear: true,
+            strategy,
+        };
+        let stats = &mut allocator.stats[0];
+        for block in &blocks {
+            stats.device_memory_count += 1;
+            stats.block_count += 1;
+            stats.reserved_bytes += block.size;
+        }
+        allocator.pools.insert(
+            key,
+            Pool {
+                block_size: BLOCK_SIZE,
+                newest: blocks.len().checked_sub(1),
+                blocks: blocks.into_iter().map(Some).collect(),
+            },
+        );
+        key
+    }
+
+    #[test]
+    fn buddy_merges_back_into_one_free_block() {
+        let mut buddy = Buddy::new(4096);
+        let allocations: Vec<_> = [256, 1000, 256, 512]
+            .iter()
+            .map(|&size| buddy.allocate(size, 1).unwrap())
+            .collect();
+        assert_eq!(allocations[0], (0, 0));
+        assert_eq!(allocations[1], (1024, 2));
+        assert_eq!(allocations[2], (256, 0));
+        assert_eq!(allocations[3], (512, 1));
+        assert!(!buddy.is_empty());
+
+        for &(offset, order) in allocations.iter().rev() {
+            buddy.free(offset, order);
+        }
+        assert!(buddy.is_empty());
+        let free_count: usize = buddy.free.iter().map(BTreeSet::len).sum();
+        assert_eq!(free_count, 1);
+    }
+
+    #[test]
+    fn buddy_fails_when_full() {
+        let mut buddy = Buddy::new(1024);
+        assert_eq!(buddy.allocate(2048, 1), None);
+        let whole = buddy.allocate(1024, 1).unwrap();
+        assert_eq!(buddy.allocate(256, 1), None);
+        buddy.free(whole.0, whole.1);
+        assert!(buddy.allocate(256, 1).is_some());
+    }
+
+    #[test]
+    fn allocations_are_aligned() {
+        let mut buddy = buddy_block(BLOCK_SIZE);
+        let mut linear = linear_block(BLOCK_SIZE);
+        for &(size, alignment) in &[(100, 1), (300, 256), (64, 4096), (5000, 1024), (1, 65536)] {
+            for block in [&mut buddy, &mut linear].iter_mut() {
+                let (offset, _, used) = block.allocate(size, alignment).unwrap();
+                assert_eq!(
+                    offset % alignment,
+                    0,
+                    "size {} alignment {}",
+                    size,
+                    alignment
+                );
+                assert!(used >= size);
+            }
+        }
+    }
+
+    #[test]
+    fn linear_block_resets_once_everything_is_freed() {
+        let mut block = linear_block(4096);
+        let (first, _, first_used) = block.allocate(100, 1).unwrap();
+        let (second, _, second_used) = block.allocate(1000, 256).unwrap();
+        assert_eq!((first, second), (0, 256));
+        assert_eq!(first_used + second_used, 1256);
+        assert_eq!(block.allocate(3000, 1), None);
+
+        // Nothing is reclaimed while the block still has allocations
+        assert_eq!(block.free(first, 0), 0);
+        assert!(!block.is_empty());
+        assert_eq!(block.allocate(3000, 1), None);
+
+        assert_eq!(block.free(second, 0), 1256);
+        assert!(block.is_empty());
+        assert_eq!(block.allocate(4096, 1).map(|(offset, ..)| offset), Some(0));
+    }
+
+    #[test]
+    fn large_resources_are_dedicated() {
+        let desc = AllocationDesc {
+            name: "test",
+            location: MemoryLocation::GpuOnly,
+            strategy: AllocationStrategy::Buddy,
+            dedicated: false,
+        };
+        assert!(!wants_dedicated(&desc, false, BLOCK_SIZE / 2, BLOCK_SIZE));
+        assert!(wants_dedicated(
+            &desc,
+            false,
+            BLOCK_SIZE / 2 + 1,
+            BLOCK_SIZE
+        ));
+        assert!(wants_dedicated(&desc, true, 256, BLOCK_SIZE));
+        let desc = AllocationDesc {
+            dedicated: true,
+            ..desc
+        };
+        assert!(wants_dedicated(&desc, false, 256, BLOCK_SIZE));
+    }
+
+    #[test]
+    fn block_size_is_limited_by_the_heap() {
+        assert_eq!(allocator(1 << 30).block_size(0), BLOCK_SIZE);
+        // An eighth of the heap, rounded down to a power of two
+        assert_eq!(allocator(3 << 20).block_size(0), 256 << 10);
+        assert_eq!(allocator(1024).block_size(0), MIN_BUDDY_SIZE);
+    }
+
+    #[test]
+    fn stats_are_updated_on_free() {
+        let mut allocator = allocator(1 << 30);
+        let key = add_pool(
+            &mut allocator,
+            AllocationStrategy::Buddy,
+            vec![buddy_block(BLOCK_SIZE), buddy_block(BLOCK_SIZE)],
+        );
+        let first = allocator.allocate_from_blocks(key, 1000, 1).unwrap();
+        let second = allocator.allocate_from_blocks(key, 256, 1).unwrap();
+        let stats = allocator.stats();
+        assert_eq!(stats.allocation_count, 2);
+        assert_eq!(stats.used_bytes, 1024 + 256);
+        assert_eq!(stats.reserved_bytes, 2 * BLOCK_SIZE);
+
+        assert_eq!(allocator.release(&first), None);
+        let stats = allocator.stats();
+        assert_eq!(stats.allocation_count, 1);
+        assert_eq!(stats.used_bytes, 256);
+
+        // The second block was never used, so the first one can be released once it is empty
+        allocator.release(&second).unwrap();
+        assert_eq!(
+            allocator.stats(),
+            AllocatorStats {
+                device_memory_count: 1,
+                block_count: 1,
+                reserved_bytes: BLOCK_SIZE,
+                ..Default::default()
+            }
+        );
+        assert_eq!(allocator.heap_stats(), vec![allocator.stats()]);
+    }
+
+    #[test]
+    fn linear_pools_only_use_their_newest_block() {
+        let mut allocator = allocator(1 << 30);
+        let key = add_pool(
+            &mut allocator,
+            AllocationStrategy::Linear,
+            vec![linear_block(BLOCK_SIZE), linear_block(BLOCK_SIZE)],
+        );
+        let allocation = allocator.allocate_from_blocks(key, 1000, 1).unwrap();
+        assert!(matches!(
+            allocation.placement,
+            Placement::Block { block: 1, .. }
+        ));
+        assert!(allocator.allocate_from_blocks(key, BLOCK_SIZE, 1).is_none());
+        allocator.release(&allocation);
+        assert_eq!(allocator.stats().used_bytes, 0);
+    }
+
+    #[test]
+    fn linear_pools_reuse_the_slots_of_released_blocks() {
+        let mut allocator = allocator(1 << 30);
+        let key = add_pool(
+            &mut allocator,
+            AllocationStrategy::Linear,
+            vec![linear_block(BLOCK_SIZE), linear_block(BLOCK_SIZE)],
+        );
+        let pool = allocator.pools.get_mut(&key).unwrap();
+        // As if the older block had drained and been released
+        pool.blocks[0] = None;
+        assert_eq!(pool.insert(linear_block(BLOCK_SIZE)), 0);
+        assert_eq!(pool.blocks.len(), 2);
+
+        let allocation = allocator.allocate_from_blocks(key, 1000, 1).unwrap();
+        assert!(matches!(
+            allocation.placement,
+            Placement::Block { block: 0, .. }
+        ));
+    }
+}
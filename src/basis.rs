@@ -0,0 +1,436 @@
+use ash::vk;
+
+use crate::error::{RendererError, RendererResult};
+
+/// The channels of a Basis Universal texture
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BasisChannels {
+    Rgb,
+    Rgba,
+    /// Stored as RRR, for example roughness or occlusion
+    Red,
+    /// Stored as RRRG or RG, for example normal maps
+    RedGreen,
+}
+
+/// The two ways Basis Universal encodes textures
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BasisEncoding {
+    /// 4x4 blocks of 16 bytes that are transcoded one level at a time
+    Uastc,
+    /// Supercompressed with BasisLZ, with codebooks shared by all levels in the supercompression
+    /// global data. A second slice per level stores alpha or green.
+    Etc1s,
+}
+
+/// What a KTX2 file with Basis Universal data stores, according to its data format descriptor
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BasisFormat {
+    pub encoding: BasisEncoding,
+    pub channels: BasisChannels,
+    pub srgb: bool,
+}
+
+impl BasisFormat {
+    /// Reads the data format descriptor of a KTX2 file, `None` if it doesn't describe UASTC or
+    /// ETC1S
+    pub fn from_ktx2(reader: &ktx2::Reader<&[u8]>) -> Option<BasisFormat> {
+        let block = reader
+            .dfd_blocks()
+            .find_map(|block| ktx2::DfdBlockBasic::parse(block.data).ok())?;
+        let mut channel_types = block.sample_information().map(|sample| sample.channel_type);
+        let (encoding, channels) = match block.header.color_model? {
+            ktx2::ColorModel::UASTC => {
+                let channels = match channel_types.next()? {
+                    0 => BasisChannels::Rgb,
+                    3 => BasisChannels::Rgba,
+                    4 => BasisChannels::Red,
+                    5 | 6 => BasisChannels::RedGreen,
+                    _ => return None,
+                };
+                (BasisEncoding::Uastc, channels)
+            }
+            // The second slice's channel is AAA or GGG
+            ktx2::ColorModel::ETC1S => {
+                let channels = match (channel_types.next()?, channel_types.next()) {
+                    (0, None) => BasisChannels::Rgb,
+                    (0, Some(15)) => BasisChannels::Rgba,
+                    (3, None) => BasisChannels::Red,
+                    (3, Some(4)) => BasisChannels::RedGreen,
+                    _ => return None,
+                };
+                (BasisEncoding::Etc1s, channels)
+            }
+            _ => return None,
+        };
+        Some(BasisFormat {
+            encoding,
+            channels,
+            srgb: block.header.transfer_function == Some(ktx2::TransferFunction::SRGB),
+        })
+    }
+
+    /// The format to transcode into: the first one of ASTC, BC7 and ETC2 for colors, BC4 and
+    /// EAC for red or BC5 and EAC for red and green that `supports_format`. If the device
+    /// supports none of them, the first one is decompressed later.
+    pub fn target_format(self, supports_format: impl Fn(vk::Format) -> bool) -> vk::Format {
+        let candidates: &[vk::Format] = match (self.channels, self.srgb) {
+            (BasisChannels::Rgb | BasisChannels::Rgba, false) => &[
+                vk::Format::ASTC_4X4_UNORM_BLOCK,
+                vk::Format::BC7_UNORM_BLOCK,
+                vk::Format::ETC2_R8G8B8A8_UNORM_BLOCK,
+            ],
+            (BasisChannels::Rgb | BasisChannels::Rgba, true) => &[
+                vk::Format::ASTC_4X4_SRGB_BLOCK,
+                vk::Format::BC7_SRGB_BLOCK,
+                vk::Format::ETC2_R8G8B8A8_SRGB_BLOCK,
+            ],
+            (BasisChannels::Red, _) => {
+                &[vk::Format::BC4_UNORM_BLOCK, vk::Format::EAC_R11_UNORM_BLOCK]
+            }
+            (BasisChannels::RedGreen, _) => &[
+                vk::Format::BC5_UNORM_BLOCK,
+                vk::Format::EAC_R11G11_UNORM_BLOCK,
+            ],
+        };
+        candidates
+            .iter()
+            .copied()
+            .find(|&format| supports_format(format))
+            .unwrap_or(candidates[0])
+    }
+
+    /// Transcodes a level of `extent` in UASTC blocks into `format`, one of the formats of
+    /// `target_format`. `name` is used in error messages.
+    #[cfg(feature = "basis")]
+    pub fn transcode_uastc(
+        self,
+        name: &str,
+        format: vk::Format,
+        extent: vk::Extent2D,
+        data: &[u8],
+    ) -> RendererResult<Vec<u8>> {
+        use basis_universal::{
+            DecodeFlags, LowLevelUastcTranscoder, SliceParametersUastc, TranscoderBlockFormat,
+        };
+
+        let block_format = match format {
+            vk::Format::ASTC_4X4_UNORM_BLOCK | vk::Format::ASTC_4X4_SRGB_BLOCK => {
+                TranscoderBlockFormat::ASTC_4x4
+            }
+            vk::Format::BC7_UNORM_BLOCK | vk::Format::BC7_SRGB_BLOCK => TranscoderBlockFormat::BC7,
+            vk::Format::ETC2_R8G8B8A8_UNORM_BLOCK | vk::Format::ETC2_R8G8B8A8_SRGB_BLOCK => {
+                TranscoderBlockFormat::ETC2_RGBA
+            }
+            vk::Format::BC4_UNORM_BLOCK => TranscoderBlockFormat::BC4,
+            vk::Format::EAC_R11_UNORM_BLOCK => TranscoderBlockFormat::ETC2_EAC_R11,
+            vk::Format::BC5_UNORM_BLOCK => TranscoderBlockFormat::BC5,
+            vk::Format::EAC_R11G11_UNORM_BLOCK => TranscoderBlockFormat::ETC2_EAC_RG11,
+            _ => {
+                return Err(RendererError::Unsupported(format!(
+                    "Can't transcode {} to {:?}",
+                    name, format
+                )))
+            }
+        };
+        basis_universal::transcoder_init();
+        let parameters = SliceParametersUastc {
+            num_blocks_x: extent.width.div_ceil(4),
+            num_blocks_y: extent.height.div_ceil(4),
+            has_alpha: self.channels == BasisChannels::Rgba,
+            original_width: extent.width,
+            original_height: extent.height,
+        };
+        LowLevelUastcTranscoder::new()
+            .transcode_slice(data, parameters, DecodeFlags::HIGH_QUALITY, block_format)
+            .map_err(|err| {
+                RendererError::Image(format!(
+                    "Can't transcode {} to {:?}: {:?}",
+                    name, format, err
+                ))
+            })
+    }
+
+    /// Transcodes all levels of a 2D ETC1S texture into `format`, one of the formats of
+    /// `target_format`, starting with the largest one. `name` is used in error messages.
+    #[cfg(feature = "basis")]
+    pub fn transcode_etc1s(
+        self,
+        name: &str,
+        format: vk::Format,
+        reader: &ktx2::Reader<&[u8]>,
+    ) -> RendererResult<Vec<Vec<u8>>> {
+        use basis_universal::{
+            DecodeFlags, TranscodeParameters, Transcoder, TranscoderTextureFormat,
+        };
+
+        let texture_format = match format {
+            vk::Format::ASTC_4X4_UNORM_BLOCK | vk::Format::ASTC_4X4_SRGB_BLOCK => {
+                TranscoderTextureFormat::ASTC_4x4_RGBA
+            }
+            vk::Format::BC7_UNORM_BLOCK | vk::Format::BC7_SRGB_BLOCK => {
+                TranscoderTextureFormat::BC7_RGBA
+            }
+            vk::Format::ETC2_R8G8B8A8_UNORM_BLOCK | vk::Format::ETC2_R8G8B8A8_SRGB_BLOCK => {
+                TranscoderTextureFormat::ETC2_RGBA
+            }
+            vk::Format::BC4_UNORM_BLOCK => TranscoderTextureFormat::BC4_R,
+            vk::Format::EAC_R11_UNORM_BLOCK => TranscoderTextureFormat::ETC2_EAC_R11,
+            vk::Format::BC5_UNORM_BLOCK => TranscoderTextureFormat::BC5_RG,
+            vk::Format::EAC_R11G11_UNORM_BLOCK => TranscoderTextureFormat::ETC2_EAC_RG11,
+            _ => {
+                return Err(RendererError::Unsupported(format!(
+                    "Can't transcode {} to {:?}",
+                    name, format
+                )))
+            }
+        };
+        let invalid = |reason: String| {
+            RendererError::Image(format!(
+                "Can't transcode {} to {:?}: {}",
+                name, format, reason
+            ))
+        };
+        let file = etc1s_basis_file(reader, self).map_err(invalid)?;
+        let mut transcoder = Transcoder::new();
+        transcoder
+            .prepare_transcoding(&file)
+            .map_err(|()| invalid("invalid codebooks".to_string()))?;
+        (0..reader.levels().len() as u32)
+            .map(|level| {
+                let parameters = TranscodeParameters {
+                    image_index: 0,
+                    level_index: level,
+                    decode_flags: Some(DecodeFlags::HIGH_QUALITY),
+                    ..TranscodeParameters::default()
+                };
+                transcoder
+                    .transcode_image_level(&file, texture_format, parameters)
+                    .map_err(|err| invalid(format!("{:?} in level {}", err, level)))
+            })
+            .collect()
+    }
+
+    /// Transcoding needs the `basis` feature, without it this always fails
+    #[cfg(not(feature = "basis"))]
+    pub fn transcode_uastc(
+        self,
+        name: &str,
+        _format: vk::Format,
+        _extent: vk::Extent2D,
+        _data: &[u8],
+    ) -> RendererResult<Vec<u8>> {
+        Err(missing_basis_feature(name))
+    }
+
+    /// Transcoding needs the `basis` feature, without it this always fails
+    #[cfg(not(feature = "basis"))]
+    pub fn transcode_etc1s(
+        self,
+        name: &str,
+        _format: vk::Format,
+        _reader: &ktx2::Reader<&[u8]>,
+    ) -> RendererResult<Vec<Vec<u8>>> {
+        Err(missing_basis_feature(name))
+    }
+}
+
+#[cfg(not(feature = "basis"))]
+fn missing_basis_feature(name: &str) -> RendererError {
+    RendererError::Unsupported(format!(
+        "{} needs Basis Universal transcoding, build with the basis feature to load it",
+        name
+    ))
+}
+
+/// The CRC-16 that .basis files protect their header and data with
+#[cfg(feature = "basis")]
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = !0u16;
+    for &byte in data {
+        let q = u16::from(byte) ^ (crc >> 8);
+        let k = (q >> 4) ^ q;
+        crc = (crc << 8) ^ k ^ (k << 5) ^ (k << 12);
+    }
+    !crc
+}
+
+/// Repackages the levels of a KTX2 file with ETC1S data into the .basis file that
+/// `basis_universal::Transcoder` reads, one image with a slice per level and channel. KTX2 keeps
+/// the codebooks, the Huffman tables and where each level's slices are in the supercompression
+/// global data.
+#[cfg(feature = "basis")]
+fn etc1s_basis_file(reader: &ktx2::Reader<&[u8]>, format: BasisFormat) -> Result<Vec<u8>, String> {
+    const HEADER_SIZE: usize = 77;
+    const SLICE_DESC_SIZE: usize = 23;
+    const GLOBAL_HEADER_SIZE: usize = 20;
+    const IMAGE_DESC_SIZE: usize = 20;
+
+    let global_data = reader.supercompression_global_data();
+    let read = |offset: usize, size: usize| -> Result<u32, String> {
+        let bytes = global_data
+            .get(offset..offset + size)
+            .ok_or_else(|| "the global data is truncated".to_string())?;
+        Ok(bytes
+            .iter()
+            .rev()
+            .fold(0, |value, &byte| value << 8 | u32::from(byte)))
+    };
+    let endpoint_count = read(0, 2)?;
+    let selector_count = read(2, 2)?;
+    let endpoints_length = read(4, 4)? as usize;
+    let selectors_length = read(8, 4)? as usize;
+    let tables_length = read(12, 4)? as usize;
+
+    let two_slices = matches!(
+        format.channels,
+        BasisChannels::Rgba | BasisChannels::RedGreen
+    );
+    let header = reader.header();
+    let mut slices = Vec::new();
+    for (level, level_data) in reader.levels().enumerate() {
+        // The offsets and lengths of the slices for color and alpha or green
+        let image_desc = GLOBAL_HEADER_SIZE + level * IMAGE_DESC_SIZE;
+        let desc_offsets: &[usize] = if two_slices { &[4, 12] } else { &[4] };
+        for (index, &desc_offset) in desc_offsets.iter().enumerate() {
+            let start = read(image_desc + desc_offset, 4)? as usize;
+            let length = read(image_desc + desc_offset + 4, 4)? as usize;
+            let slice = level_data
+                .data
+                .get(start..start + length)
+                .ok_or_else(|| format!("a slice of level {} is out of bounds", level))?;
+            slices.push((level, index == 1, slice));
+        }
+    }
+    let codebooks_start = GLOBAL_HEADER_SIZE + reader.levels().len() * IMAGE_DESC_SIZE;
+    let codebooks = global_data
+        .get(codebooks_start..codebooks_start + endpoints_length + selectors_length + tables_length)
+        .ok_or_else(|| "the codebooks are truncated".to_string())?;
+
+    let put = |bytes: &mut Vec<u8>, value: usize, size: usize| {
+        bytes.extend_from_slice(&(value as u64).to_le_bytes()[..size]);
+    };
+    let codebooks_offset = HEADER_SIZE + slices.len() * SLICE_DESC_SIZE;
+    let mut data = Vec::new();
+    let mut slice_offset = codebooks_offset + codebooks.len();
+    for &(level, alpha, slice) in &slices {
+        let width = (header.pixel_width >> level).max(1) as usize;
+        let height = (header.pixel_height >> level).max(1) as usize;
+        if width > 0xffff || height > 0xffff {
+            return Err(format!("{}x{} is too large", width, height));
+        }
+        put(&mut data, 0, 3);
+        put(&mut data, level, 1);
+        put(&mut data, alpha as usize, 1);
+        put(&mut data, width, 2);
+        put(&mut data, height, 2);
+        put(&mut data, width.div_ceil(4), 2);
+        put(&mut data, height.div_ceil(4), 2);
+        put(&mut data, slice_offset, 4);
+        put(&mut data, slice.len(), 4);
+        put(&mut data, crc16(slice) as usize, 2);
+        slice_offset += slice.len();
+    }
+    data.extend_from_slice(codebooks);
+    for &(_, _, slice) in &slices {
+        data.extend_from_slice(slice);
+    }
+
+    // ETC1S, alpha slices and sRGB
+    let flags = 1 | if two_slices { 4 } else { 0 } | if format.srgb { 16 } else { 0 };
+    let mut fields = Vec::with_capacity(HEADER_SIZE);
+    put(&mut fields, data.len(), 4);
+    put(&mut fields, crc16(&data) as usize, 2);
+    put(&mut fields, slices.len(), 3);
+    put(&mut fields, 1, 3);
+    put(&mut fields, 0, 1);
+    put(&mut fields, flags, 2);
+    // A 2D texture without a frame rate or user data
+    put(&mut fields, 0, 1);
+    put(&mut fields, 0, 3);
+    for _ in 0..3 {
+        put(&mut fields, 0, 4);
+    }
+    put(&mut fields, endpoint_count as usize, 2);
+    put(&mut fields, codebooks_offset, 4);
+    put(&mut fields, endpoints_length, 3);
+    put(&mut fields, selector_count as usize, 2);
+    put(&mut fields, codebooks_offset + endpoints_length, 4);
+    put(&mut fields, selectors_length, 3);
+    put(
+        &mut fields,
+        codebooks_offset + endpoints_length + selectors_length,
+        4,
+    );
+    put(&mut fields, tables_length, 4);
+    put(&mut fields, HEADER_SIZE, 4);
+    // No extended data
+    put(&mut fields, 0, 8);
+
+    let mut file = Vec::with_capacity(HEADER_SIZE + data.len());
+    file.extend_from_slice(b"sB");
+    put(&mut file, 0x13, 2);
+    put(&mut file, HEADER_SIZE, 2);
+    put(&mut file, crc16(&fields) as usize, 2);
+    file.extend_from_slice(&fields);
+    file.extend_from_slice(&data);
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn etc1s_format_is_read_from_the_data_format_descriptor() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/checker_etc1s.ktx2");
+        let bytes = std::fs::read(path).unwrap();
+        let reader = ktx2::Reader::new(bytes.as_slice()).unwrap();
+        let format = BasisFormat::from_ktx2(&reader).unwrap();
+        assert_eq!(
+            format,
+            BasisFormat {
+                encoding: BasisEncoding::Etc1s,
+                channels: BasisChannels::Rgb,
+                srgb: true,
+            }
+        );
+    }
+
+    #[test]
+    fn target_format_prefers_supported_formats() {
+        let color = BasisFormat {
+            encoding: BasisEncoding::Uastc,
+            channels: BasisChannels::Rgba,
+            srgb: true,
+        };
+        let bc = |format: vk::Format| {
+            [vk::Format::BC7_SRGB_BLOCK, vk::Format::BC5_UNORM_BLOCK].contains(&format)
+        };
+        assert_eq!(color.target_format(bc), vk::Format::BC7_SRGB_BLOCK);
+        assert_eq!(
+            color.target_format(|_| true),
+            vk::Format::ASTC_4X4_SRGB_BLOCK
+        );
+
+        let normals = BasisFormat {
+            encoding: BasisEncoding::Etc1s,
+            channels: BasisChannels::RedGreen,
+            srgb: false,
+        };
+        assert_eq!(normals.target_format(bc), vk::Format::BC5_UNORM_BLOCK);
+    }
+
+    #[test]
+    fn target_format_falls_back_to_decompressible_format() {
+        let red = BasisFormat {
+            encoding: BasisEncoding::Uastc,
+            channels: BasisChannels::Red,
+            srgb: false,
+        };
+        let format = red.target_format(|_| false);
+        assert_eq!(format, vk::Format::BC4_UNORM_BLOCK);
+        assert!(crate::decompress::decompressed_format(format).is_some());
+    }
+}
@@ -0,0 +1,347 @@
+use ash::{vk, Instance};
+use std::io::Read;
+use std::path::Path;
+
+use ktx2::SupercompressionScheme;
+
+use crate::basis::{BasisEncoding, BasisFormat};
+use crate::error::{RendererError, RendererResult};
+use crate::mipmap::{mip_level_count, mip_level_extent};
+
+/// The size in texels and in bytes of the blocks that a format stores its texels in. Formats
+/// that aren't block-compressed have 1x1 blocks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockLayout {
+    pub width: u32,
+    pub height: u32,
+    pub bytes: u32,
+}
+
+impl BlockLayout {
+    /// The layout of `format`, `None` if textures can't be loaded in it
+    pub fn of(format: vk::Format) -> Option<BlockLayout> {
+        let layout = |width, height, bytes| {
+            Some(BlockLayout {
+                width,
+                height,
+                bytes,
+            })
+        };
+        match format {
+            vk::Format::R8G8B8A8_UNORM | vk::Format::R8G8B8A8_SRGB => layout(1, 1, 4),
+            vk::Format::BC1_RGB_UNORM_BLOCK
+            | vk::Format::BC1_RGB_SRGB_BLOCK
+            | vk::Format::BC1_RGBA_UNORM_BLOCK
+            | vk::Format::BC1_RGBA_SRGB_BLOCK
+            | vk::Format::BC4_UNORM_BLOCK
+            | vk::Format::BC4_SNORM_BLOCK
+            | vk::Format::ETC2_R8G8B8_UNORM_BLOCK
+            | vk::Format::ETC2_R8G8B8_SRGB_BLOCK
+            | vk::Format::ETC2_R8G8B8A1_UNORM_BLOCK
+            | vk::Format::ETC2_R8G8B8A1_SRGB_BLOCK
+            | vk::Format::EAC_R11_UNORM_BLOCK
+            | vk::Format::EAC_R11_SNORM_BLOCK => layout(4, 4, 8),
+            vk::Format::BC2_UNORM_BLOCK
+            | vk::Format::BC2_SRGB_BLOCK
+            | vk::Format::BC3_UNORM_BLOCK
+            | vk::Format::BC3_SRGB_BLOCK
+            | vk::Format::BC5_UNORM_BLOCK
+            | vk::Format::BC5_SNORM_BLOCK
+            | vk::Format::BC6H_UFLOAT_BLOCK
+            | vk::Format::BC6H_SFLOAT_BLOCK
+            | vk::Format::BC7_UNORM_BLOCK
+            | vk::Format::BC7_SRGB_BLOCK
+            | vk::Format::ETC2_R8G8B8A8_UNORM_BLOCK
+            | vk::Format::ETC2_R8G8B8A8_SRGB_BLOCK
+            | vk::Format::EAC_R11G11_UNORM_BLOCK
+            | vk::Format::EAC_R11G11_SNORM_BLOCK => layout(4, 4, 16),
+            vk::Format::ASTC_4X4_UNORM_BLOCK | vk::Format::ASTC_4X4_SRGB_BLOCK => layout(4, 4, 16),
+            vk::Format::ASTC_5X4_UNORM_BLOCK | vk::Format::ASTC_5X4_SRGB_BLOCK => layout(5, 4, 16),
+            vk::Format::ASTC_5X5_UNORM_BLOCK | vk::Format::ASTC_5X5_SRGB_BLOCK => layout(5, 5, 16),
+            vk::Format::ASTC_6X5_UNORM_BLOCK | vk::Format::ASTC_6X5_SRGB_BLOCK => layout(6, 5, 16),
+            vk::Format::ASTC_6X6_UNORM_BLOCK | vk::Format::ASTC_6X6_SRGB_BLOCK => layout(6, 6, 16),
+            vk::Format::ASTC_8X5_UNORM_BLOCK | vk::Format::ASTC_8X5_SRGB_BLOCK => layout(8, 5, 16),
+            vk::Format::ASTC_8X6_UNORM_BLOCK | vk::Format::ASTC_8X6_SRGB_BLOCK => layout(8, 6, 16),
+            vk::Format::ASTC_8X8_UNORM_BLOCK | vk::Format::ASTC_8X8_SRGB_BLOCK => layout(8, 8, 16),
+            vk::Format::ASTC_10X5_UNORM_BLOCK | vk::Format::ASTC_10X5_SRGB_BLOCK => {
+                layout(10, 5, 16)
+            }
+            vk::Format::ASTC_10X6_UNORM_BLOCK | vk::Format::ASTC_10X6_SRGB_BLOCK => {
+                layout(10, 6, 16)
+            }
+            vk::Format::ASTC_10X8_UNORM_BLOCK | vk::Format::ASTC_10X8_SRGB_BLOCK => {
+                layout(10, 8, 16)
+            }
+            vk::Format::ASTC_10X10_UNORM_BLOCK | vk::Format::ASTC_10X10_SRGB_BLOCK => {
+                layout(10, 10, 16)
+            }
+            vk::Format::ASTC_12X10_UNORM_BLOCK | vk::Format::ASTC_12X10_SRGB_BLOCK => {
+                layout(12, 10, 16)
+            }
+            vk::Format::ASTC_12X12_UNORM_BLOCK | vk::Format::ASTC_12X12_SRGB_BLOCK => {
+                layout(12, 12, 16)
+            }
+            _ => None,
+        }
+    }
+
+    /// The size of a tightly packed image of `extent`, including partially covered blocks
+    pub fn image_size(self, extent: vk::Extent2D) -> u64 {
+        let columns = u64::from(extent.width.div_ceil(self.width));
+        let rows = u64::from(extent.height.div_ceil(self.height));
+        columns * rows * u64::from(self.bytes)
+    }
+}
+
+/// Checks that `levels` are a valid mip chain of a texture of `extent` in `format`: one of the
+/// formats of `BlockLayout::of`, and each level tightly packed. `name` is used in the error.
+pub fn check_levels<L: AsRef<[u8]>>(
+    name: &str,
+    format: vk::Format,
+    extent: vk::Extent2D,
+    levels: &[L],
+) -> RendererResult<()> {
+    let invalid = |reason: String| RendererError::Image(format!("{} {}", name, reason));
+    let layout = BlockLayout::of(format)
+        .ok_or_else(|| invalid(format!("has the unsupported format {:?}", format)))?;
+    if extent.width == 0 || extent.height == 0 {
+        return Err(invalid(format!(
+            "has the size {}x{}",
+            extent.width, extent.height
+        )));
+    }
+    if levels.is_empty() || levels.len() as u32 > mip_level_count(extent) {
+        return Err(invalid(format!(
+            "has {} levels for a size of {}x{}",
+            levels.len(),
+            extent.width,
+            extent.height
+        )));
+    }
+    for (level, data) in levels.iter().enumerate() {
+        let size = layout.image_size(mip_level_extent(extent, level as u32));
+        if data.as_ref().len() as u64 != size {
+            return Err(invalid(format!(
+                "has {} bytes in level {} instead of {}",
+                data.as_ref().len(),
+                level,
+                size
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Whether the device can create textures of `format` and upload to them
+pub fn supports_texture_format(
+    instance: &Instance,
+    physical_device: vk::PhysicalDevice,
+    format: vk::Format,
+) -> bool {
+    let properties =
+        unsafe { instance.get_physical_device_format_properties(physical_device, format) };
+    properties
+        .optimal_tiling_features
+        .contains(vk::FormatFeatureFlags::SAMPLED_IMAGE | vk::FormatFeatureFlags::TRANSFER_DST)
+}
+
+/// A 2D image in a format that the GPU samples directly, usually block-compressed, with all of
+/// its mip levels
+#[derive(Clone, Debug)]
+pub struct CompressedTextureData {
+    pub extent: vk::Extent2D,
+    pub format: vk::Format,
+    /// Tightly packed levels, starting with the largest one
+    pub levels: Vec<Vec<u8>>,
+}
+
+impl CompressedTextureData {
+    /// Reads a KTX2 file with a 2D texture in one of the formats of `BlockLayout::of`. Levels
+    /// supercompressed with Zstandard or zlib are inflated.
+    ///
+    /// Basis Universal UASTC and ETC1S files are transcoded into a format that `supports_format`,
+    /// see `BasisFormat::target_format`, if the `basis` feature is enabled.
+    pub fn load(
+        path: &Path,
+        supports_format: impl Fn(vk::Format) -> bool,
+    ) -> RendererResult<CompressedTextureData> {
+        let bytes = std::fs::read(path)?;
+        CompressedTextureData::from_ktx2(&bytes, &path.to_string_lossy(), supports_format)
+    }
+
+    /// Reads the contents of a KTX2 file, see `load`. `name` is used in error messages.
+    pub fn from_ktx2(
+        bytes: &[u8],
+        name: &str,
+        supports_format: impl Fn(vk::Format) -> bool,
+    ) -> RendererResult<CompressedTextureData> {
+        let invalid =
+            |reason: String| RendererError::Image(format!("Can't load {}: {}", name, reason));
+        let reader = ktx2::Reader::new(bytes).map_err(|err| invalid(err.to_string()))?;
+        let header = reader.header();
+
+        let scheme = header.supercompression_scheme;
+        // Basis Universal data is stored without a format. UASTC comes in 4x4 blocks of 16
+        // bytes, ETC1S is supercompressed with BasisLZ.
+        let (format, basis) = match header.format {
+            Some(format) => (vk::Format::from_raw(format.value() as i32), None),
+            None => {
+                let basis = BasisFormat::from_ktx2(&reader).ok_or_else(|| {
+                    RendererError::Unsupported(format!("{} has an unsupported format", name))
+                })?;
+                (basis.target_format(supports_format), Some(basis))
+            }
+        };
+        let (etc1s, uastc) = match basis {
+            Some(basis) if basis.encoding == BasisEncoding::Etc1s => (Some(basis), None),
+            basis => (None, basis),
+        };
+        if etc1s.is_some() != (scheme == Some(SupercompressionScheme::BasisLZ)) {
+            return Err(invalid(
+                "ETC1S data must be supercompressed with BasisLZ and other data can't be"
+                    .to_string(),
+            ));
+        }
+        let stored_format = match uastc {
+            Some(_) => vk::Format::ASTC_4X4_UNORM_BLOCK,
+            None => format,
+        };
+        let layout = BlockLayout::of(stored_format).ok_or_else(|| {
+            RendererError::Unsupported(format!("{} has the unsupported format {:?}", name, format))
+        })?;
+        if header.pixel_height == 0
+            || header.pixel_depth > 1
+            || header.layer_count > 1
+            || header.face_count > 1
+        {
+            return Err(RendererError::Unsupported(format!(
+                "{} isn't a 2D texture",
+                name
+            )));
+        }
+
+        let extent = vk::Extent2D {
+            width: header.pixel_width,
+            height: header.pixel_height,
+        };
+        // The levels share codebooks and are transcoded together
+        if let Some(etc1s) = etc1s {
+            return Ok(CompressedTextureData {
+                extent,
+                format,
+                levels: etc1s.transcode_etc1s(name, format, &reader)?,
+            });
+        }
+        let levels = reader
+            .levels()
+            .enumerate()
+            .map(|(level, data)| {
+                let level_data = match scheme {
+                    None => data.data.to_vec(),
+                    Some(SupercompressionScheme::Zstandard) => {
+                        let mut level_data = Vec::new();
+                        ruzstd::decoding::StreamingDecoder::new(data.data)
+                            .map_err(|err| invalid(err.to_string()))?
+                            .read_to_end(&mut level_data)
+                            .map_err(|err| invalid(err.to_string()))?;
+                        level_data
+                    }
+                    Some(SupercompressionScheme::ZLIB) => {
+                        let mut level_data = Vec::new();
+                        flate2::read::ZlibDecoder::new(data.data)
+                            .read_to_end(&mut level_data)
+                            .map_err(|err| invalid(err.to_string()))?;
+                        level_data
+                    }
+                    Some(scheme) => {
+                        return Err(RendererError::Unsupported(format!(
+                            "{} uses the unsupported supercompression scheme {:?}",
+                            name, scheme
+                        )))
+                    }
+                };
+                let level_extent = mip_level_extent(extent, level as u32);
+                let size = layout.image_size(level_extent);
+                if level_data.len() as u64 != size {
+                    return Err(invalid(format!(
+                        "level {} has {} bytes instead of {}",
+                        level,
+                        level_data.len(),
+                        size
+                    )));
+                }
+                match uastc {
+                    Some(uastc) => uastc.transcode_uastc(name, format, level_extent, &level_data),
+                    None => Ok(level_data),
+                }
+            })
+            .collect::<RendererResult<Vec<_>>>()?;
+
+        Ok(CompressedTextureData {
+            extent,
+            format,
+            levels,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_must_match_the_mip_chain() {
+        let extent = vk::Extent2D {
+            width: 6,
+            height: 3,
+        };
+        let format = vk::Format::BC1_RGBA_UNORM_BLOCK;
+        // 2x1, 1x1 and 1x1 blocks
+        let levels = [vec![0; 16], vec![0; 8], vec![0; 8]];
+        assert!(check_levels("texture", format, extent, &levels).is_ok());
+        assert!(check_levels("texture", format, extent, &levels[..1]).is_ok());
+
+        let too_short = [vec![0; 16], vec![0; 4]];
+        assert!(matches!(
+            check_levels("texture", format, extent, &too_short),
+            Err(RendererError::Image(_))
+        ));
+        let too_many = [vec![0; 16], vec![0; 8], vec![0; 8], vec![0; 8]];
+        assert!(check_levels("texture", format, extent, &too_many).is_err());
+        let none: [Vec<u8>; 0] = [];
+        assert!(check_levels("texture", format, extent, &none).is_err());
+        assert!(check_levels("texture", vk::Format::D32_SFLOAT, extent, &levels).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "basis")]
+    fn etc1s_is_transcoded() {
+        use crate::decompress::decompress;
+        use crate::texture::TextureData;
+
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let data = CompressedTextureData::load(&fixtures.join("checker_etc1s.ktx2"), |format| {
+            format == vk::Format::BC7_SRGB_BLOCK
+        })
+        .unwrap();
+        assert_eq!(data.format, vk::Format::BC7_SRGB_BLOCK);
+        assert_eq!(data.levels.len(), 2);
+        check_levels("checker", data.format, data.extent, &data.levels).unwrap();
+
+        let expected = TextureData::load(&fixtures.join("checker.png")).unwrap();
+        let pixels = decompress(data.format, data.extent, &data.levels[0]);
+        for (channel, expected) in pixels.iter().zip(&expected.pixels) {
+            assert!((i32::from(*channel) - i32::from(*expected)).abs() <= 8);
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "basis"))]
+    fn etc1s_needs_the_basis_feature() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/checker_etc1s.ktx2");
+        assert!(matches!(
+            CompressedTextureData::load(&path, |_| true),
+            Err(RendererError::Unsupported(_))
+        ));
+    }
+}
@@ -0,0 +1,41 @@
+use ash::vk;
+
+use crate::allocator::AllocatorConfig;
+use crate::device::DeviceSelection;
+use crate::swapchain::{PresentPolicy, SurfaceFormatPolicy};
+use crate::validation::ValidationConfig;
+
+/// Options that control how the renderer sets up Vulkan.
+#[derive(Clone, Debug)]
+pub struct RendererConfig {
+    pub device: DeviceSelection,
+    pub surface_format: SurfaceFormatPolicy,
+    pub present_policy: PresentPolicy,
+    /// How many frames the CPU may record ahead of the GPU
+    pub frames_in_flight: usize,
+    /// Size of the host-visible buffer each frame in flight gets for uniforms and uploads
+    pub frame_upload_buffer_size: vk::DeviceSize,
+    /// Size of the staging ring that uploads to device-local resources go through. Larger
+    /// uploads get a temporary staging buffer.
+    pub staging_buffer_size: vk::DeviceSize,
+    pub validation: ValidationConfig,
+    pub allocator: AllocatorConfig,
+    /// Use render passes even if dynamic rendering is supported
+    pub disable_dynamic_rendering: bool,
+}
+
+impl Default for RendererConfig {
+    fn default() -> Self {
+        RendererConfig {
+            device: DeviceSelection::default(),
+            surface_format: SurfaceFormatPolicy::default(),
+            present_policy: PresentPolicy::default(),
+            frames_in_flight: 2,
+            frame_upload_buffer_size: 1 << 20,
+            staging_buffer_size: 16 << 20,
+            validation: ValidationConfig::default(),
+            allocator: AllocatorConfig::default(),
+            disable_dynamic_rendering: false,
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use ash::extensions::ext::DebugUtils;
+use ash::{vk, Device, Entry, Instance};
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::error::RendererResult;
+
+/// Counts messages reported by the debug messenger. Shared with the callback through its user
+/// data pointer, so it must stay at the same address while the messenger exists.
+#[derive(Default)]
+pub struct DebugCounters {
+    errors: AtomicUsize,
+    warnings: AtomicUsize,
+}
+
+impl DebugCounters {
+    pub fn errors(&self) -> usize {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    pub fn warnings(&self) -> usize {
+        self.warnings.load(Ordering::Relaxed)
+    }
+}
+
+unsafe fn cstr_or_empty<'a>(ptr: *const c_char) -> std::borrow::Cow<'a, str> {
+    if ptr.is_null() {
+        "".into()
+    } else {
+        CStr::from_ptr(ptr).to_string_lossy()
+    }
+}
+
+unsafe extern "system" fn vulkan_debug_utils_callback(
+    message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+    message_type: vk::DebugUtilsMessageTypeFlagsEXT,
+    p_callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT,
+    p_user_data: *mut std::ffi::c_void,
+) -> vk::Bool32 {
+    let level = match message_severity {
+        vk::DebugUtilsMessageSeverityFlagsEXT::ERROR => log::Level::Error,
+        vk::DebugUtilsMessageSeverityFlagsEXT::WARNING => log::Level::Warn,
+        vk::DebugUtilsMessageSeverityFlagsEXT::INFO => log::Level::Debug,
+        _ => log::Level::Trace,
+    };
+    if let Some(counters) = (p_user_data as *const DebugCounters).as_ref() {
+        match level {
+            log::Level::Error => counters.errors.fetch_add(1, Ordering::Relaxed),
+            log::Level::Warn => counters.warnings.fetch_add(1, Ordering::Relaxed),
+            _ => 0,
+        };
+    }
+
+    let callback_data = &*p_callback_data;
+    let objects = if callback_data.object_count == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(callback_data.p_objects, callback_data.object_count as usize)
+    };
+    let objects = objects
+        .iter()
+        .map(|object| {
+            let name = cstr_or_empty(object.p_object_name);
+            if name.is_empty() {
+                format!("{:?} {:#x}", object.object_type, object.object_handle)
+            } else {
+                format!(
+                    "{:?} {:#x} \"{}\"",
+                    object.object_type, object.object_handle, name
+                )
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let ty = format!("{:?}", message_type).to_lowercase();
+    let objects = if objects.is_empty() {
+        String::new()
+    } else {
+        format!(" [objects: {}]", objects.join(", "))
+    };
+    log::log!(
+        target: "vulkan",
+        level,
+        "[{}] {} ({:#010x}): {}{}",
+        ty,
+        cstr_or_empty(callback_data.p_message_id_name),
+        callback_data.message_id_number,
+        cstr_or_empty(callback_data.p_message),
+        objects
+    );
+    vk::FALSE
+}
+
+/// Info for a messenger that logs through the `log` crate under the `vulkan` target.
+///
+/// Chain it into the instance create info to also get messages from instance creation and
+/// destruction. `counters` must outlive the instance.
+pub fn debug_messenger_create_info(
+    counters: &DebugCounters,
+) -> vk::DebugUtilsMessengerCreateInfoEXT {
+    // Don't make the layers format messages that would be filtered out anyway
+    let mut message_severity = vk::DebugUtilsMessageSeverityFlagsEXT::WARNING
+        | vk::DebugUtilsMessageSeverityFlagsEXT::ERROR;
+    if log::log_enabled!(target: "vulkan", log::Level::Debug) {
+        message_severity |= vk::DebugUtilsMessageSeverityFlagsEXT::INFO;
+    }
+    if log::log_enabled!(target: "vulkan", log::Level::Trace) {
+        message_severity |= vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE;
+    }
+
+    vk::DebugUtilsMessengerCreateInfoEXT::builder()
+        .message_severity(message_severity)
+        .message_type(
+            vk::DebugUtilsMessageTypeFlagsEXT::GENERAL
+                | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE
+                | vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION,
+        )
+        .pfn_user_callback(Some(vulkan_debug_utils_callback))
+        .user_data(counters as *const DebugCounters as *mut std::ffi::c_void)
+        .build()
+}
+
+/// A debug messenger that lives as long as the instance and counts the messages it reports.
+pub struct DebugMessenger {
+    loader: DebugUtils,
+    messenger: vk::DebugUtilsMessengerEXT,
+    counters: Box<DebugCounters>,
+}
+
+impl DebugMessenger {
+    /// `counters` should be the same ones that were chained into instance creation, so that all
+    /// messages are counted together.
+    pub fn new(
+        entry: &Entry,
+        instance: &Instance,
+        counters: Box<DebugCounters>,
+    ) -> RendererResult<DebugMessenger> {
+        let loader = DebugUtils::new(entry, instance);
+        let create_info = debug_messenger_create_info(&counters);
+        let messenger = unsafe { loader.create_debug_utils_messenger(&create_info, None)? };
+        Ok(DebugMessenger {
+            loader,
+            messenger,
+            counters,
+        })
+    }
+
+    pub fn counters(&self) -> &DebugCounters {
+        &self.counters
+    }
+
+    /// Attaches a name to a Vulkan object, which shows up in validation messages and debuggers.
+    pub fn set_object_name<H: vk::Handle>(&self, device: &Device, handle: H, name: &str) {
+        let name = match CString::new(name) {
+            Ok(name) => name,
+            Err(_) => return,
+        };
+        let name_info = vk::DebugUtilsObjectNameInfoEXT::builder()
+            .object_type(H::TYPE)
+            .object_handle(handle.as_raw())
+            .object_name(&name);
+        // Names are only a debugging aid, so failing to set one isn't an error
+        let _ = unsafe {
+            self.loader
+                .debug_utils_set_object_name(device.handle(), &name_info)
+        };
+    }
+
+    /// # Safety
+    /// Must be called before the instance is destroyed.
+    pub unsafe fn destroy(&mut self) {
+        self.loader
+            .destroy_debug_utils_messenger(self.messenger, None);
+    }
+}
@@ -0,0 +1,1559 @@
+use ash::vk;
+use std::convert::TryInto;
+
+use crate::compressed::BlockLayout;
+
+/// The texels of a 4x4 block in rows, as 8-bit RGBA
+type Texels = [[u8; 4]; 16];
+
+/// ETC1 and ETC2 intensity modifiers, indexed by the table codeword and the pixel index
+const ETC_MODIFIERS: [[i32; 4]; 8] = [
+    [2, 8, -2, -8],
+    [5, 17, -5, -17],
+    [9, 29, -9, -29],
+    [13, 42, -13, -42],
+    [18, 60, -18, -60],
+    [24, 80, -24, -80],
+    [33, 106, -33, -106],
+    [47, 183, -47, -183],
+];
+
+/// The distances of the ETC2 T and H modes
+const ETC_DISTANCES: [i32; 8] = [3, 6, 11, 16, 23, 32, 41, 64];
+
+/// EAC alpha modifiers, indexed by the table index and the pixel index
+const EAC_MODIFIERS: [[i32; 8]; 16] = [
+    [-3, -6, -9, -15, 2, 5, 8, 14],
+    [-3, -7, -10, -13, 2, 6, 9, 12],
+    [-2, -5, -8, -13, 1, 4, 7, 12],
+    [-2, -4, -6, -13, 1, 3, 5, 12],
+    [-3, -6, -8, -12, 2, 5, 7, 11],
+    [-3, -7, -9, -11, 2, 6, 8, 10],
+    [-4, -7, -8, -11, 3, 6, 7, 10],
+    [-3, -5, -8, -11, 2, 4, 7, 10],
+    [-2, -6, -8, -10, 1, 5, 7, 9],
+    [-2, -5, -8, -10, 1, 4, 7, 9],
+    [-2, -4, -8, -10, 1, 3, 7, 9],
+    [-2, -5, -7, -10, 1, 4, 6, 9],
+    [-3, -4, -7, -10, 2, 3, 6, 9],
+    [-1, -2, -3, -10, 0, 1, 2, 9],
+    [-4, -6, -8, -9, 3, 5, 7, 8],
+    [-3, -5, -7, -9, 2, 4, 6, 8],
+];
+
+/// The fields of a BC7 mode, in the order that they are stored in
+struct Bc7Mode {
+    subsets: usize,
+    partition_bits: u32,
+    rotation_bits: u32,
+    index_selection_bits: u32,
+    color_bits: u32,
+    alpha_bits: u32,
+    /// Whether each endpoint has its own P-bit, the shared least significant bit of its channels
+    endpoint_p_bits: bool,
+    /// Whether both endpoints of each subset share a P-bit
+    subset_p_bits: bool,
+    index_bits: u32,
+    /// The bits of the second set of indices of modes 4 and 5, which interpolate alpha or color
+    secondary_index_bits: u32,
+}
+
+const BC7_MODES: [Bc7Mode; 8] = [
+    Bc7Mode {
+        subsets: 3,
+        partition_bits: 4,
+        rotation_bits: 0,
+        index_selection_bits: 0,
+        color_bits: 4,
+        alpha_bits: 0,
+        endpoint_p_bits: true,
+        subset_p_bits: false,
+        index_bits: 3,
+        secondary_index_bits: 0,
+    },
+    Bc7Mode {
+        subsets: 2,
+        partition_bits: 6,
+        rotation_bits: 0,
+        index_selection_bits: 0,
+        color_bits: 6,
+        alpha_bits: 0,
+        endpoint_p_bits: false,
+        subset_p_bits: true,
+        index_bits: 3,
+        secondary_index_bits: 0,
+    },
+    Bc7Mode {
+        subsets: 3,
+        partition_bits: 6,
+        rotation_bits: 0,
+        index_selection_bits: 0,
+        color_bits: 5,
+        alpha_bits: 0,
+        endpoint_p_bits: false,
+        subset_p_bits: false,
+        index_bits: 2,
+        secondary_index_bits: 0,
+    },
+    Bc7Mode {
+        subsets: 2,
+        partition_bits: 6,
+        rotation_bits: 0,
+        index_selection_bits: 0,
+        color_bits: 7,
+        alpha_bits: 0,
+        endpoint_p_bits: true,
+        subset_p_bits: false,
+        index_bits: 2,
+        secondary_index_bits: 0,
+    },
+    Bc7Mode {
+        subsets: 1,
+        partition_bits: 0,
+        rotation_bits: 2,
+        index_selection_bits: 1,
+        color_bits: 5,
+        alpha_bits: 6,
+        endpoint_p_bits: false,
+        subset_p_bits: false,
+        index_bits: 2,
+        secondary_index_bits: 3,
+    },
+    Bc7Mode {
+        subsets: 1,
+        partition_bits: 0,
+        rotation_bits: 2,
+        index_selection_bits: 0,
+        color_bits: 7,
+        alpha_bits: 8,
+        endpoint_p_bits: false,
+        subset_p_bits: false,
+        index_bits: 2,
+        secondary_index_bits: 2,
+    },
+    Bc7Mode {
+        subsets: 1,
+        partition_bits: 0,
+        rotation_bits: 0,
+        index_selection_bits: 0,
+        color_bits: 7,
+        alpha_bits: 7,
+        endpoint_p_bits: true,
+        subset_p_bits: false,
+        index_bits: 4,
+        secondary_index_bits: 0,
+    },
+    Bc7Mode {
+        subsets: 2,
+        partition_bits: 6,
+        rotation_bits: 0,
+        index_selection_bits: 0,
+        color_bits: 5,
+        alpha_bits: 5,
+        endpoint_p_bits: true,
+        subset_p_bits: false,
+        index_bits: 2,
+        secondary_index_bits: 0,
+    },
+];
+
+/// The subsets of the pixels of BC7 partitions with two subsets, one bit per pixel
+const BC7_PARTITIONS2: [u16; 64] = [
+    0xcccc, 0x8888, 0xeeee, 0xecc8, 0xc880, 0xfeec, 0xfec8, 0xec80, 0xc800, 0xffec, 0xfe80, 0xe800,
+    0xffe8, 0xff00, 0xfff0, 0xf000, 0xf710, 0x008e, 0x7100, 0x08ce, 0x008c, 0x7310, 0x3100, 0x8cce,
+    0x088c, 0x3110, 0x6666, 0x366c, 0x17e8, 0x0ff0, 0x718e, 0x399c, 0xaaaa, 0xf0f0, 0x5a5a, 0x33cc,
+    0x3c3c, 0x55aa, 0x9696, 0xa55a, 0x73ce, 0x13c8, 0x324c, 0x3bdc, 0x6996, 0xc33c, 0x9966, 0x0660,
+    0x0272, 0x04e4, 0x4e40, 0x2720, 0xc936, 0x936c, 0x39c6, 0x639c, 0x9336, 0x9cc6, 0x817e, 0xe718,
+    0xccf0, 0x0fcc, 0x7744, 0xee22,
+];
+
+/// The subsets of the pixels of BC7 partitions with three subsets, two bits per pixel
+const BC7_PARTITIONS3: [u32; 64] = [
+    0xaa685050, 0x6a5a5040, 0x5a5a4200, 0x5450a0a8, 0xa5a50000, 0xa0a05050, 0x5555a0a0, 0x5a5a5050,
+    0xaa550000, 0xaa555500, 0xaaaa5500, 0x90909090, 0x94949494, 0xa4a4a4a4, 0xa9a59450, 0x2a0a4250,
+    0xa5945040, 0x0a425054, 0xa5a5a500, 0x55a0a0a0, 0xa8a85454, 0x6a6a4040, 0xa4a45000, 0x1a1a0500,
+    0x0050a4a4, 0xaaa59090, 0x14696914, 0x69691400, 0xa08585a0, 0xaa821414, 0x50a4a450, 0x6a5a0200,
+    0xa9a58000, 0x5090a0a8, 0xa8a09050, 0x24242424, 0x00aa5500, 0x24924924, 0x24499224, 0x50a50a50,
+    0x500aa550, 0xaaaa4444, 0x66660000, 0xa5a0a5a0, 0x50a050a0, 0x69286928, 0x44aaaa44, 0x66666600,
+    0xaa444444, 0x54a854a8, 0x95809580, 0x96969600, 0xa85454a8, 0x80959580, 0xaa141414, 0x96960000,
+    0xaaaa1414, 0xa05050a0, 0xa0a5a5a0, 0x96000000, 0x40804080, 0xa9a8a9a8, 0xaaaaaa44, 0x2a4a5254,
+];
+
+/// The anchor pixel of the second subset of BC7 partitions with two subsets
+const BC7_ANCHORS2: [u8; 64] = [
+    15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 15, 2, 8, 2, 2, 8, 8, 15, 2, 8,
+    2, 2, 8, 8, 2, 2, 15, 15, 6, 8, 2, 8, 15, 15, 2, 8, 2, 2, 2, 15, 15, 6, 6, 2, 6, 8, 15, 15, 2,
+    2, 15, 15, 15, 15, 15, 2, 2, 15,
+];
+
+/// The anchor pixels of the second and the third subset of BC7 partitions with three subsets
+const BC7_ANCHORS3: [[u8; 64]; 2] = [
+    [
+        3, 3, 15, 15, 8, 3, 15, 15, 8, 8, 6, 6, 6, 5, 3, 3, 3, 3, 8, 15, 3, 3, 6, 10, 5, 8, 8, 6,
+        8, 5, 15, 15, 8, 15, 3, 5, 6, 10, 8, 15, 15, 3, 15, 5, 15, 15, 15, 15, 3, 15, 5, 5, 5, 8,
+        5, 10, 5, 10, 8, 13, 15, 12, 3, 3,
+    ],
+    [
+        15, 8, 8, 3, 15, 15, 3, 8, 15, 15, 15, 15, 15, 15, 15, 8, 15, 8, 15, 3, 15, 8, 15, 8, 3,
+        15, 6, 10, 15, 15, 10, 8, 15, 3, 15, 10, 10, 8, 9, 10, 6, 15, 8, 15, 3, 6, 6, 8, 15, 3, 15,
+        15, 15, 15, 15, 15, 15, 15, 15, 15, 3, 15, 15, 8,
+    ],
+];
+
+/// BC7 interpolation weights out of 64 for 2, 3 and 4-bit indices
+const BC7_WEIGHTS2: [u32; 4] = [0, 21, 43, 64];
+const BC7_WEIGHTS3: [u32; 8] = [0, 9, 18, 27, 37, 46, 55, 64];
+const BC7_WEIGHTS4: [u32; 16] = [0, 4, 9, 13, 17, 21, 26, 30, 34, 38, 43, 47, 51, 55, 60, 64];
+
+/// The numbers of levels that ASTC quantizes weights and endpoint colors to, in increasing order
+const ASTC_LEVELS: [u32; 21] = [
+    2, 3, 4, 5, 6, 8, 10, 12, 16, 20, 24, 32, 40, 48, 64, 80, 96, 128, 160, 192, 256,
+];
+
+/// The color of ASTC blocks that are invalid or need HDR decoding
+const ASTC_ERROR_COLOR: [u8; 4] = [255, 0, 255, 255];
+
+/// The 8-bit RGBA format that `decompress` turns `format` into, `None` if it can't decompress
+/// it. BC6H formats aren't supported, and ASTC blocks with HDR colors decode to magenta.
+pub fn decompressed_format(format: vk::Format) -> Option<vk::Format> {
+    match format {
+        vk::Format::BC1_RGB_UNORM_BLOCK
+        | vk::Format::BC1_RGBA_UNORM_BLOCK
+        | vk::Format::BC2_UNORM_BLOCK
+        | vk::Format::BC3_UNORM_BLOCK
+        | vk::Format::BC4_UNORM_BLOCK
+        | vk::Format::BC5_UNORM_BLOCK
+        | vk::Format::BC7_UNORM_BLOCK
+        | vk::Format::ETC2_R8G8B8_UNORM_BLOCK
+        | vk::Format::ETC2_R8G8B8A1_UNORM_BLOCK
+        | vk::Format::ETC2_R8G8B8A8_UNORM_BLOCK
+        | vk::Format::EAC_R11_UNORM_BLOCK
+        | vk::Format::EAC_R11G11_UNORM_BLOCK => Some(vk::Format::R8G8B8A8_UNORM),
+        vk::Format::BC1_RGB_SRGB_BLOCK
+        | vk::Format::BC1_RGBA_SRGB_BLOCK
+        | vk::Format::BC2_SRGB_BLOCK
+        | vk::Format::BC3_SRGB_BLOCK
+        | vk::Format::BC7_SRGB_BLOCK
+        | vk::Format::ETC2_R8G8B8_SRGB_BLOCK
+        | vk::Format::ETC2_R8G8B8A1_SRGB_BLOCK
+        | vk::Format::ETC2_R8G8B8A8_SRGB_BLOCK => Some(vk::Format::R8G8B8A8_SRGB),
+        vk::Format::BC4_SNORM_BLOCK
+        | vk::Format::BC5_SNORM_BLOCK
+        | vk::Format::EAC_R11_SNORM_BLOCK
+        | vk::Format::EAC_R11G11_SNORM_BLOCK => Some(vk::Format::R8G8B8A8_SNORM),
+        _ => match astc_format(format) {
+            Some(false) => Some(vk::Format::R8G8B8A8_UNORM),
+            Some(true) => Some(vk::Format::R8G8B8A8_SRGB),
+            None => None,
+        },
+    }
+}
+
+/// Decodes a tightly packed image of `extent` in `format` into 8-bit RGBA, for devices that
+/// can't sample `format`. sRGB texels stay encoded, and signed ones are stored in two's
+/// complement.
+///
+/// # Panics
+/// If `decompressed_format` doesn't support `format` or `data` doesn't have the size of the
+/// image.
+pub fn decompress(format: vk::Format, extent: vk::Extent2D, data: &[u8]) -> Vec<u8> {
+    assert!(
+        decompressed_format(format).is_some(),
+        "Can't decompress {:?}",
+        format
+    );
+    let layout = BlockLayout::of(format).unwrap();
+    assert_eq!(data.len() as u64, layout.image_size(extent));
+    let (block_width, block_height) = (layout.width as usize, layout.height as usize);
+    let decode_block = |block: &[u8]| -> Vec<[u8; 4]> {
+        if let Some(srgb) = astc_format(format) {
+            return astc(block, block_width, block_height, srgb);
+        }
+        let texels = match format {
+            vk::Format::BC1_RGB_UNORM_BLOCK | vk::Format::BC1_RGB_SRGB_BLOCK => {
+                bc1(block, ColorMode::ThreeColorsAndBlack)
+            }
+            vk::Format::BC1_RGBA_UNORM_BLOCK | vk::Format::BC1_RGBA_SRGB_BLOCK => {
+                bc1(block, ColorMode::ThreeColorsAndTransparent)
+            }
+            vk::Format::BC2_UNORM_BLOCK | vk::Format::BC2_SRGB_BLOCK => {
+                let mut texels = bc1(&block[8..], ColorMode::FourColors);
+                let alpha = u64::from_le_bytes(block[..8].try_into().unwrap());
+                for (i, texel) in texels.iter_mut().enumerate() {
+                    texel[3] = ((alpha >> (4 * i)) & 0xf) as u8 * 17;
+                }
+                texels
+            }
+            vk::Format::BC3_UNORM_BLOCK | vk::Format::BC3_SRGB_BLOCK => {
+                let mut texels = bc1(&block[8..], ColorMode::FourColors);
+                for (texel, alpha) in texels.iter_mut().zip(bc4(&block[..8], false)) {
+                    texel[3] = alpha;
+                }
+                texels
+            }
+            vk::Format::BC4_UNORM_BLOCK => bc4(block, false).map(|red| [red, 0, 0, 255]),
+            vk::Format::BC4_SNORM_BLOCK => bc4(block, true).map(|red| [red, 0, 0, 127]),
+            vk::Format::BC5_UNORM_BLOCK => {
+                red_green(bc4(&block[..8], false), bc4(&block[8..], false), 255)
+            }
+            vk::Format::BC5_SNORM_BLOCK => {
+                red_green(bc4(&block[..8], true), bc4(&block[8..], true), 127)
+            }
+            vk::Format::BC7_UNORM_BLOCK | vk::Format::BC7_SRGB_BLOCK => bc7(block),
+            vk::Format::ETC2_R8G8B8_UNORM_BLOCK | vk::Format::ETC2_R8G8B8_SRGB_BLOCK => {
+                etc2(block, false)
+            }
+            vk::Format::ETC2_R8G8B8A1_UNORM_BLOCK | vk::Format::ETC2_R8G8B8A1_SRGB_BLOCK => {
+                etc2(block, true)
+            }
+            vk::Format::ETC2_R8G8B8A8_UNORM_BLOCK | vk::Format::ETC2_R8G8B8A8_SRGB_BLOCK => {
+                let mut texels = etc2(&block[8..], false);
+                for (texel, alpha) in texels.iter_mut().zip(eac_alpha(&block[..8])) {
+                    texel[3] = alpha;
+                }
+                texels
+            }
+            vk::Format::EAC_R11_UNORM_BLOCK => eac_r11(block, false).map(|red| [red, 0, 0, 255]),
+            vk::Format::EAC_R11_SNORM_BLOCK => eac_r11(block, true).map(|red| [red, 0, 0, 127]),
+            vk::Format::EAC_R11G11_UNORM_BLOCK => red_green(
+                eac_r11(&block[..8], false),
+                eac_r11(&block[8..], false),
+                255,
+            ),
+            vk::Format::EAC_R11G11_SNORM_BLOCK => {
+                red_green(eac_r11(&block[..8], true), eac_r11(&block[8..], true), 127)
+            }
+            _ => unreachable!(),
+        };
+        texels.to_vec()
+    };
+
+    let (width, height) = (extent.width as usize, extent.height as usize);
+    let columns = width.div_ceil(block_width);
+    let mut pixels = vec![0; width * height * 4];
+    for (index, block) in data.chunks_exact(layout.bytes as usize).enumerate() {
+        let block_x = index % columns * block_width;
+        let block_y = index / columns * block_height;
+        let texels = decode_block(block);
+        // Blocks at the right and bottom edge may be partially outside the image
+        for y in 0..block_height.min(height - block_y) {
+            for x in 0..block_width.min(width - block_x) {
+                let offset = ((block_y + y) * width + block_x + x) * 4;
+                pixels[offset..offset + 4].copy_from_slice(&texels[y * block_width + x]);
+            }
+        }
+    }
+    pixels
+}
+
+/// Whether `format` is an ASTC format and whether it's sRGB, `None` if it isn't ASTC
+fn astc_format(format: vk::Format) -> Option<bool> {
+    // Each block size has a UNORM format followed by an sRGB one
+    let first = vk::Format::ASTC_4X4_UNORM_BLOCK.as_raw();
+    let last = vk::Format::ASTC_12X12_SRGB_BLOCK.as_raw();
+    (first..=last)
+        .contains(&format.as_raw())
+        .then_some((format.as_raw() - first) % 2 == 1)
+}
+
+/// Reads the bits of a block from the least significant one on
+struct BitReader {
+    bits: u128,
+    offset: u32,
+}
+
+impl BitReader {
+    fn new(bits: u128) -> BitReader {
+        BitReader { bits, offset: 0 }
+    }
+
+    fn read(&mut self, count: u32) -> u32 {
+        let value = bit_field(self.bits, self.offset, count);
+        self.offset += count;
+        value
+    }
+}
+
+/// The `count` bits of `bits` from `offset` on, which are zero past the end
+fn bit_field(bits: u128, offset: u32, count: u32) -> u32 {
+    (bits.checked_shr(offset).unwrap_or(0) & low_bits(count)) as u32
+}
+
+/// A mask of the `count` least significant bits
+fn low_bits(count: u32) -> u128 {
+    1u128.checked_shl(count).map_or(!0, |bit| bit - 1)
+}
+
+/// Divides and rounds half away from zero
+fn divide_rounded(numerator: i32, denominator: i32) -> i32 {
+    if numerator < 0 {
+        (numerator - denominator / 2) / denominator
+    } else {
+        (numerator + denominator / 2) / denominator
+    }
+}
+
+/// Combines single-channel blocks into the red and green channels, with `one` as alpha
+fn red_green(red: [u8; 16], green: [u8; 16], one: u8) -> Texels {
+    let mut texels = [[0, 0, 0, one]; 16];
+    for (i, texel) in texels.iter_mut().enumerate() {
+        texel[0] = red[i];
+        texel[1] = green[i];
+    }
+    texels
+}
+
+/// What the BC1 color block holds when its first endpoint isn't greater than the second one
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ColorMode {
+    /// BC1 without alpha: three colors and opaque black
+    ThreeColorsAndBlack,
+    /// BC1 with alpha: three colors and transparent black
+    ThreeColorsAndTransparent,
+    /// BC2 and BC3 always have four colors
+    FourColors,
+}
+
+fn rgb565(color: u16) -> [u32; 3] {
+    let (red, green, blue) = (
+        u32::from(color >> 11),
+        u32::from(color >> 5 & 0x3f),
+        u32::from(color & 0x1f),
+    );
+    [
+        red << 3 | red >> 2,
+        green << 2 | green >> 4,
+        blue << 3 | blue >> 2,
+    ]
+}
+
+fn bc1(block: &[u8], mode: ColorMode) -> Texels {
+    let color0 = u16::from_le_bytes([block[0], block[1]]);
+    let color1 = u16::from_le_bytes([block[2], block[3]]);
+    let (endpoint0, endpoint1) = (rgb565(color0), rgb565(color1));
+    let mix = |weight0: u32, weight1: u32| {
+        let mut color = [0, 0, 0, 255];
+        for channel in 0..3 {
+            let sum = endpoint0[channel] * weight0 + endpoint1[channel] * weight1;
+            color[channel] = ((sum + (weight0 + weight1) / 2) / (weight0 + weight1)) as u8;
+        }
+        color
+    };
+    let palette = if color0 > color1 || mode == ColorMode::FourColors {
+        [mix(1, 0), mix(0, 1), mix(2, 1), mix(1, 2)]
+    } else if mode == ColorMode::ThreeColorsAndBlack {
+        [mix(1, 0), mix(0, 1), mix(1, 1), [0, 0, 0, 255]]
+    } else {
+        [mix(1, 0), mix(0, 1), mix(1, 1), [0, 0, 0, 0]]
+    };
+
+    let indices = u32::from_le_bytes(block[4..8].try_into().unwrap());
+    let mut texels = [[0; 4]; 16];
+    for (i, texel) in texels.iter_mut().enumerate() {
+        *texel = palette[(indices >> (2 * i) & 3) as usize];
+    }
+    texels
+}
+
+/// Decodes a single-channel BC4 block, which BC3 also stores alpha in. Signed values are
+/// returned in two's complement.
+fn bc4(block: &[u8], signed: bool) -> [u8; 16] {
+    let (value0, value1, min, max) = if signed {
+        // -128 is an alias of -127
+        let value = |byte: u8| i32::from(byte as i8).max(-127);
+        (value(block[0]), value(block[1]), -127, 127)
+    } else {
+        (i32::from(block[0]), i32::from(block[1]), 0, 255)
+    };
+    let mut palette = [value0, value1, 0, 0, 0, 0, min, max];
+    if value0 > value1 {
+        for i in 1..7 {
+            palette[i as usize + 1] = divide_rounded(value0 * (7 - i) + value1 * i, 7);
+        }
+    } else {
+        for i in 1..5 {
+            palette[i as usize + 1] = divide_rounded(value0 * (5 - i) + value1 * i, 5);
+        }
+    }
+
+    let mut index_bytes = [0; 8];
+    index_bytes[..6].copy_from_slice(&block[2..8]);
+    let indices = u64::from_le_bytes(index_bytes);
+    let mut values = [0; 16];
+    for (i, value) in values.iter_mut().enumerate() {
+        *value = palette[(indices >> (3 * i) & 7) as usize] as u8;
+    }
+    values
+}
+
+fn bc7(block: &[u8]) -> Texels {
+    let bits = u128::from_le_bytes(block.try_into().unwrap());
+    // The mode is the number of zeros before the first set bit
+    let mode = bits.trailing_zeros();
+    if mode >= 8 {
+        return [[0; 4]; 16];
+    }
+    let m = &BC7_MODES[mode as usize];
+    let mut reader = BitReader {
+        bits,
+        offset: mode + 1,
+    };
+    let partition = reader.read(m.partition_bits) as usize;
+    let rotation = reader.read(m.rotation_bits);
+    let index_selection = reader.read(m.index_selection_bits);
+
+    // Endpoints are stored channel by channel, then their P-bits
+    let endpoint_count = 2 * m.subsets;
+    let mut endpoints = [[0; 4]; 6];
+    for channel in 0..4 {
+        let bits = if channel < 3 {
+            m.color_bits
+        } else {
+            m.alpha_bits
+        };
+        for endpoint in &mut endpoints[..endpoint_count] {
+            endpoint[channel] = reader.read(bits);
+        }
+    }
+    let has_p_bits = m.endpoint_p_bits || m.subset_p_bits;
+    if has_p_bits {
+        let p_bit_count = if m.endpoint_p_bits {
+            endpoint_count
+        } else {
+            m.subsets
+        };
+        let p_bits: Vec<u32> = (0..p_bit_count).map(|_| reader.read(1)).collect();
+        for (i, endpoint) in endpoints[..endpoint_count].iter_mut().enumerate() {
+            let p_bit = p_bits[i * p_bit_count / endpoint_count];
+            for value in endpoint.iter_mut() {
+                *value = *value << 1 | p_bit;
+            }
+        }
+    }
+    for endpoint in &mut endpoints[..endpoint_count] {
+        for (channel, value) in endpoint.iter_mut().enumerate() {
+            let bits = if channel < 3 {
+                m.color_bits
+            } else {
+                m.alpha_bits
+            };
+            if bits == 0 {
+                *value = 255;
+            } else {
+                let bits = bits + has_p_bits as u32;
+                *value = *value << (8 - bits) | *value >> (2 * bits - 8);
+            }
+        }
+    }
+
+    let subset = |pixel: usize| match m.subsets {
+        1 => 0,
+        2 => (BC7_PARTITIONS2[partition] >> pixel & 1) as usize,
+        _ => (BC7_PARTITIONS3[partition] >> (2 * pixel) & 3) as usize,
+    };
+    // The most significant index bit of the first pixel of each subset, its anchor, is zero
+    // and isn't stored
+    let is_anchor = |pixel: usize| {
+        pixel == 0
+            || match m.subsets {
+                2 => pixel == BC7_ANCHORS2[partition] as usize,
+                3 => BC7_ANCHORS3
+                    .iter()
+                    .any(|anchors| pixel == anchors[partition] as usize),
+                _ => false,
+            }
+    };
+    let mut indices = [0; 16];
+    for (pixel, index) in indices.iter_mut().enumerate() {
+        *index = reader.read(m.index_bits - is_anchor(pixel) as u32) as usize;
+    }
+    let mut secondary_indices = [0; 16];
+    if m.secondary_index_bits > 0 {
+        for (pixel, index) in secondary_indices.iter_mut().enumerate() {
+            *index = reader.read(m.secondary_index_bits - (pixel == 0) as u32) as usize;
+        }
+    }
+
+    // Modes 4 and 5 interpolate alpha with the second set of indices, or color if the index
+    // selection bit is set
+    let (color_indices, color_index_bits, alpha_indices, alpha_index_bits) =
+        if m.secondary_index_bits == 0 {
+            (&indices, m.index_bits, &indices, m.index_bits)
+        } else if index_selection == 0 {
+            (
+                &indices,
+                m.index_bits,
+                &secondary_indices,
+                m.secondary_index_bits,
+            )
+        } else {
+            (
+                &secondary_indices,
+                m.secondary_index_bits,
+                &indices,
+                m.index_bits,
+            )
+        };
+    let weight = |bits: u32, index: usize| match bits {
+        2 => BC7_WEIGHTS2[index],
+        3 => BC7_WEIGHTS3[index],
+        _ => BC7_WEIGHTS4[index],
+    };
+    let mut texels = [[0; 4]; 16];
+    for (pixel, texel) in texels.iter_mut().enumerate() {
+        let subset = subset(pixel);
+        let (endpoint0, endpoint1) = (endpoints[2 * subset], endpoints[2 * subset + 1]);
+        for (channel, value) in texel.iter_mut().enumerate() {
+            let weight = if channel < 3 {
+                weight(color_index_bits, color_indices[pixel])
+            } else {
+                weight(alpha_index_bits, alpha_indices[pixel])
+            };
+            *value = (((64 - weight) * endpoint0[channel] + weight * endpoint1[channel] + 32) >> 6)
+                as u8;
+        }
+        // The rotation swaps alpha with one of the color channels
+        if rotation > 0 {
+            texel.swap(rotation as usize - 1, 3);
+        }
+    }
+    texels
+}
+
+/// Decodes an ETC2 RGB block, which is backwards compatible with ETC1. With `punchthrough`,
+/// the differential bit says whether the block is opaque.
+fn etc2(block: &[u8], punchthrough: bool) -> Texels {
+    let bits = u64::from_be_bytes(block[..8].try_into().unwrap());
+    let field = |offset: u32, len: u32| (bits >> offset & ((1 << len) - 1)) as i32;
+    let flag = field(33, 1) == 1;
+    let opaque = !punchthrough || flag;
+    // Pixels are stored in columns, with the low and the high bits of their indices apart
+    let pixel_index = |pixel: u32| (field(16 + pixel, 1) << 1 | field(pixel, 1)) as usize;
+    let extend4 = |value: i32| value << 4 | value;
+    let extend5 = |value: i32| value << 3 | value >> 2;
+    let offset = |color: [i32; 3], amount: i32| color.map(|channel| channel + amount);
+
+    // Without punchthrough alpha, the flag chooses between the individual and the differential
+    // mode. Overflows in the differential mode select the T, H and planar modes.
+    let (red, green, blue) = (field(59, 5), field(51, 5), field(43, 5));
+    let delta = |offset| match field(offset, 3) {
+        value if value >= 4 => value - 8,
+        value => value,
+    };
+    let (red2, green2, blue2) = (red + delta(56), green + delta(48), blue + delta(40));
+    let in_range = |value: i32| (0..32).contains(&value);
+    if punchthrough || flag {
+        if !in_range(red2) {
+            let color0 = [field(59, 2) << 2 | field(56, 2), field(52, 4), field(48, 4)];
+            let color0 = color0.map(extend4);
+            let color1 = [field(44, 4), field(40, 4), field(36, 4)].map(extend4);
+            let distance = ETC_DISTANCES[(field(34, 2) << 1 | field(32, 1)) as usize];
+            let paint = [
+                color0,
+                offset(color1, distance),
+                color1,
+                offset(color1, -distance),
+            ];
+            return paint_texels(paint, opaque, pixel_index);
+        }
+        if !in_range(green2) {
+            let color0 = [
+                field(59, 4),
+                field(56, 3) << 1 | field(52, 1),
+                field(51, 1) << 3 | field(47, 3),
+            ];
+            let color1 = [field(43, 4), field(39, 4), field(35, 4)];
+            let value = |color: [i32; 3]| color[0] << 8 | color[1] << 4 | color[2];
+            let order = (value(color0) >= value(color1)) as i32;
+            let distance = ETC_DISTANCES[(field(34, 1) << 2 | field(32, 1) << 1 | order) as usize];
+            let (color0, color1) = (color0.map(extend4), color1.map(extend4));
+            let paint = [
+                offset(color0, distance),
+                offset(color0, -distance),
+                offset(color1, distance),
+                offset(color1, -distance),
+            ];
+            return paint_texels(paint, opaque, pixel_index);
+        }
+        if !in_range(blue2) {
+            let extend6 = |value: i32| value << 2 | value >> 4;
+            let extend7 = |value: i32| value << 1 | value >> 6;
+            let extend =
+                |color: [i32; 3]| [extend6(color[0]), extend7(color[1]), extend6(color[2])];
+            let origin = extend([
+                field(57, 6),
+                field(56, 1) << 6 | field(49, 6),
+                field(48, 1) << 5 | field(43, 2) << 3 | field(39, 3),
+            ]);
+            let horizontal = extend([field(34, 5) << 1 | field(32, 1), field(25, 7), field(19, 6)]);
+            let vertical = extend([field(13, 6), field(6, 7), field(0, 6)]);
+            let mut texels = [[0; 4]; 16];
+            for (i, texel) in texels.iter_mut().enumerate() {
+                let (x, y) = ((i % 4) as i32, (i / 4) as i32);
+                let mut color = [0; 3];
+                for channel in 0..3 {
+                    color[channel] = (x * (horizontal[channel] - origin[channel])
+                        + y * (vertical[channel] - origin[channel])
+                        + 4 * origin[channel]
+                        + 2)
+                        >> 2;
+                }
+                *texel = opaque_texel(color);
+            }
+            return texels;
+        }
+    }
+
+    let base_colors = if punchthrough || flag {
+        [
+            [red, green, blue].map(extend5),
+            [red2, green2, blue2].map(extend5),
+        ]
+    } else {
+        [
+            [field(60, 4), field(52, 4), field(44, 4)].map(extend4),
+            [field(56, 4), field(48, 4), field(40, 4)].map(extend4),
+        ]
+    };
+    let tables = [field(37, 3) as usize, field(34, 3) as usize];
+    // The block is split into two 2x4 subblocks side by side, or two 4x2 ones on top of each
+    // other if flipped
+    let flipped = field(32, 1) == 1;
+    let mut texels = [[0; 4]; 16];
+    for pixel in 0..16 {
+        let (x, y) = (pixel / 4, pixel % 4);
+        let subblock = if flipped { y / 2 } else { x / 2 } as usize;
+        let index = pixel_index(pixel);
+        if !opaque && index == 2 {
+            texels[(y * 4 + x) as usize] = [0; 4];
+            continue;
+        }
+        // Pixels with punchthrough alpha keep the base color instead of a positive modifier
+        let modifier = match index {
+            0 if !opaque => 0,
+            _ => ETC_MODIFIERS[tables[subblock]][index],
+        };
+        texels[(y * 4 + x) as usize] = opaque_texel(offset(base_colors[subblock], modifier));
+    }
+    texels
+}
+
+fn opaque_texel(color: [i32; 3]) -> [u8; 4] {
+    let [red, green, blue] = color.map(|channel| channel.clamp(0, 255) as u8);
+    [red, green, blue, 255]
+}
+
+/// Colors the pixels of the T and H modes with one of four paint colors each. Transparent
+/// blocks replace the third one with transparent black.
+fn paint_texels(paint: [[i32; 3]; 4], opaque: bool, pixel_index: impl Fn(u32) -> usize) -> Texels {
+    let mut texels = [[0; 4]; 16];
+    for pixel in 0..16 {
+        let (x, y) = (pixel / 4, pixel % 4);
+        let index = pixel_index(pixel);
+        texels[(y * 4 + x) as usize] = if !opaque && index == 2 {
+            [0; 4]
+        } else {
+            opaque_texel(paint[index])
+        };
+    }
+    texels
+}
+
+/// Decodes the alpha block of ETC2 RGBA8
+fn eac_alpha(block: &[u8]) -> [u8; 16] {
+    let bits = u64::from_be_bytes(block[..8].try_into().unwrap());
+    let base = (bits >> 56) as i32;
+    let multiplier = (bits >> 52 & 0xf) as i32;
+    let modifiers = EAC_MODIFIERS[(bits >> 48 & 0xf) as usize];
+    let mut values = [0; 16];
+    // Pixels are stored in columns, starting at the most significant bits
+    for pixel in 0..16 {
+        let (x, y) = (pixel / 4, pixel % 4);
+        let index = (bits >> (45 - 3 * pixel) & 7) as usize;
+        values[y * 4 + x] = (base + modifiers[index] * multiplier).clamp(0, 255) as u8;
+    }
+    values
+}
+
+/// Decodes an 11-bit EAC block into 8-bit values, in two's complement if `signed`
+fn eac_r11(block: &[u8], signed: bool) -> [u8; 16] {
+    let bits = u64::from_be_bytes(block[..8].try_into().unwrap());
+    let base = if signed {
+        // -128 is an alias of -127
+        i32::from((bits >> 56) as u8 as i8).max(-127) * 8
+    } else {
+        (bits >> 56) as i32 * 8 + 4
+    };
+    let multiplier = (bits >> 52 & 0xf) as i32;
+    let modifiers = EAC_MODIFIERS[(bits >> 48 & 0xf) as usize];
+    let mut values = [0; 16];
+    for pixel in 0..16 {
+        let (x, y) = (pixel / 4, pixel % 4);
+        let modifier = modifiers[(bits >> (45 - 3 * pixel) & 7) as usize];
+        // A zero multiplier keeps the modifiers at 11-bit precision
+        let value = match multiplier {
+            0 => base + modifier,
+            _ => base + modifier * multiplier * 8,
+        };
+        values[y * 4 + x] = if signed {
+            divide_rounded(value.clamp(-1023, 1023) * 127, 1023) as u8
+        } else {
+            divide_rounded(value.clamp(0, 2047) * 255, 2047) as u8
+        };
+    }
+    values
+}
+
+/// The weight grid of an ASTC block
+struct AstcWeightGrid {
+    width: usize,
+    height: usize,
+    /// Whether one channel has a second plane of weights
+    dual_plane: bool,
+    levels: u32,
+}
+
+/// Decodes the 11-bit block mode of a 2D ASTC block, `None` if it's reserved
+fn astc_weight_grid(mode: u32) -> Option<AstcWeightGrid> {
+    let field = |offset: u32, count: u32| mode >> offset & ((1 << count) - 1);
+    let (a, b) = (field(5, 2) as usize, field(7, 2) as usize);
+    // Grids of 6-9 by 6-9 weights need the dual plane and precision bits for their height
+    let large = field(0, 2) == 0 && field(7, 2) == 2;
+    let (range, width, height) = if field(0, 2) != 0 {
+        let (width, height) = match field(2, 2) {
+            0 => (b + 4, a + 2),
+            1 => (b + 8, a + 2),
+            2 => (a + 2, b + 8),
+            _ if field(8, 1) == 0 => (a + 2, (b & 1) + 6),
+            _ => ((b & 1) + 2, a + 2),
+        };
+        (field(4, 1) | field(0, 2) << 1, width, height)
+    } else {
+        let (width, height) = match field(7, 2) {
+            0 => (12, a + 2),
+            1 => (a + 2, 12),
+            2 => (a + 6, field(9, 2) as usize + 6),
+            _ => match a {
+                0 => (6, 10),
+                1 => (10, 6),
+                _ => return None,
+            },
+        };
+        (field(4, 1) | field(2, 2) << 1, width, height)
+    };
+    if range < 2 {
+        return None;
+    }
+    let high_precision = !large && field(9, 1) == 1;
+    Some(AstcWeightGrid {
+        width,
+        height,
+        dual_plane: !large && field(10, 1) == 1,
+        levels: ASTC_LEVELS[(range - 2) as usize + 6 * high_precision as usize],
+    })
+}
+
+/// How ASTC integer sequences store values with `levels` levels: the number of plain bits of
+/// each value, and the radix of the trit or quint above them, 1 if there isn't one
+fn ise_encoding(levels: u32) -> (u32, u32) {
+    let radix = match levels {
+        _ if levels.is_multiple_of(3) => 3,
+        _ if levels.is_multiple_of(5) => 5,
+        _ => 1,
+    };
+    ((levels / radix).trailing_zeros(), radix)
+}
+
+/// The number of bits that an integer sequence of `count` values with `levels` levels takes
+fn ise_size(count: u32, levels: u32) -> u32 {
+    let (bits, radix) = ise_encoding(levels);
+    count * bits
+        + match radix {
+            3 => (8 * count).div_ceil(5),
+            5 => (7 * count).div_ceil(3),
+            _ => 0,
+        }
+}
+
+/// Decodes `count` values with `levels` levels from an integer sequence that starts at the
+/// least significant bit of `bits`, which must be zero past the sequence
+fn decode_ise(bits: u128, count: usize, levels: u32) -> Vec<u32> {
+    let (value_bits, radix) = ise_encoding(levels);
+    // Groups of five trits are packed into 8 bits and groups of three quints into 7 bits, which
+    // are interleaved with the plain bits of the values
+    let packed_bits: &[u32] = match radix {
+        3 => &[2, 2, 1, 2, 1],
+        5 => &[3, 2, 2],
+        _ => &[0],
+    };
+    let mut reader = BitReader::new(bits);
+    let mut values = Vec::with_capacity(count + 4);
+    while values.len() < count {
+        let mut low = [0; 5];
+        let mut packed = 0;
+        let mut shift = 0;
+        for (value, &packed_count) in low.iter_mut().zip(packed_bits) {
+            *value = reader.read(value_bits);
+            packed |= reader.read(packed_count) << shift;
+            shift += packed_count;
+        }
+        let high = match radix {
+            3 => trits(packed),
+            5 => {
+                let [quint0, quint1, quint2] = quints(packed);
+                [quint0, quint1, quint2, 0, 0]
+            }
+            _ => [0; 5],
+        };
+        for (high, low) in high.iter().zip(low).take(packed_bits.len()) {
+            values.push(high << value_bits | low);
+        }
+    }
+    values.truncate(count);
+    values
+}
+
+/// Unpacks five trits from 8 bits
+fn trits(packed: u32) -> [u32; 5] {
+    let bit = |value: u32, index: u32| value >> index & 1;
+    let (c, trit4, trit3) = if packed >> 2 & 7 == 7 {
+        ((packed >> 5 & 7) << 2 | packed & 3, 2, 2)
+    } else if packed >> 5 & 3 == 3 {
+        (packed & 0x1f, 2, bit(packed, 7))
+    } else {
+        (packed & 0x1f, bit(packed, 7), packed >> 5 & 3)
+    };
+    let (trit2, trit1, trit0) = if c & 3 == 3 {
+        (2, bit(c, 4), bit(c, 3) << 1 | (bit(c, 2) & !bit(c, 3) & 1))
+    } else if c >> 2 & 3 == 3 {
+        (2, 2, c & 3)
+    } else {
+        (
+            bit(c, 4),
+            c >> 2 & 3,
+            bit(c, 1) << 1 | (bit(c, 0) & !bit(c, 1) & 1),
+        )
+    };
+    [trit0, trit1, trit2, trit3, trit4]
+}
+
+/// Unpacks three quints from 7 bits
+fn quints(packed: u32) -> [u32; 3] {
+    let bit = |value: u32, index: u32| value >> index & 1;
+    if packed >> 1 & 3 == 3 && packed >> 5 & 3 == 0 {
+        let not0 = !bit(packed, 0) & 1;
+        let quint2 = bit(packed, 0) << 2 | (bit(packed, 4) & not0) << 1 | (bit(packed, 3) & not0);
+        return [4, 4, quint2];
+    }
+    let (quint2, c) = if packed >> 1 & 3 == 3 {
+        (
+            4,
+            (packed >> 3 & 3) << 3 | (!packed >> 5 & 3) << 1 | bit(packed, 0),
+        )
+    } else {
+        (packed >> 5 & 3, packed & 0x1f)
+    };
+    if c & 7 == 5 {
+        [c >> 3 & 3, 4, quint2]
+    } else {
+        [c & 7, c >> 3 & 3, quint2]
+    }
+}
+
+/// Repeats the `bits` bits of `value` until they fill `target` bits
+fn replicate(value: u32, bits: u32, target: u32) -> u32 {
+    let (mut result, mut filled) = (0, 0);
+    while filled < target {
+        result = result << bits | value;
+        filled += bits;
+    }
+    result >> (filled - target)
+}
+
+/// Unquantizes an ASTC endpoint color value with `levels` levels to 8 bits. Endpoints have at
+/// least 6 levels.
+fn unquantize_color(value: u32, levels: u32) -> u32 {
+    let (bits, radix) = ise_encoding(levels);
+    if radix == 1 {
+        return replicate(value, bits, 8);
+    }
+    let (high, low) = (value >> bits, value & ((1 << bits) - 1));
+    let [b, c, d, e, f] = [1, 2, 3, 4, 5].map(|index| low >> index & 1);
+    let (scale, offset) = match (radix, bits) {
+        (3, 1) => (204, 0),
+        (3, 2) => (93, b << 8 | b << 4 | b << 2 | b << 1),
+        (3, 3) => (44, c << 8 | b << 7 | c << 3 | b << 2 | c << 1 | b),
+        (3, 4) => (22, d << 8 | c << 7 | b << 6 | d << 2 | c << 1 | b),
+        (3, 5) => (11, e << 8 | d << 7 | c << 6 | b << 5 | e << 1 | d),
+        (3, _) => (5, f << 8 | e << 7 | d << 6 | c << 5 | b << 4 | f),
+        (_, 1) => (113, 0),
+        (_, 2) => (54, b << 8 | b << 3 | b << 2),
+        (_, 3) => (26, c << 8 | b << 7 | c << 2 | b << 1 | c),
+        (_, 4) => (13, d << 8 | c << 7 | b << 6 | d << 1 | c),
+        _ => (6, e << 8 | d << 7 | c << 6 | b << 5 | e),
+    };
+    let mask = if low & 1 == 1 { 0x1ff } else { 0 };
+    (mask & 0x80) | ((high * scale + offset) ^ mask) >> 2
+}
+
+/// Unquantizes an ASTC weight with `levels` levels to a weight out of 64
+fn unquantize_weight(value: u32, levels: u32) -> u32 {
+    let (bits, radix) = ise_encoding(levels);
+    let (high, low) = (value >> bits, value & ((1 << bits) - 1));
+    let [b, c] = [1, 2].map(|index| low >> index & 1);
+    let mask = if low & 1 == 1 { 0x7f } else { 0 };
+    let unquantize =
+        |scale: u32, offset: u32| (mask & 0x20) | ((high * scale + offset) ^ mask) >> 2;
+    let weight = match (radix, bits) {
+        (1, _) => replicate(value, bits, 6),
+        (3, 0) => return [0, 32, 64][value as usize],
+        (5, 0) => return [0, 16, 32, 48, 64][value as usize],
+        (3, 1) => unquantize(50, 0),
+        (3, 2) => unquantize(23, b << 6 | b << 2 | b),
+        (3, _) => unquantize(11, c << 6 | b << 5 | c << 1 | b),
+        (_, 1) => unquantize(28, 0),
+        _ => unquantize(13, b << 6 | b << 1),
+    };
+    if weight > 32 {
+        weight + 1
+    } else {
+        weight
+    }
+}
+
+/// Decodes the two 8-bit RGBA endpoints of color endpoint mode `mode` from its `values`,
+/// `None` for HDR modes
+fn astc_endpoints(mode: u32, values: &[u32]) -> Option<[[u32; 4]; 2]> {
+    let v: Vec<i32> = values.iter().map(|&value| value as i32).collect();
+    // Moves the most significant bit of `offset` into `base`, leaving a signed 6-bit offset
+    let transfer_bit = |offset: i32, base: i32| {
+        let (offset, base) = (offset >> 1 & 0x3f, base >> 1 | (offset & 0x80));
+        (
+            if offset & 0x20 != 0 {
+                offset - 0x40
+            } else {
+                offset
+            },
+            base,
+        )
+    };
+    let blue_contract =
+        |[red, green, blue, alpha]: [i32; 4]| [(red + blue) >> 1, (green + blue) >> 1, blue, alpha];
+    let scale = |value: i32| (value * v[3]) >> 8;
+    let endpoints = match mode {
+        0 => [[v[0], v[0], v[0], 255], [v[1], v[1], v[1], 255]],
+        1 => {
+            let low = v[0] >> 2 | (v[1] & 0xc0);
+            let high = (low + (v[1] & 0x3f)).min(255);
+            [[low, low, low, 255], [high, high, high, 255]]
+        }
+        4 => [[v[0], v[0], v[0], v[2]], [v[1], v[1], v[1], v[3]]],
+        5 => {
+            let (offset, base) = transfer_bit(v[1], v[0]);
+            let (alpha_offset, alpha) = transfer_bit(v[3], v[2]);
+            let high = base + offset;
+            [
+                [base, base, base, alpha],
+                [high, high, high, alpha + alpha_offset],
+            ]
+        }
+        6 => [
+            [scale(v[0]), scale(v[1]), scale(v[2]), 255],
+            [v[0], v[1], v[2], 255],
+        ],
+        8 | 12 => {
+            let alpha = if mode == 12 { [v[6], v[7]] } else { [255; 2] };
+            let endpoint0 = [v[0], v[2], v[4], alpha[0]];
+            let endpoint1 = [v[1], v[3], v[5], alpha[1]];
+            if v[1] + v[3] + v[5] >= v[0] + v[2] + v[4] {
+                [endpoint0, endpoint1]
+            } else {
+                [blue_contract(endpoint1), blue_contract(endpoint0)]
+            }
+        }
+        9 | 13 => {
+            let (mut base, mut offset) = ([0, 0, 0, 255], [0; 4]);
+            let channels = if mode == 13 { 4 } else { 3 };
+            for channel in 0..channels {
+                let (channel_offset, channel_base) =
+                    transfer_bit(v[2 * channel + 1], v[2 * channel]);
+                base[channel] = channel_base;
+                offset[channel] = channel_offset;
+            }
+            let sum = [0, 1, 2, 3].map(|channel| base[channel] + offset[channel]);
+            if offset[0] + offset[1] + offset[2] >= 0 {
+                [base, sum]
+            } else {
+                [blue_contract(sum), blue_contract(base)]
+            }
+        }
+        10 => [
+            [scale(v[0]), scale(v[1]), scale(v[2]), v[4]],
+            [v[0], v[1], v[2], v[5]],
+        ],
+        _ => return None,
+    };
+    Some(endpoints.map(|endpoint| endpoint.map(|channel| channel.clamp(0, 255) as u32)))
+}
+
+/// The partition of the texel at `x`, `y` of an ASTC block with `count` partitions, which are
+/// generated from `seed`
+fn astc_partition(seed: u32, x: u32, y: u32, count: u32, small_block: bool) -> usize {
+    let (x, y) = if small_block {
+        (x << 1, y << 1)
+    } else {
+        (x, y)
+    };
+    let seed = seed + (count - 1) * 1024;
+    let mut hash = seed;
+    hash ^= hash >> 15;
+    hash = hash.wrapping_sub(hash << 17);
+    hash = hash.wrapping_add(hash << 7);
+    hash = hash.wrapping_add(hash << 4);
+    hash ^= hash >> 5;
+    hash = hash.wrapping_add(hash << 16);
+    hash ^= hash >> 7;
+    hash ^= hash >> 3;
+    hash ^= hash << 6;
+    hash ^= hash >> 17;
+
+    let shift_odd = if seed & 2 != 0 { 4 } else { 5 };
+    let shift_three = if count == 3 { 6 } else { 5 };
+    let (shift1, shift2) = if seed & 1 == 1 {
+        (shift_odd, shift_three)
+    } else {
+        (shift_three, shift_odd)
+    };
+    // Blocks are 2D, so the seeds that would multiply z aren't needed
+    let seeds: [u32; 8] = std::array::from_fn(|i| {
+        let seed = hash >> (4 * i) & 0xf;
+        (seed * seed) >> if i % 2 == 0 { shift1 } else { shift2 }
+    });
+    let [a, b, c, d] = [(0, 14), (2, 10), (4, 6), (6, 2)]
+        .map(|(i, shift)| (seeds[i] * x + seeds[i + 1] * y + (hash >> shift)) & 0x3f);
+    let c = if count < 3 { 0 } else { c };
+    let d = if count < 4 { 0 } else { d };
+    if a >= b && a >= c && a >= d {
+        0
+    } else if b >= c && b >= d {
+        1
+    } else if c >= d {
+        2
+    } else {
+        3
+    }
+}
+
+/// Decodes an LDR ASTC block of `width` by `height` texels. Invalid blocks and blocks with HDR
+/// colors are magenta.
+fn astc(block: &[u8], width: usize, height: usize, srgb: bool) -> Vec<[u8; 4]> {
+    decode_astc(block, width, height, srgb)
+        .unwrap_or_else(|| vec![ASTC_ERROR_COLOR; width * height])
+}
+
+fn decode_astc(block: &[u8], width: usize, height: usize, srgb: bool) -> Option<Vec<[u8; 4]>> {
+    let bits = u128::from_le_bytes(block.try_into().unwrap());
+    let field = |offset: u32, count: u32| bit_field(bits, offset, count);
+    // Void-extent blocks have a single color with 16-bit channels
+    if field(0, 9) == 0x1fc {
+        if field(9, 1) == 1 {
+            return None;
+        }
+        let texel = [0, 1, 2, 3].map(|channel| (field(64 + 16 * channel, 16) >> 8) as u8);
+        return Some(vec![texel; width * height]);
+    }
+
+    let grid = astc_weight_grid(field(0, 11))?;
+    let partitions = field(11, 2) + 1;
+    let planes = if grid.dual_plane { 2 } else { 1 };
+    let weight_count = grid.width * grid.height * planes;
+    let weight_size = ise_size(weight_count as u32, grid.levels);
+    if grid.width > width
+        || grid.height > height
+        || weight_count > 64
+        || !(24..=96).contains(&weight_size)
+        || (partitions == 4 && grid.dual_plane)
+    {
+        return None;
+    }
+
+    // Partitions either share a color endpoint mode, or have modes of two adjacent classes
+    // whose bits partly sit below the weights
+    let mut color_end = 128 - weight_size;
+    let modes: Vec<u32> = if partitions == 1 {
+        vec![field(13, 4)]
+    } else if field(23, 2) == 0 {
+        vec![field(25, 4); partitions as usize]
+    } else {
+        let extra_bits = 3 * partitions - 4;
+        color_end -= extra_bits;
+        let bits = field(25, 4) | field(color_end, extra_bits) << 4;
+        let base_class = field(23, 2) - 1;
+        (0..partitions)
+            .map(|i| (base_class + (bits >> i & 1)) << 2 | bits >> (partitions + 2 * i) & 3)
+            .collect()
+    };
+    // The channel that the second plane of weights applies to
+    let second_plane_channel = if grid.dual_plane {
+        color_end -= 2;
+        Some(field(color_end, 2) as usize)
+    } else {
+        None
+    };
+
+    // Endpoint colors are quantized to the most levels that fit between the header and the
+    // weights
+    let color_start = if partitions == 1 { 17 } else { 29 };
+    let value_count: u32 = modes.iter().map(|mode| 2 * (mode / 4 + 1)).sum();
+    let color_size = color_end.checked_sub(color_start)?;
+    let color_levels = ASTC_LEVELS
+        .iter()
+        .rev()
+        .copied()
+        .find(|&levels| ise_size(value_count, levels) <= color_size)?;
+    if value_count > 18 || color_levels < 6 {
+        return None;
+    }
+    let color_bits = bits >> color_start & low_bits(ise_size(value_count, color_levels));
+    let values: Vec<u32> = decode_ise(color_bits, value_count as usize, color_levels)
+        .into_iter()
+        .map(|value| unquantize_color(value, color_levels))
+        .collect();
+    let mut endpoints = Vec::with_capacity(modes.len());
+    let mut remaining = values.as_slice();
+    for &mode in &modes {
+        let (mode_values, rest) = remaining.split_at(2 * (mode as usize / 4 + 1));
+        endpoints.push(astc_endpoints(mode, mode_values)?);
+        remaining = rest;
+    }
+
+    // Weights are stored from the most significant bit down, interleaved by plane
+    let weight_bits = bits.reverse_bits() & low_bits(weight_size);
+    let weights: Vec<u32> = decode_ise(weight_bits, weight_count, grid.levels)
+        .into_iter()
+        .map(|weight| unquantize_weight(weight, grid.levels))
+        .collect();
+
+    // Texels bilinearly interpolate the weights of a grid that may be smaller than the block,
+    // with 4-bit fractions
+    let (step_x, step_y) = (
+        (1024 + width / 2) / (width - 1),
+        (1024 + height / 2) / (height - 1),
+    );
+    let small_block = width * height < 31;
+    let mut texels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            let grid_x = (step_x * x * (grid.width - 1) + 32) >> 6;
+            let grid_y = (step_y * y * (grid.height - 1) + 32) >> 6;
+            let (fraction_x, fraction_y) = (grid_x & 0xf, grid_y & 0xf);
+            let index = (grid_x >> 4) + (grid_y >> 4) * grid.width;
+            let factor11 = (fraction_x * fraction_y + 8) >> 4;
+            let factors = [
+                (index, 16 + factor11 - fraction_x - fraction_y),
+                (index + 1, fraction_x - factor11),
+                (index + grid.width, fraction_y - factor11),
+                (index + grid.width + 1, factor11),
+            ];
+            let texel_weight = |plane: usize| {
+                let sum: usize = factors
+                    .iter()
+                    .map(|&(index, factor)| {
+                        weights
+                            .get(index * planes + plane)
+                            .map_or(0, |&weight| weight as usize * factor)
+                    })
+                    .sum();
+                ((sum + 8) >> 4) as u32
+            };
+            let plane_weights = [texel_weight(0), texel_weight(planes - 1)];
+
+            let partition = match partitions {
+                1 => 0,
+                _ => astc_partition(field(13, 10), x as u32, y as u32, partitions, small_block),
+            };
+            let [endpoint0, endpoint1] = endpoints[partition];
+            let mut texel = [0; 4];
+            for (channel, value) in texel.iter_mut().enumerate() {
+                let weight = plane_weights[(second_plane_channel == Some(channel)) as usize];
+                // Endpoints are expanded to 16 bits, sRGB colors with a rounding bias
+                let expand = |endpoint: u32| {
+                    if srgb && channel < 3 {
+                        endpoint << 8 | 0x80
+                    } else {
+                        endpoint << 8 | endpoint
+                    }
+                };
+                let interpolated = (expand(endpoint0[channel]) * (64 - weight)
+                    + expand(endpoint1[channel]) * weight
+                    + 32)
+                    >> 6;
+                *value = (interpolated >> 8) as u8;
+            }
+            texels.push(texel);
+        }
+    }
+    Some(texels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decodes a single block of `format` into its texels
+    fn decode(format: vk::Format, block: &[u8]) -> Vec<[u8; 4]> {
+        let layout = BlockLayout::of(format).unwrap();
+        let extent = vk::Extent2D {
+            width: layout.width,
+            height: layout.height,
+        };
+        decompress(format, extent, block)
+            .chunks_exact(4)
+            .map(|texel| texel.try_into().unwrap())
+            .collect()
+    }
+
+    /// Packs `(value, bits)` fields into a 128-bit block, starting at the least significant bit
+    fn pack(fields: &[(u128, u32)]) -> u128 {
+        let mut offset = 0;
+        let mut bits = 0;
+        for &(value, count) in fields {
+            bits |= value << offset;
+            offset += count;
+        }
+        assert!(offset <= 128);
+        bits
+    }
+
+    #[test]
+    fn bc1_interpolates_endpoints() {
+        // Red and blue endpoints, with indices 0-3 in the first row
+        let block = [0x00, 0xf8, 0x1f, 0x00, 0b11_10_01_00, 0, 0, 0];
+        let texels = decode(vk::Format::BC1_RGB_UNORM_BLOCK, &block);
+        assert_eq!(
+            texels[..4],
+            [
+                [255, 0, 0, 255],
+                [0, 0, 255, 255],
+                [170, 0, 85, 255],
+                [85, 0, 170, 255]
+            ]
+        );
+    }
+
+    #[test]
+    fn bc1_has_three_colors_and_transparent_black() {
+        let block = [0x1f, 0x00, 0x00, 0xf8, 0b11_10_01_00, 0, 0, 0];
+        let texels = decode(vk::Format::BC1_RGBA_UNORM_BLOCK, &block);
+        assert_eq!(texels[2], [128, 0, 128, 255]);
+        assert_eq!(texels[3], [0, 0, 0, 0]);
+        let texels = decode(vk::Format::BC1_RGB_UNORM_BLOCK, &block);
+        assert_eq!(texels[3], [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn decompress_crops_edge_blocks() {
+        let block = [0x00, 0xf8, 0x1f, 0x00, 0b11_10_01_00, 0b01, 0, 0];
+        let extent = vk::Extent2D {
+            width: 3,
+            height: 2,
+        };
+        let pixels = decompress(vk::Format::BC1_RGB_UNORM_BLOCK, extent, &block);
+        assert_eq!(pixels.len(), 3 * 2 * 4);
+        assert_eq!(pixels[8..12], [170, 0, 85, 255]);
+        assert_eq!(pixels[12..16], [0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn bc4_interpolates_six_values() {
+        let indices = (1u64 << 3 | 2 << 6 | 7 << 9).to_le_bytes();
+        let mut block = vec![255, 0];
+        block.extend_from_slice(&indices[..6]);
+        let texels = decode(vk::Format::BC4_UNORM_BLOCK, &block);
+        let red: Vec<u8> = texels[..4].iter().map(|texel| texel[0]).collect();
+        assert_eq!(red, [255, 0, 219, 36]);
+        assert_eq!(texels[0], [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn bc4_decodes_signed_values() {
+        let indices = (1u64 << 3 | 2 << 6).to_le_bytes();
+        let mut block = vec![0x7f, 0x80];
+        block.extend_from_slice(&indices[..6]);
+        let texels = decode(vk::Format::BC4_SNORM_BLOCK, &block);
+        let red: Vec<i8> = texels[..3].iter().map(|texel| texel[0] as i8).collect();
+        assert_eq!(red, [127, -127, 91]);
+        assert_eq!(texels[0][3], 127);
+    }
+
+    #[test]
+    fn bc7_decodes_mode_6() {
+        // Black to red endpoints with P-bits 0 and 1, alpha 127 in both
+        let mut fields = vec![
+            (1 << 6, 7),
+            (0, 7),
+            (127, 7),
+            (0, 7),
+            (0, 7),
+            (0, 7),
+            (0, 7),
+            (127, 7),
+            (127, 7),
+            (0, 1),
+            (1, 1),
+            (0, 3),
+            (5, 4),
+        ];
+        fields.extend([(0, 4); 13]);
+        fields.push((15, 4));
+        let block = pack(&fields).to_le_bytes();
+        let texels = decode(vk::Format::BC7_UNORM_BLOCK, &block);
+        assert_eq!(texels[0], [0, 0, 0, 254]);
+        assert_eq!(texels[1], [84, 0, 0, 254]);
+        assert_eq!(texels[15], [255, 1, 1, 255]);
+    }
+
+    #[test]
+    fn bc7_decodes_partitions_with_shared_p_bits() {
+        // Partition 0 puts the two right columns in the second subset, which is white
+        let mut fields = vec![(0b10, 2), (0, 6)];
+        fields.extend([(0, 6), (0, 6), (63, 6), (63, 6)].repeat(3));
+        fields.extend([(0, 1), (1, 1), (0, 46)]);
+        let block = pack(&fields).to_le_bytes();
+        let texels = decode(vk::Format::BC7_SRGB_BLOCK, &block);
+        for (pixel, texel) in texels.iter().enumerate() {
+            let expected = if pixel % 4 < 2 {
+                [0, 0, 0, 255]
+            } else {
+                [255; 4]
+            };
+            assert_eq!(*texel, expected, "pixel {}", pixel);
+        }
+    }
+
+    #[test]
+    fn bc7_decodes_reserved_mode_to_transparent_black() {
+        let texels = decode(vk::Format::BC7_UNORM_BLOCK, &[0; 16]);
+        assert!(texels.iter().all(|texel| *texel == [0; 4]));
+    }
+
+    /// An ETC2 block in the differential mode with the base color 16, 16, 16 in both subblocks
+    fn etc2_differential_block() -> u64 {
+        16 << 59 | 16 << 51 | 16 << 43 | 1 << 33
+    }
+
+    #[test]
+    fn etc2_applies_modifiers() {
+        // Pixel (1, 0) has index 3
+        let bits = etc2_differential_block() | 1 << 20 | 1 << 4;
+        let texels = decode(vk::Format::ETC2_R8G8B8_UNORM_BLOCK, &bits.to_be_bytes());
+        assert_eq!(texels[0], [134, 134, 134, 255]);
+        assert_eq!(texels[1], [124, 124, 124, 255]);
+        assert_eq!(texels[4], [134, 134, 134, 255]);
+    }
+
+    #[test]
+    fn etc2_punchthrough_makes_index_2_transparent() {
+        // Without the opaque bit, pixel (1, 0) has index 2 and the others keep the base color
+        let bits = etc2_differential_block() & !(1 << 33) | 1 << 20;
+        let texels = decode(vk::Format::ETC2_R8G8B8A1_UNORM_BLOCK, &bits.to_be_bytes());
+        assert_eq!(texels[0], [132, 132, 132, 255]);
+        assert_eq!(texels[1], [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn eac_decodes_alpha() {
+        // Base 128, multiplier 1, table 0 and index 7 for the first pixel
+        let alpha: u64 = 128 << 56 | 1 << 52 | 7 << 45;
+        let mut block = alpha.to_be_bytes().to_vec();
+        block.extend_from_slice(&etc2_differential_block().to_be_bytes());
+        let texels = decode(vk::Format::ETC2_R8G8B8A8_UNORM_BLOCK, &block);
+        assert_eq!(texels[0], [134, 134, 134, 142]);
+        assert_eq!(texels[1], [134, 134, 134, 125]);
+    }
+
+    #[test]
+    fn eac_decodes_11_bit_channels() {
+        let red: u64 = 128 << 56 | 1 << 52 | 7 << 45;
+        let texels = decode(vk::Format::EAC_R11_UNORM_BLOCK, &red.to_be_bytes());
+        assert_eq!(texels[0], [142, 0, 0, 255]);
+        assert_eq!(texels[1], [125, 0, 0, 255]);
+
+        // -128 is read as -127, index 0 clamps to -1023
+        let mut block = red.to_be_bytes().to_vec();
+        block.extend_from_slice(&red.to_be_bytes());
+        let texels = decode(vk::Format::EAC_R11G11_SNORM_BLOCK, &block);
+        assert_eq!(texels[0].map(|value| value as i8), [-112, -112, 0, 127]);
+        assert_eq!(texels[1].map(|value| value as i8), [-127, -127, 0, 127]);
+    }
+
+    #[test]
+    fn astc_unquantizes_evenly() {
+        let sorted = |values: Vec<u32>| {
+            let mut values = values;
+            values.sort_unstable();
+            values
+        };
+        let colors = sorted((0..6).map(|value| unquantize_color(value, 6)).collect());
+        assert_eq!(colors, [0, 51, 102, 153, 204, 255]);
+        let weights = sorted((0..6).map(|value| unquantize_weight(value, 6)).collect());
+        assert_eq!(weights, [0, 12, 25, 39, 52, 64]);
+        let weights = sorted((0..4).map(|value| unquantize_weight(value, 4)).collect());
+        assert_eq!(weights, [0, 21, 43, 64]);
+        for levels in ASTC_LEVELS.iter().copied().filter(|&levels| levels >= 6) {
+            let mut colors = sorted((0..levels).map(|v| unquantize_color(v, levels)).collect());
+            colors.dedup();
+            assert_eq!(colors.len() as u32, levels);
+            assert_eq!((colors[0], colors[colors.len() - 1]), (0, 255));
+        }
+    }
+
+    #[test]
+    fn astc_decodes_void_extent() {
+        let block = pack(&[
+            (0x1fc, 9),
+            (0, 1),
+            (0b11, 2),
+            ((1 << 52) - 1, 52),
+            (0xffff, 16),
+            (0x8000, 16),
+            (0, 16),
+            (0xffff, 16),
+        ]);
+        let texels = decode(vk::Format::ASTC_6X5_UNORM_BLOCK, &block.to_le_bytes());
+        assert_eq!(texels, vec![[255, 128, 0, 255]; 30]);
+    }
+
+    #[test]
+    fn astc_interpolates_luminance() {
+        // A 4x4 grid of 2-bit weights and the luminance endpoints 0 and 255
+        let header = pack(&[(0x42, 11), (0, 2), (0, 4), (0, 8), (255, 8)]);
+        let weights = pack(&[(0, 2), (1, 2), (2, 2), (3, 2)].repeat(4));
+        let block = header | weights.reverse_bits();
+        let texels = decode(vk::Format::ASTC_4X4_UNORM_BLOCK, &block.to_le_bytes());
+        for row in texels.chunks(4) {
+            let luminance: Vec<[u8; 4]> = [0, 84, 171, 255]
+                .iter()
+                .map(|&value| [value, value, value, 255])
+                .collect();
+            assert_eq!(row, luminance.as_slice());
+        }
+    }
+
+    #[test]
+    fn astc_decodes_trit_weights() {
+        // Weights with 3 levels: the first five are packed into 8 bits, here the trits 2, 1, 0
+        let header = pack(&[(0x51, 11), (0, 2), (0, 4), (0, 8), (255, 8)]);
+        let block = header | pack(&[(0b110, 8)]).reverse_bits();
+        let texels = decode(vk::Format::ASTC_4X4_SRGB_BLOCK, &block.to_le_bytes());
+        assert_eq!(texels[0], [255; 4]);
+        assert_eq!(texels[1], [128, 128, 128, 255]);
+        assert_eq!(texels[2], [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn astc_decodes_reserved_blocks_to_magenta() {
+        let texels = decode(vk::Format::ASTC_8X8_UNORM_BLOCK, &[0; 16]);
+        assert_eq!(texels, vec![ASTC_ERROR_COLOR; 64]);
+    }
+}
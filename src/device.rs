@@ -0,0 +1,508 @@
+use ash::extensions::khr;
+use ash::{vk, Instance};
+use std::ffi::CStr;
+use std::str::FromStr;
+
+use crate::error::{RendererError, RendererResult};
+use crate::features::DeviceFeatures;
+
+/// How the physical device is chosen
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum DeviceSelection {
+    /// The suitable device with the highest score
+    #[default]
+    Auto,
+    /// The device at this position in `vkEnumeratePhysicalDevices`
+    Index(usize),
+    /// The best suitable device whose name contains this string, ignoring case
+    Name(String),
+}
+
+/// Parses the value of the `CHARLIE_DEVICE` environment variable or the `--device` flag: a
+/// device index, a part of the device name, or `auto`.
+impl FromStr for DeviceSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err("The device selection can't be empty".to_string());
+        }
+        if s.eq_ignore_ascii_case("auto") {
+            return Ok(DeviceSelection::Auto);
+        }
+        Ok(match s.parse() {
+            Ok(index) => DeviceSelection::Index(index),
+            Err(_) => DeviceSelection::Name(s.to_string()),
+        })
+    }
+}
+
+/// The queue families the renderer uses. Several roles can share a family.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QueueFamilyIndices {
+    pub graphics: u32,
+    /// `None` without a surface. The graphics family is preferred if it can present.
+    pub present: Option<u32>,
+    /// A family without graphics support (for async compute) if there is one, otherwise the
+    /// graphics family
+    pub compute: u32,
+    /// A family with neither graphics nor compute support (usually backed by a DMA engine) if
+    /// there is one, otherwise a family without graphics support or the graphics family
+    pub transfer: u32,
+}
+
+impl QueueFamilyIndices {
+    /// The distinct families in order of first use. Each gets one queue.
+    pub fn unique(&self) -> Vec<u32> {
+        let mut families = Vec::with_capacity(4);
+        for family in [
+            Some(self.graphics),
+            self.present,
+            Some(self.compute),
+            Some(self.transfer),
+        ]
+        .iter()
+        .flatten()
+        {
+            if !families.contains(family) {
+                families.push(*family);
+            }
+        }
+        families
+    }
+
+    pub fn has_async_compute(&self) -> bool {
+        self.compute != self.graphics
+    }
+
+    pub fn has_dedicated_transfer(&self) -> bool {
+        self.transfer != self.graphics && self.transfer != self.compute
+    }
+}
+
+/// Picks the queue families for each role. A device without the families the renderer needs is
+/// rejected with `RendererError::NoSuitableDevice` and the reason. Without a surface,
+/// presentation isn't needed.
+pub fn find_queue_family_indices(
+    instance: &Instance,
+    physical_device: vk::PhysicalDevice,
+    surface: Option<(vk::SurfaceKHR, &khr::Surface)>,
+) -> RendererResult<QueueFamilyIndices> {
+    let reject = |reason: &str| Err(RendererError::NoSuitableDevice(reason.to_string()));
+    let queue_family_properties =
+        unsafe { instance.get_physical_device_queue_family_properties(physical_device) };
+    let families = queue_family_properties
+        .iter()
+        .enumerate()
+        .filter(|(_, family)| family.queue_count > 0)
+        .map(|(index, family)| {
+            let supports_present = match surface {
+                Some((surface, surface_fn)) => unsafe {
+                    surface_fn.get_physical_device_surface_support(
+                        physical_device,
+                        index as u32,
+                        surface,
+                    )?
+                },
+                None => false,
+            };
+            Ok((index as u32, family.queue_flags, supports_present))
+        })
+        .collect::<RendererResult<Vec<_>>>()?;
+    let find = |predicate: &dyn Fn(vk::QueueFlags, bool) -> bool| {
+        families
+            .iter()
+            .find(|(_, flags, supports_present)| predicate(*flags, *supports_present))
+            .map(|(index, _, _)| *index)
+    };
+
+    let graphics = match surface {
+        // Presenting from the graphics family avoids sharing images between families
+        Some(_) => find(&|flags, present| flags.contains(vk::QueueFlags::GRAPHICS) && present),
+        None => None,
+    }
+    .or_else(|| find(&|flags, _| flags.contains(vk::QueueFlags::GRAPHICS)));
+    let graphics = match graphics {
+        Some(graphics) => graphics,
+        None => return reject("No queue family supports graphics"),
+    };
+
+    let present = match surface {
+        Some(_) => {
+            let graphics_can_present = families
+                .iter()
+                .any(|(index, _, present)| *index == graphics && *present);
+            let present = if graphics_can_present {
+                Some(graphics)
+            } else {
+                find(&|_, present| present)
+            };
+            match present {
+                Some(present) => Some(present),
+                None => return reject("No queue family can present to the surface"),
+            }
+        }
+        None => None,
+    };
+
+    let compute = find(&|flags, _| {
+        flags.contains(vk::QueueFlags::COMPUTE) && !flags.contains(vk::QueueFlags::GRAPHICS)
+    })
+    .unwrap_or(graphics);
+
+    // Graphics and compute families support transfers even if they don't report it
+    let transfer = find(&|flags, _| {
+        flags.contains(vk::QueueFlags::TRANSFER)
+            && !flags.intersects(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE)
+    })
+    .or_else(|| {
+        find(&|flags, _| {
+            !flags.contains(vk::QueueFlags::GRAPHICS) && flags.contains(vk::QueueFlags::COMPUTE)
+        })
+    })
+    .unwrap_or(graphics);
+
+    Ok(QueueFamilyIndices {
+        graphics,
+        present,
+        compute,
+        transfer,
+    })
+}
+
+/// Extensions the device must support regardless of its features. Extensions for features are
+/// given by `DeviceFeatures::extensions`.
+pub fn required_device_extensions(enable_swapchain: bool) -> Vec<&'static CStr> {
+    let mut extensions = Vec::new();
+    if enable_swapchain {
+        extensions.push(khr::Swapchain::name());
+    }
+    extensions
+}
+
+/// A physical device together with what the renderer found out about it
+pub struct PhysicalDeviceInfo {
+    pub physical_device: vk::PhysicalDevice,
+    /// Position in `vkEnumeratePhysicalDevices`
+    pub index: usize,
+    pub name: String,
+    pub device_type: vk::PhysicalDeviceType,
+    pub api_version: u32,
+    pub driver_version: u32,
+    pub vendor_id: u32,
+    pub device_id: u32,
+    /// Size of the largest device-local memory heap
+    pub device_local_memory: vk::DeviceSize,
+    /// The supported features the renderer can use
+    pub features: DeviceFeatures,
+    /// `None` if the device was rejected
+    pub queue_family_indices: Option<QueueFamilyIndices>,
+    pub missing_extensions: Vec<String>,
+    pub missing_features: Vec<String>,
+    /// Why the device can't be used, including the missing extensions and features. Empty for
+    /// suitable devices.
+    pub rejection_reasons: Vec<String>,
+    /// Higher is better. Only meaningful for suitable devices.
+    pub score: DeviceScore,
+}
+
+impl PhysicalDeviceInfo {
+    pub fn is_suitable(&self) -> bool {
+        self.rejection_reasons.is_empty()
+    }
+}
+
+/// Orders devices by their type first, so that e.g. a discrete GPU always beats an integrated
+/// one, and by their device-local memory among devices of the same type.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DeviceScore {
+    pub device_type_rank: u32,
+    /// In GiB
+    pub device_local_memory: vk::DeviceSize,
+}
+
+fn device_type_rank(device_type: vk::PhysicalDeviceType) -> u32 {
+    match device_type {
+        vk::PhysicalDeviceType::DISCRETE_GPU => 4,
+        vk::PhysicalDeviceType::INTEGRATED_GPU => 3,
+        vk::PhysicalDeviceType::VIRTUAL_GPU => 2,
+        vk::PhysicalDeviceType::CPU => 1,
+        _ => 0,
+    }
+}
+
+fn inspect_physical_device(
+    instance: &Instance,
+    instance_api_version: u32,
+    physical_device: vk::PhysicalDevice,
+    index: usize,
+    surface: Option<(vk::SurfaceKHR, &khr::Surface)>,
+    required_extensions: &[&CStr],
+) -> RendererResult<PhysicalDeviceInfo> {
+    let properties = unsafe { instance.get_physical_device_properties(physical_device) };
+    let memory_properties =
+        unsafe { instance.get_physical_device_memory_properties(physical_device) };
+    let device_local_memory = memory_properties.memory_heaps
+        [..memory_properties.memory_heap_count as usize]
+        .iter()
+        .filter(|heap| heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL))
+        .map(|heap| heap.size)
+        .max()
+        .unwrap_or(0);
+
+    let available_extensions =
+        unsafe { instance.enumerate_device_extension_properties(physical_device)? };
+    let missing_extensions = required_extensions
+        .iter()
+        .filter(|required| {
+            !available_extensions.iter().any(|extension| {
+                let name = unsafe { CStr::from_ptr(extension.extension_name.as_ptr()) };
+                name == **required
+            })
+        })
+        .map(|required| required.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    let features = DeviceFeatures::query(
+        instance,
+        instance_api_version,
+        physical_device,
+        &available_extensions,
+    );
+    let missing_features = features.missing_required();
+
+    let mut rejection_reasons = missing_extensions
+        .iter()
+        .map(|extension| format!("Missing extension {}", extension))
+        .chain(missing_features.iter().cloned())
+        .collect::<Vec<_>>();
+    let queue_family_indices = match find_queue_family_indices(instance, physical_device, surface) {
+        Ok(queue_family_indices) => Some(queue_family_indices),
+        Err(RendererError::NoSuitableDevice(reason)) => {
+            rejection_reasons.push(reason);
+            None
+        }
+        Err(err) => return Err(err),
+    };
+
+    let score = DeviceScore {
+        device_type_rank: device_type_rank(properties.device_type),
+        device_local_memory: device_local_memory >> 30,
+    };
+
+    Ok(PhysicalDeviceInfo {
+        physical_device,
+        index,
+        name: unsafe { CStr::from_ptr(properties.device_name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned(),
+        device_type: properties.device_type,
+        api_version: properties.api_version,
+        driver_version: properties.driver_version,
+        vendor_id: properties.vendor_id,
+        device_id: properties.device_id,
+        device_local_memory,
+        features,
+        queue_family_indices: if rejection_reasons.is_empty() {
+            queue_family_indices
+        } else {
+            None
+        },
+        missing_extensions,
+        missing_features,
+        rejection_reasons,
+        score,
+    })
+}
+
+/// Inspects all physical devices in enumeration order, as used with at most
+/// `instance_api_version`. Devices that can't present to `surface` or lack any of
+/// `required_extensions` are rejected.
+pub fn enumerate_physical_devices(
+    instance: &Instance,
+    instance_api_version: u32,
+    surface: Option<(vk::SurfaceKHR, &khr::Surface)>,
+    required_extensions: &[&CStr],
+) -> RendererResult<Vec<PhysicalDeviceInfo>> {
+    let physical_devices = unsafe { instance.enumerate_physical_devices()? };
+    physical_devices
+        .into_iter()
+        .enumerate()
+        .map(|(index, physical_device)| {
+            inspect_physical_device(
+                instance,
+                instance_api_version,
+                physical_device,
+                index,
+                surface,
+                required_extensions,
+            )
+        })
+        .collect()
+}
+
+/// Picks a suitable device according to `selection`. An explicitly selected device that is not
+/// suitable is an error rather than silently replaced.
+pub fn select_physical_device(
+    devices: Vec<PhysicalDeviceInfo>,
+    selection: &DeviceSelection,
+) -> RendererResult<PhysicalDeviceInfo> {
+    if devices.is_empty() {
+        return Err(RendererError::NoSuitableDevice(
+            "No Vulkan devices are available".to_string(),
+        ));
+    }
+
+    let candidates = match selection {
+        DeviceSelection::Auto => devices,
+        DeviceSelection::Index(index) => {
+            let device_count = devices.len();
+            let device = devices
+                .into_iter()
+                .find(|device| device.index == *index)
+                .ok_or_else(|| {
+                    RendererError::NoSuitableDevice(format!(
+                        "There is no device {}, only {} device(s) are available",
+                        index, device_count
+                    ))
+                })?;
+            // Be specific about why an explicitly selected device can't be used
+            if !device.missing_extensions.is_empty() {
+                return Err(RendererError::MissingExtension(
+                    device.missing_extensions.join(", "),
+                ));
+            }
+            if !device.missing_features.is_empty() {
+                return Err(RendererError::MissingFeature(
+                    device.missing_features.join(", "),
+                ));
+            }
+            vec![device]
+        }
+        DeviceSelection::Name(name) => {
+            let name = name.to_lowercase();
+            let matching = devices
+                .into_iter()
+                .filter(|device| device.name.to_lowercase().contains(&name))
+                .collect::<Vec<_>>();
+            if matching.is_empty() {
+                return Err(RendererError::NoSuitableDevice(format!(
+                    "No device name contains `{}`",
+                    name
+                )));
+            }
+            matching
+        }
+    };
+
+    let rejections = candidates
+        .iter()
+        .map(|device| format!("{}: {}", device.name, device.rejection_reasons.join(", ")))
+        .collect::<Vec<_>>();
+    candidates
+        .into_iter()
+        .filter(PhysicalDeviceInfo::is_suitable)
+        // `max_by_key` returns the last maximum, but ties should go to the first device
+        .rev()
+        .max_by_key(|device| device.score)
+        .ok_or_else(|| RendererError::NoSuitableDevice(rejections.join("; ")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(
+        index: usize,
+        device_type: vk::PhysicalDeviceType,
+        device_local_memory: vk::DeviceSize,
+    ) -> PhysicalDeviceInfo {
+        PhysicalDeviceInfo {
+            physical_device: vk::PhysicalDevice::null(),
+            index,
+            name: format!("device {}", index),
+            device_type,
+            api_version: 0,
+            driver_version: 0,
+            vendor_id: 0,
+            device_id: 0,
+            device_local_memory,
+            features: DeviceFeatures::default(),
+            queue_family_indices: None,
+            missing_extensions: Vec::new(),
+            missing_features: Vec::new(),
+            rejection_reasons: Vec::new(),
+            score: DeviceScore {
+                device_type_rank: device_type_rank(device_type),
+                device_local_memory: device_local_memory >> 30,
+            },
+        }
+    }
+
+    #[test]
+    fn device_type_outranks_memory() {
+        let devices = vec![
+            device(0, vk::PhysicalDeviceType::INTEGRATED_GPU, 64 << 30),
+            device(1, vk::PhysicalDeviceType::DISCRETE_GPU, 2 << 30),
+            device(2, vk::PhysicalDeviceType::CPU, 128 << 30),
+        ];
+        let selected = select_physical_device(devices, &DeviceSelection::Auto).unwrap();
+        assert_eq!(selected.index, 1);
+    }
+
+    #[test]
+    fn memory_breaks_ties_and_the_first_device_wins_otherwise() {
+        let devices = vec![
+            device(0, vk::PhysicalDeviceType::DISCRETE_GPU, 8 << 30),
+            device(1, vk::PhysicalDeviceType::DISCRETE_GPU, 16 << 30),
+            device(2, vk::PhysicalDeviceType::DISCRETE_GPU, 16 << 30),
+        ];
+        let selected = select_physical_device(devices, &DeviceSelection::Auto).unwrap();
+        assert_eq!(selected.index, 1);
+    }
+
+    #[test]
+    fn rejected_devices_are_not_selected() {
+        let mut discrete = device(0, vk::PhysicalDeviceType::DISCRETE_GPU, 8 << 30);
+        discrete
+            .rejection_reasons
+            .push("No queue family supports graphics".to_string());
+        let devices = vec![
+            discrete,
+            device(1, vk::PhysicalDeviceType::INTEGRATED_GPU, 1 << 30),
+        ];
+        let selected = select_physical_device(devices, &DeviceSelection::Auto).unwrap();
+        assert_eq!(selected.index, 1);
+
+        let mut cpu = device(0, vk::PhysicalDeviceType::CPU, 0);
+        cpu.rejection_reasons.push("Missing extension".to_string());
+        match select_physical_device(vec![cpu], &DeviceSelection::Name("DEVICE".to_string())) {
+            Err(RendererError::NoSuitableDevice(reason)) => {
+                assert_eq!(reason, "device 0: Missing extension")
+            }
+            _ => panic!("The rejected device was selected"),
+        }
+    }
+
+    #[test]
+    fn every_family_in_use_gets_one_queue() {
+        let shared = QueueFamilyIndices {
+            graphics: 0,
+            present: Some(0),
+            compute: 0,
+            transfer: 0,
+        };
+        assert_eq!(shared.unique(), vec![0]);
+        assert!(!shared.has_async_compute());
+
+        let separate = QueueFamilyIndices {
+            graphics: 0,
+            present: None,
+            compute: 2,
+            transfer: 1,
+        };
+        assert_eq!(separate.unique(), vec![0, 2, 1]);
+        assert!(separate.has_async_compute());
+        assert!(separate.has_dedicated_transfer());
+    }
+}
@@ -1 +0,0 @@
-pub type DynResult<T> = Result<T, Box<dyn std::error::Error>>;
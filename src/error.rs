@@ -0,0 +1,115 @@
+use ash::vk;
+use std::fmt;
+
+/// Errors reported by the renderer.
+///
+/// Most variants correspond to situations the caller can react to: recreate the swapchain or
+/// surface, retry with another device, or free memory.
+#[derive(Debug)]
+pub enum RendererError {
+    /// The Vulkan loader library couldn't be loaded
+    LoaderNotFound(String),
+    /// None of the physical devices meets the renderer's requirements
+    NoSuitableDevice(String),
+    /// A required instance or device extension is not available
+    MissingExtension(String),
+    /// A required device feature is not supported
+    MissingFeature(String),
+    /// The surface is no longer usable and has to be recreated together with the renderer
+    SurfaceLost,
+    /// The swapchain no longer matches the surface and has to be recreated
+    SwapchainOutOfDate,
+    /// The logical device was lost, e.g. after a driver crash or GPU reset
+    DeviceLost,
+    /// Either host or device memory ran out
+    OutOfMemory {
+        /// `true` for device memory, `false` for host memory
+        device: bool,
+    },
+    /// The validation layer reported this many errors, returned in strict validation mode
+    ValidationErrors(usize),
+    /// The device can't do what was asked, e.g. a missing memory type or an unsupported format
+    Unsupported(String),
+    /// Any other error returned by a Vulkan call
+    Vulkan(vk::Result),
+    Io(std::io::Error),
+    /// Encoding or decoding an image file failed
+    Image(String),
+    /// Importing a model or scene file failed
+    Model(String),
+}
+
+pub type RendererResult<T> = Result<T, RendererError>;
+
+impl fmt::Display for RendererError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RendererError::LoaderNotFound(reason) => {
+                write!(f, "Can't load the Vulkan loader: {}", reason)
+            }
+            RendererError::NoSuitableDevice(reason) => {
+                write!(f, "Can't find a suitable physical device: {}", reason)
+            }
+            RendererError::MissingExtension(name) => {
+                write!(f, "The Vulkan extension {} is not available", name)
+            }
+            RendererError::MissingFeature(name) => {
+                write!(f, "The device feature {} is not supported", name)
+            }
+            RendererError::SurfaceLost => write!(f, "The surface was lost"),
+            RendererError::SwapchainOutOfDate => write!(f, "The swapchain is out of date"),
+            RendererError::DeviceLost => write!(f, "The device was lost"),
+            RendererError::OutOfMemory { device: true } => write!(f, "Out of device memory"),
+            RendererError::OutOfMemory { device: false } => write!(f, "Out of host memory"),
+            RendererError::ValidationErrors(count) => {
+                write!(f, "The validation layer reported {} error(s)", count)
+            }
+            RendererError::Unsupported(reason) => write!(f, "{}", reason),
+            RendererError::Vulkan(result) => write!(f, "Vulkan error: {}", result),
+            RendererError::Io(err) => write!(f, "{}", err),
+            RendererError::Image(reason) => write!(f, "Image error: {}", reason),
+            RendererError::Model(reason) => write!(f, "Model error: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for RendererError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RendererError::Vulkan(result) => Some(result),
+            RendererError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<vk::Result> for RendererError {
+    fn from(result: vk::Result) -> Self {
+        match result {
+            vk::Result::ERROR_SURFACE_LOST_KHR => RendererError::SurfaceLost,
+            vk::Result::ERROR_OUT_OF_DATE_KHR => RendererError::SwapchainOutOfDate,
+            vk::Result::ERROR_DEVICE_LOST => RendererError::DeviceLost,
+            vk::Result::ERROR_OUT_OF_HOST_MEMORY => RendererError::OutOfMemory { device: false },
+            vk::Result::ERROR_OUT_OF_DEVICE_MEMORY => RendererError::OutOfMemory { device: true },
+            result => RendererError::Vulkan(result),
+        }
+    }
+}
+
+impl From<ash::LoadingError> for RendererError {
+    fn from(err: ash::LoadingError) -> Self {
+        RendererError::LoaderNotFound(err.to_string())
+    }
+}
+
+impl From<std::io::Error> for RendererError {
+    fn from(err: std::io::Error) -> Self {
+        RendererError::Io(err)
+    }
+}
+
+impl From<png::EncodingError> for RendererError {
+    fn from(err: png::EncodingError) -> Self {
+        RendererError::Image(err.to_string())
+    }
+}
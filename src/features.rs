@@ -0,0 +1,258 @@
+use ash::extensions::khr;
+use ash::{vk, Device, Instance};
+use std::ffi::{CStr, CString};
+
+use crate::error::{RendererError, RendererResult};
+
+/// The highest Vulkan version the renderer uses. The instance is created with the lower of this
+/// and the loader's version, and devices are used with the lower of that and their own version.
+pub const API_VERSION: u32 = vk::make_api_version(0, 1, 3, 0);
+
+const API_VERSION_1_3: u32 = vk::make_api_version(0, 1, 3, 0);
+
+/// Device features the renderer can make use of, either supported by a device or enabled on it.
+///
+/// Features that became core in the device's Vulkan version are used without their extension.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DeviceFeatures {
+    /// The Vulkan version the device is used with
+    pub api_version: u32,
+    /// Core in Vulkan 1.3, `VK_KHR_dynamic_rendering` before. Render passes are used without it.
+    pub dynamic_rendering: bool,
+    /// Core in Vulkan 1.3, `VK_KHR_synchronization2` before
+    pub synchronization2: bool,
+    /// Core in Vulkan 1.2
+    pub timeline_semaphore: bool,
+    /// Non-uniform indexing into partially bound, variable-sized and update-after-bind sampled
+    /// image arrays, as needed for bindless textures. Core in Vulkan 1.2.
+    pub descriptor_indexing: bool,
+    /// Anisotropic texture filtering. Samplers ask for none without it.
+    pub sampler_anisotropy: bool,
+    /// BC1 to BC7 block-compressed formats, usually only on desktop GPUs
+    pub texture_compression_bc: bool,
+    /// ETC2 and EAC block-compressed formats, usually only on mobile GPUs
+    pub texture_compression_etc2: bool,
+    /// ASTC block-compressed formats with 8-bit precision, usually only on mobile GPUs
+    pub texture_compression_astc_ldr: bool,
+}
+
+/// The version to create the instance with, given the loader's version from
+/// `vkEnumerateInstanceVersion`. `None` stands for a Vulkan 1.0 loader, which lacks that function
+/// and would reject any other version with `VK_ERROR_INCOMPATIBLE_DRIVER`.
+pub fn instance_api_version(loader_version: Option<u32>) -> RendererResult<u32> {
+    match loader_version {
+        Some(version) if version >= vk::API_VERSION_1_1 => Ok(version.min(API_VERSION)),
+        _ => Err(RendererError::Unsupported(
+            "The Vulkan loader only supports Vulkan 1.0, the renderer needs Vulkan 1.1".to_string(),
+        )),
+    }
+}
+
+fn has_extension(available_extensions: &[vk::ExtensionProperties], name: &CStr) -> bool {
+    available_extensions
+        .iter()
+        .any(|extension| unsafe { CStr::from_ptr(extension.extension_name.as_ptr()) } == name)
+}
+
+impl DeviceFeatures {
+    /// Queries what the device supports through `vkGetPhysicalDeviceFeatures2`. The device is
+    /// used with at most `instance_api_version`, the version the instance was created with.
+    pub fn query(
+        instance: &Instance,
+        instance_api_version: u32,
+        physical_device: vk::PhysicalDevice,
+        available_extensions: &[vk::ExtensionProperties],
+    ) -> DeviceFeatures {
+        let properties = unsafe { instance.get_physical_device_properties(physical_device) };
+        let api_version = properties.api_version.min(instance_api_version);
+        // `vkGetPhysicalDeviceFeatures2` is core since Vulkan 1.1
+        if api_version < vk::API_VERSION_1_1 {
+            return DeviceFeatures {
+                api_version,
+                ..Default::default()
+            };
+        }
+
+        // Only structs of core versions or available extensions may be chained
+        let is_1_3 = api_version >= API_VERSION_1_3;
+        let has_dynamic_rendering =
+            is_1_3 || has_extension(available_extensions, khr::DynamicRendering::name());
+        let has_synchronization2 =
+            is_1_3 || has_extension(available_extensions, khr::Synchronization2::name());
+        let has_vulkan12 = api_version >= vk::API_VERSION_1_2;
+
+        let mut dynamic_rendering = vk::PhysicalDeviceDynamicRenderingFeaturesKHR::default();
+        let mut synchronization2 = vk::PhysicalDeviceSynchronization2FeaturesKHR::default();
+        let mut vulkan12 = vk::PhysicalDeviceVulkan12Features::default();
+        let mut features2 = vk::PhysicalDeviceFeatures2::builder();
+        if has_dynamic_rendering {
+            features2 = features2.push_next(&mut dynamic_rendering);
+        }
+        if has_synchronization2 {
+            features2 = features2.push_next(&mut synchronization2);
+        }
+        if has_vulkan12 {
+            features2 = features2.push_next(&mut vulkan12);
+        }
+        unsafe { instance.get_physical_device_features2(physical_device, &mut features2) };
+        let vulkan10 = features2.features;
+
+        DeviceFeatures {
+            api_version,
+            dynamic_rendering: dynamic_rendering.dynamic_rendering == vk::TRUE,
+            synchronization2: synchronization2.synchronization2 == vk::TRUE,
+            timeline_semaphore: vulkan12.timeline_semaphore == vk::TRUE,
+            descriptor_indexing: vulkan12.descriptor_indexing == vk::TRUE
+                && vulkan12.shader_sampled_image_array_non_uniform_indexing == vk::TRUE
+                && vulkan12.descriptor_binding_sampled_image_update_after_bind == vk::TRUE
+                && vulkan12.descriptor_binding_partially_bound == vk::TRUE
+                && vulkan12.descriptor_binding_variable_descriptor_count == vk::TRUE
+                && vulkan12.runtime_descriptor_array == vk::TRUE,
+            sampler_anisotropy: vulkan10.sampler_anisotropy == vk::TRUE,
+            texture_compression_bc: vulkan10.texture_compression_bc == vk::TRUE,
+            texture_compression_etc2: vulkan10.texture_compression_etc2 == vk::TRUE,
+            texture_compression_astc_ldr: vulkan10.texture_compression_astc_ldr == vk::TRUE,
+        }
+    }
+
+    /// Describes the required features that are missing. Empty if the device can be used.
+    pub fn missing_required(&self) -> Vec<String> {
+        let mut missing = Vec::new();
+        if self.api_version < vk::API_VERSION_1_1 {
+            missing.push("Vulkan 1.1 is not supported".to_string());
+        }
+        missing
+    }
+
+    pub fn is_dynamic_rendering_core(&self) -> bool {
+        self.api_version >= API_VERSION_1_3
+    }
+
+    /// The extensions needed for the features that are not core in `api_version`
+    pub fn extensions(&self) -> Vec<&'static CStr> {
+        let mut extensions = Vec::new();
+        if self.api_version < API_VERSION_1_3 {
+            if self.dynamic_rendering {
+                extensions.push(khr::DynamicRendering::name());
+            }
+            if self.synchronization2 {
+                extensions.push(khr::Synchronization2::name());
+            }
+        }
+        extensions
+    }
+}
+
+/// The feature structs that enable a set of `DeviceFeatures` at device creation
+pub struct DeviceFeatureChain {
+    features: DeviceFeatures,
+    vulkan10: vk::PhysicalDeviceFeatures,
+    dynamic_rendering: vk::PhysicalDeviceDynamicRenderingFeaturesKHR,
+    synchronization2: vk::PhysicalDeviceSynchronization2FeaturesKHR,
+    vulkan12: vk::PhysicalDeviceVulkan12Features,
+}
+
+impl DeviceFeatureChain {
+    pub fn new(features: &DeviceFeatures) -> Self {
+        let descriptor_indexing = features.descriptor_indexing;
+        DeviceFeatureChain {
+            features: *features,
+            vulkan10: vk::PhysicalDeviceFeatures::builder()
+                .sampler_anisotropy(features.sampler_anisotropy)
+                .texture_compression_bc(features.texture_compression_bc)
+                .texture_compression_etc2(features.texture_compression_etc2)
+                .texture_compression_astc_ldr(features.texture_compression_astc_ldr)
+                .build(),
+            dynamic_rendering: vk::PhysicalDeviceDynamicRenderingFeaturesKHR::builder()
+                .dynamic_rendering(true)
+                .build(),
+            synchronization2: vk::PhysicalDeviceSynchronization2FeaturesKHR::builder()
+                .synchronization2(true)
+                .build(),
+            vulkan12: vk::PhysicalDeviceVulkan12Features::builder()
+                .timeline_semaphore(features.timeline_semaphore)
+                .descriptor_indexing(descriptor_indexing)
+                .shader_sampled_image_array_non_uniform_indexing(descriptor_indexing)
+                .descriptor_binding_sampled_image_update_after_bind(descriptor_indexing)
+                .descriptor_binding_partially_bound(descriptor_indexing)
+                .descriptor_binding_variable_descriptor_count(descriptor_indexing)
+                .runtime_descriptor_array(descriptor_indexing)
+                .build(),
+        }
+    }
+
+    /// Chains the structs of the enabled features into `device_create_info`.
+    pub fn push_into<'a>(
+        &'a mut self,
+        mut device_create_info: vk::DeviceCreateInfoBuilder<'a>,
+    ) -> vk::DeviceCreateInfoBuilder<'a> {
+        device_create_info = device_create_info.enabled_features(&self.vulkan10);
+        if self.features.dynamic_rendering {
+            device_create_info = device_create_info.push_next(&mut self.dynamic_rendering);
+        }
+        if self.features.synchronization2 {
+            device_create_info = device_create_info.push_next(&mut self.synchronization2);
+        }
+        if self.features.api_version >= vk::API_VERSION_1_2 {
+            device_create_info = device_create_info.push_next(&mut self.vulkan12);
+        }
+        device_create_info
+    }
+}
+
+/// `vkCmdBeginRendering` and `vkCmdEndRendering`, loaded from the core or the KHR entry points
+/// depending on the device's Vulkan version.
+pub struct DynamicRendering {
+    fp: vk::KhrDynamicRenderingFn,
+}
+
+impl DynamicRendering {
+    pub fn new(instance: &Instance, device: &Device, features: &DeviceFeatures) -> Self {
+        let is_core = features.is_dynamic_rendering_core();
+        let fp = vk::KhrDynamicRenderingFn::load(|name| {
+            // The core functions have the same signatures, just without the suffix
+            let name = match name.to_str() {
+                Ok(name) if is_core => CString::new(name.trim_end_matches("KHR")).unwrap(),
+                _ => name.to_owned(),
+            };
+            unsafe {
+                std::mem::transmute(instance.get_device_proc_addr(device.handle(), name.as_ptr()))
+            }
+        });
+        DynamicRendering { fp }
+    }
+
+    /// # Safety
+    /// Same as `vkCmdBeginRendering`.
+    pub unsafe fn cmd_begin_rendering(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        rendering_info: &vk::RenderingInfoKHR,
+    ) {
+        self.fp
+            .cmd_begin_rendering_khr(command_buffer, rendering_info)
+    }
+
+    /// # Safety
+    /// Same as `vkCmdEndRendering`.
+    pub unsafe fn cmd_end_rendering(&self, command_buffer: vk::CommandBuffer) {
+        self.fp.cmd_end_rendering_khr(command_buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instance_version_is_clamped_to_the_loader() {
+        let loader_1_2 = vk::make_api_version(0, 1, 2, 198);
+        assert_eq!(instance_api_version(Some(loader_1_2)).unwrap(), loader_1_2);
+        let loader_1_4 = vk::make_api_version(0, 1, 4, 0);
+        assert_eq!(instance_api_version(Some(loader_1_4)).unwrap(), API_VERSION);
+        assert!(matches!(
+            instance_api_version(None),
+            Err(RendererError::Unsupported(_))
+        ));
+    }
+}
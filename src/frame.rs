@@ -0,0 +1,249 @@
+use ash::{vk, Device};
+use bytemuck::{Pod, Zeroable};
+use glam::Mat4;
+
+use crate::allocator::{AllocationStrategy, MemoryLocation};
+use crate::error::RendererResult;
+use crate::resource::{Buffer, BufferDesc, ResourceManager};
+
+/// How many descriptor sets each of a frame's descriptor pools holds, and how many descriptors
+/// of each type
+const FRAME_DESCRIPTOR_SETS: u32 = 256;
+
+/// Data shared by all draws of a frame, read from the frame's upload buffer as a uniform buffer
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+pub struct FrameUniforms {
+    /// From world to clip space
+    pub view_projection: Mat4,
+}
+
+/// A persistently mapped, host-visible buffer that is sub-allocated linearly during a frame and
+/// reset once the GPU is done with that frame.
+pub struct FrameUploadBuffer {
+    pub buffer: Buffer,
+    mapped: *mut u8,
+    size: vk::DeviceSize,
+    offset: vk::DeviceSize,
+}
+
+impl FrameUploadBuffer {
+    fn new(
+        device: &Device,
+        resources: &mut ResourceManager,
+        size: vk::DeviceSize,
+    ) -> RendererResult<FrameUploadBuffer> {
+        let buffer = resources.create_buffer(
+            device,
+            &BufferDesc {
+                name: "frame upload buffer",
+                size,
+                usage: vk::BufferUsageFlags::UNIFORM_BUFFER
+                    | vk::BufferUsageFlags::STORAGE_BUFFER
+                    | vk::BufferUsageFlags::TRANSFER_SRC,
+                location: MemoryLocation::CpuToGpu,
+                strategy: AllocationStrategy::Buddy,
+            },
+        )?;
+        let mapped = buffer
+            .mapped_ptr()
+            .expect("CPU to GPU memory is mapped")
+            .as_ptr();
+
+        Ok(FrameUploadBuffer {
+            buffer,
+            mapped,
+            size,
+            offset: 0,
+        })
+    }
+
+    /// Copies `data` into the buffer and returns its offset, or `None` if the buffer is full.
+    /// `alignment` must be a power of two.
+    pub fn push(&mut self, data: &[u8], alignment: vk::DeviceSize) -> Option<vk::DeviceSize> {
+        let offset = (self.offset + alignment - 1) & !(alignment - 1);
+        let end = offset + data.len() as vk::DeviceSize;
+        if end > self.size {
+            return None;
+        }
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                data.as_ptr(),
+                self.mapped.add(offset as usize),
+                data.len(),
+            );
+        }
+        self.offset = end;
+        Some(offset)
+    }
+
+    fn reset(&mut self) {
+        self.offset = 0;
+    }
+}
+
+fn create_descriptor_pool(device: &Device) -> RendererResult<vk::DescriptorPool> {
+    let pool_sizes = [
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::UNIFORM_BUFFER,
+            descriptor_count: FRAME_DESCRIPTOR_SETS,
+        },
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            descriptor_count: FRAME_DESCRIPTOR_SETS,
+        },
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::SAMPLED_IMAGE,
+            descriptor_count: FRAME_DESCRIPTOR_SETS,
+        },
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::STORAGE_IMAGE,
+            descriptor_count: FRAME_DESCRIPTOR_SETS,
+        },
+    ];
+    let descriptor_pool_create_info = vk::DescriptorPoolCreateInfo::builder()
+        .max_sets(FRAME_DESCRIPTOR_SETS)
+        .pool_sizes(&pool_sizes);
+    Ok(unsafe { device.create_descriptor_pool(&descriptor_pool_create_info, None) }?)
+}
+
+/// Allocates the descriptor sets that are only used by one frame. Another descriptor pool is
+/// created whenever the ones so far are full, and all of them are reset together.
+pub struct DescriptorAllocator {
+    pools: Vec<vk::DescriptorPool>,
+    /// The pool that sets are allocated from. The ones before it are full.
+    current: usize,
+}
+
+impl DescriptorAllocator {
+    fn new(device: &Device) -> RendererResult<DescriptorAllocator> {
+        Ok(DescriptorAllocator {
+            pools: vec![create_descriptor_pool(device)?],
+            current: 0,
+        })
+    }
+
+    /// Allocates a descriptor set for each of `set_layouts`, from a new pool if the current one
+    /// is full
+    pub fn allocate(
+        &mut self,
+        device: &Device,
+        set_layouts: &[vk::DescriptorSetLayout],
+    ) -> RendererResult<Vec<vk::DescriptorSet>> {
+        let allocate = |descriptor_pool| {
+            let descriptor_set_allocate_info = vk::DescriptorSetAllocateInfo::builder()
+                .descriptor_pool(descriptor_pool)
+                .set_layouts(set_layouts);
+            unsafe { device.allocate_descriptor_sets(&descriptor_set_allocate_info) }
+        };
+        match allocate(self.pools[self.current]) {
+            Err(vk::Result::ERROR_OUT_OF_POOL_MEMORY) | Err(vk::Result::ERROR_FRAGMENTED_POOL) => {
+                self.current += 1;
+                if self.current == self.pools.len() {
+                    self.pools.push(create_descriptor_pool(device)?);
+                }
+                // The pool is empty, so only a request that no pool can hold fails again
+                Ok(allocate(self.pools[self.current])?)
+            }
+            result => Ok(result?),
+        }
+    }
+
+    /// Frees all descriptor sets. Pools created in earlier frames are kept for the next ones.
+    ///
+    /// # Safety
+    /// The GPU must be done with all command buffers that use the sets.
+    unsafe fn reset(&mut self, device: &Device) -> RendererResult<()> {
+        for &descriptor_pool in &self.pools {
+            device.reset_descriptor_pool(descriptor_pool, vk::DescriptorPoolResetFlags::empty())?;
+        }
+        self.current = 0;
+        Ok(())
+    }
+
+    unsafe fn destroy(&mut self, device: &Device) {
+        for descriptor_pool in self.pools.drain(..) {
+            device.destroy_descriptor_pool(descriptor_pool, None);
+        }
+    }
+}
+
+/// Everything that is needed to record and submit one frame. The renderer keeps a ring of these
+/// so that the CPU can record a frame while the GPU is still executing the previous ones.
+pub struct FrameData {
+    pub command_pool: vk::CommandPool,
+    pub command_buffer: vk::CommandBuffer,
+    pub present_semaphore: vk::Semaphore,
+    pub render_semaphore: vk::Semaphore,
+    pub render_fence: vk::Fence,
+    pub upload_buffer: FrameUploadBuffer,
+    /// For descriptor sets that are only used by this frame. They are freed when it is reset.
+    pub descriptors: DescriptorAllocator,
+}
+
+impl FrameData {
+    pub fn new(
+        device: &Device,
+        graphics_queue_family: u32,
+        resources: &mut ResourceManager,
+        upload_buffer_size: vk::DeviceSize,
+    ) -> RendererResult<FrameData> {
+        let command_pool_create_info = vk::CommandPoolCreateInfo::builder()
+            .queue_family_index(graphics_queue_family)
+            .flags(vk::CommandPoolCreateFlags::TRANSIENT);
+        let command_pool = unsafe { device.create_command_pool(&command_pool_create_info, None)? };
+
+        let command_buffer_allocate_info = vk::CommandBufferAllocateInfo::builder()
+            .command_pool(command_pool)
+            .command_buffer_count(1)
+            .level(vk::CommandBufferLevel::PRIMARY);
+        let command_buffer =
+            unsafe { device.allocate_command_buffers(&command_buffer_allocate_info) }?[0];
+
+        let semaphore_create_info = vk::SemaphoreCreateInfo::builder();
+        let present_semaphore = unsafe { device.create_semaphore(&semaphore_create_info, None) }?;
+        let render_semaphore = unsafe { device.create_semaphore(&semaphore_create_info, None) }?;
+
+        let fence_create_info =
+            vk::FenceCreateInfo::builder().flags(vk::FenceCreateFlags::SIGNALED);
+        let render_fence = unsafe { device.create_fence(&fence_create_info, None) }?;
+
+        let upload_buffer = FrameUploadBuffer::new(device, resources, upload_buffer_size)?;
+
+        let descriptors = DescriptorAllocator::new(device)?;
+
+        Ok(FrameData {
+            command_pool,
+            command_buffer,
+            present_semaphore,
+            render_semaphore,
+            render_fence,
+            upload_buffer,
+            descriptors,
+        })
+    }
+
+    /// Recycles the command buffer, the upload buffer and the descriptor sets. Must only be
+    /// called once `render_fence` has been signaled.
+    pub fn reset(&mut self, device: &Device) -> RendererResult<()> {
+        unsafe {
+            device.reset_command_pool(self.command_pool, vk::CommandPoolResetFlags::empty())?;
+            self.descriptors.reset(device)?;
+        };
+        self.upload_buffer.reset();
+        Ok(())
+    }
+
+    /// Destroys the frame's synchronization and command objects. The upload buffer is destroyed
+    /// when the frame is dropped.
+    ///
+    /// # Safety
+    /// The GPU must be done with all work that was submitted from this frame.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        device.destroy_semaphore(self.render_semaphore, None);
+        device.destroy_semaphore(self.present_semaphore, None);
+        device.destroy_fence(self.render_fence, None);
+        device.destroy_command_pool(self.command_pool, None);
+        self.descriptors.destroy(device);
+    }
+}
@@ -0,0 +1,26 @@
+pub mod allocator;
+pub mod basis;
+pub mod compressed;
+pub mod config;
+pub mod debug;
+pub mod decompress;
+pub mod device;
+pub mod error;
+pub mod features;
+pub mod frame;
+pub mod material;
+pub mod memory;
+pub mod mesh;
+pub mod mipmap;
+pub mod obj;
+pub mod offscreen;
+pub mod pipeline;
+pub mod readback;
+pub mod renderer;
+pub mod rendering;
+pub mod resource;
+pub mod scene;
+pub mod swapchain;
+pub mod texture;
+pub mod upload;
+pub mod validation;
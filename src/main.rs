@@ -1,30 +1,278 @@
-mod dyn_result;
-mod renderer;
-
-use crate::dyn_result::DynResult;
-use crate::renderer::Renderer;
-use winit::event::{Event, WindowEvent};
+use ash::vk;
+use charlie_renderer::config::RendererConfig;
+use charlie_renderer::error::{RendererError, RendererResult};
+use charlie_renderer::mesh::Aabb;
+use charlie_renderer::readback::Screenshot;
+use charlie_renderer::renderer::Renderer;
+use charlie_renderer::scene::Scene;
+use glam::{Mat4, Vec3};
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use winit::event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent};
 use winit::event_loop::{ControlFlow, EventLoop};
 use winit::window::Window;
 
-fn main() -> DynResult<()> {
+fn save_screenshot(screenshot: &Screenshot) {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let path = format!("screenshot-{}.png", timestamp);
+    match screenshot.save_png(&path) {
+        Ok(()) => log::info!("Saved screenshot to {}", path),
+        Err(err) => log::error!("Can't save screenshot to {}: {}", path, err),
+    }
+}
+
+/// What the command line asks for besides renderer options
+#[derive(Default)]
+struct Options {
+    list_devices: bool,
+    /// A glTF or OBJ file to show instead of the test triangle
+    model: Option<PathBuf>,
+}
+
+/// Applies the command line flags to `config`. They take precedence over environment variables.
+fn parse_args(config: &mut RendererConfig) -> Result<Options, String> {
+    let mut options = Options::default();
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--validation" => config.validation.enabled = true,
+            "--no-validation" => config.validation.enabled = false,
+            "--list-devices" => options.list_devices = true,
+            _ => {
+                if let Some(value) = arg.strip_prefix("--validation=") {
+                    config.validation = value.parse()?;
+                } else if let Some(value) = arg.strip_prefix("--device=") {
+                    config.device = value.parse()?;
+                } else if !arg.starts_with('-') && options.model.is_none() {
+                    options.model = Some(arg.into());
+                } else {
+                    return Err(format!("Unknown argument `{}`", arg));
+                }
+            }
+        }
+    }
+    Ok(options)
+}
+
+fn load_model(renderer: &mut Renderer, path: Option<&Path>) -> RendererResult<Option<Scene>> {
+    path.map(|path| Scene::load(renderer, path)).transpose()
+}
+
+/// Creates a renderer for `window` with the model loaded, e.g. after the device was lost
+fn create_renderer(
+    window: &Window,
+    config: &RendererConfig,
+    model_path: Option<&Path>,
+) -> RendererResult<(Renderer, Option<Scene>)> {
+    let mut renderer = Renderer::new(window, config.clone())?;
+    let model = load_model(&mut renderer, model_path)?;
+    Ok((renderer, model))
+}
+
+/// A camera that circles around `bounds` and keeps all of it in view
+fn orbit_camera(bounds: Aabb, aspect_ratio: f32, seconds: f32) -> Mat4 {
+    let fov_y = 45f32.to_radians();
+    let radius = (bounds.size().length() * 0.5).max(1e-3);
+    let distance = radius / (fov_y * 0.5).sin();
+    let angle = seconds * 0.5;
+    let direction = Vec3::new(angle.sin(), 0.4, angle.cos()).normalize();
+    let view = Mat4::look_at_rh(
+        bounds.center() + direction * distance,
+        bounds.center(),
+        Vec3::Y,
+    );
+    let projection = Mat4::perspective_rh(
+        fov_y,
+        aspect_ratio,
+        distance * 0.01,
+        distance + radius * 2.0,
+    );
+    // Vulkan's clip space has y pointing down
+    Mat4::from_scale(Vec3::new(1.0, -1.0, 1.0)) * projection * view
+}
+
+fn format_version(version: u32) -> String {
+    format!(
+        "{}.{}.{}",
+        vk::api_version_major(version),
+        vk::api_version_minor(version),
+        vk::api_version_patch(version)
+    )
+}
+
+fn print_devices(config: &RendererConfig) -> Result<(), Box<dyn std::error::Error>> {
+    for device in Renderer::list_devices(config)? {
+        println!(
+            "[{}] {} ({:?})",
+            device.index, device.name, device.device_type
+        );
+        println!(
+            "    Vulkan {}, driver {:#x}, vendor {:#06x}, device {:#06x}, {} MiB device-local memory",
+            format_version(device.api_version),
+            device.driver_version,
+            device.vendor_id,
+            device.device_id,
+            device.device_local_memory >> 20
+        );
+        if device.is_suitable() {
+            println!(
+                "    score: device type rank {}, {} GiB device-local memory",
+                device.score.device_type_rank, device.score.device_local_memory
+            );
+        }
+        if let Some(families) = &device.queue_family_indices {
+            println!(
+                "    queue families: graphics {}, compute {}, transfer {}",
+                families.graphics, families.compute, families.transfer
+            );
+        }
+        for reason in &device.rejection_reasons {
+            println!("    rejected: {}", reason);
+        }
+    }
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let mut config = RendererConfig::default();
+    if let Ok(hdr_mode) = std::env::var("CHARLIE_HDR") {
+        config.surface_format.hdr = Some(hdr_mode.parse()?);
+    }
+    if let Ok(validation) = std::env::var("CHARLIE_VALIDATION") {
+        config.validation = validation.parse()?;
+    }
+    if let Ok(device) = std::env::var("CHARLIE_DEVICE") {
+        config.device = device.parse()?;
+    }
+    let options = parse_args(&mut config)?;
+    if options.list_devices {
+        return print_devices(&config);
+    }
+
     let event_loop = EventLoop::new();
     let window = Window::new(&event_loop)?;
-    let mut renderer = Renderer::new(&window)?;
+
+    let (new_renderer, new_model) = create_renderer(&window, &config, options.model.as_deref())?;
+    // Dropped before the window closes
+    let mut renderer = Some(new_renderer);
+    // Its buffers must be dropped before the renderer
+    let mut model = new_model;
+    let start_time = Instant::now();
+    let mut screenshot_requested = false;
 
     event_loop.run(move |event, _, control_flow| {
         *control_flow = ControlFlow::Poll;
+        let renderer_ref = match renderer.as_mut() {
+            Some(renderer) => renderer,
+            None => return,
+        };
 
         match event {
             Event::WindowEvent {
                 event: WindowEvent::CloseRequested,
                 window_id,
             } if window_id == window.id() => {
-                let _ = &renderer; // so we can drop the renderer
+                model = None;
+                renderer = None;
                 *control_flow = ControlFlow::Exit
             }
+            Event::WindowEvent {
+                event: WindowEvent::Resized(size),
+                window_id,
+            } if window_id == window.id() => {
+                renderer_ref.resize(size.width, size.height);
+            }
+            Event::WindowEvent {
+                event: WindowEvent::ScaleFactorChanged { new_inner_size, .. },
+                window_id,
+            } if window_id == window.id() => {
+                renderer_ref.resize(new_inner_size.width, new_inner_size.height);
+            }
+            Event::WindowEvent {
+                event:
+                    WindowEvent::KeyboardInput {
+                        input:
+                            KeyboardInput {
+                                state: ElementState::Pressed,
+                                virtual_keycode: Some(key),
+                                ..
+                            },
+                        ..
+                    },
+                window_id,
+            } if window_id == window.id() => match key {
+                VirtualKeyCode::P => {
+                    let present_policy = renderer_ref.present_policy().next();
+                    log::info!("Present policy: {:?}", present_policy);
+                    renderer_ref.set_present_policy(present_policy);
+                }
+                VirtualKeyCode::F12 => match renderer_ref.request_capture() {
+                    Ok(()) => screenshot_requested = true,
+                    Err(err) => log::error!("Can't take a screenshot: {}", err),
+                },
+                _ => (),
+            },
             Event::MainEventsCleared => {
-                renderer.render().unwrap();
+                if let Some(model) = &model {
+                    let size = window.inner_size();
+                    let aspect_ratio = size.width as f32 / size.height.max(1) as f32;
+                    // Scenes with a camera are shown through it
+                    let view_projection =
+                        model.camera_view_projection(aspect_ratio).or_else(|| {
+                            let bounds = model.bounds()?;
+                            let seconds = start_time.elapsed().as_secs_f32();
+                            Some(orbit_camera(bounds, aspect_ratio, seconds))
+                        });
+                    if let Some(view_projection) = view_projection {
+                        renderer_ref.set_view_projection(view_projection);
+                    }
+                    model.draw(renderer_ref, Mat4::IDENTITY);
+                }
+                match renderer_ref.render() {
+                    Ok(()) => {}
+                    Err(err @ (RendererError::SurfaceLost | RendererError::DeviceLost)) => {
+                        log::error!("{}, recreating the renderer", err);
+                        // The old renderer owns the window surface, so it has to go first
+                        model = None;
+                        renderer = None;
+                        screenshot_requested = false;
+                        match create_renderer(&window, &config, options.model.as_deref()) {
+                            Ok((new_renderer, new_model)) => {
+                                renderer = Some(new_renderer);
+                                model = new_model;
+                            }
+                            Err(err) => {
+                                log::error!("Can't recreate the renderer: {}", err);
+                                *control_flow = ControlFlow::Exit;
+                            }
+                        }
+                        return;
+                    }
+                    Err(err) => {
+                        log::error!("Can't render: {}", err);
+                        model = None;
+                        renderer = None;
+                        *control_flow = ControlFlow::Exit;
+                        return;
+                    }
+                }
+                if screenshot_requested {
+                    match renderer_ref.take_capture() {
+                        Ok(Some(screenshot)) => {
+                            screenshot_requested = false;
+                            save_screenshot(&screenshot);
+                        }
+                        Ok(None) => {}
+                        Err(err) => {
+                            screenshot_requested = false;
+                            log::error!("Can't take a screenshot: {}", err);
+                        }
+                    }
+                }
             }
 
             _ => (),
@@ -0,0 +1,78 @@
+use glam::{Vec3, Vec4};
+
+use crate::texture::{SamplerDesc, Texture};
+
+/// How the alpha channel of the base color is interpreted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AlphaMode {
+    /// Alpha is ignored
+    Opaque,
+    /// Fragments with an alpha below `Material::alpha_cutoff` are discarded
+    Mask,
+    /// Alpha blending
+    Blend,
+}
+
+/// A texture as used by a material
+#[derive(Clone, Debug)]
+pub struct TextureBinding {
+    pub texture: Texture,
+    pub sampler: SamplerDesc,
+    /// Which set of texture coordinates is used. Only the first set is imported so far.
+    pub tex_coord: u32,
+}
+
+/// A metallic-roughness PBR material, modeled after the glTF one. Materials are referred to by
+/// index from `Submesh::material`.
+///
+/// Only `base_color` and `base_color_texture` are rendered so far. The other textures and
+/// factors, `alpha_mode` and `double_sided` are imported but not used by the renderer yet.
+#[derive(Clone, Debug)]
+pub struct Material {
+    pub name: String,
+    /// Linear RGB and opacity, multiplied with the vertex color
+    pub base_color: Vec4,
+    /// An sRGB texture that the base color is multiplied with
+    pub base_color_texture: Option<TextureBinding>,
+    pub metallic: f32,
+    pub roughness: f32,
+    /// Metalness in the blue and roughness in the green channel, multiplied with the factors
+    pub metallic_roughness_texture: Option<TextureBinding>,
+    /// A tangent-space normal map
+    pub normal_texture: Option<TextureBinding>,
+    pub normal_scale: f32,
+    /// Ambient occlusion in the red channel
+    pub occlusion_texture: Option<TextureBinding>,
+    pub occlusion_strength: f32,
+    /// Linear RGB
+    pub emissive: Vec3,
+    /// An sRGB texture that the emissive color is multiplied with
+    pub emissive_texture: Option<TextureBinding>,
+    pub alpha_mode: AlphaMode,
+    pub alpha_cutoff: f32,
+    /// Back faces are culled unless this is set
+    pub double_sided: bool,
+}
+
+impl Default for Material {
+    /// A white, fully rough dielectric, like glTF's default material
+    fn default() -> Self {
+        Material {
+            name: String::new(),
+            base_color: Vec4::ONE,
+            base_color_texture: None,
+            metallic: 0.0,
+            roughness: 1.0,
+            metallic_roughness_texture: None,
+            normal_texture: None,
+            normal_scale: 1.0,
+            occlusion_texture: None,
+            occlusion_strength: 1.0,
+            emissive: Vec3::ZERO,
+            emissive_texture: None,
+            alpha_mode: AlphaMode::Opaque,
+            alpha_cutoff: 0.5,
+            double_sided: false,
+        }
+    }
+}
@@ -0,0 +1,16 @@
+use ash::vk;
+
+/// Finds a memory type that is allowed by `memory_type_bits` and has all of `flags`.
+pub fn find_memory_type_index(
+    memory_properties: &vk::PhysicalDeviceMemoryProperties,
+    memory_type_bits: u32,
+    flags: vk::MemoryPropertyFlags,
+) -> Option<u32> {
+    memory_properties.memory_types[..memory_properties.memory_type_count as usize]
+        .iter()
+        .enumerate()
+        .find(|(index, memory_type)| {
+            (1 << index) & memory_type_bits != 0 && memory_type.property_flags.contains(flags)
+        })
+        .map(|(index, _)| index as u32)
+}
@@ -0,0 +1,310 @@
+use ash::{vk, Device};
+use bytemuck::{Pod, Zeroable};
+use glam::{Mat3, Mat4, Vec2, Vec3, Vec4};
+use std::mem::size_of;
+use std::rc::Rc;
+
+use crate::resource::Buffer;
+
+/// The attributes of `Vertex`, in the order of their shader locations
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VertexAttribute {
+    Position,
+    Normal,
+    Uv,
+    /// xyz is the tangent, w the sign of the bitangent
+    Tangent,
+    Color,
+}
+
+impl VertexAttribute {
+    pub const ALL: [VertexAttribute; 5] = [
+        VertexAttribute::Position,
+        VertexAttribute::Normal,
+        VertexAttribute::Uv,
+        VertexAttribute::Tangent,
+        VertexAttribute::Color,
+    ];
+
+    pub fn location(self) -> u32 {
+        self as u32
+    }
+
+    pub fn format(self) -> vk::Format {
+        match self {
+            VertexAttribute::Position | VertexAttribute::Normal => vk::Format::R32G32B32_SFLOAT,
+            VertexAttribute::Uv => vk::Format::R32G32_SFLOAT,
+            VertexAttribute::Tangent | VertexAttribute::Color => vk::Format::R32G32B32A32_SFLOAT,
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            VertexAttribute::Position | VertexAttribute::Normal => size_of::<Vec3>(),
+            VertexAttribute::Uv => size_of::<Vec2>(),
+            VertexAttribute::Tangent | VertexAttribute::Color => size_of::<Vec4>(),
+        }
+    }
+
+    /// Byte offset of the attribute in `Vertex`
+    pub fn offset(self) -> u32 {
+        Self::ALL[..self as usize]
+            .iter()
+            .map(|attribute| attribute.size())
+            .sum::<usize>() as u32
+    }
+}
+
+/// The vertex format of all meshes. The fields are laid out in the order of `VertexAttribute`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Pod, Zeroable)]
+pub struct Vertex {
+    pub position: Vec3,
+    pub normal: Vec3,
+    pub uv: Vec2,
+    pub tangent: Vec4,
+    pub color: Vec4,
+}
+
+impl Default for Vertex {
+    fn default() -> Self {
+        Vertex {
+            position: Vec3::ZERO,
+            normal: Vec3::Z,
+            uv: Vec2::ZERO,
+            tangent: Vec4::new(1.0, 0.0, 0.0, 1.0),
+            color: Vec4::ONE,
+        }
+    }
+}
+
+impl Vertex {
+    /// Meshes are drawn from a single interleaved vertex buffer at binding 0
+    pub const BINDING: u32 = 0;
+
+    pub fn binding_description() -> vk::VertexInputBindingDescription {
+        vk::VertexInputBindingDescription {
+            binding: Self::BINDING,
+            stride: size_of::<Vertex>() as u32,
+            input_rate: vk::VertexInputRate::VERTEX,
+        }
+    }
+
+    pub fn attribute_descriptions() -> Vec<vk::VertexInputAttributeDescription> {
+        VertexAttribute::ALL
+            .iter()
+            .map(|attribute| vk::VertexInputAttributeDescription {
+                location: attribute.location(),
+                binding: Self::BINDING,
+                format: attribute.format(),
+                offset: attribute.offset(),
+            })
+            .collect()
+    }
+}
+
+/// Per-draw data of the mesh pipeline, pushed to the vertex shader
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+pub struct MeshPushConstants {
+    /// From model to world space
+    pub model: Mat4,
+    /// The columns of the matrix that transforms normals from model to world space, padded to
+    /// match a GLSL `mat3`
+    pub normal_matrix: [Vec4; 3],
+    pub base_color: Vec4,
+}
+
+impl MeshPushConstants {
+    pub fn new(model: Mat4, base_color: Vec4) -> Self {
+        let normal_matrix = Mat3::from_mat4(model).inverse().transpose();
+        MeshPushConstants {
+            model,
+            normal_matrix: [
+                normal_matrix.x_axis.extend(0.0),
+                normal_matrix.y_axis.extend(0.0),
+                normal_matrix.z_axis.extend(0.0),
+            ],
+            base_color,
+        }
+    }
+}
+
+/// An axis-aligned bounding box
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    /// The smallest box around `points`, or `None` if there are none
+    pub fn from_points(points: impl IntoIterator<Item = Vec3>) -> Option<Aabb> {
+        points.into_iter().fold(None, |aabb, point| {
+            Some(match aabb {
+                Some(Aabb { min, max }) => Aabb {
+                    min: min.min(point),
+                    max: max.max(point),
+                },
+                None => Aabb {
+                    min: point,
+                    max: point,
+                },
+            })
+        })
+    }
+
+    pub fn center(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn size(&self) -> Vec3 {
+        self.max - self.min
+    }
+}
+
+/// A range of a mesh's indices that is drawn with one material
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Submesh {
+    pub first_index: u32,
+    pub index_count: u32,
+    /// Index into the materials the mesh is drawn with, `None` for the default material
+    pub material: Option<usize>,
+}
+
+/// Vertices and indices in host memory, e.g. from an importer, to create a `Mesh` from
+#[derive(Clone, Debug, Default)]
+pub struct MeshData {
+    pub vertices: Vec<Vertex>,
+    pub indices: Vec<u32>,
+    /// If empty, all indices are drawn with the default material
+    pub submeshes: Vec<Submesh>,
+}
+
+impl MeshData {
+    pub fn bounds(&self) -> Option<Aabb> {
+        Aabb::from_points(self.vertices.iter().map(|vertex| vertex.position))
+    }
+}
+
+struct MeshBuffers {
+    vertex_buffer: Buffer,
+    index_buffer: Buffer,
+    vertex_count: u32,
+    index_count: u32,
+    submeshes: Vec<Submesh>,
+    bounds: Aabb,
+    name: String,
+}
+
+/// Device-local vertex and index buffers with 32-bit indices, drawn as a triangle list.
+///
+/// Cloning a mesh is cheap and shares the buffers. They are destroyed once the last clone is
+/// dropped and no frame in flight draws them anymore.
+#[derive(Clone)]
+pub struct Mesh {
+    buffers: Rc<MeshBuffers>,
+}
+
+impl Mesh {
+    /// `vertex_buffer` and `index_buffer` must hold the vertices and indices of `data`, which
+    /// must not be empty.
+    pub fn new(vertex_buffer: Buffer, index_buffer: Buffer, data: &MeshData, name: &str) -> Mesh {
+        let index_count = data.indices.len() as u32;
+        let submeshes = if data.submeshes.is_empty() {
+            vec![Submesh {
+                first_index: 0,
+                index_count,
+                material: None,
+            }]
+        } else {
+            data.submeshes.clone()
+        };
+        Mesh {
+            buffers: Rc::new(MeshBuffers {
+                vertex_buffer,
+                index_buffer,
+                vertex_count: data.vertices.len() as u32,
+                index_count,
+                submeshes,
+                bounds: data.bounds().expect("Meshes have vertices"),
+                name: name.to_string(),
+            }),
+        }
+    }
+
+    pub fn vertex_buffer(&self) -> &Buffer {
+        &self.buffers.vertex_buffer
+    }
+
+    pub fn index_buffer(&self) -> &Buffer {
+        &self.buffers.index_buffer
+    }
+
+    pub fn vertex_count(&self) -> u32 {
+        self.buffers.vertex_count
+    }
+
+    pub fn index_count(&self) -> u32 {
+        self.buffers.index_count
+    }
+
+    /// Never empty
+    pub fn submeshes(&self) -> &[Submesh] {
+        &self.buffers.submeshes
+    }
+
+    /// The bounds of the vertex positions in model space
+    pub fn bounds(&self) -> Aabb {
+        self.buffers.bounds
+    }
+
+    pub fn name(&self) -> &str {
+        &self.buffers.name
+    }
+
+    /// Binds the vertex and index buffers.
+    ///
+    /// # Safety
+    /// `command_buffer` must be recording.
+    pub unsafe fn bind(&self, device: &Device, command_buffer: vk::CommandBuffer) {
+        device.cmd_bind_vertex_buffers(
+            command_buffer,
+            Vertex::BINDING,
+            &[self.vertex_buffer().handle()],
+            &[0],
+        );
+        device.cmd_bind_index_buffer(
+            command_buffer,
+            self.index_buffer().handle(),
+            0,
+            vk::IndexType::UINT32,
+        );
+    }
+
+    /// Draws the indices of `submesh`.
+    ///
+    /// # Safety
+    /// `command_buffer` must be recording inside a rendering scope, with the mesh bound and a
+    /// pipeline that takes `Vertex` input.
+    pub unsafe fn draw_submesh(
+        &self,
+        device: &Device,
+        command_buffer: vk::CommandBuffer,
+        submesh: &Submesh,
+    ) {
+        device.cmd_draw_indexed(
+            command_buffer,
+            submesh.index_count,
+            1,
+            submesh.first_index,
+            0,
+            0,
+        );
+    }
+
+    /// Whether both meshes share the same buffers
+    pub fn ptr_eq(&self, other: &Mesh) -> bool {
+        Rc::ptr_eq(&self.buffers, &other.buffers)
+    }
+}
@@ -0,0 +1,521 @@
+use ash::{vk, Device, Instance};
+
+use vk_shader_macros::include_glsl;
+
+use crate::error::RendererResult;
+use crate::frame::DescriptorAllocator;
+use crate::pipeline::{create_shader_module, ComputePipeline};
+use crate::resource::{Image, ResourceManager};
+use crate::texture::Texture;
+
+const MIPMAP_COMP: &[u32] = include_glsl!("shaders/mipmap.comp");
+
+/// Width and height of the compute shader's workgroups
+const WORKGROUP_SIZE: u32 = 8;
+
+/// Stages that may sample textures once their mip chain is complete
+const SAMPLING_STAGES: vk::PipelineStageFlags = vk::PipelineStageFlags::from_raw(
+    vk::PipelineStageFlags::VERTEX_SHADER.as_raw()
+        | vk::PipelineStageFlags::FRAGMENT_SHADER.as_raw()
+        | vk::PipelineStageFlags::COMPUTE_SHADER.as_raw(),
+);
+
+/// The number of levels of a full mip chain, down to 1x1
+pub fn mip_level_count(extent: vk::Extent2D) -> u32 {
+    32 - extent.width.max(extent.height).max(1).leading_zeros()
+}
+
+/// The extent of mip `level` of an image of `extent`
+pub fn mip_level_extent(extent: vk::Extent2D, level: u32) -> vk::Extent2D {
+    vk::Extent2D {
+        width: (extent.width >> level).max(1),
+        height: (extent.height >> level).max(1),
+    }
+}
+
+fn level_extent(image: &Image, level: u32) -> vk::Extent2D {
+    mip_level_extent(image.extent(), level)
+}
+
+fn level_range(image: &Image, level: u32) -> vk::ImageSubresourceRange {
+    vk::ImageSubresourceRange {
+        base_mip_level: level,
+        level_count: 1,
+        ..image.subresource_range()
+    }
+}
+
+/// The format that the compute shader writes `format` through, `None` if it can't
+fn storage_format(format: vk::Format) -> Option<vk::Format> {
+    match format {
+        vk::Format::R8G8B8A8_UNORM | vk::Format::R8G8B8A8_SRGB => Some(vk::Format::R8G8B8A8_UNORM),
+        _ => None,
+    }
+}
+
+/// How the mip chain of a texture is generated from its first level
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MipmapMethod {
+    /// Blits each level into the next one with linear filtering
+    Blit,
+    /// A compute shader box filter, for 8-bit RGBA formats that can't be blitted with linear
+    /// filtering
+    Compute,
+}
+
+impl MipmapMethod {
+    /// How the device can generate mipmaps for images of `format`, `None` if it can't
+    pub fn select(
+        instance: &Instance,
+        physical_device: vk::PhysicalDevice,
+        format: vk::Format,
+    ) -> Option<MipmapMethod> {
+        let features = |format| {
+            unsafe { instance.get_physical_device_format_properties(physical_device, format) }
+                .optimal_tiling_features
+        };
+        let blit_features = vk::FormatFeatureFlags::BLIT_SRC
+            | vk::FormatFeatureFlags::BLIT_DST
+            | vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR;
+        let can_store = match storage_format(format) {
+            Some(storage_format) => {
+                features(storage_format).contains(vk::FormatFeatureFlags::STORAGE_IMAGE)
+            }
+            None => false,
+        };
+        if features(format).contains(blit_features) {
+            Some(MipmapMethod::Blit)
+        } else if can_store && features(format).contains(vk::FormatFeatureFlags::SAMPLED_IMAGE) {
+            Some(MipmapMethod::Compute)
+        } else {
+            None
+        }
+    }
+
+    /// The layout all levels must be in when the generation is recorded
+    pub fn initial_layout(self) -> vk::ImageLayout {
+        match self {
+            MipmapMethod::Blit => vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            MipmapMethod::Compute => vk::ImageLayout::GENERAL,
+        }
+    }
+
+    /// The usage images of `format` need besides `SAMPLED` and `TRANSFER_DST`, with the flags
+    /// it requires
+    pub fn image_usage(self, format: vk::Format) -> (vk::ImageUsageFlags, vk::ImageCreateFlags) {
+        match self {
+            MipmapMethod::Blit => (
+                vk::ImageUsageFlags::TRANSFER_SRC,
+                vk::ImageCreateFlags::empty(),
+            ),
+            MipmapMethod::Compute if storage_format(format) == Some(format) => {
+                (vk::ImageUsageFlags::STORAGE, vk::ImageCreateFlags::empty())
+            }
+            // Written through views in the storage format, which the image's format may not
+            // support storage for
+            MipmapMethod::Compute => (
+                vk::ImageUsageFlags::STORAGE,
+                vk::ImageCreateFlags::MUTABLE_FORMAT | vk::ImageCreateFlags::EXTENDED_USAGE,
+            ),
+        }
+    }
+}
+
+/// Generates the mip chains of textures on the graphics queue, at the start of the frame after
+/// their first level was uploaded.
+pub struct MipmapGenerator {
+    set_layout: vk::DescriptorSetLayout,
+    pipeline: ComputePipeline,
+    pending: Vec<(Texture, MipmapMethod)>,
+}
+
+impl MipmapGenerator {
+    pub fn new(device: &Device) -> RendererResult<MipmapGenerator> {
+        let bindings = [
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(0)
+                .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                .build(),
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(1)
+                .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                .build(),
+        ];
+        let set_layout_create_info =
+            vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings);
+        let set_layout =
+            unsafe { device.create_descriptor_set_layout(&set_layout_create_info, None)? };
+
+        let shader_module = match create_shader_module(device, MIPMAP_COMP) {
+            Ok(shader_module) => shader_module,
+            Err(err) => {
+                unsafe { device.destroy_descriptor_set_layout(set_layout, None) };
+                return Err(err);
+            }
+        };
+        let push_constant_range = vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            offset: 0,
+            size: std::mem::size_of::<u32>() as u32,
+        };
+        let pipeline =
+            ComputePipeline::new(device, shader_module, &[set_layout], &[push_constant_range]);
+        unsafe { device.destroy_shader_module(shader_module, None) };
+        let pipeline = match pipeline {
+            Ok(pipeline) => pipeline,
+            Err(err) => {
+                unsafe { device.destroy_descriptor_set_layout(set_layout, None) };
+                return Err(err);
+            }
+        };
+
+        Ok(MipmapGenerator {
+            set_layout,
+            pipeline,
+            pending: Vec::new(),
+        })
+    }
+
+    pub fn pipeline(&self) -> &ComputePipeline {
+        &self.pipeline
+    }
+
+    /// Generates the mip chain of `texture` in the next frame. Its first level must have been
+    /// uploaded and all of its levels must be in `method.initial_layout()` by then. It ends up
+    /// in `SHADER_READ_ONLY_OPTIMAL`.
+    pub fn queue(&mut self, texture: Texture, method: MipmapMethod) {
+        self.pending.push((texture, method));
+    }
+
+    /// Records the generation of the queued mip chains. All of them are complete once the
+    /// command buffer reaches the draws, so none is sampled before its chain is.
+    ///
+    /// # Safety
+    /// `command_buffer` must be recording outside of a rendering scope on the graphics queue,
+    /// after the acquire barriers of the uploads. `descriptors` must not be reset before the
+    /// command buffer has completed.
+    pub unsafe fn record(
+        &mut self,
+        device: &Device,
+        resources: &mut ResourceManager,
+        command_buffer: vk::CommandBuffer,
+        descriptors: &mut DescriptorAllocator,
+    ) -> RendererResult<()> {
+        for (texture, method) in std::mem::take(&mut self.pending) {
+            match method {
+                MipmapMethod::Blit => record_blits(device, command_buffer, texture.image()),
+                MipmapMethod::Compute => self.record_compute(
+                    device,
+                    resources,
+                    command_buffer,
+                    descriptors,
+                    texture.image(),
+                )?,
+            }
+        }
+        Ok(())
+    }
+
+    unsafe fn record_compute(
+        &self,
+        device: &Device,
+        resources: &mut ResourceManager,
+        command_buffer: vk::CommandBuffer,
+        descriptors: &mut DescriptorAllocator,
+        image: &Image,
+    ) -> RendererResult<()> {
+        let format = image.format();
+        let storage_format = storage_format(format).expect("Compute is only selected for those");
+        let set_layouts = vec![self.set_layout; image.mip_levels() as usize - 1];
+        let descriptor_sets = descriptors.allocate(device, &set_layouts)?;
+
+        device.cmd_bind_pipeline(
+            command_buffer,
+            vk::PipelineBindPoint::COMPUTE,
+            self.pipeline.pipeline,
+        );
+        let srgb = (format == vk::Format::R8G8B8A8_SRGB) as u32;
+        device.cmd_push_constants(
+            command_buffer,
+            self.pipeline.layout,
+            vk::ShaderStageFlags::COMPUTE,
+            0,
+            &srgb.to_ne_bytes(),
+        );
+        // The views are destroyed once the frame has completed
+        let mut views = Vec::new();
+        for (level, descriptor_set) in (1..image.mip_levels()).zip(descriptor_sets) {
+            let source = resources.create_image_view_with_format(
+                device,
+                image,
+                format,
+                vk::ImageUsageFlags::SAMPLED,
+                level_range(image, level - 1),
+                "mipmap source",
+            )?;
+            let destination = resources.create_image_view_with_format(
+                device,
+                image,
+                storage_format,
+                vk::ImageUsageFlags::STORAGE,
+                level_range(image, level),
+                "mipmap destination",
+            )?;
+            let source_info = [vk::DescriptorImageInfo {
+                sampler: vk::Sampler::null(),
+                image_view: source.handle(),
+                image_layout: vk::ImageLayout::GENERAL,
+            }];
+            let destination_info = [vk::DescriptorImageInfo {
+                sampler: vk::Sampler::null(),
+                image_view: destination.handle(),
+                image_layout: vk::ImageLayout::GENERAL,
+            }];
+            let writes = [
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(descriptor_set)
+                    .dst_binding(0)
+                    .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                    .image_info(&source_info)
+                    .build(),
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(descriptor_set)
+                    .dst_binding(1)
+                    .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                    .image_info(&destination_info)
+                    .build(),
+            ];
+            device.update_descriptor_sets(&writes, &[]);
+            views.push(source);
+            views.push(destination);
+
+            device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::COMPUTE,
+                self.pipeline.layout,
+                0,
+                &[descriptor_set],
+                &[],
+            );
+            let extent = level_extent(image, level);
+            device.cmd_dispatch(
+                command_buffer,
+                extent.width.div_ceil(WORKGROUP_SIZE),
+                extent.height.div_ceil(WORKGROUP_SIZE),
+                1,
+            );
+            // The next dispatch reads this level
+            let barrier = vk::ImageMemoryBarrier::builder()
+                .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ)
+                .old_layout(vk::ImageLayout::GENERAL)
+                .new_layout(vk::ImageLayout::GENERAL)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(image.handle())
+                .subresource_range(level_range(image, level))
+                .build();
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[barrier],
+            );
+        }
+
+        let to_shader_read = vk::ImageMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+            .old_layout(vk::ImageLayout::GENERAL)
+            .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image.handle())
+            .subresource_range(image.subresource_range())
+            .build();
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            SAMPLING_STAGES,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &[to_shader_read],
+        );
+        Ok(())
+    }
+
+    /// # Safety
+    /// The GPU must be done with all frames that generated mipmaps.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        self.pending.clear();
+        self.pipeline.destroy(device);
+        device.destroy_descriptor_set_layout(self.set_layout, None);
+    }
+}
+
+/// Blits each level of `image` into the next one. All levels start out in
+/// `TRANSFER_SRC_OPTIMAL`.
+unsafe fn record_blits(device: &Device, command_buffer: vk::CommandBuffer, image: &Image) {
+    let barrier = |level, old_layout, new_layout, src_access, dst_access| {
+        vk::ImageMemoryBarrier::builder()
+            .src_access_mask(src_access)
+            .dst_access_mask(dst_access)
+            .old_layout(old_layout)
+            .new_layout(new_layout)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image.handle())
+            .subresource_range(level_range(image, level))
+            .build()
+    };
+    let offsets = |level| {
+        let extent = level_extent(image, level);
+        [
+            vk::Offset3D::default(),
+            vk::Offset3D {
+                x: extent.width as i32,
+                y: extent.height as i32,
+                z: 1,
+            },
+        ]
+    };
+    let layers = |level| vk::ImageSubresourceLayers {
+        aspect_mask: image.aspect_mask(),
+        mip_level: level,
+        base_array_layer: 0,
+        layer_count: 1,
+    };
+
+    for level in 1..image.mip_levels() {
+        // The level's contents are about to be overwritten
+        let to_transfer_dst = barrier(
+            level,
+            vk::ImageLayout::UNDEFINED,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            vk::AccessFlags::empty(),
+            vk::AccessFlags::TRANSFER_WRITE,
+        );
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &[to_transfer_dst],
+        );
+        let blit = vk::ImageBlit {
+            src_subresource: layers(level - 1),
+            src_offsets: offsets(level - 1),
+            dst_subresource: layers(level),
+            dst_offsets: offsets(level),
+        };
+        device.cmd_blit_image(
+            command_buffer,
+            image.handle(),
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            image.handle(),
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &[blit],
+            vk::Filter::LINEAR,
+        );
+        // The next blit reads this level
+        let to_transfer_src = barrier(
+            level,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            vk::AccessFlags::TRANSFER_WRITE,
+            vk::AccessFlags::TRANSFER_READ,
+        );
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &[to_transfer_src],
+        );
+    }
+
+    let to_shader_read = vk::ImageMemoryBarrier {
+        subresource_range: image.subresource_range(),
+        ..barrier(
+            0,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            vk::AccessFlags::TRANSFER_WRITE,
+            vk::AccessFlags::SHADER_READ,
+        )
+    };
+    device.cmd_pipeline_barrier(
+        command_buffer,
+        vk::PipelineStageFlags::TRANSFER,
+        SAMPLING_STAGES,
+        vk::DependencyFlags::empty(),
+        &[],
+        &[],
+        &[to_shader_read],
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extent(width: u32, height: u32) -> vk::Extent2D {
+        vk::Extent2D { width, height }
+    }
+
+    #[test]
+    fn mip_chains_go_down_to_one_pixel() {
+        assert_eq!(mip_level_count(extent(1, 1)), 1);
+        assert_eq!(mip_level_count(extent(256, 256)), 9);
+        assert_eq!(mip_level_count(extent(255, 1)), 8);
+        assert_eq!(mip_level_count(extent(3, 300)), 9);
+        // Zero-sized extents still have their first level
+        assert_eq!(mip_level_count(extent(0, 0)), 1);
+
+        let base = extent(300, 5);
+        let last = mip_level_count(base) - 1;
+        assert_eq!(mip_level_extent(base, last), extent(1, 1));
+        assert_ne!(mip_level_extent(base, last - 1), extent(1, 1));
+    }
+
+    #[test]
+    fn mip_extents_are_halved_and_rounded_down() {
+        let base = extent(300, 5);
+        assert_eq!(mip_level_extent(base, 0), base);
+        assert_eq!(mip_level_extent(base, 1), extent(150, 2));
+        assert_eq!(mip_level_extent(base, 2), extent(75, 1));
+        assert_eq!(mip_level_extent(base, 3), extent(37, 1));
+        assert_eq!(mip_level_extent(base, 8), extent(1, 1));
+    }
+
+    #[test]
+    fn srgb_images_are_written_through_unorm_views() {
+        assert_eq!(
+            MipmapMethod::Compute.image_usage(vk::Format::R8G8B8A8_UNORM),
+            (vk::ImageUsageFlags::STORAGE, vk::ImageCreateFlags::empty())
+        );
+        assert_eq!(
+            MipmapMethod::Compute.image_usage(vk::Format::R8G8B8A8_SRGB),
+            (
+                vk::ImageUsageFlags::STORAGE,
+                vk::ImageCreateFlags::MUTABLE_FORMAT | vk::ImageCreateFlags::EXTENDED_USAGE
+            )
+        );
+        assert_eq!(
+            MipmapMethod::Blit.image_usage(vk::Format::R8G8B8A8_SRGB).0,
+            vk::ImageUsageFlags::TRANSFER_SRC
+        );
+    }
+}
@@ -0,0 +1,354 @@
+use glam::{Vec2, Vec3, Vec4};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::error::{RendererError, RendererResult};
+use crate::material::{Material, TextureBinding};
+use crate::mesh::{Mesh, MeshData, Submesh, Vertex};
+use crate::renderer::Renderer;
+use crate::texture::{SamplerDesc, TextureUsage};
+
+/// A Wavefront OBJ file on the GPU. The mesh has a submesh per material.
+pub struct ObjModel {
+    pub mesh: Mesh,
+    pub materials: Vec<Material>,
+}
+
+/// A material from an MTL file, with the texture it refers to
+#[derive(Clone, Debug)]
+pub struct ObjMaterial {
+    pub material: Material,
+    /// The `map_Kd` file, relative to the working directory
+    pub diffuse_texture: Option<PathBuf>,
+}
+
+impl ObjModel {
+    /// Imports the OBJ file at `path` together with its MTL files and textures and uploads it.
+    /// Textures that can't be loaded are only logged.
+    pub fn load(renderer: &mut Renderer, path: &Path) -> RendererResult<ObjModel> {
+        let (data, obj_materials) = import_obj(path)?;
+        let name = path
+            .file_stem()
+            .map_or_else(|| "obj".into(), |stem| stem.to_string_lossy());
+        let mesh = renderer.create_mesh(&data, &name)?;
+
+        // Materials often share textures
+        let mut textures = HashMap::new();
+        let mut materials = Vec::with_capacity(obj_materials.len());
+        for ObjMaterial {
+            mut material,
+            diffuse_texture,
+        } in obj_materials
+        {
+            if let Some(texture_path) = diffuse_texture {
+                let texture = textures
+                    .entry(texture_path)
+                    .or_insert_with_key(|texture_path| {
+                        match renderer.load_texture(texture_path, TextureUsage::Color) {
+                            Ok(texture) => Some(texture),
+                            Err(err) => {
+                                log::warn!("{}", err);
+                                None
+                            }
+                        }
+                    })
+                    .clone();
+                material.base_color_texture = texture.map(|texture| TextureBinding {
+                    texture,
+                    sampler: SamplerDesc::default(),
+                    tex_coord: 0,
+                });
+            }
+            materials.push(material);
+        }
+
+        log::info!(
+            "Loaded {} with {} vertices, {} triangles and {} material(s)",
+            path.display(),
+            mesh.vertex_count(),
+            mesh.index_count() / 3,
+            materials.len()
+        );
+        Ok(ObjModel { mesh, materials })
+    }
+}
+
+/// Reads the OBJ file at `path` and the MTL files it references into a single mesh.
+///
+/// Faces are triangulated, and vertices that share their position, texture coordinate and normal
+/// are merged. Objects and groups that use the same material end up in the same submesh. Normals
+/// are generated by averaging the adjacent faces for objects and groups without them. A missing
+/// MTL file is only logged, and the affected faces use the default material.
+pub fn import_obj(path: &Path) -> RendererResult<(MeshData, Vec<ObjMaterial>)> {
+    // Unlike with separate indices, tobj doesn't make up normals and texture coordinates for
+    // faces without them, so their absence can be detected
+    let load_options = tobj::LoadOptions {
+        single_index: true,
+        triangulate: true,
+        ignore_points: true,
+        ignore_lines: true,
+    };
+    let (models, materials) = tobj::load_obj(path, &load_options)
+        .map_err(|err| RendererError::Model(format!("Can't load {}: {}", path.display(), err)))?;
+    let materials = materials.unwrap_or_else(|err| {
+        log::warn!("Can't load the materials of {}: {}", path.display(), err);
+        Vec::new()
+    });
+    let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let materials = materials
+        .iter()
+        .map(|material| convert_material(material, base_dir))
+        .collect::<Vec<_>>();
+
+    // Keep the materials in the order they are first used
+    let mut used_materials = Vec::new();
+    for model in &models {
+        let material = model
+            .mesh
+            .material_id
+            .filter(|material| *material < materials.len());
+        if !used_materials.contains(&material) {
+            used_materials.push(material);
+        }
+    }
+
+    let generated_normals = generate_normals(&models);
+    let mut data = MeshData::default();
+    let mut vertex_indices = HashMap::new();
+    for material in used_materials {
+        let first_index = data.indices.len() as u32;
+        for model in &models {
+            let model_material = model
+                .mesh
+                .material_id
+                .filter(|material| *material < materials.len());
+            if model_material == material {
+                append_obj_mesh(
+                    &mut data,
+                    &mut vertex_indices,
+                    &model.mesh,
+                    &generated_normals,
+                );
+            }
+        }
+        let index_count = data.indices.len() as u32 - first_index;
+        if index_count > 0 {
+            data.submeshes.push(Submesh {
+                first_index,
+                index_count,
+                material,
+            });
+        }
+    }
+    if data.indices.is_empty() {
+        return Err(RendererError::Model(format!(
+            "{} has no faces",
+            path.display()
+        )));
+    }
+    Ok((data, materials))
+}
+
+fn convert_material(material: &tobj::Material, base_dir: &Path) -> ObjMaterial {
+    let [red, green, blue] = material.diffuse;
+    ObjMaterial {
+        material: Material {
+            name: material.name.clone(),
+            base_color: Vec4::new(red, green, blue, material.dissolve),
+            ..Default::default()
+        },
+        diffuse_texture: (!material.diffuse_texture.is_empty())
+            .then(|| base_dir.join(&material.diffuse_texture)),
+    }
+}
+
+fn position_key(position: Vec3) -> [u32; 3] {
+    let [x, y, z] = position.to_array();
+    [x.to_bits(), y.to_bits(), z.to_bits()]
+}
+
+/// Appends the triangles of `mesh`. `vertex_indices` maps the vertices that were already added
+/// to their index.
+fn append_obj_mesh(
+    data: &mut MeshData,
+    vertex_indices: &mut HashMap<[u32; 16], u32>,
+    mesh: &tobj::Mesh,
+    generated_normals: &HashMap<[u32; 3], Vec3>,
+) {
+    let vec3 = |values: &[f32], index: u32| Vec3::from_slice(&values[index as usize * 3..]);
+    // Attributes that only some faces of the mesh have are ignored
+    let vertex_count = mesh.positions.len() / 3;
+    let has_texcoords = mesh.texcoords.len() / 2 == vertex_count;
+    let has_normals = mesh.normals.len() / 3 == vertex_count;
+    let has_colors = mesh.vertex_color.len() / 3 == vertex_count;
+
+    for index in mesh.indices.iter().copied() {
+        let position = vec3(&mesh.positions, index);
+        let normal = if has_normals {
+            vec3(&mesh.normals, index)
+        } else {
+            generated_normals[&position_key(position)]
+        };
+        // OBJ texture coordinates start at the bottom of the image
+        let uv = if has_texcoords {
+            let uv = Vec2::from_slice(&mesh.texcoords[index as usize * 2..]);
+            Vec2::new(uv.x, 1.0 - uv.y)
+        } else {
+            Vec2::ZERO
+        };
+        let color = if has_colors {
+            vec3(&mesh.vertex_color, index).extend(1.0)
+        } else {
+            Vec4::ONE
+        };
+        let vertex = Vertex {
+            position,
+            normal,
+            uv,
+            color,
+            ..Default::default()
+        };
+        // tobj only merges vertices within an object or group, so compare the actual values
+        let index = *vertex_indices
+            .entry(bytemuck::cast(vertex))
+            .or_insert_with(|| {
+                data.vertices.push(vertex);
+                data.vertices.len() as u32 - 1
+            });
+        data.indices.push(index);
+    }
+}
+
+/// Smooth normals for the positions of the meshes without normals, the average of the adjacent
+/// faces weighted by their area. They are keyed by position so that faces of different objects
+/// and groups are smoothed together.
+fn generate_normals(models: &[tobj::Model]) -> HashMap<[u32; 3], Vec3> {
+    let mut normals = HashMap::new();
+    for mesh in models.iter().map(|model| &model.mesh) {
+        if mesh.normals.len() == mesh.positions.len() {
+            continue;
+        }
+        let position = |index: u32| Vec3::from_slice(&mesh.positions[index as usize * 3..]);
+        for triangle in mesh.indices.chunks_exact(3) {
+            let [a, b, c] = [
+                position(triangle[0]),
+                position(triangle[1]),
+                position(triangle[2]),
+            ];
+            // The cross product's length is twice the triangle's area
+            let normal = (b - a).cross(c - a);
+            for corner in [a, b, c].iter() {
+                *normals.entry(position_key(*corner)).or_insert(Vec3::ZERO) += normal;
+            }
+        }
+    }
+    for normal in normals.values_mut() {
+        *normal = normal.normalize_or_zero();
+        if *normal == Vec3::ZERO {
+            *normal = Vec3::Z;
+        }
+    }
+    normals
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn import_fixture() -> (MeshData, Vec<ObjMaterial>) {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/quads.obj");
+        import_obj(&path).unwrap()
+    }
+
+    /// The vertices of the triangles of a submesh
+    fn submesh_vertices(data: &MeshData, submesh: usize) -> Vec<Vertex> {
+        let submesh = data.submeshes[submesh];
+        let first = submesh.first_index as usize;
+        data.indices[first..first + submesh.index_count as usize]
+            .iter()
+            .map(|index| data.vertices[*index as usize])
+            .collect()
+    }
+
+    #[test]
+    fn submeshes_are_grouped_by_material() {
+        let (data, materials) = import_fixture();
+        let names: Vec<_> = materials
+            .iter()
+            .map(|material| material.material.name.as_str())
+            .collect();
+        assert_eq!(names, ["red", "blue"]);
+        assert_eq!(
+            materials[0].material.base_color,
+            Vec4::new(1.0, 0.0, 0.0, 1.0)
+        );
+        assert_eq!(
+            materials[1].material.base_color,
+            Vec4::new(0.0, 0.0, 1.0, 0.5)
+        );
+        assert_eq!(materials[0].diffuse_texture, None);
+        assert_eq!(
+            materials[1].diffuse_texture.as_deref(),
+            Some(
+                Path::new(env!("CARGO_MANIFEST_DIR"))
+                    .join("tests/fixtures/blue.png")
+                    .as_path()
+            )
+        );
+
+        // Both quads use the first material, the roof comes after them
+        let ranges: Vec<_> = data
+            .submeshes
+            .iter()
+            .map(|submesh| (submesh.first_index, submesh.index_count, submesh.material))
+            .collect();
+        assert_eq!(ranges, [(0, 12, Some(0)), (12, 6, Some(1))]);
+        assert_eq!(data.indices.len(), 18);
+    }
+
+    #[test]
+    fn identical_vertices_are_merged() {
+        let (data, _) = import_fixture();
+        // 4 + 2 vertices for the quads that share an edge, 4 for the roof
+        assert_eq!(data.vertices.len(), 10);
+        let quads = submesh_vertices(&data, 0);
+        assert!(quads.iter().all(|vertex| vertex.normal == Vec3::Z));
+        let unique_quad_indices: std::collections::HashSet<_> = data.indices[..12].iter().collect();
+        assert_eq!(unique_quad_indices.len(), 6);
+    }
+
+    #[test]
+    fn texture_coordinates_are_flipped() {
+        let (data, _) = import_fixture();
+        let uv_at = |position: Vec3| {
+            data.vertices
+                .iter()
+                .find(|vertex| vertex.position == position)
+                .unwrap()
+                .uv
+        };
+        assert_eq!(uv_at(Vec3::ZERO), Vec2::new(0.0, 1.0));
+        assert_eq!(uv_at(Vec3::new(1.0, 1.0, 0.0)), Vec2::new(1.0, 0.0));
+        assert_eq!(uv_at(Vec3::new(2.0, 0.0, 0.0)), Vec2::new(2.0, 1.0));
+    }
+
+    #[test]
+    fn missing_normals_are_generated() {
+        let (data, _) = import_fixture();
+        let roof = submesh_vertices(&data, 1);
+        let normal_at = |position: Vec3| {
+            roof.iter()
+                .find(|vertex| vertex.position == position)
+                .unwrap()
+                .normal
+        };
+        // The ridge averages both slopes, the eaves only touch one of them
+        assert!(normal_at(Vec3::new(0.0, 3.0, 0.0)).abs_diff_eq(Vec3::Y, 1e-6));
+        assert!(normal_at(Vec3::new(0.0, 3.0, 1.0)).abs_diff_eq(Vec3::Y, 1e-6));
+        assert!(normal_at(Vec3::new(-1.0, 2.0, 0.0))
+            .abs_diff_eq(Vec3::new(-1.0, 1.0, 0.0).normalize(), 1e-6));
+        assert!(normal_at(Vec3::new(1.0, 2.0, 0.0))
+            .abs_diff_eq(Vec3::new(1.0, 1.0, 0.0).normalize(), 1e-6));
+        assert!(roof.iter().all(|vertex| vertex.uv == Vec2::ZERO));
+    }
+}
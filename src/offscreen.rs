@@ -0,0 +1,52 @@
+use ash::{vk, Device};
+
+use crate::error::RendererResult;
+use crate::resource::{Image, ImageDesc, ImageView, ResourceManager};
+
+/// Color format of the offscreen target used in headless mode
+pub const OFFSCREEN_COLOR_FORMAT: vk::Format = vk::Format::R8G8B8A8_SRGB;
+
+/// A device-local color image that the renderer draws into when there is no window to present
+/// to.
+pub struct OffscreenTarget {
+    pub image: Image,
+    pub image_view: ImageView,
+}
+
+impl OffscreenTarget {
+    pub fn new(
+        device: &Device,
+        resources: &mut ResourceManager,
+        format: vk::Format,
+        extent: vk::Extent2D,
+    ) -> RendererResult<OffscreenTarget> {
+        // Render targets are recreated on resize and are worth their own memory
+        let image = resources.create_image(
+            device,
+            &ImageDesc {
+                name: "offscreen target",
+                extent,
+                format,
+                mip_levels: 1,
+                usage: vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC,
+                flags: vk::ImageCreateFlags::empty(),
+                dedicated: true,
+            },
+        )?;
+        let image_view = resources.create_image_view(
+            device,
+            &image,
+            image.subresource_range(),
+            "offscreen target",
+        )?;
+        Ok(OffscreenTarget { image, image_view })
+    }
+
+    pub fn format(&self) -> vk::Format {
+        self.image.format()
+    }
+
+    pub fn extent(&self) -> vk::Extent2D {
+        self.image.extent()
+    }
+}
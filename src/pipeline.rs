@@ -0,0 +1,402 @@
+use ash::{vk, Device};
+use std::ffi::CStr;
+
+use crate::error::RendererResult;
+
+pub fn create_shader_module(device: &Device, code: &[u32]) -> RendererResult<vk::ShaderModule> {
+    let shader_module_create_info = vk::ShaderModuleCreateInfo::builder().code(code);
+    Ok(unsafe { device.create_shader_module(&shader_module_create_info, None) }?)
+}
+
+/// A graphics pipeline together with the layout it was created with.
+pub struct GraphicsPipeline {
+    pub pipeline: vk::Pipeline,
+    pub layout: vk::PipelineLayout,
+}
+
+impl GraphicsPipeline {
+    /// # Safety
+    /// The caller must make sure that the pipeline is no longer in use by the GPU.
+    pub unsafe fn destroy(&self, device: &Device) {
+        device.destroy_pipeline(self.pipeline, None);
+        device.destroy_pipeline_layout(self.layout, None);
+    }
+}
+
+/// A compute pipeline together with the layout it was created with.
+pub struct ComputePipeline {
+    pub pipeline: vk::Pipeline,
+    pub layout: vk::PipelineLayout,
+}
+
+impl ComputePipeline {
+    /// The shader's entry point must be `main`. The module can be destroyed once this returns.
+    pub fn new(
+        device: &Device,
+        module: vk::ShaderModule,
+        descriptor_set_layouts: &[vk::DescriptorSetLayout],
+        push_constant_ranges: &[vk::PushConstantRange],
+    ) -> RendererResult<ComputePipeline> {
+        let pipeline_layout_create_info = vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(descriptor_set_layouts)
+            .push_constant_ranges(push_constant_ranges);
+        let layout = unsafe { device.create_pipeline_layout(&pipeline_layout_create_info, None) }?;
+
+        let entry_point = unsafe { CStr::from_bytes_with_nul_unchecked(b"main\0") };
+        let stage = vk::PipelineShaderStageCreateInfo::builder()
+            .stage(vk::ShaderStageFlags::COMPUTE)
+            .module(module)
+            .name(entry_point)
+            .build();
+        let pipeline_create_info = vk::ComputePipelineCreateInfo::builder()
+            .stage(stage)
+            .layout(layout)
+            .build();
+        let pipeline_result = unsafe {
+            device.create_compute_pipelines(
+                vk::PipelineCache::null(),
+                &[pipeline_create_info],
+                None,
+            )
+        };
+        match pipeline_result {
+            Ok(pipelines) => Ok(ComputePipeline {
+                pipeline: pipelines[0],
+                layout,
+            }),
+            Err((_, err)) => {
+                unsafe { device.destroy_pipeline_layout(layout, None) };
+                Err(err.into())
+            }
+        }
+    }
+
+    /// # Safety
+    /// The caller must make sure that the pipeline is no longer in use by the GPU.
+    pub unsafe fn destroy(&self, device: &Device) {
+        device.destroy_pipeline(self.pipeline, None);
+        device.destroy_pipeline_layout(self.layout, None);
+    }
+}
+
+/// How the fragment shader output is combined with the color attachment
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlendMode {
+    Opaque,
+    /// Standard "over" blending with non-premultiplied alpha
+    AlphaBlend,
+    Additive,
+}
+
+impl BlendMode {
+    fn attachment_state(self) -> vk::PipelineColorBlendAttachmentState {
+        let write_all = vk::ColorComponentFlags::R
+            | vk::ColorComponentFlags::G
+            | vk::ColorComponentFlags::B
+            | vk::ColorComponentFlags::A;
+        let builder = vk::PipelineColorBlendAttachmentState::builder().color_write_mask(write_all);
+        match self {
+            BlendMode::Opaque => builder.blend_enable(false),
+            BlendMode::AlphaBlend => builder
+                .blend_enable(true)
+                .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
+                .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+                .color_blend_op(vk::BlendOp::ADD)
+                .src_alpha_blend_factor(vk::BlendFactor::ONE)
+                .dst_alpha_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+                .alpha_blend_op(vk::BlendOp::ADD),
+            BlendMode::Additive => builder
+                .blend_enable(true)
+                .src_color_blend_factor(vk::BlendFactor::ONE)
+                .dst_color_blend_factor(vk::BlendFactor::ONE)
+                .color_blend_op(vk::BlendOp::ADD)
+                .src_alpha_blend_factor(vk::BlendFactor::ONE)
+                .dst_alpha_blend_factor(vk::BlendFactor::ONE)
+                .alpha_blend_op(vk::BlendOp::ADD),
+        }
+        .build()
+    }
+}
+
+/// Builds graphics pipelines for dynamic rendering, or for a render pass if one is set.
+///
+/// The defaults are a triangle list without vertex input, no culling, no depth testing, opaque
+/// blending and dynamic viewport and scissor. Shader modules are only borrowed and can be
+/// destroyed once `build` returns.
+pub struct GraphicsPipelineBuilder {
+    shader_stages: Vec<(vk::ShaderStageFlags, vk::ShaderModule)>,
+    specialization_constants: Vec<(vk::ShaderStageFlags, u32, u32)>,
+    vertex_bindings: Vec<vk::VertexInputBindingDescription>,
+    vertex_attributes: Vec<vk::VertexInputAttributeDescription>,
+    topology: vk::PrimitiveTopology,
+    polygon_mode: vk::PolygonMode,
+    cull_mode: vk::CullModeFlags,
+    front_face: vk::FrontFace,
+    depth_test: bool,
+    depth_write: bool,
+    depth_compare_op: vk::CompareOp,
+    blend_mode: BlendMode,
+    dynamic_states: Vec<vk::DynamicState>,
+    color_attachment_formats: Vec<vk::Format>,
+    depth_attachment_format: vk::Format,
+    render_pass: vk::RenderPass,
+    descriptor_set_layouts: Vec<vk::DescriptorSetLayout>,
+    push_constant_ranges: Vec<vk::PushConstantRange>,
+}
+
+impl Default for GraphicsPipelineBuilder {
+    fn default() -> Self {
+        GraphicsPipelineBuilder {
+            shader_stages: Vec::new(),
+            specialization_constants: Vec::new(),
+            vertex_bindings: Vec::new(),
+            vertex_attributes: Vec::new(),
+            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+            polygon_mode: vk::PolygonMode::FILL,
+            cull_mode: vk::CullModeFlags::NONE,
+            front_face: vk::FrontFace::COUNTER_CLOCKWISE,
+            depth_test: false,
+            depth_write: false,
+            depth_compare_op: vk::CompareOp::LESS_OR_EQUAL,
+            blend_mode: BlendMode::Opaque,
+            dynamic_states: vec![vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR],
+            color_attachment_formats: Vec::new(),
+            depth_attachment_format: vk::Format::UNDEFINED,
+            render_pass: vk::RenderPass::null(),
+            descriptor_set_layouts: Vec::new(),
+            push_constant_ranges: Vec::new(),
+        }
+    }
+}
+
+impl GraphicsPipelineBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a shader stage. The entry point is always `main`.
+    pub fn shader(mut self, stage: vk::ShaderStageFlags, module: vk::ShaderModule) -> Self {
+        self.shader_stages.push((stage, module));
+        self
+    }
+
+    /// Sets the `uint` specialization constant `constant_id` of the shader stage `stage`
+    pub fn specialization_constant(
+        mut self,
+        stage: vk::ShaderStageFlags,
+        constant_id: u32,
+        value: u32,
+    ) -> Self {
+        self.specialization_constants
+            .push((stage, constant_id, value));
+        self
+    }
+
+    pub fn vertex_input(
+        mut self,
+        bindings: &[vk::VertexInputBindingDescription],
+        attributes: &[vk::VertexInputAttributeDescription],
+    ) -> Self {
+        self.vertex_bindings = bindings.to_vec();
+        self.vertex_attributes = attributes.to_vec();
+        self
+    }
+
+    pub fn topology(mut self, topology: vk::PrimitiveTopology) -> Self {
+        self.topology = topology;
+        self
+    }
+
+    pub fn polygon_mode(mut self, polygon_mode: vk::PolygonMode) -> Self {
+        self.polygon_mode = polygon_mode;
+        self
+    }
+
+    pub fn cull_mode(mut self, cull_mode: vk::CullModeFlags, front_face: vk::FrontFace) -> Self {
+        self.cull_mode = cull_mode;
+        self.front_face = front_face;
+        self
+    }
+
+    /// Enables depth testing against a depth attachment of `format`.
+    pub fn depth(mut self, format: vk::Format, write: bool, compare_op: vk::CompareOp) -> Self {
+        self.depth_attachment_format = format;
+        self.depth_test = true;
+        self.depth_write = write;
+        self.depth_compare_op = compare_op;
+        self
+    }
+
+    /// Sets the blend mode used for all color attachments
+    pub fn blend_mode(mut self, blend_mode: BlendMode) -> Self {
+        self.blend_mode = blend_mode;
+        self
+    }
+
+    /// Replaces the default dynamic states (viewport and scissor)
+    pub fn dynamic_states(mut self, dynamic_states: &[vk::DynamicState]) -> Self {
+        self.dynamic_states = dynamic_states.to_vec();
+        self
+    }
+
+    pub fn color_attachment_formats(mut self, formats: &[vk::Format]) -> Self {
+        self.color_attachment_formats = formats.to_vec();
+        self
+    }
+
+    /// Builds the pipeline for subpass 0 of `render_pass` instead of for dynamic rendering. The
+    /// attachment formats still determine the number of color blend attachments.
+    pub fn render_pass(mut self, render_pass: Option<vk::RenderPass>) -> Self {
+        self.render_pass = render_pass.unwrap_or_else(vk::RenderPass::null);
+        self
+    }
+
+    pub fn descriptor_set_layouts(mut self, layouts: &[vk::DescriptorSetLayout]) -> Self {
+        self.descriptor_set_layouts = layouts.to_vec();
+        self
+    }
+
+    pub fn push_constant_range(
+        mut self,
+        stage_flags: vk::ShaderStageFlags,
+        offset: u32,
+        size: u32,
+    ) -> Self {
+        self.push_constant_ranges.push(vk::PushConstantRange {
+            stage_flags,
+            offset,
+            size,
+        });
+        self
+    }
+
+    pub fn build(&self, device: &Device) -> RendererResult<GraphicsPipeline> {
+        let pipeline_layout_create_info = vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(&self.descriptor_set_layouts)
+            .push_constant_ranges(&self.push_constant_ranges);
+        let layout = unsafe { device.create_pipeline_layout(&pipeline_layout_create_info, None) }?;
+
+        // The map entries and data of each stage's constants, which are all 4 bytes long
+        let specializations = self
+            .shader_stages
+            .iter()
+            .map(|(stage, _)| {
+                let constants = self
+                    .specialization_constants
+                    .iter()
+                    .filter(|(constant_stage, _, _)| constant_stage == stage);
+                let map_entries = constants
+                    .clone()
+                    .enumerate()
+                    .map(|(i, (_, constant_id, _))| vk::SpecializationMapEntry {
+                        constant_id: *constant_id,
+                        offset: (i * std::mem::size_of::<u32>()) as u32,
+                        size: std::mem::size_of::<u32>(),
+                    })
+                    .collect::<Vec<_>>();
+                let data = constants
+                    .flat_map(|(_, _, value)| value.to_ne_bytes())
+                    .collect::<Vec<_>>();
+                (map_entries, data)
+            })
+            .collect::<Vec<_>>();
+        let specialization_infos = specializations
+            .iter()
+            .map(|(map_entries, data)| {
+                vk::SpecializationInfo::builder()
+                    .map_entries(map_entries)
+                    .data(data)
+                    .build()
+            })
+            .collect::<Vec<_>>();
+
+        let entry_point = unsafe { CStr::from_bytes_with_nul_unchecked(b"main\0") };
+        let shader_stages = self
+            .shader_stages
+            .iter()
+            .zip(&specialization_infos)
+            .map(|((stage, module), specialization_info)| {
+                vk::PipelineShaderStageCreateInfo::builder()
+                    .stage(*stage)
+                    .module(*module)
+                    .name(entry_point)
+                    .specialization_info(specialization_info)
+                    .build()
+            })
+            .collect::<Vec<_>>();
+
+        let vertex_input_state = vk::PipelineVertexInputStateCreateInfo::builder()
+            .vertex_binding_descriptions(&self.vertex_bindings)
+            .vertex_attribute_descriptions(&self.vertex_attributes);
+        let input_assembly_state =
+            vk::PipelineInputAssemblyStateCreateInfo::builder().topology(self.topology);
+        // Viewport and scissor are expected to be dynamic, only their count is baked in
+        let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
+            .viewport_count(1)
+            .scissor_count(1);
+        let rasterization_state = vk::PipelineRasterizationStateCreateInfo::builder()
+            .polygon_mode(self.polygon_mode)
+            .cull_mode(self.cull_mode)
+            .front_face(self.front_face)
+            .line_width(1.0);
+        let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+        let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfo::builder()
+            .depth_test_enable(self.depth_test)
+            .depth_write_enable(self.depth_write)
+            .depth_compare_op(self.depth_compare_op)
+            .max_depth_bounds(1.0);
+        let color_blend_attachments = self
+            .color_attachment_formats
+            .iter()
+            .map(|_| self.blend_mode.attachment_state())
+            .collect::<Vec<_>>();
+        let color_blend_state =
+            vk::PipelineColorBlendStateCreateInfo::builder().attachments(&color_blend_attachments);
+        let dynamic_state =
+            vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&self.dynamic_states);
+
+        // We use dynamic rendering, so the attachment formats are given here instead of a render
+        // pass
+        let mut rendering_create_info = vk::PipelineRenderingCreateInfoKHR::builder()
+            .color_attachment_formats(&self.color_attachment_formats)
+            .depth_attachment_format(self.depth_attachment_format);
+
+        let mut pipeline_create_info = vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&shader_stages)
+            .vertex_input_state(&vertex_input_state)
+            .input_assembly_state(&input_assembly_state)
+            .viewport_state(&viewport_state)
+            .rasterization_state(&rasterization_state)
+            .multisample_state(&multisample_state)
+            .depth_stencil_state(&depth_stencil_state)
+            .color_blend_state(&color_blend_state)
+            .dynamic_state(&dynamic_state)
+            .layout(layout);
+        pipeline_create_info = if self.render_pass == vk::RenderPass::null() {
+            pipeline_create_info.push_next(&mut rendering_create_info)
+        } else {
+            pipeline_create_info
+                .render_pass(self.render_pass)
+                .subpass(0)
+        };
+
+        let pipeline_result = unsafe {
+            device.create_graphics_pipelines(
+                vk::PipelineCache::null(),
+                &[pipeline_create_info.build()],
+                None,
+            )
+        };
+        match pipeline_result {
+            Ok(pipelines) => Ok(GraphicsPipeline {
+                pipeline: pipelines[0],
+                layout,
+            }),
+            Err((_, err)) => {
+                unsafe { device.destroy_pipeline_layout(layout, None) };
+                Err(err.into())
+            }
+        }
+    }
+}
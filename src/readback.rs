@@ -0,0 +1,193 @@
+use ash::{vk, Device};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use crate::allocator::{AllocationStrategy, MemoryLocation};
+use crate::error::{RendererError, RendererResult};
+use crate::resource::{Buffer, BufferDesc, ResourceManager};
+
+/// Pixels copied back from the renderer, tightly packed as 8-bit RGBA rows from top to bottom.
+pub struct Screenshot {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl Screenshot {
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> RendererResult<()> {
+        let file = File::create(path)?;
+        let mut encoder = png::Encoder::new(BufWriter::new(file), self.width, self.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.pixels)?;
+        Ok(())
+    }
+}
+
+/// Size of a pixel of a color target in `format`, `None` if `convert_to_rgba8` doesn't support
+/// the format
+pub fn bytes_per_pixel(format: vk::Format) -> Option<vk::DeviceSize> {
+    match format {
+        vk::Format::R8G8B8A8_UNORM
+        | vk::Format::R8G8B8A8_SRGB
+        | vk::Format::B8G8R8A8_UNORM
+        | vk::Format::B8G8R8A8_SRGB
+        | vk::Format::A2B10G10R10_UNORM_PACK32
+        | vk::Format::A2R10G10B10_UNORM_PACK32 => Some(4),
+        vk::Format::R16G16B16A16_SFLOAT => Some(8),
+        _ => None,
+    }
+}
+
+/// Converts pixels of a color target in `format` to RGBA8.
+///
+/// Encoded data is passed through unchanged, which is what image files expect for sRGB. 10-bit
+/// HDR10 pixels keep their PQ encoding and lose their two lowest bits. Linear half-float pixels
+/// are clamped to the SDR range and sRGB-encoded.
+pub fn convert_to_rgba8(format: vk::Format, data: &[u8]) -> RendererResult<Vec<u8>> {
+    match format {
+        vk::Format::R8G8B8A8_UNORM | vk::Format::R8G8B8A8_SRGB => Ok(data.to_vec()),
+        vk::Format::B8G8R8A8_UNORM | vk::Format::B8G8R8A8_SRGB => Ok(data
+            .chunks_exact(4)
+            .flat_map(|bgra| [bgra[2], bgra[1], bgra[0], bgra[3]])
+            .collect()),
+        vk::Format::A2B10G10R10_UNORM_PACK32 | vk::Format::A2R10G10B10_UNORM_PACK32 => Ok(data
+            .chunks_exact(4)
+            .flat_map(|pixel| {
+                let pixel = u32::from_le_bytes([pixel[0], pixel[1], pixel[2], pixel[3]]);
+                let channel = |shift: u32| (pixel >> (shift + 2)) as u8;
+                let alpha = (pixel >> 30) as u8 * 0x55;
+                if format == vk::Format::A2B10G10R10_UNORM_PACK32 {
+                    [channel(0), channel(10), channel(20), alpha]
+                } else {
+                    [channel(20), channel(10), channel(0), alpha]
+                }
+            })
+            .collect()),
+        vk::Format::R16G16B16A16_SFLOAT => Ok(data
+            .chunks_exact(8)
+            .flat_map(|pixel| {
+                let channel = |index: usize| {
+                    f16_to_f32(u16::from_le_bytes([pixel[2 * index], pixel[2 * index + 1]]))
+                        .clamp(0.0, 1.0)
+                };
+                let srgb = |index: usize| (linear_to_srgb(channel(index)) * 255.0).round() as u8;
+                [
+                    srgb(0),
+                    srgb(1),
+                    srgb(2),
+                    (channel(3) * 255.0).round() as u8,
+                ]
+            })
+            .collect()),
+        _ => Err(RendererError::Unsupported(format!(
+            "Can't convert pixels of format {:?} to RGBA8",
+            format
+        ))),
+    }
+}
+
+fn f16_to_f32(bits: u16) -> f32 {
+    let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = i32::from((bits >> 10) & 0x1F);
+    let mantissa = f32::from(bits & 0x3FF);
+    match exponent {
+        0 => sign * mantissa * 2f32.powi(-24),
+        0x1F if mantissa == 0.0 => sign * f32::INFINITY,
+        0x1F => f32::NAN,
+        _ => sign * (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
+
+fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// A host-visible buffer that color targets are copied into so that the CPU can read them.
+pub struct ReadbackBuffer {
+    pub buffer: Buffer,
+    pub size: vk::DeviceSize,
+}
+
+impl ReadbackBuffer {
+    pub fn new(
+        device: &Device,
+        resources: &mut ResourceManager,
+        size: vk::DeviceSize,
+    ) -> RendererResult<ReadbackBuffer> {
+        let buffer = resources.create_buffer(
+            device,
+            &BufferDesc {
+                name: "readback buffer",
+                size,
+                usage: vk::BufferUsageFlags::TRANSFER_DST,
+                location: MemoryLocation::GpuToCpu,
+                strategy: AllocationStrategy::Buddy,
+            },
+        )?;
+        Ok(ReadbackBuffer { buffer, size })
+    }
+
+    /// Copies the contents of the buffer to the CPU.
+    ///
+    /// # Safety
+    /// All GPU writes to the buffer must have completed.
+    pub unsafe fn read(&self) -> Vec<u8> {
+        let mapped = self
+            .buffer
+            .mapped_ptr()
+            .expect("GPU to CPU memory is mapped");
+        std::slice::from_raw_parts(mapped.as_ptr(), self.size as usize).to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bgra_is_swizzled() {
+        let pixels =
+            convert_to_rgba8(vk::Format::B8G8R8A8_SRGB, &[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        assert_eq!(pixels, [3, 2, 1, 4, 7, 6, 5, 8]);
+    }
+
+    #[test]
+    fn ten_bit_pixels_are_truncated() {
+        let pixel: u32 = (3 << 30) | (0x3FF << 20) | (0x200 << 10) | 0x007;
+        let data = pixel.to_le_bytes();
+        assert_eq!(
+            convert_to_rgba8(vk::Format::A2B10G10R10_UNORM_PACK32, &data).unwrap(),
+            [1, 128, 255, 255]
+        );
+        assert_eq!(
+            convert_to_rgba8(vk::Format::A2R10G10B10_UNORM_PACK32, &data).unwrap(),
+            [255, 128, 1, 255]
+        );
+    }
+
+    #[test]
+    fn half_float_pixels_are_srgb_encoded() {
+        // 0.5, 2.0, -1.0 and 1.0
+        let data: Vec<u8> = [0x3800u16, 0x4000, 0xBC00, 0x3C00]
+            .iter()
+            .flat_map(|half| half.to_le_bytes())
+            .collect();
+        assert_eq!(
+            convert_to_rgba8(vk::Format::R16G16B16A16_SFLOAT, &data).unwrap(),
+            [188, 255, 0, 255]
+        );
+    }
+
+    #[test]
+    fn unsupported_formats_are_rejected() {
+        assert_eq!(bytes_per_pixel(vk::Format::R32G32B32A32_SFLOAT), None);
+        assert!(convert_to_rgba8(vk::Format::R32G32B32A32_SFLOAT, &[0; 16]).is_err());
+    }
+}
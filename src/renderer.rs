@@ -1,367 +1,1640 @@
 use ash::extensions::khr;
-use ash::extensions::khr::{Surface, Swapchain};
-use ash::vk::{
-    CommandBufferUsageFlags, Image, ImageView, Offset2D, PhysicalDevice, SurfaceKHR, SwapchainKHR,
-};
+use ash::vk::{CommandBufferUsageFlags, Offset2D};
 use ash::{vk, Device, Entry, Instance};
+use glam::{Mat4, Vec4};
+use std::collections::hash_map::Entry as HashMapEntry;
+use std::collections::HashMap;
 use std::ffi::CStr;
+use std::path::Path;
 use winit::window::Window;
 
 use vk_shader_macros::include_glsl;
 
-use crate::dyn_result::DynResult;
+use crate::allocator::{AllocationStrategy, Allocator, AllocatorStats, MemoryLocation};
+use crate::compressed::{check_levels, supports_texture_format, CompressedTextureData};
+use crate::config::RendererConfig;
+use crate::debug::{debug_messenger_create_info, DebugCounters, DebugMessenger};
+use crate::decompress::{decompress, decompressed_format};
+use crate::device::{
+    enumerate_physical_devices, required_device_extensions, select_physical_device,
+    PhysicalDeviceInfo, QueueFamilyIndices,
+};
+use crate::error::{RendererError, RendererResult};
+use crate::features::{instance_api_version, DeviceFeatureChain, DeviceFeatures, DynamicRendering};
+use crate::frame::{DescriptorAllocator, FrameData, FrameUniforms};
+use crate::material::Material;
+use crate::mesh::{Mesh, MeshData, MeshPushConstants, Submesh, Vertex};
+use crate::mipmap::{mip_level_count, mip_level_extent, MipmapGenerator, MipmapMethod};
+use crate::offscreen::{OffscreenTarget, OFFSCREEN_COLOR_FORMAT};
+use crate::pipeline::{create_shader_module, GraphicsPipeline, GraphicsPipelineBuilder};
+use crate::readback::{bytes_per_pixel, convert_to_rgba8, ReadbackBuffer, Screenshot};
+use crate::rendering::{Attachment, Rendering, RenderingInfo};
+use crate::resource::{Buffer, BufferDesc, Image, ImageDesc, ImageView, ResourceManager, Sampler};
+use crate::swapchain::{OutputEncoding, PresentPolicy, Swapchain, SwapchainDesc};
+use crate::texture::{SamplerCache, SamplerDesc, Texture, TextureData, TextureUsage};
+use crate::upload::Uploader;
+use crate::validation::{query_validation_support, ValidationConfig, VALIDATION_LAYER_NAME};
 
 const TRIANGLE_VERT: &[u32] = include_glsl!("shaders/triangle.vert");
 const TRIANGLE_FRAG: &[u32] = include_glsl!("shaders/triangle.frag");
+const MESH_VERT: &[u32] = include_glsl!("shaders/mesh.vert");
+const MESH_FRAG: &[u32] = include_glsl!("shaders/mesh.frag");
 
-unsafe extern "system" fn vulkan_debug_utils_callback(
-    message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
-    message_type: vk::DebugUtilsMessageTypeFlagsEXT,
-    p_callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT,
-    _p_user_data: *mut std::ffi::c_void,
-) -> vk::Bool32 {
-    let message = std::ffi::CStr::from_ptr((*p_callback_data).p_message);
-    let severity = format!("{:?}", message_severity).to_lowercase();
-    let ty = format!("{:?}", message_type).to_lowercase();
-    println!("[Debug][{}][{}] {:?}", severity, ty, message);
-    vk::FALSE
+/// Depth formats in order of preference. `D16_UNORM` is supported everywhere.
+const DEPTH_FORMATS: [vk::Format; 4] = [
+    vk::Format::D32_SFLOAT,
+    vk::Format::D32_SFLOAT_S8_UINT,
+    vk::Format::D24_UNORM_S8_UINT,
+    vk::Format::D16_UNORM,
+];
+
+/// What `create_instance` could enable out of what was requested
+struct InstanceSupport {
+    /// The Vulkan version the instance was created with
+    api_version: u32,
+    validation: bool,
+    debug_utils: bool,
 }
 
-fn create_instance(entry: &Entry, window: &Window) -> DynResult<Instance> {
+/// `debug_counters` receive the messages of instance creation and must outlive the instance.
+fn create_instance(
+    entry: &Entry,
+    window: Option<&Window>,
+    validation: &ValidationConfig,
+    debug_counters: &DebugCounters,
+) -> RendererResult<(Instance, InstanceSupport)> {
+    let api_version = instance_api_version(entry.try_enumerate_instance_version()?)?;
     let app_info = vk::ApplicationInfo {
-        api_version: vk::make_api_version(0, 1, 2, 0),
+        api_version,
         ..Default::default()
     };
 
-    let layer_names: Vec<std::ffi::CString> =
-        vec![std::ffi::CString::new("VK_LAYER_KHRONOS_validation").unwrap()];
-    let layer_name_pointers: Vec<*const i8> = layer_names
-        .iter()
-        .map(|layer_name| layer_name.as_ptr())
-        .collect();
+    let validation_support = query_validation_support(entry, validation)?;
+    let layer_name_pointers: Vec<*const i8> = if validation_support.layer {
+        vec![VALIDATION_LAYER_NAME.as_ptr() as *const i8]
+    } else {
+        Vec::new()
+    };
+
+    let available_extensions = entry.enumerate_instance_extension_properties()?;
+    let is_extension_available = |name: &CStr| {
+        available_extensions
+            .iter()
+            .any(|extension| unsafe { CStr::from_ptr(extension.extension_name.as_ptr()) } == name)
+    };
 
     let extensions = {
-        let mut extensions = ash_window::enumerate_required_extensions(&window)?;
-        extensions.push(ash::extensions::ext::DebugUtils::name());
+        // Surface extensions are only needed if we present to a window
+        let mut extensions = match window {
+            Some(window) => ash_window::enumerate_required_extensions(window)?,
+            None => Vec::new(),
+        };
+        // Exposes the HDR color spaces in the surface formats
+        if window.is_some() && is_extension_available(vk::ExtSwapchainColorspaceFn::name()) {
+            extensions.push(vk::ExtSwapchainColorspaceFn::name());
+        }
         extensions
+    };
+    if let Some(missing) = extensions
+        .iter()
+        .find(|extension| !is_extension_available(extension))
+    {
+        return Err(RendererError::MissingExtension(
+            missing.to_string_lossy().into_owned(),
+        ));
+    }
+    let mut extensions = extensions
+        .iter()
+        .map(|cstring| cstring.as_ptr())
+        .collect::<Vec<_>>();
+    // Only needed to see the validation messages
+    let enable_debug_utils = validation_support.layer
+        && is_extension_available(ash::extensions::ext::DebugUtils::name());
+    if enable_debug_utils {
+        extensions.push(ash::extensions::ext::DebugUtils::name().as_ptr());
+    }
+    // Provided by the validation layer itself
+    if validation_support.validation_features {
+        extensions.push(vk::ExtValidationFeaturesFn::name().as_ptr());
     }
-    .iter()
-    .map(|cstring| cstring.as_ptr())
-    .collect::<Vec<_>>();
 
-    let mut debugcreateinfo = vk::DebugUtilsMessengerCreateInfoEXT::builder()
-        .message_severity(
-            vk::DebugUtilsMessageSeverityFlagsEXT::WARNING
-                | vk::DebugUtilsMessageSeverityFlagsEXT::ERROR,
-        )
-        .message_type(
-            vk::DebugUtilsMessageTypeFlagsEXT::GENERAL
-                | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE
-                | vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION,
-        )
-        .pfn_user_callback(Some(vulkan_debug_utils_callback));
+    let mut debugcreateinfo = debug_messenger_create_info(debug_counters);
+
+    let enabled_validation_features = validation.enabled_features();
+    let mut validation_features = vk::ValidationFeaturesEXT::builder()
+        .enabled_validation_features(&enabled_validation_features);
 
-    let instance_create_info = vk::InstanceCreateInfo::builder()
-        .push_next(&mut debugcreateinfo)
+    let mut instance_create_info = vk::InstanceCreateInfo::builder()
         .application_info(&app_info)
         .enabled_layer_names(&layer_name_pointers)
         .enabled_extension_names(&extensions);
+    if enable_debug_utils {
+        instance_create_info = instance_create_info.push_next(&mut debugcreateinfo);
+    }
+    if validation_support.validation_features {
+        instance_create_info = instance_create_info.push_next(&mut validation_features);
+    }
 
     let instance = unsafe { entry.create_instance(&instance_create_info, None)? };
-    Ok(instance)
+    Ok((
+        instance,
+        InstanceSupport {
+            api_version,
+            validation: validation_support.layer,
+            debug_utils: enable_debug_utils,
+        },
+    ))
 }
 
-fn find_physical_device(instance: &Instance) -> DynResult<vk::PhysicalDevice> {
-    let physical_devices = unsafe { instance.enumerate_physical_devices()? };
-    let mut pd_properties_pairs = physical_devices
-        .iter()
-        .map(|pd| (*pd, unsafe { instance.get_physical_device_properties(*pd) }));
-    Ok(pd_properties_pairs
-        .clone()
-        .find(|(_, prop)| prop.device_type == vk::PhysicalDeviceType::DISCRETE_GPU) // Prefer Discrete
-        .or_else(|| pd_properties_pairs.next())
-        .expect("Can't find a physical device")
-        .0)
-}
+fn create_device(
+    instance: &Instance,
+    physical_device: vk::PhysicalDevice,
+    queue_family_indices: &QueueFamilyIndices,
+    features: &DeviceFeatures,
+    enable_swapchain: bool,
+) -> RendererResult<Device> {
+    let priorities = [1.0f32];
+    // Each family may only be listed once
+    let queue_infos = queue_family_indices
+        .unique()
+        .into_iter()
+        .map(|family| {
+            vk::DeviceQueueCreateInfo::builder()
+                .queue_family_index(family)
+                .queue_priorities(&priorities)
+                .build()
+        })
+        .collect::<Vec<_>>();
 
-struct QueueFamilyIndices {
-    graphics: u32,
-    transfer: u32,
+    let extensions = required_device_extensions(enable_swapchain)
+        .into_iter()
+        .chain(features.extensions())
+        .map(|extension| extension.as_ptr())
+        .collect::<Vec<_>>();
+    let mut feature_chain = DeviceFeatureChain::new(features);
+    let device_create_info = feature_chain.push_into(
+        vk::DeviceCreateInfo::builder()
+            .queue_create_infos(&queue_infos)
+            .enabled_extension_names(&extensions),
+    );
+    Ok(unsafe { instance.create_device(physical_device, &device_create_info, None) }?)
 }
 
-fn find_queue_family_indices(
+fn find_depth_format(
     instance: &Instance,
     physical_device: vk::PhysicalDevice,
-    surface: vk::SurfaceKHR,
-    surface_fn: &khr::Surface,
-) -> DynResult<QueueFamilyIndices> {
-    let queue_family_properties =
-        unsafe { instance.get_physical_device_queue_family_properties(physical_device) };
-    {
-        let mut graphics_qf_index_opt = None;
-        let mut transfer_qf_index_opt = None;
-        for (index, qfam) in queue_family_properties.iter().enumerate() {
-            if qfam.queue_count > 0 {
-                if qfam.queue_flags.contains(vk::QueueFlags::GRAPHICS)
-                    && unsafe {
-                        surface_fn.get_physical_device_surface_support(
-                            physical_device,
-                            index as u32,
-                            surface,
-                        )?
-                    }
-                {
-                    graphics_qf_index_opt = Some(index as u32);
-                }
-                if qfam.queue_flags.contains(vk::QueueFlags::TRANSFER) {
-                    if transfer_qf_index_opt.is_none()
-                        || !qfam.queue_flags.contains(vk::QueueFlags::GRAPHICS)
-                    {
-                        transfer_qf_index_opt = Some(index as u32);
-                    }
-                }
-            }
-        }
-        Ok(QueueFamilyIndices {
-            graphics: graphics_qf_index_opt.unwrap(),
-            transfer: transfer_qf_index_opt.unwrap(),
+) -> RendererResult<vk::Format> {
+    DEPTH_FORMATS
+        .iter()
+        .copied()
+        .find(|format| {
+            let properties =
+                unsafe { instance.get_physical_device_format_properties(physical_device, *format) };
+            properties
+                .optimal_tiling_features
+                .contains(vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT)
         })
+        .ok_or_else(|| RendererError::Unsupported("No depth attachment format".to_string()))
+}
+
+fn create_triangle_pipeline(
+    device: &Device,
+    rendering: &mut Rendering,
+    color_attachment_format: vk::Format,
+    output_encoding: OutputEncoding,
+    depth_attachment_format: vk::Format,
+) -> RendererResult<GraphicsPipeline> {
+    let render_pass = rendering.pipeline_render_pass(
+        device,
+        &[color_attachment_format],
+        Some(depth_attachment_format),
+    )?;
+    let triangle_vert_shader = create_shader_module(device, TRIANGLE_VERT)?;
+    let triangle_frag_shader = match create_shader_module(device, TRIANGLE_FRAG) {
+        Ok(shader_module) => shader_module,
+        Err(err) => {
+            unsafe { device.destroy_shader_module(triangle_vert_shader, None) };
+            return Err(err);
+        }
+    };
+
+    // The triangle is drawn on top of everything
+    let pipeline = GraphicsPipelineBuilder::new()
+        .shader(vk::ShaderStageFlags::VERTEX, triangle_vert_shader)
+        .shader(vk::ShaderStageFlags::FRAGMENT, triangle_frag_shader)
+        .specialization_constant(vk::ShaderStageFlags::FRAGMENT, 0, output_encoding as u32)
+        .color_attachment_formats(&[color_attachment_format])
+        .depth(depth_attachment_format, false, vk::CompareOp::ALWAYS)
+        .render_pass(render_pass)
+        .build(device);
+
+    unsafe {
+        device.destroy_shader_module(triangle_vert_shader, None);
+        device.destroy_shader_module(triangle_frag_shader, None);
     }
+    pipeline
 }
 
-fn create_device(
-    instance: &Instance,
-    physical_device: vk::PhysicalDevice,
-    queue_family_indices: &QueueFamilyIndices,
-) -> DynResult<Device> {
-    let priorities = [1.0f32];
-    let queue_infos = [
-        vk::DeviceQueueCreateInfo::builder()
-            .queue_family_index(queue_family_indices.graphics)
-            .queue_priorities(&priorities)
-            .build(),
-        vk::DeviceQueueCreateInfo::builder()
-            .queue_family_index(queue_family_indices.transfer)
-            .queue_priorities(&priorities)
-            .build(),
-    ];
-
-    let extensions: Vec<*const i8> = [
-        ash::extensions::khr::Swapchain::name(),
-        ash::extensions::khr::DynamicRendering::name(),
-    ]
-    .iter()
-    .map(|name| name.as_ptr())
-    .collect::<Vec<_>>();
-    let device_create_info = vk::DeviceCreateInfo::builder()
-        .queue_create_infos(&queue_infos)
-        .enabled_extension_names(&extensions);
-    Ok(unsafe { instance.create_device(physical_device, &device_create_info, None) }?)
+/// Combines the result of rendering a frame with the validation check that follows it. Losing
+/// the device or surface usually triggers validation errors too, and the caller has to see the
+/// loss to recreate the renderer.
+fn frame_result(rendered: RendererResult<()>, validated: RendererResult<()>) -> RendererResult<()> {
+    rendered.and(validated)
 }
 
-fn create_swapchain(
-    instance: &Instance,
-    surface: SurfaceKHR,
-    surface_fn: &Surface,
-    physical_device: PhysicalDevice,
-    queue_family_indices: &QueueFamilyIndices,
+/// The layout of the first descriptor set that meshes are drawn with: the `FrameUniforms`
+fn create_frame_set_layout(device: &Device) -> RendererResult<vk::DescriptorSetLayout> {
+    let bindings = [vk::DescriptorSetLayoutBinding::builder()
+        .binding(0)
+        .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+        .descriptor_count(1)
+        .stage_flags(vk::ShaderStageFlags::VERTEX)
+        .build()];
+    let set_layout_create_info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings);
+    Ok(unsafe { device.create_descriptor_set_layout(&set_layout_create_info, None)? })
+}
+
+/// Allocates a descriptor set with the layout of `create_frame_set_layout` from
+/// `descriptors` and points it to the `FrameUniforms` at `offset` in `buffer`.
+///
+/// # Safety
+/// The buffer must outlive the frames that use the set.
+unsafe fn allocate_frame_set(
     device: &Device,
-) -> DynResult<(Swapchain, SwapchainKHR, Vec<Image>, Vec<ImageView>)> {
-    let surface_capabilities =
-        unsafe { surface_fn.get_physical_device_surface_capabilities(physical_device, surface)? };
-    let surface_formats =
-        unsafe { surface_fn.get_physical_device_surface_formats(physical_device, surface)? };
-
-    let swapcahin_queue_family_indices = [queue_family_indices.graphics];
-    let swapchain_create_info = vk::SwapchainCreateInfoKHR::builder()
-        .surface(surface)
-        .min_image_count(
-            3.max(surface_capabilities.min_image_count)
-                .min(surface_capabilities.max_image_count),
+    descriptors: &mut DescriptorAllocator,
+    set_layout: vk::DescriptorSetLayout,
+    buffer: vk::Buffer,
+    offset: vk::DeviceSize,
+) -> RendererResult<vk::DescriptorSet> {
+    let descriptor_set = descriptors.allocate(device, &[set_layout])?[0];
+    let buffer_info = [vk::DescriptorBufferInfo {
+        buffer,
+        offset,
+        range: std::mem::size_of::<FrameUniforms>() as vk::DeviceSize,
+    }];
+    let write = vk::WriteDescriptorSet::builder()
+        .dst_set(descriptor_set)
+        .dst_binding(0)
+        .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+        .buffer_info(&buffer_info)
+        .build();
+    device.update_descriptor_sets(&[write], &[]);
+    Ok(descriptor_set)
+}
+
+/// The layout of the second descriptor set that meshes are drawn with: the base color texture
+/// and its sampler
+fn create_material_set_layout(device: &Device) -> RendererResult<vk::DescriptorSetLayout> {
+    let bindings = [vk::DescriptorSetLayoutBinding::builder()
+        .binding(0)
+        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+        .descriptor_count(1)
+        .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+        .build()];
+    let set_layout_create_info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings);
+    Ok(unsafe { device.create_descriptor_set_layout(&set_layout_create_info, None)? })
+}
+
+/// Allocates a descriptor set with the layout of `create_material_set_layout` from
+/// `descriptors` and points it to `image_view` and `sampler`.
+///
+/// # Safety
+/// The image view and sampler must outlive the frames that use the set.
+unsafe fn allocate_material_set(
+    device: &Device,
+    descriptors: &mut DescriptorAllocator,
+    set_layout: vk::DescriptorSetLayout,
+    image_view: vk::ImageView,
+    sampler: vk::Sampler,
+) -> RendererResult<vk::DescriptorSet> {
+    let descriptor_set = descriptors.allocate(device, &[set_layout])?[0];
+    let image_info = [vk::DescriptorImageInfo {
+        sampler,
+        image_view,
+        image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+    }];
+    let write = vk::WriteDescriptorSet::builder()
+        .dst_set(descriptor_set)
+        .dst_binding(0)
+        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+        .image_info(&image_info)
+        .build();
+    device.update_descriptor_sets(&[write], &[]);
+    Ok(descriptor_set)
+}
+
+fn create_mesh_pipeline(
+    device: &Device,
+    rendering: &mut Rendering,
+    color_attachment_format: vk::Format,
+    output_encoding: OutputEncoding,
+    depth_attachment_format: vk::Format,
+    set_layouts: &[vk::DescriptorSetLayout],
+) -> RendererResult<GraphicsPipeline> {
+    let render_pass = rendering.pipeline_render_pass(
+        device,
+        &[color_attachment_format],
+        Some(depth_attachment_format),
+    )?;
+    let mesh_vert_shader = create_shader_module(device, MESH_VERT)?;
+    let mesh_frag_shader = match create_shader_module(device, MESH_FRAG) {
+        Ok(shader_module) => shader_module,
+        Err(err) => {
+            unsafe { device.destroy_shader_module(mesh_vert_shader, None) };
+            return Err(err);
+        }
+    };
+
+    let pipeline = GraphicsPipelineBuilder::new()
+        .shader(vk::ShaderStageFlags::VERTEX, mesh_vert_shader)
+        .shader(vk::ShaderStageFlags::FRAGMENT, mesh_frag_shader)
+        .specialization_constant(vk::ShaderStageFlags::FRAGMENT, 0, output_encoding as u32)
+        .vertex_input(
+            &[Vertex::binding_description()],
+            &Vertex::attribute_descriptions(),
         )
-        .image_format(surface_formats.first().unwrap().format)
-        .image_color_space(surface_formats.first().unwrap().color_space)
-        .image_extent(surface_capabilities.current_extent)
-        .image_array_layers(1)
-        .image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT)
-        .image_sharing_mode(vk::SharingMode::EXCLUSIVE)
-        .queue_family_indices(&swapcahin_queue_family_indices)
-        .pre_transform(surface_capabilities.current_transform)
-        .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
-        .present_mode(vk::PresentModeKHR::FIFO);
-    let swapchain_loader = khr::Swapchain::new(&instance, &device);
-    let swapchain = unsafe { swapchain_loader.create_swapchain(&swapchain_create_info, None)? };
-    let swapchain_images = unsafe { swapchain_loader.get_swapchain_images(swapchain)? };
-    let swapchain_image_views = swapchain_images
-        .iter()
-        .map(|image| {
-            let subresource_range = vk::ImageSubresourceRange::builder()
-                .aspect_mask(vk::ImageAspectFlags::COLOR)
-                .base_mip_level(0)
-                .level_count(1)
-                .base_array_layer(0)
-                .layer_count(1);
-            let imageview_create_info = vk::ImageViewCreateInfo::builder()
-                .image(*image)
-                .view_type(vk::ImageViewType::TYPE_2D)
-                .format(vk::Format::B8G8R8A8_UNORM)
-                .subresource_range(*subresource_range);
-            unsafe { device.create_image_view(&imageview_create_info, None) }.unwrap()
-        })
-        .collect::<Vec<_>>();
-    Ok((
-        swapchain_loader,
-        swapchain,
-        swapchain_images,
-        swapchain_image_views,
-    ))
+        .color_attachment_formats(&[color_attachment_format])
+        .depth(depth_attachment_format, true, vk::CompareOp::LESS)
+        .push_constant_range(
+            vk::ShaderStageFlags::VERTEX,
+            0,
+            std::mem::size_of::<MeshPushConstants>() as u32,
+        )
+        .descriptor_set_layouts(set_layouts)
+        .render_pass(render_pass)
+        .build(device);
+
+    unsafe {
+        device.destroy_shader_module(mesh_vert_shader, None);
+        device.destroy_shader_module(mesh_frag_shader, None);
+    }
+    pipeline
+}
+
+/// The depth attachment, shared by all frames in flight like the offscreen target
+struct DepthBuffer {
+    image: Image,
+    image_view: ImageView,
+}
+
+impl DepthBuffer {
+    fn new(
+        device: &Device,
+        resources: &mut ResourceManager,
+        format: vk::Format,
+        extent: vk::Extent2D,
+    ) -> RendererResult<DepthBuffer> {
+        let image = resources.create_image(
+            device,
+            &ImageDesc {
+                name: "depth buffer",
+                extent,
+                format,
+                mip_levels: 1,
+                usage: vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+                flags: vk::ImageCreateFlags::empty(),
+                dedicated: true,
+            },
+        )?;
+        let image_view = resources.create_image_view(
+            device,
+            &image,
+            image.subresource_range(),
+            "depth buffer",
+        )?;
+        Ok(DepthBuffer { image, image_view })
+    }
+}
+
+/// Where the renderer draws into
+enum RenderTarget {
+    /// A window surface that we present to
+    Window {
+        surface_fn: khr::Surface,
+        swapchain: Swapchain,
+        /// The fence of the frame that last rendered into each swapchain image
+        images_in_flight: Vec<vk::Fence>,
+    },
+    /// An image that is never presented, used in headless mode
+    Offscreen(OffscreenTarget),
+}
+
+impl RenderTarget {
+    fn format(&self) -> vk::Format {
+        match self {
+            RenderTarget::Window { swapchain, .. } => swapchain.format.format,
+            RenderTarget::Offscreen(offscreen) => offscreen.format(),
+        }
+    }
+
+    /// The offscreen target has an `*_SRGB` format, so its output is always linear
+    fn output_encoding(&self) -> OutputEncoding {
+        match self {
+            RenderTarget::Window { swapchain, .. } => OutputEncoding::of(&swapchain.format),
+            RenderTarget::Offscreen(_) => OutputEncoding::Linear,
+        }
+    }
+
+    fn extent(&self) -> vk::Extent2D {
+        match self {
+            RenderTarget::Window { swapchain, .. } => swapchain.extent,
+            RenderTarget::Offscreen(offscreen) => offscreen.extent(),
+        }
+    }
+
+    fn supports_capture(&self) -> bool {
+        match self {
+            RenderTarget::Window { swapchain, .. } => swapchain
+                .image_usage
+                .contains(vk::ImageUsageFlags::TRANSFER_SRC),
+            RenderTarget::Offscreen(_) => true,
+        }
+    }
+}
+
+/// A submesh queued to be drawn in the next frame
+struct MeshDraw {
+    mesh: Mesh,
+    submesh: Submesh,
+    base_color: Vec4,
+    base_color_texture: Texture,
+    sampler: SamplerDesc,
+    model: Mat4,
+}
+
+/// A frame whose color target is being copied into the readback buffer
+struct PendingCapture {
+    render_fence: vk::Fence,
+    format: vk::Format,
+    extent: vk::Extent2D,
+}
+
+/// The image that the current frame renders into
+struct TargetImage {
+    /// `None` when rendering offscreen
+    swapchain_index: Option<u32>,
+    image: vk::Image,
+    image_view: vk::ImageView,
 }
 
 pub struct Renderer {
-    entry: Entry,
+    config: RendererConfig,
+    validation_enabled: bool,
+    debug_messenger: Option<DebugMessenger>,
+    /// Validation errors up to this count have already been returned in strict mode
+    reported_validation_errors: usize,
+    /// Keeps the Vulkan library loaded while the instance exists
+    _entry: Entry,
     instance: Instance,
-    surface: vk::SurfaceKHR,
-    surface_fn: khr::Surface,
     physical_device: vk::PhysicalDevice,
+    resources: ResourceManager,
     device: Device,
-    dynamic_rendering_loader: khr::DynamicRendering,
+    features: DeviceFeatures,
+    rendering: Rendering,
     queue_family_indices: QueueFamilyIndices,
     graphics_queue: vk::Queue,
-    transfer_queue: vk::Queue,
-    swapchain_loader: khr::Swapchain,
-    swapchain: vk::SwapchainKHR,
-    swapchain_images: Vec<vk::Image>,
-    swapchain_image_views: Vec<vk::ImageView>,
+    /// The graphics queue unless presentation needs a different family
+    present_queue: vk::Queue,
+    /// The graphics queue unless the device has an async compute family
+    compute_queue: vk::Queue,
+    uploader: Uploader,
+    /// Only `None` while being dropped
+    target: Option<RenderTarget>,
+    target_needs_recreation: bool,
+    /// The window size, or the size of the offscreen target in headless mode
+    target_extent: vk::Extent2D,
+    depth_format: vk::Format,
+    /// Only `None` while being dropped
+    depth_buffer: Option<DepthBuffer>,
 
-    graphics_command_pool: vk::CommandPool,
-    main_graphics_command_buffer: vk::CommandBuffer,
+    frames: Vec<FrameData>,
 
-    present_semaphore: vk::Semaphore,
-    render_semaphore: vk::Semaphore,
-    render_fence: vk::Fence,
+    triangle_pipeline: GraphicsPipeline,
+    frame_set_layout: vk::DescriptorSetLayout,
+    material_set_layout: vk::DescriptorSetLayout,
+    mesh_pipeline: GraphicsPipeline,
+    /// Sampled by meshes whose material has no base color texture. Only `None` while the
+    /// renderer is being created or dropped.
+    white_texture: Option<Texture>,
+    /// `minUniformBufferOffsetAlignment`
+    uniform_alignment: vk::DeviceSize,
+    view_projection: Mat4,
+    mesh_draws: Vec<MeshDraw>,
+
+    mipmaps: MipmapGenerator,
+    samplers: SamplerCache,
+
+    readback_buffer: Option<ReadbackBuffer>,
+    capture_requested: bool,
+    pending_capture: Option<PendingCapture>,
 
     frame_number: u64,
 }
 
 impl Renderer {
-    pub fn new(window: &winit::window::Window) -> DynResult<Renderer> {
-        let entry = Entry::linked();
+    pub fn new(window: &winit::window::Window, config: RendererConfig) -> RendererResult<Renderer> {
+        let window_size = window.inner_size();
+        let window_extent = vk::Extent2D {
+            width: window_size.width,
+            height: window_size.height,
+        };
+        Self::create(Some(window), window_extent, config)
+    }
+
+    /// Creates a renderer without a window or surface that renders into an offscreen image of
+    /// the given size. This works on machines without a display, e.g. with lavapipe on CI.
+    ///
+    /// In strict validation mode, returns `RendererError::ValidationErrors` if the validation
+    /// layer reported errors while creating the renderer.
+    pub fn new_headless(
+        width: u32,
+        height: u32,
+        config: RendererConfig,
+    ) -> RendererResult<Renderer> {
+        Self::create(None, vk::Extent2D { width, height }, config)
+    }
+
+    /// Inspects all physical devices the way `Renderer::new` would, without creating a renderer.
+    /// Presentation support is not checked since there is no surface.
+    ///
+    /// The returned `physical_device` handles belong to a temporary instance and are no longer
+    /// valid.
+    pub fn list_devices(config: &RendererConfig) -> RendererResult<Vec<PhysicalDeviceInfo>> {
+        let entry = unsafe { Entry::load()? };
+        let debug_counters = DebugCounters::default();
+        let (instance, instance_support) =
+            create_instance(&entry, None, &config.validation, &debug_counters)?;
+        let devices = enumerate_physical_devices(
+            &instance,
+            instance_support.api_version,
+            None,
+            &required_device_extensions(true),
+        );
+        unsafe { instance.destroy_instance(None) };
+        devices
+    }
 
-        let instance = create_instance(&entry, &window)?;
-        let surface = unsafe { ash_window::create_surface(&entry, &instance, &window, None)? };
-        let surface_fn = ash::extensions::khr::Surface::new(&entry, &instance);
-        let physical_device = find_physical_device(&instance)?;
-        let queue_family_indices =
-            find_queue_family_indices(&instance, physical_device, surface, &surface_fn)?;
-        let device = create_device(&instance, physical_device, &queue_family_indices)?;
+    fn create(
+        window: Option<&Window>,
+        target_extent: vk::Extent2D,
+        config: RendererConfig,
+    ) -> RendererResult<Renderer> {
+        let entry = unsafe { Entry::load()? };
 
-        let dynamic_rendering_loader = khr::DynamicRendering::new(&instance, &device);
+        let debug_counters = Box::new(DebugCounters::default());
+        let (instance, instance_support) =
+            create_instance(&entry, window, &config.validation, &debug_counters)?;
+        let debug_messenger = if instance_support.debug_utils {
+            Some(DebugMessenger::new(&entry, &instance, debug_counters)?)
+        } else {
+            None
+        };
+        let surface = match window {
+            Some(window) => Some((
+                unsafe { ash_window::create_surface(&entry, &instance, window, None)? },
+                khr::Surface::new(&entry, &instance),
+            )),
+            None => None,
+        };
+        let devices = enumerate_physical_devices(
+            &instance,
+            instance_support.api_version,
+            surface
+                .as_ref()
+                .map(|(surface, surface_fn)| (*surface, surface_fn)),
+            &required_device_extensions(surface.is_some()),
+        )?;
+        let selected_device = select_physical_device(devices, &config.device)?;
+        log::info!("Using {}", selected_device.name);
+        let physical_device = selected_device.physical_device;
+        let queue_family_indices = selected_device
+            .queue_family_indices
+            .expect("Suitable devices have queue families");
+        log::debug!("Using queue families {:?}", queue_family_indices);
+        // Enable everything the device supports that we know how to use
+        let mut features = selected_device.features;
+        if config.disable_dynamic_rendering {
+            features.dynamic_rendering = false;
+        }
+        log::debug!("Enabling {:?}", features);
+        let device = create_device(
+            &instance,
+            physical_device,
+            &queue_family_indices,
+            &features,
+            surface.is_some(),
+        )?;
+
+        let mut rendering = Rendering::new(
+            features
+                .dynamic_rendering
+                .then(|| DynamicRendering::new(&instance, &device, &features)),
+        );
+        if !rendering.uses_dynamic_rendering() {
+            log::info!("Dynamic rendering is unavailable, using render passes");
+        }
 
         let graphics_queue = unsafe { device.get_device_queue(queue_family_indices.graphics, 0) };
+        // Only used with a surface
+        let present_queue = match queue_family_indices.present {
+            Some(present) => unsafe { device.get_device_queue(present, 0) },
+            None => graphics_queue,
+        };
+        let compute_queue = unsafe { device.get_device_queue(queue_family_indices.compute, 0) };
         let transfer_queue = unsafe { device.get_device_queue(queue_family_indices.transfer, 0) };
 
-        let (swapchain_loader, swapchain, swapchain_images, swapchain_image_views) =
-            create_swapchain(
-                &instance,
-                surface,
-                &surface_fn,
-                physical_device,
-                &queue_family_indices,
-                &device,
-            )?;
-
-        let command_pool_create_info = vk::CommandPoolCreateInfo::builder()
-            .queue_family_index(queue_family_indices.graphics)
-            .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER);
-        let graphics_command_pool =
-            unsafe { device.create_command_pool(&command_pool_create_info, None)? };
+        let memory_properties =
+            unsafe { instance.get_physical_device_memory_properties(physical_device) };
+        let limits = unsafe { instance.get_physical_device_properties(physical_device) }.limits;
+        let mut resources =
+            ResourceManager::new(Allocator::new(memory_properties, &limits, config.allocator));
 
-        let command_buffer_allocate_info = vk::CommandBufferAllocateInfo::builder()
-            .command_pool(graphics_command_pool)
-            .command_buffer_count(1)
-            .level(vk::CommandBufferLevel::PRIMARY);
-        let main_graphics_command_buffer =
-            unsafe { device.allocate_command_buffers(&command_buffer_allocate_info) }?[0];
+        let target = match surface {
+            Some((surface, surface_fn)) => {
+                let swapchain = Swapchain::new(
+                    &instance,
+                    &device,
+                    surface,
+                    &surface_fn,
+                    physical_device,
+                    &queue_family_indices,
+                    SwapchainDesc {
+                        window_extent: target_extent,
+                        surface_format: &config.surface_format,
+                        present_policy: config.present_policy,
+                    },
+                )?;
+                RenderTarget::Window {
+                    surface_fn,
+                    images_in_flight: vec![vk::Fence::null(); swapchain.images.len()],
+                    swapchain,
+                }
+            }
+            None => RenderTarget::Offscreen(OffscreenTarget::new(
+                &device,
+                &mut resources,
+                OFFSCREEN_COLOR_FORMAT,
+                target_extent,
+            )?),
+        };
 
-        let semaphore_create_info = vk::SemaphoreCreateInfo::builder();
-        let present_semaphore = unsafe { device.create_semaphore(&semaphore_create_info, None) }?;
-        let render_semaphore = unsafe { device.create_semaphore(&semaphore_create_info, None) }?;
+        let uploader = Uploader::new(
+            &device,
+            &mut resources,
+            &queue_family_indices,
+            transfer_queue,
+            &limits,
+            config.staging_buffer_size,
+        )?;
 
-        let fence_create_info =
-            vk::FenceCreateInfo::builder().flags(vk::FenceCreateFlags::SIGNALED);
-        let render_fence = unsafe { device.create_fence(&fence_create_info, None) }?;
+        let frames = (0..config.frames_in_flight.max(1))
+            .map(|_| {
+                FrameData::new(
+                    &device,
+                    queue_family_indices.graphics,
+                    &mut resources,
+                    config.frame_upload_buffer_size,
+                )
+            })
+            .collect::<RendererResult<Vec<_>>>()?;
 
-        let vert_shader_create_info = vk::ShaderModuleCreateInfo::builder().code(TRIANGLE_VERT);
-        let triangle_vert_shader =
-            unsafe { device.create_shader_module(&vert_shader_create_info, None) }?;
+        let depth_format = find_depth_format(&instance, physical_device)?;
+        let depth_buffer =
+            DepthBuffer::new(&device, &mut resources, depth_format, target.extent())?;
 
-        let frag_shader_create_info = vk::ShaderModuleCreateInfo::builder().code(TRIANGLE_FRAG);
-        let triangle_frag_shader =
-            unsafe { device.create_shader_module(&frag_shader_create_info, None) }?;
+        let triangle_pipeline = create_triangle_pipeline(
+            &device,
+            &mut rendering,
+            target.format(),
+            target.output_encoding(),
+            depth_format,
+        )?;
+        let frame_set_layout = create_frame_set_layout(&device)?;
+        let material_set_layout = create_material_set_layout(&device)?;
+        let mesh_pipeline = create_mesh_pipeline(
+            &device,
+            &mut rendering,
+            target.format(),
+            target.output_encoding(),
+            depth_format,
+            &[frame_set_layout, material_set_layout],
+        )?;
 
-        unsafe {
-            device.destroy_shader_module(triangle_vert_shader, None);
-            device.destroy_shader_module(triangle_frag_shader, None);
-        }
+        let mipmaps = MipmapGenerator::new(&device)?;
+        let samplers = SamplerCache::new(
+            features
+                .sampler_anisotropy
+                .then_some(limits.max_sampler_anisotropy),
+        );
 
-        Ok(Renderer {
-            entry,
+        let mut renderer = Renderer {
+            config,
+            validation_enabled: instance_support.validation,
+            debug_messenger,
+            reported_validation_errors: 0,
+            _entry: entry,
             instance,
-            surface,
-            surface_fn,
             physical_device,
+            resources,
             device,
-            dynamic_rendering_loader,
+            features,
+            rendering,
             queue_family_indices,
             graphics_queue,
-            transfer_queue,
-            swapchain_loader,
-            swapchain,
-            swapchain_images,
-            swapchain_image_views,
-            graphics_command_pool,
-            main_graphics_command_buffer,
-            present_semaphore,
-            render_semaphore,
-            render_fence,
+            present_queue,
+            compute_queue,
+            uploader,
+            target: Some(target),
+            target_needs_recreation: false,
+            target_extent,
+            depth_format,
+            depth_buffer: Some(depth_buffer),
+            frames,
+            triangle_pipeline,
+            frame_set_layout,
+            material_set_layout,
+            mesh_pipeline,
+            uniform_alignment: limits.min_uniform_buffer_offset_alignment,
+            white_texture: None,
+            view_projection: Mat4::IDENTITY,
+            mesh_draws: Vec::new(),
+            mipmaps,
+            samplers,
+            readback_buffer: None,
+            capture_requested: false,
+            pending_capture: None,
             frame_number: 0u64,
+        };
+        renderer.white_texture = Some(renderer.create_texture(
+            "white",
+            vk::Extent2D {
+                width: 1,
+                height: 1,
+            },
+            vk::Format::R8G8B8A8_UNORM,
+            &[&[255; 4]],
+        )?);
+        renderer.name_objects();
+        // In strict mode, a renderer created with validation errors is not returned
+        renderer.check_validation_errors()?;
+        Ok(renderer)
+    }
+
+    /// Notifies the renderer that the window has been resized. The swapchain and all
+    /// size-dependent resources are rebuilt lazily on the next call to `render`.
+    ///
+    /// In headless mode this resizes the offscreen target.
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.target_extent = vk::Extent2D { width, height };
+        self.target_needs_recreation = true;
+    }
+
+    fn target(&self) -> &RenderTarget {
+        self.target.as_ref().unwrap()
+    }
+
+    /// The device features that are enabled
+    pub fn features(&self) -> &DeviceFeatures {
+        &self.features
+    }
+
+    /// The queue families the renderer uses
+    pub fn queue_family_indices(&self) -> &QueueFamilyIndices {
+        &self.queue_family_indices
+    }
+
+    /// A queue of `queue_family_indices().compute` for compute work that may overlap rendering.
+    /// It is the graphics queue if the device has no async compute family.
+    pub fn compute_queue(&self) -> vk::Queue {
+        self.compute_queue
+    }
+
+    /// Whether the validation layer is active. It may be requested but not installed.
+    pub fn validation_enabled(&self) -> bool {
+        self.validation_enabled
+    }
+
+    /// The number of errors reported by the validation layer so far
+    pub fn validation_error_count(&self) -> usize {
+        self.debug_messenger
+            .as_ref()
+            .map_or(0, |messenger| messenger.counters().errors())
+    }
+
+    /// GPU memory usage of all resources
+    pub fn memory_stats(&self) -> AllocatorStats {
+        self.resources.memory_stats()
+    }
+
+    /// Creates a buffer that is destroyed once it is dropped and no frame in flight uses it
+    /// anymore. Like all resources, it must be dropped before the renderer.
+    pub fn create_buffer(&mut self, desc: &BufferDesc) -> RendererResult<Buffer> {
+        let buffer = self.resources.create_buffer(&self.device, desc)?;
+        self.set_object_name(buffer.handle(), buffer.name());
+        Ok(buffer)
+    }
+
+    pub fn create_image(&mut self, desc: &ImageDesc) -> RendererResult<Image> {
+        let image = self.resources.create_image(&self.device, desc)?;
+        self.set_object_name(image.handle(), image.name());
+        Ok(image)
+    }
+
+    pub fn create_image_view(
+        &mut self,
+        image: &Image,
+        subresource_range: vk::ImageSubresourceRange,
+        name: &str,
+    ) -> RendererResult<ImageView> {
+        let image_view =
+            self.resources
+                .create_image_view(&self.device, image, subresource_range, name)?;
+        self.set_object_name(image_view.handle(), name);
+        Ok(image_view)
+    }
+
+    pub fn create_sampler(
+        &mut self,
+        create_info: &vk::SamplerCreateInfo,
+        name: &str,
+    ) -> RendererResult<Sampler> {
+        let sampler = self
+            .resources
+            .create_sampler(&self.device, create_info, name)?;
+        self.set_object_name(sampler.handle(), name);
+        Ok(sampler)
+    }
+
+    /// Copies `data` into `buffer` at `offset` through the staging ring. The buffer needs
+    /// `TRANSFER_DST` usage and may not be used by a frame in flight. The upload is visible to
+    /// the next rendered frame.
+    pub fn upload_buffer(
+        &mut self,
+        buffer: &Buffer,
+        offset: vk::DeviceSize,
+        data: &[u8],
+    ) -> RendererResult<()> {
+        self.uploader
+            .upload_buffer(&self.device, &mut self.resources, buffer, offset, data)
+    }
+
+    /// Uploads tightly packed mip levels, starting at level 0, into `image` and transitions it to
+    /// `final_layout`. The image needs `TRANSFER_DST` usage and may not be used by a frame in
+    /// flight. The upload is visible to the next rendered frame.
+    pub fn upload_image(
+        &mut self,
+        image: &Image,
+        levels: &[&[u8]],
+        final_layout: vk::ImageLayout,
+    ) -> RendererResult<()> {
+        self.uploader.upload_image(
+            &self.device,
+            &mut self.resources,
+            image,
+            levels,
+            final_layout,
+        )
+    }
+
+    /// Creates a mesh and uploads its vertices and indices, which must not be empty. It can be
+    /// drawn from the next frame on.
+    pub fn create_mesh(&mut self, data: &MeshData, name: &str) -> RendererResult<Mesh> {
+        let (vertices, indices) = (&data.vertices, &data.indices);
+        if vertices.is_empty() || indices.is_empty() {
+            return Err(RendererError::Unsupported(format!(
+                "The mesh {} has no triangles",
+                name
+            )));
+        }
+        let vertex_data: &[u8] = bytemuck::cast_slice(vertices);
+        let vertex_buffer = self.create_buffer(&BufferDesc {
+            name: &format!("{} vertices", name),
+            size: vertex_data.len() as vk::DeviceSize,
+            usage: vk::BufferUsageFlags::VERTEX_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+            location: MemoryLocation::GpuOnly,
+            strategy: AllocationStrategy::Buddy,
+        })?;
+        self.upload_buffer(&vertex_buffer, 0, vertex_data)?;
+
+        let index_data: &[u8] = bytemuck::cast_slice(indices);
+        let index_buffer = self.create_buffer(&BufferDesc {
+            name: &format!("{} indices", name),
+            size: index_data.len() as vk::DeviceSize,
+            usage: vk::BufferUsageFlags::INDEX_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+            location: MemoryLocation::GpuOnly,
+            strategy: AllocationStrategy::Buddy,
+        })?;
+        self.upload_buffer(&index_buffer, 0, index_data)?;
+
+        Ok(Mesh::new(vertex_buffer, index_buffer, data, name))
+    }
+
+    /// Creates a 2D texture and uploads its mip levels, starting with the largest one. Each level
+    /// must be tightly packed in `format`, one of the formats of `BlockLayout::of`. It can be
+    /// sampled from the next frame on.
+    pub fn create_texture(
+        &mut self,
+        name: &str,
+        extent: vk::Extent2D,
+        format: vk::Format,
+        levels: &[&[u8]],
+    ) -> RendererResult<Texture> {
+        check_levels(name, format, extent, levels)?;
+        let image = self.create_image(&ImageDesc {
+            name,
+            extent,
+            format,
+            mip_levels: levels.len() as u32,
+            usage: vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST,
+            flags: vk::ImageCreateFlags::empty(),
+            dedicated: false,
+        })?;
+        self.upload_image(&image, levels, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)?;
+        self.create_texture_view(image)
+    }
+
+    /// Creates a texture from `data` in the format for `usage`. Its mip chain is generated on
+    /// the GPU at the start of the next frame, by blitting or, for formats that can't be
+    /// blitted with linear filtering, by a compute shader.
+    pub fn create_texture_with_mipmaps(
+        &mut self,
+        name: &str,
+        data: &TextureData,
+        usage: TextureUsage,
+    ) -> RendererResult<Texture> {
+        let extent = data.extent;
+        if data.pixels.len() as u64 != u64::from(extent.width) * u64::from(extent.height) * 4 {
+            return Err(RendererError::Image(format!(
+                "{} has {} bytes of pixels for a size of {}x{}",
+                name,
+                data.pixels.len(),
+                extent.width,
+                extent.height
+            )));
+        }
+        let format = usage.rgba8_format();
+        let mip_levels = mip_level_count(extent);
+        let method = match MipmapMethod::select(&self.instance, self.physical_device, format) {
+            Some(method) if mip_levels > 1 => method,
+            Some(_) => return self.create_texture(name, extent, format, &[&data.pixels]),
+            None => {
+                log::warn!(
+                    "Can't generate mipmaps for {:?}, {} has one level",
+                    format,
+                    name
+                );
+                return self.create_texture(name, extent, format, &[&data.pixels]);
+            }
+        };
+        let (usage, flags) = method.image_usage(format);
+        let image = self.create_image(&ImageDesc {
+            name,
+            extent,
+            format,
+            mip_levels,
+            usage: vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST | usage,
+            flags,
+            dedicated: false,
+        })?;
+        self.upload_image(&image, &[&data.pixels], method.initial_layout())?;
+        let texture = self.create_texture_view(image)?;
+        self.mipmaps.queue(texture.clone(), method);
+        Ok(texture)
+    }
+
+    /// Creates a texture from `data` in its own format if the device supports it. Otherwise
+    /// its levels are decompressed on the CPU into 8-bit RGBA, see `decompress`. Single levels
+    /// in 8-bit RGBA get mipmaps, see `create_texture_with_mipmaps`.
+    pub fn create_compressed_texture(
+        &mut self,
+        name: &str,
+        data: &CompressedTextureData,
+    ) -> RendererResult<Texture> {
+        let extent = data.extent;
+        check_levels(name, data.format, extent, &data.levels)?;
+        let decompressed;
+        let (format, levels) =
+            if supports_texture_format(&self.instance, self.physical_device, data.format) {
+                (data.format, &data.levels)
+            } else {
+                let format = decompressed_format(data.format).ok_or_else(|| {
+                    RendererError::Unsupported(format!(
+                        "{:?} of {} is neither supported by the device nor decompressible",
+                        data.format, name
+                    ))
+                })?;
+                log::info!(
+                    "{:?} isn't supported by the device, decompressing {}",
+                    data.format,
+                    name
+                );
+                decompressed = data
+                    .levels
+                    .iter()
+                    .enumerate()
+                    .map(|(level, pixels)| {
+                        decompress(data.format, mip_level_extent(extent, level as u32), pixels)
+                    })
+                    .collect::<Vec<_>>();
+                (format, &decompressed)
+            };
+
+        let usage = match format {
+            vk::Format::R8G8B8A8_SRGB => Some(TextureUsage::Color),
+            vk::Format::R8G8B8A8_UNORM => Some(TextureUsage::Data),
+            _ => None,
+        };
+        match (usage, levels.as_slice()) {
+            (Some(usage), [pixels]) => {
+                let data = TextureData {
+                    extent,
+                    pixels: pixels.clone(),
+                };
+                self.create_texture_with_mipmaps(name, &data, usage)
+            }
+            _ => {
+                let levels: Vec<&[u8]> = levels.iter().map(Vec::as_slice).collect();
+                self.create_texture(name, extent, format, &levels)
+            }
+        }
+    }
+
+    /// Loads a texture from a KTX2 file, see `create_compressed_texture`, or decodes a PNG, JPEG
+    /// or TGA file into a texture with mipmaps, see `create_texture_with_mipmaps`. KTX2 textures
+    /// keep their format regardless of `usage`.
+    pub fn load_texture(&mut self, path: &Path, usage: TextureUsage) -> RendererResult<Texture> {
+        let name = path.to_string_lossy();
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+        let texture = if extension.as_deref() == Some("ktx2") {
+            let data = CompressedTextureData::load(path, |format| {
+                supports_texture_format(&self.instance, self.physical_device, format)
+            })?;
+            self.create_compressed_texture(&name, &data)?
+        } else {
+            let data = TextureData::load(path)?;
+            self.create_texture_with_mipmaps(&name, &data, usage)?
+        };
+        log::debug!("Loaded {:?}", texture);
+        Ok(texture)
+    }
+
+    /// A sampler for `desc`, shared with all other users of the same settings. It lives as long
+    /// as the renderer.
+    pub fn sampler(&mut self, desc: &SamplerDesc) -> RendererResult<vk::Sampler> {
+        self.samplers.get(&self.device, &mut self.resources, desc)
+    }
+
+    /// Wraps `image` in a texture with a view that can only be sampled
+    fn create_texture_view(&mut self, image: Image) -> RendererResult<Texture> {
+        let image_view = self.resources.create_image_view_with_format(
+            &self.device,
+            &image,
+            image.format(),
+            vk::ImageUsageFlags::SAMPLED,
+            image.subresource_range(),
+            image.name(),
+        )?;
+        self.set_object_name(image_view.handle(), image_view.name());
+        Ok(Texture::new(image, image_view))
+    }
+
+    /// Sets the transform from world to clip space that meshes are drawn with. Clip space is
+    /// Vulkan's, with y pointing down and depth ranging from 0 to 1.
+    pub fn set_view_projection(&mut self, view_projection: Mat4) {
+        self.view_projection = view_projection;
+    }
+
+    /// Queues `mesh` to be drawn in the next frame with `model` as its transform to world space.
+    /// Its submeshes refer to `materials`, missing ones are replaced by the default material.
+    /// Only the base color and its texture are rendered so far.
+    ///
+    /// Draws have to be queued again for every frame. Frames without any show the test triangle.
+    pub fn draw_mesh(&mut self, mesh: &Mesh, materials: &[Material], model: Mat4) {
+        for submesh in mesh.submeshes() {
+            let material = submesh
+                .material
+                .and_then(|material| materials.get(material));
+            let base_color = material.map_or(Vec4::ONE, |material| material.base_color);
+            let (base_color_texture, sampler) =
+                match material.and_then(|material| material.base_color_texture.as_ref()) {
+                    Some(binding) => (binding.texture.clone(), binding.sampler),
+                    None => (self.white_texture.clone().unwrap(), SamplerDesc::default()),
+                };
+            self.mesh_draws.push(MeshDraw {
+                mesh: mesh.clone(),
+                submesh: *submesh,
+                base_color,
+                base_color_texture,
+                sampler,
+                model,
+            });
+        }
+    }
+
+    fn set_object_name<H: vk::Handle>(&self, handle: H, name: &str) {
+        if let Some(debug_messenger) = &self.debug_messenger {
+            debug_messenger.set_object_name(&self.device, handle, name);
+        }
+    }
+
+    /// Names the renderer's objects so that validation messages can refer to them.
+    fn name_objects(&self) {
+        if self.debug_messenger.is_none() {
+            return;
+        }
+        for (index, frame) in self.frames.iter().enumerate() {
+            self.set_object_name(frame.command_buffer, &format!("frame {} commands", index));
+            self.set_object_name(frame.render_fence, &format!("frame {} render fence", index));
+            self.set_object_name(
+                frame.upload_buffer.buffer.handle(),
+                &format!("frame {} upload buffer", index),
+            );
+        }
+        match self.target.as_ref().unwrap() {
+            RenderTarget::Window { swapchain, .. } => {
+                for (index, image) in swapchain.images.iter().enumerate() {
+                    self.set_object_name(*image, &format!("swapchain image {}", index));
+                }
+            }
+            RenderTarget::Offscreen(offscreen) => {
+                self.set_object_name(offscreen.image.handle(), offscreen.image.name());
+            }
+        }
+        if let Some(depth_buffer) = &self.depth_buffer {
+            self.set_object_name(depth_buffer.image.handle(), depth_buffer.image.name());
+        }
+        self.set_object_name(self.triangle_pipeline.pipeline, "triangle pipeline");
+        self.set_object_name(self.mesh_pipeline.pipeline, "mesh pipeline");
+        self.set_object_name(self.mipmaps.pipeline().pipeline, "mipmap pipeline");
+    }
+
+    /// In strict mode, turns validation errors reported since the last check into an error.
+    fn check_validation_errors(&mut self) -> RendererResult<()> {
+        if !self.config.validation.strict {
+            return Ok(());
+        }
+        let error_count = self.validation_error_count();
+        let new_errors = error_count - self.reported_validation_errors;
+        self.reported_validation_errors = error_count;
+        if new_errors > 0 {
+            return Err(RendererError::ValidationErrors(new_errors));
+        }
+        Ok(())
+    }
+
+    pub fn present_policy(&self) -> PresentPolicy {
+        self.config.present_policy
+    }
+
+    /// Switches to another present policy. Takes effect on the next call to `render`.
+    pub fn set_present_policy(&mut self, present_policy: PresentPolicy) {
+        if self.config.present_policy != present_policy {
+            self.config.present_policy = present_policy;
+            self.target_needs_recreation = true;
+        }
+    }
+
+    /// Makes the next call to `render` copy its color target into a host-visible buffer. The
+    /// pixels can then be retrieved with `take_capture`.
+    pub fn request_capture(&mut self) -> RendererResult<()> {
+        if !self.target().supports_capture() {
+            return Err(RendererError::Unsupported(
+                "The swapchain images of this surface can't be copied from".to_string(),
+            ));
+        }
+        if bytes_per_pixel(self.target().format()).is_none() {
+            return Err(RendererError::Unsupported(format!(
+                "Can't take captures of {:?} images",
+                self.target().format()
+            )));
+        }
+        self.capture_requested = true;
+        Ok(())
+    }
+
+    /// Waits for the frame requested with `request_capture` and returns its pixels as RGBA8.
+    /// Returns `None` if no capture has been recorded yet, e.g. because the frame was skipped.
+    pub fn take_capture(&mut self) -> RendererResult<Option<Screenshot>> {
+        let pending_capture = match self.pending_capture.take() {
+            Some(pending_capture) => pending_capture,
+            None => return Ok(None),
+        };
+        let readback_buffer = self
+            .readback_buffer
+            .as_ref()
+            .expect("A pending capture always has a readback buffer");
+
+        let data = unsafe {
+            self.device
+                .wait_for_fences(&[pending_capture.render_fence], true, u64::MAX)?;
+            readback_buffer.read()
+        };
+        let extent = pending_capture.extent;
+        let byte_count = (extent.width as vk::DeviceSize
+            * extent.height as vk::DeviceSize
+            * bytes_per_pixel(pending_capture.format).expect("Captures are of supported formats"))
+            as usize;
+        Ok(Some(Screenshot {
+            width: extent.width,
+            height: extent.height,
+            pixels: convert_to_rgba8(pending_capture.format, &data[..byte_count])?,
+        }))
+    }
+
+    /// Renders a frame and returns its pixels.
+    pub fn capture_frame(&mut self) -> RendererResult<Screenshot> {
+        self.request_capture()?;
+        self.render()?;
+        self.take_capture()?.ok_or_else(|| {
+            RendererError::Unsupported(
+                "No frame was rendered, the target may be zero-sized".to_string(),
+            )
         })
     }
 
-    pub fn render(&mut self) -> DynResult<()> {
-        const ONE_SECOND_IN_NANO_SECONDS: u64 = 1_000_000_000;
-        let render_fence_array = [self.render_fence];
+    /// Records a copy of `image`, which must be in the `COLOR_ATTACHMENT_OPTIMAL` layout, into
+    /// the readback buffer. Leaves the image in `TRANSFER_SRC_OPTIMAL`.
+    fn record_capture(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        image: vk::Image,
+        render_fence: vk::Fence,
+    ) -> RendererResult<()> {
+        let format = self.target().format();
+        let extent = self.target().extent();
+        let bytes_per_pixel = bytes_per_pixel(format).ok_or_else(|| {
+            RendererError::Unsupported(format!("Can't take captures of {:?} images", format))
+        })?;
+        let size =
+            extent.width as vk::DeviceSize * extent.height as vk::DeviceSize * bytes_per_pixel;
+
+        let has_large_enough_buffer = matches!(
+            &self.readback_buffer,
+            Some(readback_buffer) if readback_buffer.size >= size
+        );
+        if !has_large_enough_buffer {
+            // The old buffer is destroyed once the frames that used it have completed
+            self.readback_buffer = Some(ReadbackBuffer::new(
+                &self.device,
+                &mut self.resources,
+                size,
+            )?);
+        }
+        let readback_buffer = self.readback_buffer.as_ref().unwrap().buffer.handle();
+
+        let color_subresource_range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        let image_memory_barrier = vk::ImageMemoryBarrier::builder()
+            .image(image)
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .old_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+            .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .subresource_range(color_subresource_range)
+            .build();
+        let region = vk::BufferImageCopy::builder()
+            .image_subresource(vk::ImageSubresourceLayers {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: 0,
+                base_array_layer: 0,
+                layer_count: 1,
+            })
+            .image_extent(vk::Extent3D {
+                width: extent.width,
+                height: extent.height,
+                depth: 1,
+            })
+            .build();
+        let buffer_memory_barrier = vk::BufferMemoryBarrier::builder()
+            .buffer(readback_buffer)
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::HOST_READ)
+            .offset(0)
+            .size(vk::WHOLE_SIZE)
+            .build();
+
         unsafe {
-            self.device
-                .wait_for_fences(&render_fence_array, true, ONE_SECOND_IN_NANO_SECONDS)?;
-            self.device.reset_fences(&render_fence_array)?;
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[image_memory_barrier],
+            );
+            self.device.cmd_copy_image_to_buffer(
+                command_buffer,
+                image,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                readback_buffer,
+                &[region],
+            );
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::HOST,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[buffer_memory_barrier],
+                &[],
+            );
+        }
+
+        self.pending_capture = Some(PendingCapture {
+            render_fence,
+            format,
+            extent,
+        });
+        Ok(())
+    }
+
+    fn recreate_render_target(&mut self) -> RendererResult<()> {
+        unsafe {
+            self.device.device_wait_idle()?;
+            self.resources
+                .collect_garbage(&self.device, self.frame_number);
+            self.uploader.collect(&self.device, self.frame_number)?;
+            // They refer to the image views that are about to be destroyed
+            self.rendering.destroy_framebuffers(&self.device);
+        }
+        let old_format = self.target().format();
+        let old_output_encoding = self.target().output_encoding();
+
+        match self.target.as_mut().unwrap() {
+            RenderTarget::Window {
+                surface_fn,
+                swapchain,
+                images_in_flight,
+            } => {
+                let recreate_result = swapchain.recreate(
+                    &self.device,
+                    surface_fn,
+                    self.physical_device,
+                    &self.queue_family_indices,
+                    SwapchainDesc {
+                        window_extent: self.target_extent,
+                        surface_format: &self.config.surface_format,
+                        present_policy: self.config.present_policy,
+                    },
+                );
+                let recreated = match recreate_result {
+                    Ok(recreated) => recreated,
+                    // The surface changed again in the meantime, retry on the next frame
+                    Err(RendererError::SwapchainOutOfDate) => false,
+                    Err(err) => return Err(err),
+                };
+                self.target_needs_recreation = !recreated;
+                *images_in_flight = vec![vk::Fence::null(); swapchain.images.len()];
+            }
+            RenderTarget::Offscreen(offscreen) => {
+                *offscreen = OffscreenTarget::new(
+                    &self.device,
+                    &mut self.resources,
+                    offscreen.format(),
+                    self.target_extent,
+                )?;
+                self.target_needs_recreation = false;
+            }
+        }
+
+        let extent = self.target().extent();
+        let format = self.target().format();
+        let output_encoding = self.target().output_encoding();
+        if extent != self.depth_buffer.as_ref().unwrap().image.extent() {
+            self.depth_buffer = Some(DepthBuffer::new(
+                &self.device,
+                &mut self.resources,
+                self.depth_format,
+                extent,
+            )?);
         }
 
-        let (swapchain_image_index, _) = unsafe {
-            self.swapchain_loader.acquire_next_image(
-                self.swapchain,
-                ONE_SECOND_IN_NANO_SECONDS,
-                self.present_semaphore,
+        // Pipelines bake in the color attachment format and the output encoding
+        if format != old_format || output_encoding != old_output_encoding {
+            let triangle_pipeline = create_triangle_pipeline(
+                &self.device,
+                &mut self.rendering,
+                format,
+                output_encoding,
+                self.depth_format,
+            )?;
+            unsafe { self.triangle_pipeline.destroy(&self.device) };
+            self.triangle_pipeline = triangle_pipeline;
+            let mesh_pipeline = create_mesh_pipeline(
+                &self.device,
+                &mut self.rendering,
+                format,
+                output_encoding,
+                self.depth_format,
+                &[self.frame_set_layout, self.material_set_layout],
+            )?;
+            unsafe { self.mesh_pipeline.destroy(&self.device) };
+            self.mesh_pipeline = mesh_pipeline;
+        }
+        self.name_objects();
+        Ok(())
+    }
+
+    /// Gets the image to render the current frame into. Returns `None` if the frame should be
+    /// skipped because the swapchain had to be rebuilt.
+    fn acquire_target_image(
+        &mut self,
+        present_semaphore: vk::Semaphore,
+        render_fence: vk::Fence,
+    ) -> RendererResult<Option<TargetImage>> {
+        let swapchain = match self.target.as_ref().unwrap() {
+            RenderTarget::Window { swapchain, .. } => swapchain,
+            RenderTarget::Offscreen(offscreen) => {
+                return Ok(Some(TargetImage {
+                    swapchain_index: None,
+                    image: offscreen.image.handle(),
+                    image_view: offscreen.image_view.handle(),
+                }))
+            }
+        };
+
+        let acquire_result = unsafe {
+            swapchain.loader.acquire_next_image(
+                swapchain.handle,
+                u64::MAX,
+                present_semaphore,
                 vk::Fence::null(),
-            )?
+            )
+        };
+        let swapchain_image_index = match acquire_result {
+            Ok((index, suboptimal)) => {
+                // Still render this frame, but rebuild the swapchain before the next one
+                self.target_needs_recreation |= suboptimal;
+                index
+            }
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
+                self.recreate_render_target()?;
+                return Ok(None);
+            }
+            Err(err) => return Err(err.into()),
         };
+        let target_image = TargetImage {
+            swapchain_index: Some(swapchain_image_index),
+            image: swapchain.images[swapchain_image_index as usize],
+            image_view: swapchain.image_views[swapchain_image_index as usize],
+        };
+
+        if let RenderTarget::Window {
+            images_in_flight, ..
+        } = self.target.as_mut().unwrap()
+        {
+            // With fewer swapchain images than frames in flight, the image may still be in use
+            // by another frame
+            let image_fence = images_in_flight[swapchain_image_index as usize];
+            if image_fence != vk::Fence::null() && image_fence != render_fence {
+                unsafe {
+                    self.device
+                        .wait_for_fences(&[image_fence], true, u64::MAX)?;
+                }
+            }
+            images_in_flight[swapchain_image_index as usize] = render_fence;
+        }
 
+        Ok(Some(target_image))
+    }
+
+    /// Renders and presents a frame. Out-of-date swapchains are recreated internally.
+    ///
+    /// After `RendererError::SurfaceLost` or `RendererError::DeviceLost`, the renderer can't
+    /// render anymore and has to be recreated. In strict validation mode, returns
+    /// `RendererError::ValidationErrors` if the validation layer reported errors in the meantime.
+    pub fn render(&mut self) -> RendererResult<()> {
+        let result = self.render_frame();
+        let validation = self.check_validation_errors();
+        frame_result(result, validation)
+    }
+
+    fn render_frame(&mut self) -> RendererResult<()> {
+        // Draws are only queued for one frame, even if it is skipped
+        let mesh_draws = std::mem::take(&mut self.mesh_draws);
+
+        // Nothing to render into while the window is minimized
+        if self.target_extent.width == 0 || self.target_extent.height == 0 {
+            return Ok(());
+        }
+        if self.target_needs_recreation {
+            self.recreate_render_target()?;
+            if self.target_needs_recreation {
+                return Ok(());
+            }
+        }
+
+        let frame_index = (self.frame_number % self.frames.len() as u64) as usize;
+        let frame = &mut self.frames[frame_index];
+        let command_buffer = frame.command_buffer;
+        let present_semaphore = frame.present_semaphore;
+        let render_semaphore = frame.render_semaphore;
+        let render_fence = frame.render_fence;
+
+        // Wait until the GPU is done with the last frame that used this slot, however long that
+        // takes on slow devices such as software rasterizers
+        let render_fence_array = [render_fence];
         unsafe {
-            self.device.reset_command_buffer(
-                self.main_graphics_command_buffer,
-                vk::CommandBufferResetFlags::empty(),
-            )?
+            self.device
+                .wait_for_fences(&render_fence_array, true, u64::MAX)?;
         }
+        frame.reset(&self.device)?;
+
+        // The GPU is done with the frame that last used this slot and all frames before it
+        if let Some(completed_frame) = self.frame_number.checked_sub(self.frames.len() as u64) {
+            unsafe {
+                self.resources
+                    .collect_garbage(&self.device, completed_frame);
+                self.uploader.collect(&self.device, completed_frame)?;
+            }
+        }
+
+        let target_image = match self.acquire_target_image(present_semaphore, render_fence)? {
+            Some(target_image) => target_image,
+            None => return Ok(()),
+        };
+        let is_presented = target_image.swapchain_index.is_some();
+
+        // Only reset the fence once we know that we are going to submit work that signals it
+        unsafe { self.device.reset_fences(&render_fence_array)? };
 
         let command_buffer_begin_info =
             vk::CommandBufferBeginInfo::builder().flags(CommandBufferUsageFlags::ONE_TIME_SUBMIT);
         unsafe {
-            self.device.begin_command_buffer(
-                self.main_graphics_command_buffer,
-                &command_buffer_begin_info,
-            )
+            self.device
+                .begin_command_buffer(command_buffer, &command_buffer_begin_info)
         }?;
 
+        // Uploads made since the last frame become visible to this one
+        self.uploader.submit(&self.device)?;
+        unsafe { self.uploader.record_acquires(&self.device, command_buffer) };
+        // Before anything samples the new textures
+        unsafe {
+            self.mipmaps.record(
+                &self.device,
+                &mut self.resources,
+                command_buffer,
+                &mut self.frames[frame_index].descriptors,
+            )?
+        };
+
+        // Descriptor sets are freed when the frame is reset
+        let frame_set = if mesh_draws.is_empty() {
+            vk::DescriptorSet::null()
+        } else {
+            let uniforms = FrameUniforms {
+                view_projection: self.view_projection,
+            };
+            let frame = &mut self.frames[frame_index];
+            let offset = frame
+                .upload_buffer
+                .push(bytemuck::bytes_of(&uniforms), self.uniform_alignment)
+                .ok_or_else(|| {
+                    RendererError::Unsupported(
+                        "The frame upload buffer is too small for the frame uniforms".to_string(),
+                    )
+                })?;
+            unsafe {
+                allocate_frame_set(
+                    &self.device,
+                    &mut frame.descriptors,
+                    self.frame_set_layout,
+                    frame.upload_buffer.buffer.handle(),
+                    offset,
+                )?
+            }
+        };
+        // One for each distinct texture and sampler
+        let mut material_sets = HashMap::new();
+        let mut draw_sets = Vec::with_capacity(mesh_draws.len());
+        for draw in &mesh_draws {
+            let sampler = self
+                .samplers
+                .get(&self.device, &mut self.resources, &draw.sampler)?;
+            let image_view = draw.base_color_texture.image_view().handle();
+            let descriptor_set = match material_sets.entry((image_view, sampler)) {
+                HashMapEntry::Occupied(entry) => *entry.get(),
+                HashMapEntry::Vacant(entry) => *entry.insert(unsafe {
+                    allocate_material_set(
+                        &self.device,
+                        &mut self.frames[frame_index].descriptors,
+                        self.material_set_layout,
+                        image_view,
+                        sampler,
+                    )?
+                }),
+            };
+            draw_sets.push(descriptor_set);
+        }
+
         let color_subresource_range = vk::ImageSubresourceRange {
             aspect_mask: vk::ImageAspectFlags::COLOR,
             base_mip_level: 0,
@@ -370,18 +1643,21 @@ impl Renderer {
             layer_count: 1,
         };
         let image_memory_barrier = vk::ImageMemoryBarrier::builder()
-            .image(self.swapchain_images[swapchain_image_index as usize])
-            .src_access_mask(vk::AccessFlags::empty())
+            .image(target_image.image)
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
             .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
             .old_layout(vk::ImageLayout::UNDEFINED)
             .new_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
             .subresource_range(color_subresource_range)
             .build();
 
+        // The offscreen target is shared by all frames in flight, so wait for earlier frames to
+        // finish writing and copying it. For swapchain images this chains with the wait on
+        // `present_semaphore`.
         unsafe {
             self.device.cmd_pipeline_barrier(
-                self.main_graphics_command_buffer,
-                vk::PipelineStageFlags::TOP_OF_PIPE,
+                command_buffer,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::TRANSFER,
                 vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
                 vk::DependencyFlags::empty(),
                 &[],
@@ -390,6 +1666,32 @@ impl Renderer {
             )
         }
 
+        // Like the offscreen target, the depth buffer is shared by all frames in flight
+        let depth_buffer = self.depth_buffer.as_ref().unwrap();
+        let depth_memory_barrier = vk::ImageMemoryBarrier::builder()
+            .image(depth_buffer.image.handle())
+            .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+            .dst_access_mask(
+                vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                    | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            )
+            .old_layout(vk::ImageLayout::UNDEFINED)
+            .new_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+            .subresource_range(depth_buffer.image.subresource_range())
+            .build();
+        unsafe {
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS
+                    | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[depth_memory_barrier],
+            )
+        }
+
         let flash = f32::abs(f32::sin(self.frame_number as f32 / 50f32));
         let clear_values = vk::ClearValue {
             color: vk::ClearColorValue {
@@ -397,49 +1699,159 @@ impl Renderer {
             },
         };
 
-        let color_attachments = [vk::RenderingAttachmentInfoKHR::builder()
-            .clear_value(clear_values)
-            .image_view(self.swapchain_image_views[swapchain_image_index as usize])
-            .image_layout(vk::ImageLayout::ATTACHMENT_OPTIMAL_KHR)
-            .load_op(vk::AttachmentLoadOp::CLEAR)
-            .store_op(vk::AttachmentStoreOp::STORE)
-            .build()];
-        let render_info = vk::RenderingInfoKHR::builder()
-            // flags
-            .render_area(vk::Rect2D {
-                extent: vk::Extent2D {
-                    width: 800,
-                    height: 600,
-                }, // TODO: window extend
-                offset: Offset2D { x: 0, y: 0 },
-            })
-            .layer_count(1)
-            .color_attachments(&color_attachments);
+        let color_attachments = [Attachment {
+            image_view: target_image.image_view,
+            format: self.target().format(),
+            load_op: vk::AttachmentLoadOp::CLEAR,
+            store_op: vk::AttachmentStoreOp::STORE,
+            clear_value: clear_values,
+        }];
+        let extent = self.target().extent();
+        let render_area = vk::Rect2D {
+            extent,
+            offset: Offset2D { x: 0, y: 0 },
+        };
+        let depth_attachment = Attachment {
+            image_view: depth_buffer.image_view.handle(),
+            format: self.depth_format,
+            load_op: vk::AttachmentLoadOp::CLEAR,
+            store_op: vk::AttachmentStoreOp::DONT_CARE,
+            clear_value: vk::ClearValue {
+                depth_stencil: vk::ClearDepthStencilValue {
+                    depth: 1.0,
+                    stencil: 0,
+                },
+            },
+        };
+        let render_info = RenderingInfo {
+            render_area,
+            color_attachments: &color_attachments,
+            depth_attachment: Some(depth_attachment),
+        };
+
+        let viewport = vk::Viewport {
+            x: 0.0,
+            y: 0.0,
+            width: extent.width as f32,
+            height: extent.height as f32,
+            min_depth: 0.0,
+            max_depth: 1.0,
+        };
 
         unsafe {
-            self.dynamic_rendering_loader
-                .cmd_begin_rendering(self.main_graphics_command_buffer, &render_info);
+            self.rendering
+                .begin(&self.device, command_buffer, &render_info)?;
+            self.device.cmd_set_viewport(command_buffer, 0, &[viewport]);
+            self.device
+                .cmd_set_scissor(command_buffer, 0, &[render_area]);
+
+            if mesh_draws.is_empty() {
+                self.device.cmd_bind_pipeline(
+                    command_buffer,
+                    vk::PipelineBindPoint::GRAPHICS,
+                    self.triangle_pipeline.pipeline,
+                );
+                self.device.cmd_draw(command_buffer, 3, 1, 0, 0);
+            } else {
+                self.device.cmd_bind_pipeline(
+                    command_buffer,
+                    vk::PipelineBindPoint::GRAPHICS,
+                    self.mesh_pipeline.pipeline,
+                );
+                self.device.cmd_bind_descriptor_sets(
+                    command_buffer,
+                    vk::PipelineBindPoint::GRAPHICS,
+                    self.mesh_pipeline.layout,
+                    0,
+                    &[frame_set],
+                    &[],
+                );
+            }
+            let mut bound_mesh: Option<&Mesh> = None;
+            let mut bound_set = vk::DescriptorSet::null();
+            for (draw, descriptor_set) in mesh_draws.iter().zip(draw_sets) {
+                match bound_mesh {
+                    Some(mesh) if mesh.ptr_eq(&draw.mesh) => {}
+                    _ => {
+                        draw.mesh.bind(&self.device, command_buffer);
+                        bound_mesh = Some(&draw.mesh);
+                    }
+                }
+                if descriptor_set != bound_set {
+                    self.device.cmd_bind_descriptor_sets(
+                        command_buffer,
+                        vk::PipelineBindPoint::GRAPHICS,
+                        self.mesh_pipeline.layout,
+                        1,
+                        &[descriptor_set],
+                        &[],
+                    );
+                    bound_set = descriptor_set;
+                }
+                let push_constants = MeshPushConstants::new(draw.model, draw.base_color);
+                self.device.cmd_push_constants(
+                    command_buffer,
+                    self.mesh_pipeline.layout,
+                    vk::ShaderStageFlags::VERTEX,
+                    0,
+                    bytemuck::bytes_of(&push_constants),
+                );
+                draw.mesh
+                    .draw_submesh(&self.device, command_buffer, &draw.submesh);
+            }
         }
 
         unsafe {
-            self.dynamic_rendering_loader
-                .cmd_end_rendering(self.main_graphics_command_buffer);
+            self.rendering.end(&self.device, command_buffer);
         }
 
+        let captured = self.capture_requested;
+        if captured {
+            self.capture_requested = false;
+            self.record_capture(command_buffer, target_image.image, render_fence)?;
+        }
+        let (old_layout, src_access, src_stage) = if captured {
+            (
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                vk::AccessFlags::TRANSFER_READ,
+                vk::PipelineStageFlags::TRANSFER,
+            )
+        } else {
+            (
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            )
+        };
+
+        // Offscreen targets are left ready to be copied out
+        let (final_layout, final_access, final_stage) = if is_presented {
+            (
+                vk::ImageLayout::PRESENT_SRC_KHR,
+                vk::AccessFlags::empty(),
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+            )
+        } else {
+            (
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                vk::AccessFlags::TRANSFER_READ,
+                vk::PipelineStageFlags::TRANSFER,
+            )
+        };
         let image_memory_barrier = vk::ImageMemoryBarrier::builder()
-            .image(self.swapchain_images[swapchain_image_index as usize])
-            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
-            .dst_access_mask(vk::AccessFlags::empty())
-            .old_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
-            .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+            .image(target_image.image)
+            .src_access_mask(src_access)
+            .dst_access_mask(final_access)
+            .old_layout(old_layout)
+            .new_layout(final_layout)
             .subresource_range(color_subresource_range)
             .build();
 
         unsafe {
             self.device.cmd_pipeline_barrier(
-                self.main_graphics_command_buffer,
-                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
-                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                command_buffer,
+                src_stage,
+                final_stage,
                 vk::DependencyFlags::empty(),
                 &[],
                 &[],
@@ -448,38 +1860,63 @@ impl Renderer {
         }
 
         unsafe {
-            self.device
-                .end_command_buffer(self.main_graphics_command_buffer)?;
+            self.device.end_command_buffer(command_buffer)?;
         }
 
         // Submit
+        // Offscreen frames neither wait for an acquired image nor get presented
+        let (mut wait_semaphores, signal_semaphores) = if is_presented {
+            (vec![present_semaphore], vec![render_semaphore])
+        } else {
+            (vec![], vec![])
+        };
+        let mut wait_dst_stage_masks =
+            vec![vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT; wait_semaphores.len()];
+        for (semaphore, stage) in self.uploader.take_wait_semaphores(self.frame_number) {
+            wait_semaphores.push(semaphore);
+            wait_dst_stage_masks.push(stage);
+        }
+        let command_buffers = [command_buffer];
         let sumbit_info = vk::SubmitInfo::builder()
-            .wait_dst_stage_mask(&[vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT])
-            .wait_semaphores(&[self.present_semaphore])
-            .signal_semaphores(&[self.render_semaphore])
-            .command_buffers(&[self.main_graphics_command_buffer])
+            .wait_dst_stage_mask(&wait_dst_stage_masks)
+            .wait_semaphores(&wait_semaphores)
+            .signal_semaphores(&signal_semaphores)
+            .command_buffers(&command_buffers)
             .build();
         unsafe {
             self.device
-                .queue_submit(self.graphics_queue, &[sumbit_info], self.render_fence)
+                .queue_submit(self.graphics_queue, &[sumbit_info], render_fence)
         }?;
 
         // Present
-        let present_swapchains = [self.swapchain];
-        let present_wait_semaphore = [self.render_semaphore];
-        let present_swapchain_image_indices = [swapchain_image_index];
-
-        let present_info = vk::PresentInfoKHR::builder()
-            .swapchains(&present_swapchains)
-            .wait_semaphores(&present_wait_semaphore)
-            .image_indices(&present_swapchain_image_indices);
-        unsafe {
-            self.swapchain_loader
-                .queue_present(self.graphics_queue, &present_info)
-        }?;
+        if let (Some(swapchain_image_index), RenderTarget::Window { swapchain, .. }) =
+            (target_image.swapchain_index, self.target.as_ref().unwrap())
+        {
+            let present_swapchains = [swapchain.handle];
+            let present_wait_semaphore = [render_semaphore];
+            let present_swapchain_image_indices = [swapchain_image_index];
+
+            let present_info = vk::PresentInfoKHR::builder()
+                .swapchains(&present_swapchains)
+                .wait_semaphores(&present_wait_semaphore)
+                .image_indices(&present_swapchain_image_indices);
+            let present_result = unsafe {
+                swapchain
+                    .loader
+                    .queue_present(self.present_queue, &present_info)
+            };
+            match present_result {
+                Ok(false) => {}
+                Ok(true) | Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
+                    self.target_needs_recreation = true
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
 
-        // begin render pass
         self.frame_number += 1;
+        // Resources dropped before the next frame is recorded may still be used by its uploads
+        self.resources.begin_frame(self.frame_number);
         Ok(())
     }
 }
@@ -487,24 +1924,70 @@ impl Renderer {
 impl Drop for Renderer {
     fn drop(&mut self) {
         unsafe {
-            self.device.device_wait_idle().unwrap();
-
-            self.device.destroy_semaphore(self.render_semaphore, None);
-            self.device.destroy_semaphore(self.present_semaphore, None);
-            self.device.destroy_fence(self.render_fence, None);
+            // Nothing to wait for if the device was lost
+            let _ = self.device.device_wait_idle();
 
+            self.triangle_pipeline.destroy(&self.device);
+            self.mesh_pipeline.destroy(&self.device);
             self.device
-                .destroy_command_pool(self.graphics_command_pool, None);
+                .destroy_descriptor_set_layout(self.frame_set_layout, None);
+            self.device
+                .destroy_descriptor_set_layout(self.material_set_layout, None);
+            self.mipmaps.destroy(&self.device);
+            self.rendering.destroy(&self.device);
+
+            self.mesh_draws.clear();
+            self.white_texture = None;
+            self.samplers.clear();
+            self.depth_buffer = None;
+            self.readback_buffer = None;
+            for frame in &mut self.frames {
+                frame.destroy(&self.device);
+            }
+            self.frames.clear();
 
-            self.swapchain_image_views
-                .iter()
-                .for_each(|image_view| self.device.destroy_image_view(*image_view, None));
-            self.swapchain_loader
-                .destroy_swapchain(self.swapchain, None);
+            // Dropping an offscreen target destroys it with the other resources
+            let surface = match self.target.take().unwrap() {
+                RenderTarget::Window {
+                    surface_fn,
+                    mut swapchain,
+                    ..
+                } => {
+                    swapchain.destroy(&self.device);
+                    Some((surface_fn, swapchain.surface))
+                }
+                RenderTarget::Offscreen(_) => None,
+            };
+
+            self.uploader.destroy(&self.device);
+            self.resources.destroy(&self.device);
 
             self.device.destroy_device(None);
-            self.surface_fn.destroy_surface(self.surface, None);
+            if let Some(debug_messenger) = &mut self.debug_messenger {
+                debug_messenger.destroy();
+            }
+            if let Some((surface_fn, surface)) = surface {
+                surface_fn.destroy_surface(surface, None);
+            }
             self.instance.destroy_instance(None);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validation_errors_do_not_mask_a_lost_device() {
+        let result = frame_result(
+            Err(RendererError::DeviceLost),
+            Err(RendererError::ValidationErrors(3)),
+        );
+        assert!(matches!(result, Err(RendererError::DeviceLost)));
+
+        let result = frame_result(Ok(()), Err(RendererError::ValidationErrors(3)));
+        assert!(matches!(result, Err(RendererError::ValidationErrors(3))));
+        assert!(frame_result(Ok(()), Ok(())).is_ok());
+    }
+}
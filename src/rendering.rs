@@ -0,0 +1,286 @@
+use ash::{vk, Device};
+use std::collections::HashMap;
+
+use crate::error::RendererResult;
+use crate::features::DynamicRendering;
+
+/// An image that a rendering scope draws into. It must be in the optimal attachment layout for
+/// its aspect (`COLOR_ATTACHMENT_OPTIMAL` or `DEPTH_STENCIL_ATTACHMENT_OPTIMAL`) while rendering.
+#[derive(Clone, Copy)]
+pub struct Attachment {
+    pub image_view: vk::ImageView,
+    pub format: vk::Format,
+    pub load_op: vk::AttachmentLoadOp,
+    pub store_op: vk::AttachmentStoreOp,
+    /// Only used with `AttachmentLoadOp::CLEAR`
+    pub clear_value: vk::ClearValue,
+}
+
+pub struct RenderingInfo<'a> {
+    pub render_area: vk::Rect2D,
+    pub color_attachments: &'a [Attachment],
+    pub depth_attachment: Option<Attachment>,
+}
+
+/// Identifies compatible render passes. Pipelines only depend on the formats, but the load and
+/// store operations are baked into the render pass as well.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct RenderPassKey {
+    color: Vec<(vk::Format, vk::AttachmentLoadOp, vk::AttachmentStoreOp)>,
+    depth: Option<(vk::Format, vk::AttachmentLoadOp, vk::AttachmentStoreOp)>,
+}
+
+impl RenderPassKey {
+    fn new(info: &RenderingInfo) -> Self {
+        let key =
+            |attachment: &Attachment| (attachment.format, attachment.load_op, attachment.store_op);
+        RenderPassKey {
+            color: info.color_attachments.iter().map(key).collect(),
+            depth: info.depth_attachment.as_ref().map(key),
+        }
+    }
+
+    fn for_formats(color_formats: &[vk::Format], depth_format: Option<vk::Format>) -> Self {
+        let key = |format| {
+            (
+                format,
+                vk::AttachmentLoadOp::CLEAR,
+                vk::AttachmentStoreOp::STORE,
+            )
+        };
+        RenderPassKey {
+            color: color_formats.iter().copied().map(key).collect(),
+            depth: depth_format.map(key),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Hash)]
+struct FramebufferKey {
+    render_pass: vk::RenderPass,
+    image_views: Vec<vk::ImageView>,
+    width: u32,
+    height: u32,
+}
+
+fn create_render_pass(device: &Device, key: &RenderPassKey) -> RendererResult<vk::RenderPass> {
+    // Layout transitions are done with barriers outside of the render pass, so the attachments
+    // stay in their attachment layout
+    let attachment_description = |(format, load_op, store_op), layout| {
+        vk::AttachmentDescription::builder()
+            .format(format)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .load_op(load_op)
+            .store_op(store_op)
+            .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+            .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .initial_layout(layout)
+            .final_layout(layout)
+            .build()
+    };
+    let mut attachments = key
+        .color
+        .iter()
+        .map(|color| attachment_description(*color, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL))
+        .collect::<Vec<_>>();
+    let color_references = (0..key.color.len())
+        .map(|index| vk::AttachmentReference {
+            attachment: index as u32,
+            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        })
+        .collect::<Vec<_>>();
+    let depth_reference = key.depth.map(|depth| {
+        attachments.push(attachment_description(
+            depth,
+            vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+        ));
+        vk::AttachmentReference {
+            attachment: key.color.len() as u32,
+            layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+        }
+    });
+
+    let mut subpass = vk::SubpassDescription::builder()
+        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+        .color_attachments(&color_references);
+    if let Some(depth_reference) = &depth_reference {
+        subpass = subpass.depth_stencil_attachment(depth_reference);
+    }
+    let subpasses = [subpass.build()];
+    let render_pass_create_info = vk::RenderPassCreateInfo::builder()
+        .attachments(&attachments)
+        .subpasses(&subpasses);
+    Ok(unsafe { device.create_render_pass(&render_pass_create_info, None) }?)
+}
+
+/// Begins and ends rendering scopes with dynamic rendering where it is supported, and with
+/// render passes and framebuffers otherwise.
+///
+/// Render passes and framebuffers are created on demand and cached. Framebuffers refer to image
+/// views, so `destroy_framebuffers` must be called before destroying image views that were
+/// rendered into.
+pub struct Rendering {
+    dynamic_rendering: Option<DynamicRendering>,
+    render_passes: HashMap<RenderPassKey, vk::RenderPass>,
+    framebuffers: HashMap<FramebufferKey, vk::Framebuffer>,
+}
+
+impl Rendering {
+    /// Falls back to render passes if `dynamic_rendering` is `None`.
+    pub fn new(dynamic_rendering: Option<DynamicRendering>) -> Self {
+        Rendering {
+            dynamic_rendering,
+            render_passes: HashMap::new(),
+            framebuffers: HashMap::new(),
+        }
+    }
+
+    pub fn uses_dynamic_rendering(&self) -> bool {
+        self.dynamic_rendering.is_some()
+    }
+
+    fn get_or_create_render_pass(
+        &mut self,
+        device: &Device,
+        key: RenderPassKey,
+    ) -> RendererResult<vk::RenderPass> {
+        if let Some(render_pass) = self.render_passes.get(&key) {
+            return Ok(*render_pass);
+        }
+        let render_pass = create_render_pass(device, &key)?;
+        self.render_passes.insert(key, render_pass);
+        Ok(render_pass)
+    }
+
+    /// The render pass that pipelines rendering into these formats have to be created with, or
+    /// `None` with dynamic rendering. See `GraphicsPipelineBuilder::render_pass`.
+    pub fn pipeline_render_pass(
+        &mut self,
+        device: &Device,
+        color_formats: &[vk::Format],
+        depth_format: Option<vk::Format>,
+    ) -> RendererResult<Option<vk::RenderPass>> {
+        if self.uses_dynamic_rendering() {
+            return Ok(None);
+        }
+        let key = RenderPassKey::for_formats(color_formats, depth_format);
+        self.get_or_create_render_pass(device, key).map(Some)
+    }
+
+    /// Begins rendering into the attachments of `info`.
+    ///
+    /// # Safety
+    /// `command_buffer` must be recording and outside of a rendering scope.
+    pub unsafe fn begin(
+        &mut self,
+        device: &Device,
+        command_buffer: vk::CommandBuffer,
+        info: &RenderingInfo,
+    ) -> RendererResult<()> {
+        if let Some(dynamic_rendering) = &self.dynamic_rendering {
+            let rendering_attachment = |attachment: &Attachment, layout| {
+                vk::RenderingAttachmentInfoKHR::builder()
+                    .image_view(attachment.image_view)
+                    .image_layout(layout)
+                    .load_op(attachment.load_op)
+                    .store_op(attachment.store_op)
+                    .clear_value(attachment.clear_value)
+                    .build()
+            };
+            let color_attachments = info
+                .color_attachments
+                .iter()
+                .map(|attachment| {
+                    rendering_attachment(attachment, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                })
+                .collect::<Vec<_>>();
+            let depth_attachment = info.depth_attachment.as_ref().map(|attachment| {
+                rendering_attachment(
+                    attachment,
+                    vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                )
+            });
+            let mut rendering_info = vk::RenderingInfoKHR::builder()
+                .render_area(info.render_area)
+                .layer_count(1)
+                .color_attachments(&color_attachments);
+            if let Some(depth_attachment) = &depth_attachment {
+                rendering_info = rendering_info.depth_attachment(depth_attachment);
+            }
+            dynamic_rendering.cmd_begin_rendering(command_buffer, &rendering_info);
+            return Ok(());
+        }
+
+        let render_pass = self.get_or_create_render_pass(device, RenderPassKey::new(info))?;
+        let attachments = info
+            .color_attachments
+            .iter()
+            .chain(&info.depth_attachment)
+            .collect::<Vec<_>>();
+        let framebuffer_key = FramebufferKey {
+            render_pass,
+            image_views: attachments
+                .iter()
+                .map(|attachment| attachment.image_view)
+                .collect(),
+            width: info.render_area.offset.x as u32 + info.render_area.extent.width,
+            height: info.render_area.offset.y as u32 + info.render_area.extent.height,
+        };
+        let framebuffer = match self.framebuffers.get(&framebuffer_key) {
+            Some(framebuffer) => *framebuffer,
+            None => {
+                let framebuffer_create_info = vk::FramebufferCreateInfo::builder()
+                    .render_pass(render_pass)
+                    .attachments(&framebuffer_key.image_views)
+                    .width(framebuffer_key.width)
+                    .height(framebuffer_key.height)
+                    .layers(1);
+                let framebuffer = device.create_framebuffer(&framebuffer_create_info, None)?;
+                self.framebuffers.insert(framebuffer_key, framebuffer);
+                framebuffer
+            }
+        };
+
+        let clear_values = attachments
+            .iter()
+            .map(|attachment| attachment.clear_value)
+            .collect::<Vec<_>>();
+        let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(render_pass)
+            .framebuffer(framebuffer)
+            .render_area(info.render_area)
+            .clear_values(&clear_values);
+        device.cmd_begin_render_pass(
+            command_buffer,
+            &render_pass_begin_info,
+            vk::SubpassContents::INLINE,
+        );
+        Ok(())
+    }
+
+    /// # Safety
+    /// Must match a previous call to `begin` on the same command buffer.
+    pub unsafe fn end(&self, device: &Device, command_buffer: vk::CommandBuffer) {
+        match &self.dynamic_rendering {
+            Some(dynamic_rendering) => dynamic_rendering.cmd_end_rendering(command_buffer),
+            None => device.cmd_end_render_pass(command_buffer),
+        }
+    }
+
+    /// # Safety
+    /// The framebuffers must no longer be in use by the GPU.
+    pub unsafe fn destroy_framebuffers(&mut self, device: &Device) {
+        for (_, framebuffer) in self.framebuffers.drain() {
+            device.destroy_framebuffer(framebuffer, None);
+        }
+    }
+
+    /// # Safety
+    /// The render passes and framebuffers must no longer be in use by the GPU.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        self.destroy_framebuffers(device);
+        for (_, render_pass) in self.render_passes.drain() {
+            device.destroy_render_pass(render_pass, None);
+        }
+    }
+}
@@ -0,0 +1,459 @@
+use ash::{vk, Device};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::ptr::NonNull;
+use std::rc::Rc;
+
+use crate::allocator::{
+    Allocation, AllocationDesc, AllocationStrategy, Allocator, AllocatorStats, MemoryLocation,
+};
+use crate::error::RendererResult;
+
+/// A Vulkan object whose wrapper was dropped, waiting for the GPU to finish using it
+enum Garbage {
+    Buffer(vk::Buffer, Allocation),
+    Image(vk::Image, Allocation),
+    ImageView(vk::ImageView),
+    Sampler(vk::Sampler),
+}
+
+impl Garbage {
+    unsafe fn destroy(self, device: &Device, allocator: &mut Allocator) {
+        match self {
+            Garbage::Buffer(buffer, allocation) => {
+                device.destroy_buffer(buffer, None);
+                allocator.free(device, &allocation);
+            }
+            Garbage::Image(image, allocation) => {
+                device.destroy_image(image, None);
+                allocator.free(device, &allocation);
+            }
+            Garbage::ImageView(image_view) => device.destroy_image_view(image_view, None),
+            Garbage::Sampler(sampler) => device.destroy_sampler(sampler, None),
+        }
+    }
+}
+
+/// Objects of dropped wrappers, tagged with the number of the frame that was submitted next when
+/// they were dropped. That frame is the last one that can use them.
+#[derive(Default)]
+struct DestructionQueue {
+    frame_number: u64,
+    pending: VecDeque<(u64, Garbage)>,
+}
+
+type SharedDestructionQueue = Rc<RefCell<DestructionQueue>>;
+
+fn defer(queue: &SharedDestructionQueue, garbage: Garbage) {
+    let mut queue = queue.borrow_mut();
+    let frame_number = queue.frame_number;
+    queue.pending.push_back((frame_number, garbage));
+}
+
+pub struct BufferDesc<'a> {
+    pub name: &'a str,
+    pub size: vk::DeviceSize,
+    pub usage: vk::BufferUsageFlags,
+    pub location: MemoryLocation,
+    pub strategy: AllocationStrategy,
+}
+
+/// A buffer and its memory. Dropping it destroys both once the frames that may use it have
+/// completed.
+pub struct Buffer {
+    handle: vk::Buffer,
+    /// Only `None` while being dropped
+    allocation: Option<Allocation>,
+    size: vk::DeviceSize,
+    usage: vk::BufferUsageFlags,
+    name: String,
+    queue: SharedDestructionQueue,
+}
+
+impl Buffer {
+    pub fn handle(&self) -> vk::Buffer {
+        self.handle
+    }
+
+    pub fn size(&self) -> vk::DeviceSize {
+        self.size
+    }
+
+    pub fn usage(&self) -> vk::BufferUsageFlags {
+        self.usage
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn allocation(&self) -> &Allocation {
+        self.allocation.as_ref().unwrap()
+    }
+
+    /// Pointer to the start of the buffer for host-visible memory locations
+    pub fn mapped_ptr(&self) -> Option<NonNull<u8>> {
+        self.allocation().mapped_ptr()
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if let Some(allocation) = self.allocation.take() {
+            defer(&self.queue, Garbage::Buffer(self.handle, allocation));
+        }
+    }
+}
+
+/// A 2D image with optimal tiling in device-local memory
+pub struct ImageDesc<'a> {
+    pub name: &'a str,
+    pub extent: vk::Extent2D,
+    pub format: vk::Format,
+    pub mip_levels: u32,
+    pub usage: vk::ImageUsageFlags,
+    /// E.g. `MUTABLE_FORMAT` for views in another format than the image's
+    pub flags: vk::ImageCreateFlags,
+    /// Give the image its own memory, e.g. for render targets that are recreated on resize
+    pub dedicated: bool,
+}
+
+/// An image and its memory. Dropping it destroys both once the frames that may use it have
+/// completed.
+pub struct Image {
+    handle: vk::Image,
+    /// Only `None` while being dropped
+    allocation: Option<Allocation>,
+    extent: vk::Extent2D,
+    format: vk::Format,
+    mip_levels: u32,
+    usage: vk::ImageUsageFlags,
+    name: String,
+    queue: SharedDestructionQueue,
+}
+
+impl Image {
+    pub fn handle(&self) -> vk::Image {
+        self.handle
+    }
+
+    pub fn extent(&self) -> vk::Extent2D {
+        self.extent
+    }
+
+    pub fn format(&self) -> vk::Format {
+        self.format
+    }
+
+    pub fn mip_levels(&self) -> u32 {
+        self.mip_levels
+    }
+
+    pub fn usage(&self) -> vk::ImageUsageFlags {
+        self.usage
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn aspect_mask(&self) -> vk::ImageAspectFlags {
+        match self.format {
+            vk::Format::D16_UNORM | vk::Format::D32_SFLOAT | vk::Format::X8_D24_UNORM_PACK32 => {
+                vk::ImageAspectFlags::DEPTH
+            }
+            vk::Format::D16_UNORM_S8_UINT
+            | vk::Format::D24_UNORM_S8_UINT
+            | vk::Format::D32_SFLOAT_S8_UINT => {
+                vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL
+            }
+            _ => vk::ImageAspectFlags::COLOR,
+        }
+    }
+
+    /// All mip levels of the image
+    pub fn subresource_range(&self) -> vk::ImageSubresourceRange {
+        vk::ImageSubresourceRange {
+            aspect_mask: self.aspect_mask(),
+            base_mip_level: 0,
+            level_count: self.mip_levels,
+            base_array_layer: 0,
+            layer_count: 1,
+        }
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        if let Some(allocation) = self.allocation.take() {
+            defer(&self.queue, Garbage::Image(self.handle, allocation));
+        }
+    }
+}
+
+/// A view of an `Image`. It doesn't keep the image alive, so it must be dropped no later than
+/// the image.
+pub struct ImageView {
+    handle: vk::ImageView,
+    image: vk::Image,
+    format: vk::Format,
+    subresource_range: vk::ImageSubresourceRange,
+    name: String,
+    queue: SharedDestructionQueue,
+}
+
+impl ImageView {
+    pub fn handle(&self) -> vk::ImageView {
+        self.handle
+    }
+
+    pub fn image(&self) -> vk::Image {
+        self.image
+    }
+
+    pub fn format(&self) -> vk::Format {
+        self.format
+    }
+
+    pub fn subresource_range(&self) -> vk::ImageSubresourceRange {
+        self.subresource_range
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Drop for ImageView {
+    fn drop(&mut self) {
+        defer(&self.queue, Garbage::ImageView(self.handle));
+    }
+}
+
+pub struct Sampler {
+    handle: vk::Sampler,
+    name: String,
+    queue: SharedDestructionQueue,
+}
+
+impl Sampler {
+    pub fn handle(&self) -> vk::Sampler {
+        self.handle
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Drop for Sampler {
+    fn drop(&mut self) {
+        defer(&self.queue, Garbage::Sampler(self.handle));
+    }
+}
+
+/// Creates buffers, images, image views and samplers, and destroys them once they are dropped
+/// and the GPU is done with them.
+///
+/// The renderer tells the manager which frame is submitted next with `begin_frame` and which
+/// frames have completed with `collect_garbage`. All resources must be dropped before the
+/// manager is destroyed.
+pub struct ResourceManager {
+    allocator: Allocator,
+    queue: SharedDestructionQueue,
+}
+
+impl ResourceManager {
+    pub fn new(allocator: Allocator) -> Self {
+        ResourceManager {
+            allocator,
+            queue: Rc::default(),
+        }
+    }
+
+    pub fn allocator(&mut self) -> &mut Allocator {
+        &mut self.allocator
+    }
+
+    pub fn memory_stats(&self) -> AllocatorStats {
+        self.allocator.stats()
+    }
+
+    pub fn create_buffer(&mut self, device: &Device, desc: &BufferDesc) -> RendererResult<Buffer> {
+        let buffer_create_info = vk::BufferCreateInfo::builder()
+            .size(desc.size)
+            .usage(desc.usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+        let handle = unsafe { device.create_buffer(&buffer_create_info, None)? };
+        let allocation = self.allocator.allocate_buffer(
+            device,
+            handle,
+            &AllocationDesc {
+                name: desc.name,
+                location: desc.location,
+                strategy: desc.strategy,
+                dedicated: false,
+            },
+        );
+        let allocation = match allocation {
+            Ok(allocation) => allocation,
+            Err(err) => {
+                unsafe { device.destroy_buffer(handle, None) };
+                return Err(err);
+            }
+        };
+        Ok(Buffer {
+            handle,
+            allocation: Some(allocation),
+            size: desc.size,
+            usage: desc.usage,
+            name: desc.name.to_string(),
+            queue: self.queue.clone(),
+        })
+    }
+
+    pub fn create_image(&mut self, device: &Device, desc: &ImageDesc) -> RendererResult<Image> {
+        let image_create_info = vk::ImageCreateInfo::builder()
+            .flags(desc.flags)
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(desc.format)
+            .extent(vk::Extent3D {
+                width: desc.extent.width,
+                height: desc.extent.height,
+                depth: 1,
+            })
+            .mip_levels(desc.mip_levels)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(desc.usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .initial_layout(vk::ImageLayout::UNDEFINED);
+        let handle = unsafe { device.create_image(&image_create_info, None)? };
+        let allocation = self.allocator.allocate_image(
+            device,
+            handle,
+            &AllocationDesc {
+                name: desc.name,
+                location: MemoryLocation::GpuOnly,
+                strategy: AllocationStrategy::Buddy,
+                dedicated: desc.dedicated,
+            },
+        );
+        let allocation = match allocation {
+            Ok(allocation) => allocation,
+            Err(err) => {
+                unsafe { device.destroy_image(handle, None) };
+                return Err(err);
+            }
+        };
+        Ok(Image {
+            handle,
+            allocation: Some(allocation),
+            extent: desc.extent,
+            format: desc.format,
+            mip_levels: desc.mip_levels,
+            usage: desc.usage,
+            name: desc.name.to_string(),
+            queue: self.queue.clone(),
+        })
+    }
+
+    /// Creates a 2D view of `subresource_range` of `image`, in the image's format.
+    pub fn create_image_view(
+        &mut self,
+        device: &Device,
+        image: &Image,
+        subresource_range: vk::ImageSubresourceRange,
+        name: &str,
+    ) -> RendererResult<ImageView> {
+        self.create_image_view_with_format(
+            device,
+            image,
+            image.format,
+            image.usage,
+            subresource_range,
+            name,
+        )
+    }
+
+    /// Creates a 2D view of `subresource_range` of `image` in `format`, restricted to `usage`.
+    /// The image must have been created with `MUTABLE_FORMAT` if the formats differ, and with
+    /// `EXTENDED_USAGE` if `format` doesn't support all of the image's usage.
+    pub fn create_image_view_with_format(
+        &mut self,
+        device: &Device,
+        image: &Image,
+        format: vk::Format,
+        usage: vk::ImageUsageFlags,
+        subresource_range: vk::ImageSubresourceRange,
+        name: &str,
+    ) -> RendererResult<ImageView> {
+        let mut usage_create_info = vk::ImageViewUsageCreateInfo::builder().usage(usage);
+        let image_view_create_info = vk::ImageViewCreateInfo::builder()
+            .image(image.handle)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(format)
+            .subresource_range(subresource_range)
+            .push_next(&mut usage_create_info);
+        let handle = unsafe { device.create_image_view(&image_view_create_info, None)? };
+        Ok(ImageView {
+            handle,
+            image: image.handle,
+            format,
+            subresource_range,
+            name: name.to_string(),
+            queue: self.queue.clone(),
+        })
+    }
+
+    pub fn create_sampler(
+        &mut self,
+        device: &Device,
+        create_info: &vk::SamplerCreateInfo,
+        name: &str,
+    ) -> RendererResult<Sampler> {
+        let handle = unsafe { device.create_sampler(create_info, None)? };
+        Ok(Sampler {
+            handle,
+            name: name.to_string(),
+            queue: self.queue.clone(),
+        })
+    }
+
+    /// Resources dropped from now on may be used by `frame_number`, either by its commands or by
+    /// the uploads it waits for.
+    pub fn begin_frame(&mut self, frame_number: u64) {
+        self.queue.borrow_mut().frame_number = frame_number;
+    }
+
+    /// Destroys the resources that were dropped while recording `completed_frame` or earlier.
+    ///
+    /// # Safety
+    /// The GPU must be done with `completed_frame` and all frames before it.
+    pub unsafe fn collect_garbage(&mut self, device: &Device, completed_frame: u64) {
+        let mut queue = self.queue.borrow_mut();
+        while let Some((frame_number, _)) = queue.pending.front() {
+            if *frame_number > completed_frame {
+                break;
+            }
+            let (_, garbage) = queue.pending.pop_front().unwrap();
+            garbage.destroy(device, &mut self.allocator);
+        }
+    }
+
+    /// Destroys all dropped resources and frees the allocator's memory. Resources that are still
+    /// alive are reported as leaks.
+    ///
+    /// # Safety
+    /// The device must be idle.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        self.collect_garbage(device, u64::MAX);
+        // Every live resource holds a reference to the queue
+        let live_resources = Rc::strong_count(&self.queue) - 1;
+        if live_resources > 0 {
+            log::warn!("{} resource(s) were not dropped", live_resources);
+        }
+        self.allocator.destroy(device);
+    }
+}
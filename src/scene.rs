@@ -0,0 +1,964 @@
+use ash::vk;
+use glam::{Mat4, Vec3, Vec4};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::path::Path;
+
+use crate::error::{RendererError, RendererResult};
+use crate::material::{AlphaMode, Material, TextureBinding};
+use crate::mesh::{Aabb, Mesh, MeshData, Submesh, Vertex};
+use crate::obj::ObjModel;
+use crate::renderer::Renderer;
+use crate::texture::{SamplerDesc, Texture, TextureData, TextureUsage};
+
+/// An element of the scene hierarchy
+#[derive(Clone, Debug)]
+pub struct Node {
+    pub name: String,
+    /// Relative to the parent node
+    pub transform: Mat4,
+    /// Indices into `Scene::nodes`
+    pub children: Vec<usize>,
+    /// Index into `Scene::meshes`
+    pub mesh: Option<usize>,
+    /// Index into `Scene::cameras`
+    pub camera: Option<usize>,
+    /// Index into `Scene::lights`
+    pub light: Option<usize>,
+}
+
+impl Node {
+    fn new(name: &str, transform: Mat4) -> Node {
+        Node {
+            name: name.to_string(),
+            transform,
+            children: Vec::new(),
+            mesh: None,
+            camera: None,
+            light: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Projection {
+    Perspective {
+        /// Vertical field of view in radians
+        y_fov: f32,
+        z_near: f32,
+        /// `None` for an infinite projection
+        z_far: Option<f32>,
+    },
+    Orthographic {
+        /// Half the width of the view volume
+        x_mag: f32,
+        /// Half the height of the view volume
+        y_mag: f32,
+        z_near: f32,
+        z_far: f32,
+    },
+}
+
+/// A camera looking down its node's negative z axis, with y up
+#[derive(Clone, Debug)]
+pub struct Camera {
+    pub name: String,
+    pub projection: Projection,
+}
+
+impl Camera {
+    /// The transform from view to Vulkan's clip space. Perspective cameras always use
+    /// `aspect_ratio`, so that they fill the render target without distortion.
+    pub fn projection_matrix(&self, aspect_ratio: f32) -> Mat4 {
+        let projection = match self.projection {
+            Projection::Perspective {
+                y_fov,
+                z_near,
+                z_far: Some(z_far),
+            } => Mat4::perspective_rh(y_fov, aspect_ratio, z_near, z_far),
+            Projection::Perspective {
+                y_fov,
+                z_near,
+                z_far: None,
+            } => Mat4::perspective_infinite_rh(y_fov, aspect_ratio, z_near),
+            Projection::Orthographic {
+                x_mag,
+                y_mag,
+                z_near,
+                z_far,
+            } => Mat4::orthographic_rh(-x_mag, x_mag, -y_mag, y_mag, z_near, z_far),
+        };
+        // Vulkan's clip space has y pointing down
+        Mat4::from_scale(Vec3::new(1.0, -1.0, 1.0)) * projection
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LightKind {
+    /// Shines along its node's negative z axis
+    Directional,
+    Point,
+    /// Shines along its node's negative z axis. The angles are in radians from the axis.
+    Spot {
+        inner_cone_angle: f32,
+        outer_cone_angle: f32,
+    },
+}
+
+/// A punctual light, as defined by `KHR_lights_punctual`
+#[derive(Clone, Debug)]
+pub struct Light {
+    pub name: String,
+    /// Linear RGB
+    pub color: Vec3,
+    /// Lux for directional lights, candela otherwise
+    pub intensity: f32,
+    /// Distance at which the light's intensity may be considered zero, `None` for infinite
+    pub range: Option<f32>,
+    pub kind: LightKind,
+}
+
+/// A node hierarchy with the meshes, materials, textures, cameras and lights it refers to
+pub struct Scene {
+    pub nodes: Vec<Node>,
+    /// The nodes without a parent
+    pub roots: Vec<usize>,
+    pub meshes: Vec<Mesh>,
+    pub materials: Vec<Material>,
+    pub textures: Vec<Texture>,
+    pub cameras: Vec<Camera>,
+    pub lights: Vec<Light>,
+}
+
+impl Scene {
+    /// Loads a glTF (`.gltf` or `.glb`) or OBJ file depending on the extension of `path`.
+    pub fn load(renderer: &mut Renderer, path: &Path) -> RendererResult<Scene> {
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some("gltf") | Some("glb") => Scene::load_gltf(renderer, path),
+            Some("obj") => Ok(Scene::from_obj(ObjModel::load(renderer, path)?)),
+            _ => Err(RendererError::Model(format!(
+                "{} is neither a glTF nor an OBJ file",
+                path.display()
+            ))),
+        }
+    }
+
+    /// A scene with a single node that shows `model`
+    pub fn from_obj(model: ObjModel) -> Scene {
+        let mut node = Node::new(model.mesh.name(), Mat4::IDENTITY);
+        node.mesh = Some(0);
+        Scene {
+            nodes: vec![node],
+            roots: vec![0],
+            meshes: vec![model.mesh],
+            materials: model.materials,
+            textures: Vec::new(),
+            cameras: Vec::new(),
+            lights: Vec::new(),
+        }
+    }
+
+    /// Imports the glTF file at `path` with its buffers and images and uploads it.
+    ///
+    /// Only the default scene is imported, or the first one if there is no default. Each glTF
+    /// mesh becomes a `Mesh` with a submesh per primitive. Primitives that aren't triangle lists
+    /// are skipped, and flat normals are generated for those without normals. Images are
+    /// converted to 8-bit RGBA textures with mipmaps, in an sRGB format if a material uses them
+    /// for colors. Images that are used for colors and data get a texture for both.
+    pub fn load_gltf(renderer: &mut Renderer, path: &Path) -> RendererResult<Scene> {
+        let (document, buffers, images) = gltf::import(path).map_err(|err| {
+            RendererError::Model(format!("Can't load {}: {}", path.display(), err))
+        })?;
+        let file_name = path
+            .file_stem()
+            .map_or_else(|| "gltf".into(), |stem| stem.to_string_lossy());
+
+        // Images that are only used for data stay linear, the others get an sRGB texture, and
+        // one for data as well if they are used for both
+        let mut textures = Vec::new();
+        let mut image_textures = HashMap::new();
+        for ((data, image), usages) in images
+            .iter()
+            .zip(document.images())
+            .zip(image_usages(&document))
+        {
+            let name = match image.name() {
+                Some(name) => name.to_string(),
+                None => format!("{} image {}", file_name, image.index()),
+            };
+            let data = TextureData {
+                extent: vk::Extent2D {
+                    width: data.width,
+                    height: data.height,
+                },
+                pixels: to_rgba8(data),
+            };
+            for usage in usages {
+                let texture = renderer.create_texture_with_mipmaps(&name, &data, usage)?;
+                image_textures.insert((image.index(), usage), texture.clone());
+                textures.push(texture);
+            }
+        }
+
+        let materials = document
+            .materials()
+            .map(|material| convert_material(&material, &image_textures))
+            .collect();
+
+        // glTF meshes without triangles are left out, so their indices change
+        let mut meshes = Vec::new();
+        let mut mesh_indices = Vec::new();
+        for mesh in document.meshes() {
+            let name = match mesh.name() {
+                Some(name) => name.to_string(),
+                None => format!("{} mesh {}", file_name, mesh.index()),
+            };
+            let data = import_mesh(&mesh, &buffers, &name)?;
+            if data.indices.is_empty() {
+                log::warn!("{} has no triangles", name);
+                mesh_indices.push(None);
+            } else {
+                mesh_indices.push(Some(meshes.len()));
+                meshes.push(renderer.create_mesh(&data, &name)?);
+            }
+        }
+
+        let cameras = document
+            .cameras()
+            .map(|camera| convert_camera(&camera))
+            .collect();
+        let lights = document
+            .lights()
+            .map(|lights| lights.map(|light| convert_light(&light)).collect())
+            .unwrap_or_default();
+
+        let (nodes, roots) = import_hierarchy(&document, &mesh_indices, &file_name)?;
+        let scene = Scene {
+            nodes,
+            roots,
+            meshes,
+            materials,
+            textures,
+            cameras,
+            lights,
+        };
+        log::info!(
+            "Loaded {} with {} node(s), {} mesh(es), {} material(s), {} texture(s), {} camera(s) and {} light(s)",
+            path.display(),
+            scene.nodes.len(),
+            scene.meshes.len(),
+            scene.materials.len(),
+            scene.textures.len(),
+            scene.cameras.len(),
+            scene.lights.len()
+        );
+        Ok(scene)
+    }
+
+    /// The transform from each node to world space, indexed like `nodes`. Nodes that aren't
+    /// reachable from the roots get the identity.
+    pub fn world_transforms(&self) -> Vec<Mat4> {
+        let mut world_transforms = vec![Mat4::IDENTITY; self.nodes.len()];
+        let mut stack = self
+            .roots
+            .iter()
+            .map(|root| (*root, Mat4::IDENTITY))
+            .collect::<Vec<_>>();
+        while let Some((index, parent_transform)) = stack.pop() {
+            let node = &self.nodes[index];
+            let world_transform = parent_transform * node.transform;
+            world_transforms[index] = world_transform;
+            stack.extend(node.children.iter().map(|child| (*child, world_transform)));
+        }
+        world_transforms
+    }
+
+    /// The world-space bounds of the meshes reachable from the roots
+    pub fn bounds(&self) -> Option<Aabb> {
+        let world_transforms = self.world_transforms();
+        let corners = self.mesh_nodes().flat_map(|(index, mesh)| {
+            let Aabb { min, max } = self.meshes[mesh].bounds();
+            let world_transform = world_transforms[index];
+            (0..8).map(move |corner| {
+                let select = |bit: usize, min: f32, max: f32| {
+                    if corner & bit == 0 {
+                        min
+                    } else {
+                        max
+                    }
+                };
+                world_transform.transform_point3(Vec3::new(
+                    select(1, min.x, max.x),
+                    select(2, min.y, max.y),
+                    select(4, min.z, max.z),
+                ))
+            })
+        });
+        Aabb::from_points(corners)
+    }
+
+    /// The first camera in the hierarchy, with the transform from world to Vulkan's clip space
+    pub fn camera_view_projection(&self, aspect_ratio: f32) -> Option<Mat4> {
+        let world_transforms = self.world_transforms();
+        self.reachable_nodes().find_map(|index| {
+            let camera = &self.cameras[self.nodes[index].camera?];
+            Some(camera.projection_matrix(aspect_ratio) * world_transforms[index].inverse())
+        })
+    }
+
+    /// Queues the meshes of the scene to be drawn in the next frame, with `transform` applied
+    /// on top of the hierarchy.
+    pub fn draw(&self, renderer: &mut Renderer, transform: Mat4) {
+        let world_transforms = self.world_transforms();
+        for (index, mesh) in self.mesh_nodes() {
+            renderer.draw_mesh(
+                &self.meshes[mesh],
+                &self.materials,
+                transform * world_transforms[index],
+            );
+        }
+    }
+
+    /// The indices of the nodes reachable from the roots, parents before their children
+    fn reachable_nodes(&self) -> impl Iterator<Item = usize> + '_ {
+        let mut stack = self.roots.iter().rev().copied().collect::<Vec<_>>();
+        std::iter::from_fn(move || {
+            let index = stack.pop()?;
+            stack.extend(self.nodes[index].children.iter().rev());
+            Some(index)
+        })
+    }
+
+    /// The reachable nodes with a mesh, and that mesh
+    fn mesh_nodes(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.reachable_nodes()
+            .filter_map(move |index| Some((index, self.nodes[index].mesh?)))
+    }
+}
+
+/// The nodes of `document` and the roots of its default scene, or of the first one if there is
+/// no default. `mesh_indices` maps glTF meshes to `Scene::meshes`. Hierarchies where a node has
+/// several parents or is its own ancestor are rejected, since traversing them wouldn't end.
+fn import_hierarchy(
+    document: &gltf::Document,
+    mesh_indices: &[Option<usize>],
+    file_name: &str,
+) -> RendererResult<(Vec<Node>, Vec<usize>)> {
+    let nodes = document
+        .nodes()
+        .map(|node| Node {
+            name: node.name().unwrap_or_default().to_string(),
+            transform: Mat4::from_cols_array_2d(&node.transform().matrix()),
+            children: node.children().map(|child| child.index()).collect(),
+            mesh: node.mesh().and_then(|mesh| mesh_indices[mesh.index()]),
+            camera: node.camera().map(|camera| camera.index()),
+            light: node.light().map(|light| light.index()),
+        })
+        .collect::<Vec<_>>();
+
+    let mut parents = vec![None; nodes.len()];
+    for (index, node) in nodes.iter().enumerate() {
+        for child in &node.children {
+            if parents[*child].replace(index).is_some() {
+                return Err(RendererError::Model(format!(
+                    "Node {} of {} has more than one parent",
+                    child, file_name
+                )));
+            }
+        }
+    }
+    // With a single parent each, a node in a cycle is reached again within as many steps as
+    // there are nodes
+    for index in 0..nodes.len() {
+        let mut ancestor = parents[index];
+        for _ in 0..nodes.len() {
+            match ancestor {
+                Some(ancestor) if ancestor == index => {
+                    return Err(RendererError::Model(format!(
+                        "Node {} of {} is its own ancestor",
+                        index, file_name
+                    )))
+                }
+                Some(parent) => ancestor = parents[parent],
+                None => break,
+            }
+        }
+    }
+
+    let roots = match document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+    {
+        Some(scene) => scene.nodes().map(|node| node.index()).collect(),
+        None => Vec::new(),
+    };
+    Ok((nodes, roots))
+}
+
+/// The images that the textures of `material` refer to, and what it uses them for
+fn material_images(material: &gltf::Material) -> Vec<(usize, TextureUsage)> {
+    let pbr = material.pbr_metallic_roughness();
+    let color = pbr
+        .base_color_texture()
+        .into_iter()
+        .chain(material.emissive_texture())
+        .map(|info| (info.texture(), TextureUsage::Color));
+    let data = pbr
+        .metallic_roughness_texture()
+        .map(|info| info.texture())
+        .into_iter()
+        .chain(material.normal_texture().map(|info| info.texture()))
+        .chain(material.occlusion_texture().map(|info| info.texture()))
+        .map(|texture| (texture, TextureUsage::Data));
+    color
+        .chain(data)
+        .map(|(texture, usage)| (texture.source().index(), usage))
+        .collect()
+}
+
+/// The usages of each image, indexed like the glTF images. Images that no material uses are
+/// treated as data.
+fn image_usages(document: &gltf::Document) -> Vec<Vec<TextureUsage>> {
+    let mut usages = vec![Vec::new(); document.images().len()];
+    for material in document.materials() {
+        for (image, usage) in material_images(&material) {
+            let image_usages: &mut Vec<_> = &mut usages[image];
+            if !image_usages.contains(&usage) {
+                image_usages.push(usage);
+            }
+        }
+    }
+    for image_usages in &mut usages {
+        if image_usages.is_empty() {
+            image_usages.push(TextureUsage::Data);
+        }
+    }
+    usages
+}
+
+/// Expands the pixels of `image` to 8-bit RGBA. One and two channel images are grayscale, with
+/// alpha in the second channel.
+fn to_rgba8(image: &gltf::image::Data) -> Vec<u8> {
+    use gltf::image::Format;
+    let (channels, channel_size) = match image.format {
+        Format::R8 => (1, 1),
+        Format::R8G8 => (2, 1),
+        Format::R8G8B8 => (3, 1),
+        Format::R8G8B8A8 => (4, 1),
+        Format::R16 => (1, 2),
+        Format::R16G16 => (2, 2),
+        Format::R16G16B16 => (3, 2),
+        Format::R16G16B16A16 => (4, 2),
+        Format::R32G32B32FLOAT => (3, 4),
+        Format::R32G32B32A32FLOAT => (4, 4),
+    };
+    let to_u8 = |bytes: &[u8]| match bytes.len() {
+        1 => bytes[0],
+        2 => (u16::from_ne_bytes([bytes[0], bytes[1]]) >> 8) as u8,
+        _ => {
+            let value = f32::from_ne_bytes(bytes.try_into().unwrap());
+            (value.clamp(0.0, 1.0) * 255.0).round() as u8
+        }
+    };
+    image
+        .pixels
+        .chunks_exact(channels * channel_size)
+        .flat_map(|pixel| {
+            let channel =
+                |index: usize| to_u8(&pixel[index * channel_size..(index + 1) * channel_size]);
+            match channels {
+                1 => [channel(0), channel(0), channel(0), 255],
+                2 => [channel(0), channel(0), channel(0), channel(1)],
+                3 => [channel(0), channel(1), channel(2), 255],
+                _ => [channel(0), channel(1), channel(2), channel(3)],
+            }
+        })
+        .collect()
+}
+
+fn convert_sampler(sampler: &gltf::texture::Sampler) -> SamplerDesc {
+    use gltf::texture::{MagFilter, MinFilter, WrappingMode};
+    let mag_filter = match sampler.mag_filter() {
+        Some(MagFilter::Nearest) => vk::Filter::NEAREST,
+        Some(MagFilter::Linear) | None => vk::Filter::LINEAR,
+    };
+    let (min_filter, mipmap_mode) = match sampler.min_filter() {
+        Some(MinFilter::Nearest) => (vk::Filter::NEAREST, None),
+        Some(MinFilter::Linear) => (vk::Filter::LINEAR, None),
+        Some(MinFilter::NearestMipmapNearest) => {
+            (vk::Filter::NEAREST, Some(vk::SamplerMipmapMode::NEAREST))
+        }
+        Some(MinFilter::LinearMipmapNearest) => {
+            (vk::Filter::LINEAR, Some(vk::SamplerMipmapMode::NEAREST))
+        }
+        Some(MinFilter::NearestMipmapLinear) => {
+            (vk::Filter::NEAREST, Some(vk::SamplerMipmapMode::LINEAR))
+        }
+        Some(MinFilter::LinearMipmapLinear) | None => {
+            (vk::Filter::LINEAR, Some(vk::SamplerMipmapMode::LINEAR))
+        }
+    };
+    let address_mode = |mode| match mode {
+        WrappingMode::ClampToEdge => vk::SamplerAddressMode::CLAMP_TO_EDGE,
+        WrappingMode::MirroredRepeat => vk::SamplerAddressMode::MIRRORED_REPEAT,
+        WrappingMode::Repeat => vk::SamplerAddressMode::REPEAT,
+    };
+    SamplerDesc {
+        mag_filter,
+        min_filter,
+        mipmap_mode,
+        address_mode_u: address_mode(sampler.wrap_s()),
+        address_mode_v: address_mode(sampler.wrap_t()),
+        max_anisotropy: None,
+    }
+}
+
+/// `textures` are keyed by the index of the glTF image and how it is used
+fn texture_binding(
+    texture: &gltf::Texture,
+    tex_coord: u32,
+    usage: TextureUsage,
+    textures: &HashMap<(usize, TextureUsage), Texture>,
+) -> TextureBinding {
+    if tex_coord != 0 {
+        log::warn!(
+            "Texture {} uses texture coordinates {}, only the first set is imported",
+            texture.index(),
+            tex_coord
+        );
+    }
+    TextureBinding {
+        texture: textures[&(texture.source().index(), usage)].clone(),
+        sampler: convert_sampler(&texture.sampler()),
+        tex_coord,
+    }
+}
+
+fn convert_material(
+    material: &gltf::Material,
+    textures: &HashMap<(usize, TextureUsage), Texture>,
+) -> Material {
+    let pbr = material.pbr_metallic_roughness();
+    let binding = |usage| {
+        move |info: gltf::texture::Info| {
+            texture_binding(&info.texture(), info.tex_coord(), usage, textures)
+        }
+    };
+    let normal_texture = material.normal_texture();
+    let occlusion_texture = material.occlusion_texture();
+    let defaults = Material::default();
+    Material {
+        name: material.name().unwrap_or_default().to_string(),
+        base_color: Vec4::from(pbr.base_color_factor()),
+        base_color_texture: pbr.base_color_texture().map(binding(TextureUsage::Color)),
+        metallic: pbr.metallic_factor(),
+        roughness: pbr.roughness_factor(),
+        metallic_roughness_texture: pbr
+            .metallic_roughness_texture()
+            .map(binding(TextureUsage::Data)),
+        normal_texture: normal_texture.as_ref().map(|info| {
+            texture_binding(
+                &info.texture(),
+                info.tex_coord(),
+                TextureUsage::Data,
+                textures,
+            )
+        }),
+        normal_scale: normal_texture
+            .as_ref()
+            .map_or(defaults.normal_scale, |info| info.scale()),
+        occlusion_texture: occlusion_texture.as_ref().map(|info| {
+            texture_binding(
+                &info.texture(),
+                info.tex_coord(),
+                TextureUsage::Data,
+                textures,
+            )
+        }),
+        occlusion_strength: occlusion_texture
+            .as_ref()
+            .map_or(defaults.occlusion_strength, |info| info.strength()),
+        emissive: Vec3::from(material.emissive_factor()),
+        emissive_texture: material
+            .emissive_texture()
+            .map(binding(TextureUsage::Color)),
+        alpha_mode: match material.alpha_mode() {
+            gltf::material::AlphaMode::Opaque => AlphaMode::Opaque,
+            gltf::material::AlphaMode::Mask => AlphaMode::Mask,
+            gltf::material::AlphaMode::Blend => AlphaMode::Blend,
+        },
+        alpha_cutoff: material.alpha_cutoff().unwrap_or(defaults.alpha_cutoff),
+        double_sided: material.double_sided(),
+    }
+}
+
+fn convert_camera(camera: &gltf::Camera) -> Camera {
+    let projection = match camera.projection() {
+        gltf::camera::Projection::Perspective(perspective) => Projection::Perspective {
+            y_fov: perspective.yfov(),
+            z_near: perspective.znear(),
+            z_far: perspective.zfar(),
+        },
+        gltf::camera::Projection::Orthographic(orthographic) => Projection::Orthographic {
+            x_mag: orthographic.xmag(),
+            y_mag: orthographic.ymag(),
+            z_near: orthographic.znear(),
+            z_far: orthographic.zfar(),
+        },
+    };
+    Camera {
+        name: camera.name().unwrap_or_default().to_string(),
+        projection,
+    }
+}
+
+fn convert_light(light: &gltf::khr_lights_punctual::Light) -> Light {
+    use gltf::khr_lights_punctual::Kind;
+    Light {
+        name: light.name().unwrap_or_default().to_string(),
+        color: Vec3::from(light.color()),
+        intensity: light.intensity(),
+        range: light.range(),
+        kind: match light.kind() {
+            Kind::Directional => LightKind::Directional,
+            Kind::Point => LightKind::Point,
+            Kind::Spot {
+                inner_cone_angle,
+                outer_cone_angle,
+            } => LightKind::Spot {
+                inner_cone_angle,
+                outer_cone_angle,
+            },
+        },
+    }
+}
+
+/// Concatenates the triangle primitives of `mesh`, each one becomes a submesh.
+fn import_mesh(
+    mesh: &gltf::Mesh,
+    buffers: &[gltf::buffer::Data],
+    name: &str,
+) -> RendererResult<MeshData> {
+    let mut data = MeshData::default();
+    for primitive in mesh.primitives() {
+        if primitive.mode() != gltf::mesh::Mode::Triangles {
+            log::warn!(
+                "Skipping a primitive of {} with mode {:?}",
+                name,
+                primitive.mode()
+            );
+            continue;
+        }
+        let reader = primitive.reader(|buffer| Some(&*buffers[buffer.index()]));
+        let positions = match reader.read_positions() {
+            Some(positions) => positions,
+            None => continue,
+        };
+        let mut vertices = positions
+            .map(|position| Vertex {
+                position: Vec3::from(position),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        if let Some(normals) = reader.read_normals() {
+            for (vertex, normal) in vertices.iter_mut().zip(normals) {
+                vertex.normal = Vec3::from(normal);
+            }
+        }
+        if let Some(tex_coords) = reader.read_tex_coords(0) {
+            for (vertex, uv) in vertices.iter_mut().zip(tex_coords.into_f32()) {
+                vertex.uv = uv.into();
+            }
+        }
+        if let Some(tangents) = reader.read_tangents() {
+            for (vertex, tangent) in vertices.iter_mut().zip(tangents) {
+                vertex.tangent = Vec4::from(tangent);
+            }
+        }
+        if let Some(colors) = reader.read_colors(0) {
+            for (vertex, color) in vertices.iter_mut().zip(colors.into_rgba_f32()) {
+                vertex.color = Vec4::from(color);
+            }
+        }
+        let mut indices = match reader.read_indices() {
+            Some(indices) => indices.into_u32().collect(),
+            None => (0..vertices.len() as u32).collect::<Vec<_>>(),
+        };
+        indices.truncate(indices.len() / 3 * 3);
+        if indices
+            .iter()
+            .any(|index| *index as usize >= vertices.len())
+        {
+            return Err(RendererError::Model(format!(
+                "A primitive of {} has indices beyond its vertices",
+                name
+            )));
+        }
+        if reader.read_normals().is_none() {
+            let (flat_vertices, flat_indices) = flat_shaded(&vertices, &indices);
+            vertices = flat_vertices;
+            indices = flat_indices;
+        }
+
+        let base_vertex = data.vertices.len() as u32;
+        let first_index = data.indices.len() as u32;
+        data.vertices.extend(vertices);
+        data.indices
+            .extend(indices.iter().map(|index| base_vertex + index));
+        data.submeshes.push(Submesh {
+            first_index,
+            index_count: indices.len() as u32,
+            material: primitive.material().index(),
+        });
+    }
+    Ok(data)
+}
+
+/// Gives each triangle its own vertices with the triangle's normal, which is what glTF asks for
+/// if a primitive has no normals.
+fn flat_shaded(vertices: &[Vertex], indices: &[u32]) -> (Vec<Vertex>, Vec<u32>) {
+    let mut flat_vertices = Vec::with_capacity(indices.len());
+    for triangle in indices.chunks_exact(3) {
+        let corners = [
+            vertices[triangle[0] as usize],
+            vertices[triangle[1] as usize],
+            vertices[triangle[2] as usize],
+        ];
+        let [a, b, c] = [
+            corners[0].position,
+            corners[1].position,
+            corners[2].position,
+        ];
+        let normal = (b - a).cross(c - a).normalize_or_zero();
+        for corner in corners.iter() {
+            flat_vertices.push(Vertex { normal, ..*corner });
+        }
+    }
+    let flat_indices = (0..flat_vertices.len() as u32).collect();
+    (flat_vertices, flat_indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn import_fixture() -> (gltf::Document, Vec<gltf::image::Data>) {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/hierarchy.gltf");
+        let (document, _, images) = gltf::import(path).unwrap();
+        (document, images)
+    }
+
+    /// The fixture's hierarchy, cameras and lights without anything that needs a renderer
+    fn fixture_scene(document: &gltf::Document) -> Scene {
+        let (nodes, roots) = import_hierarchy(document, &[], "hierarchy").unwrap();
+        Scene {
+            nodes,
+            roots,
+            meshes: Vec::new(),
+            materials: Vec::new(),
+            textures: Vec::new(),
+            cameras: document
+                .cameras()
+                .map(|camera| convert_camera(&camera))
+                .collect(),
+            lights: document
+                .lights()
+                .unwrap()
+                .map(|light| convert_light(&light))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn world_transforms_follow_the_hierarchy() {
+        let (document, _) = import_fixture();
+        let scene = fixture_scene(&document);
+        assert_eq!(scene.roots, [0, 3]);
+        assert_eq!(scene.nodes[0].children, [1]);
+
+        let world_transforms = scene.world_transforms();
+        // Scaled by 2 and turned to look along -x, then moved by the root
+        let eye = world_transforms[2];
+        assert!(eye
+            .transform_point3(Vec3::ZERO)
+            .abs_diff_eq(Vec3::new(11.0, 0.0, 0.0), 1e-5));
+        assert!(eye
+            .transform_vector3(-Vec3::Z)
+            .abs_diff_eq(Vec3::new(-2.0, 0.0, 0.0), 1e-5));
+        assert_eq!(
+            world_transforms[3],
+            Mat4::from_translation(Vec3::new(0.0, 4.0, 0.0))
+        );
+        assert_eq!(world_transforms[4], Mat4::IDENTITY);
+    }
+
+    #[test]
+    fn cameras_and_lights_are_imported() {
+        let (document, _) = import_fixture();
+        let scene = fixture_scene(&document);
+        assert_eq!(scene.nodes[2].camera, Some(1));
+        assert_eq!(scene.nodes[3].light, Some(1));
+        assert_eq!(scene.cameras[0].name, "top");
+        assert_eq!(
+            scene.cameras[0].projection,
+            Projection::Orthographic {
+                x_mag: 2.0,
+                y_mag: 1.0,
+                z_near: 0.5,
+                z_far: 10.0,
+            }
+        );
+        assert_eq!(
+            scene.cameras[1].projection,
+            Projection::Perspective {
+                y_fov: 0.8,
+                z_near: 0.1,
+                z_far: Some(100.0),
+            }
+        );
+
+        let sun = &scene.lights[0];
+        assert_eq!(sun.name, "sun");
+        assert_eq!(sun.kind, LightKind::Directional);
+        assert_eq!(sun.color, Vec3::new(1.0, 0.9, 0.8));
+        assert_eq!(sun.range, None);
+        let lamp = &scene.lights[1];
+        assert_eq!(lamp.intensity, 10.0);
+        assert_eq!(lamp.range, Some(5.0));
+        assert_eq!(
+            lamp.kind,
+            LightKind::Spot {
+                inner_cone_angle: 0.2,
+                outer_cone_angle: 0.5,
+            }
+        );
+    }
+
+    #[test]
+    fn cyclic_hierarchies_are_rejected() {
+        let import = |nodes: &str| {
+            let json = format!(
+                r#"{{"asset": {{"version": "2.0"}}, "nodes": {}, "scenes": [{{"nodes": [0]}}]}}"#,
+                nodes
+            );
+            let gltf = gltf::Gltf::from_slice(json.as_bytes()).unwrap();
+            import_hierarchy(&gltf.document, &[], "test").map(|(_, roots)| roots)
+        };
+        assert_eq!(
+            import(r#"[{"children": [1, 2]}, {"children": [3]}, {}, {}]"#).unwrap(),
+            [0]
+        );
+        match import(r#"[{"children": [1]}, {"children": [0]}]"#) {
+            Err(RendererError::Model(message)) => assert!(message.contains("own ancestor")),
+            _ => panic!("A cycle through the root was accepted"),
+        }
+        match import(r#"[{}, {"children": [1]}]"#) {
+            Err(RendererError::Model(message)) => assert!(message.contains("own ancestor")),
+            _ => panic!("A node that is its own child was accepted"),
+        }
+        match import(r#"[{"children": [1, 2]}, {"children": [2]}, {}]"#) {
+            Err(RendererError::Model(message)) => assert!(message.contains("more than one parent")),
+            _ => panic!("A node with two parents was accepted"),
+        }
+    }
+
+    #[test]
+    fn scene_is_viewed_through_the_first_reachable_camera() {
+        let (document, _) = import_fixture();
+        let scene = fixture_scene(&document);
+        let view_projection = scene.camera_view_projection(2.0).unwrap();
+        // 3 units in front of the camera, which looks along -x
+        let clip = view_projection * Vec4::new(8.0, 0.0, 0.0, 1.0);
+        let ndc = clip.truncate() / clip.w;
+        assert!(ndc.x.abs() < 1e-5 && ndc.y.abs() < 1e-5);
+        assert!(ndc.z > 0.0 && ndc.z < 1.0);
+        // Above the camera ends up at the top of the image
+        let clip = view_projection * Vec4::new(8.0, 0.5, 0.0, 1.0);
+        assert!(clip.y / clip.w < 0.0);
+
+        // The other camera isn't reachable from the lamp
+        let no_cameras = Scene {
+            roots: vec![3],
+            ..fixture_scene(&document)
+        };
+        assert_eq!(no_cameras.camera_view_projection(1.0), None);
+    }
+
+    #[test]
+    fn samplers_are_converted() {
+        let (document, _) = import_fixture();
+        let textures: Vec<_> = document.textures().collect();
+        assert_eq!(
+            convert_sampler(&textures[0].sampler()),
+            SamplerDesc {
+                mag_filter: vk::Filter::NEAREST,
+                min_filter: vk::Filter::LINEAR,
+                mipmap_mode: Some(vk::SamplerMipmapMode::NEAREST),
+                address_mode_u: vk::SamplerAddressMode::MIRRORED_REPEAT,
+                address_mode_v: vk::SamplerAddressMode::CLAMP_TO_EDGE,
+                max_anisotropy: None,
+            }
+        );
+        // Without a sampler, glTF asks for repeating and the implementation's filters
+        assert_eq!(
+            convert_sampler(&textures[1].sampler()),
+            SamplerDesc {
+                mag_filter: vk::Filter::LINEAR,
+                min_filter: vk::Filter::LINEAR,
+                mipmap_mode: Some(vk::SamplerMipmapMode::LINEAR),
+                address_mode_u: vk::SamplerAddressMode::REPEAT,
+                address_mode_v: vk::SamplerAddressMode::REPEAT,
+                max_anisotropy: None,
+            }
+        );
+    }
+
+    #[test]
+    fn images_used_for_colors_and_data_get_both_usages() {
+        let (document, images) = import_fixture();
+        assert_eq!(
+            image_usages(&document),
+            [vec![TextureUsage::Color, TextureUsage::Data]]
+        );
+        assert_eq!(
+            to_rgba8(&images[0]),
+            [255, 255, 255, 255, 0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255, 255]
+        );
+    }
+
+    #[test]
+    fn pixels_are_expanded_to_rgba8() {
+        use gltf::image::{Data, Format};
+        let image = |format, pixels: Vec<u8>| Data {
+            pixels,
+            format,
+            width: 1,
+            height: 1,
+        };
+        assert_eq!(to_rgba8(&image(Format::R8, vec![7])), [7, 7, 7, 255]);
+        assert_eq!(to_rgba8(&image(Format::R8G8, vec![7, 9])), [7, 7, 7, 9]);
+        assert_eq!(
+            to_rgba8(&image(Format::R8G8B8, vec![1, 2, 3])),
+            [1, 2, 3, 255]
+        );
+        let r16g16b16a16 = [0x1234u16, 0xFFFF, 0, 0x8000]
+            .iter()
+            .flat_map(|value| value.to_ne_bytes())
+            .collect();
+        assert_eq!(
+            to_rgba8(&image(Format::R16G16B16A16, r16g16b16a16)),
+            [0x12, 0xFF, 0, 0x80]
+        );
+        let float = [0.5f32, 2.0, -1.0]
+            .iter()
+            .flat_map(|value| value.to_ne_bytes())
+            .collect();
+        assert_eq!(
+            to_rgba8(&image(Format::R32G32B32FLOAT, float)),
+            [128, 255, 0, 255]
+        );
+    }
+}
@@ -0,0 +1,704 @@
+use ash::extensions::khr;
+use ash::vk;
+use ash::{Device, Instance};
+
+use crate::device::QueueFamilyIndices;
+use crate::error::{RendererError, RendererResult};
+
+/// HDR output modes that are used instead of SDR if the surface exposes them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HdrMode {
+    /// 10-bit output with the ST.2084 (PQ) transfer function
+    Hdr10,
+    /// Half-float linear output with extended sRGB primaries
+    ScRgb,
+}
+
+impl std::str::FromStr for HdrMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "hdr10" => Ok(HdrMode::Hdr10),
+            "scrgb" => Ok(HdrMode::ScRgb),
+            _ => Err(format!("Unknown HDR mode `{}`", s)),
+        }
+    }
+}
+
+/// How the swapchain format and color space are chosen from the ones the surface supports.
+#[derive(Clone, Debug)]
+pub struct SurfaceFormatPolicy {
+    /// Use this HDR mode when the surface supports it
+    pub hdr: Option<HdrMode>,
+    /// Prefer an `*_SRGB` format in the `SRGB_NONLINEAR` color space, so that writes to the
+    /// swapchain images are gamma-corrected by the hardware
+    pub prefer_srgb: bool,
+    /// Formats to try, in order, if no preferred format is available
+    pub fallbacks: Vec<vk::SurfaceFormatKHR>,
+}
+
+impl Default for SurfaceFormatPolicy {
+    fn default() -> Self {
+        let unorm = |format| vk::SurfaceFormatKHR {
+            format,
+            color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
+        };
+        SurfaceFormatPolicy {
+            hdr: None,
+            prefer_srgb: true,
+            fallbacks: vec![
+                unorm(vk::Format::B8G8R8A8_UNORM),
+                unorm(vk::Format::R8G8B8A8_UNORM),
+            ],
+        }
+    }
+}
+
+fn is_srgb_format(format: vk::Format) -> bool {
+    matches!(
+        format,
+        vk::Format::B8G8R8A8_SRGB
+            | vk::Format::R8G8B8A8_SRGB
+            | vk::Format::A8B8G8R8_SRGB_PACK32
+            | vk::Format::B8G8R8_SRGB
+            | vk::Format::R8G8B8_SRGB
+    )
+}
+
+fn is_hdr_surface_format(mode: HdrMode, surface_format: &vk::SurfaceFormatKHR) -> bool {
+    match mode {
+        HdrMode::Hdr10 => {
+            surface_format.color_space == vk::ColorSpaceKHR::HDR10_ST2084_EXT
+                && matches!(
+                    surface_format.format,
+                    vk::Format::A2B10G10R10_UNORM_PACK32 | vk::Format::A2R10G10B10_UNORM_PACK32
+                )
+        }
+        HdrMode::ScRgb => {
+            surface_format.color_space == vk::ColorSpaceKHR::EXTENDED_SRGB_LINEAR_EXT
+                && surface_format.format == vk::Format::R16G16B16A16_SFLOAT
+        }
+    }
+}
+
+/// How the final pass encodes its linear color output for the swapchain. The values are those of
+/// the `OUTPUT_*` constants in `shaders/color.glsl`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum OutputEncoding {
+    /// Written as is, for `*_SRGB` formats that are encoded by the hardware and for scRGB
+    Linear = 0,
+    /// Encoded with the sRGB transfer function, for UNORM formats in a nonlinear color space
+    Srgb = 1,
+    /// Converted to BT.2020 and encoded with the ST.2084 (PQ) transfer function, for HDR10
+    Pq = 2,
+}
+
+impl OutputEncoding {
+    pub fn of(surface_format: &vk::SurfaceFormatKHR) -> Self {
+        match surface_format.color_space {
+            vk::ColorSpaceKHR::HDR10_ST2084_EXT => OutputEncoding::Pq,
+            vk::ColorSpaceKHR::EXTENDED_SRGB_LINEAR_EXT => OutputEncoding::Linear,
+            _ if is_srgb_format(surface_format.format) => OutputEncoding::Linear,
+            _ => OutputEncoding::Srgb,
+        }
+    }
+}
+
+/// Selects a surface format out of `available` according to `policy`.
+///
+/// Returns `None` only if `available` is empty.
+pub fn select_surface_format(
+    available: &[vk::SurfaceFormatKHR],
+    policy: &SurfaceFormatPolicy,
+) -> Option<vk::SurfaceFormatKHR> {
+    // A single UNDEFINED entry means that the surface has no preference at all
+    if let [only] = available {
+        if only.format == vk::Format::UNDEFINED {
+            let srgb = vk::SurfaceFormatKHR {
+                format: vk::Format::B8G8R8A8_SRGB,
+                color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
+            };
+            return Some(if policy.prefer_srgb {
+                srgb
+            } else {
+                policy.fallbacks.first().copied().unwrap_or(srgb)
+            });
+        }
+    }
+
+    let hdr = policy.hdr.and_then(|mode| {
+        available
+            .iter()
+            .find(|surface_format| is_hdr_surface_format(mode, surface_format))
+    });
+    let srgb = || {
+        available.iter().find(|surface_format| {
+            policy.prefer_srgb
+                && is_srgb_format(surface_format.format)
+                && surface_format.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR
+        })
+    };
+    let fallback = || {
+        policy
+            .fallbacks
+            .iter()
+            .find(|fallback| available.contains(fallback))
+    };
+
+    hdr.or_else(srgb)
+        .or_else(fallback)
+        .or_else(|| available.first())
+        .copied()
+}
+
+/// How frames are handed to the presentation engine.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PresentPolicy {
+    /// Wait for vertical blank, never tears (`FIFO`)
+    #[default]
+    Vsync,
+    /// Like `Vsync`, but tears instead of stalling when a frame arrives late (`FIFO_RELAXED`)
+    AdaptiveVsync,
+    /// Always present the newest frame at vertical blank without blocking (`MAILBOX`)
+    LowLatency,
+    /// Present immediately, may tear (`IMMEDIATE`)
+    Uncapped,
+}
+
+impl PresentPolicy {
+    /// The policy after this one, used to cycle through all policies at runtime
+    pub fn next(self) -> Self {
+        match self {
+            PresentPolicy::Vsync => PresentPolicy::AdaptiveVsync,
+            PresentPolicy::AdaptiveVsync => PresentPolicy::LowLatency,
+            PresentPolicy::LowLatency => PresentPolicy::Uncapped,
+            PresentPolicy::Uncapped => PresentPolicy::Vsync,
+        }
+    }
+
+    /// Present modes that satisfy this policy, in order of preference
+    fn candidates(self) -> &'static [vk::PresentModeKHR] {
+        match self {
+            PresentPolicy::Vsync => &[vk::PresentModeKHR::FIFO],
+            PresentPolicy::AdaptiveVsync => &[vk::PresentModeKHR::FIFO_RELAXED],
+            PresentPolicy::LowLatency => {
+                &[vk::PresentModeKHR::MAILBOX, vk::PresentModeKHR::IMMEDIATE]
+            }
+            PresentPolicy::Uncapped => {
+                &[vk::PresentModeKHR::IMMEDIATE, vk::PresentModeKHR::MAILBOX]
+            }
+        }
+    }
+}
+
+/// Selects a present mode out of `available` according to `policy`, falling back to `FIFO`,
+/// which every implementation is required to support.
+pub fn select_present_mode(
+    available: &[vk::PresentModeKHR],
+    policy: PresentPolicy,
+) -> vk::PresentModeKHR {
+    policy
+        .candidates()
+        .iter()
+        .copied()
+        .find(|mode| available.contains(mode))
+        .unwrap_or(vk::PresentModeKHR::FIFO)
+}
+
+/// The window size and user preferences a swapchain is built from
+#[derive(Clone, Copy, Debug)]
+pub struct SwapchainDesc<'a> {
+    pub window_extent: vk::Extent2D,
+    pub surface_format: &'a SurfaceFormatPolicy,
+    pub present_policy: PresentPolicy,
+}
+
+pub struct Swapchain {
+    pub surface: vk::SurfaceKHR,
+    pub loader: khr::Swapchain,
+    pub handle: vk::SwapchainKHR,
+    pub images: Vec<vk::Image>,
+    pub image_views: Vec<vk::ImageView>,
+    pub format: vk::SurfaceFormatKHR,
+    pub present_mode: vk::PresentModeKHR,
+    pub extent: vk::Extent2D,
+    pub image_usage: vk::ImageUsageFlags,
+}
+
+/// Picks the swapchain extent for the surface.
+///
+/// Most platforms report the window size through `current_extent`, but some (e.g. Wayland) set it
+/// to `0xFFFFFFFF` and let the swapchain decide, in which case we use the window's inner size
+/// clamped to the extents supported by the surface.
+fn choose_swapchain_extent(
+    surface_capabilities: &vk::SurfaceCapabilitiesKHR,
+    window_extent: vk::Extent2D,
+) -> vk::Extent2D {
+    if surface_capabilities.current_extent.width != u32::MAX {
+        return surface_capabilities.current_extent;
+    }
+
+    let min_extent = surface_capabilities.min_image_extent;
+    let max_extent = surface_capabilities.max_image_extent;
+    vk::Extent2D {
+        width: window_extent
+            .width
+            .max(min_extent.width)
+            .min(max_extent.width),
+        height: window_extent
+            .height
+            .max(min_extent.height)
+            .min(max_extent.height),
+    }
+}
+
+/// What the surface supports, with the format, present mode and extent already chosen
+struct SurfaceSupport {
+    capabilities: vk::SurfaceCapabilitiesKHR,
+    format: vk::SurfaceFormatKHR,
+    present_mode: vk::PresentModeKHR,
+    extent: vk::Extent2D,
+    image_usage: vk::ImageUsageFlags,
+}
+
+fn query_surface_support(
+    surface: vk::SurfaceKHR,
+    surface_fn: &khr::Surface,
+    physical_device: vk::PhysicalDevice,
+    desc: SwapchainDesc,
+) -> RendererResult<SurfaceSupport> {
+    let capabilities =
+        unsafe { surface_fn.get_physical_device_surface_capabilities(physical_device, surface)? };
+    let surface_formats =
+        unsafe { surface_fn.get_physical_device_surface_formats(physical_device, surface)? };
+    let present_modes =
+        unsafe { surface_fn.get_physical_device_surface_present_modes(physical_device, surface)? };
+
+    Ok(SurfaceSupport {
+        capabilities,
+        format: select_surface_format(&surface_formats, desc.surface_format).ok_or_else(|| {
+            RendererError::Unsupported("The surface reports no formats".to_string())
+        })?,
+        present_mode: select_present_mode(&present_modes, desc.present_policy),
+        extent: choose_swapchain_extent(&capabilities, desc.window_extent),
+        // Allow copying the images out (e.g. for screenshots) where supported
+        image_usage: vk::ImageUsageFlags::COLOR_ATTACHMENT
+            | (capabilities.supported_usage_flags & vk::ImageUsageFlags::TRANSFER_SRC),
+    })
+}
+
+fn create_swapchain_handle(
+    loader: &khr::Swapchain,
+    surface: vk::SurfaceKHR,
+    support: &SurfaceSupport,
+    queue_family_indices: &QueueFamilyIndices,
+    old_swapchain: vk::SwapchainKHR,
+) -> RendererResult<vk::SwapchainKHR> {
+    let surface_capabilities = &support.capabilities;
+    // A max_image_count of 0 means there is no upper limit
+    let max_image_count = if surface_capabilities.max_image_count == 0 {
+        u32::MAX
+    } else {
+        surface_capabilities.max_image_count
+    };
+
+    // The images are rendered on the graphics queue and presented on the present queue. Sharing
+    // them concurrently avoids ownership transfers when those are different families.
+    let graphics = queue_family_indices.graphics;
+    let present = queue_family_indices.present.unwrap_or(graphics);
+    let (sharing_mode, swapchain_queue_family_indices) = if present == graphics {
+        (vk::SharingMode::EXCLUSIVE, vec![])
+    } else {
+        (vk::SharingMode::CONCURRENT, vec![graphics, present])
+    };
+    let swapchain_create_info = vk::SwapchainCreateInfoKHR::builder()
+        .surface(surface)
+        .min_image_count(
+            3.max(surface_capabilities.min_image_count)
+                .min(max_image_count),
+        )
+        .image_format(support.format.format)
+        .image_color_space(support.format.color_space)
+        .image_extent(support.extent)
+        .image_array_layers(1)
+        .image_usage(support.image_usage)
+        .image_sharing_mode(sharing_mode)
+        .queue_family_indices(&swapchain_queue_family_indices)
+        .pre_transform(surface_capabilities.current_transform)
+        .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
+        .present_mode(support.present_mode)
+        .clipped(true)
+        .old_swapchain(old_swapchain);
+    Ok(unsafe { loader.create_swapchain(&swapchain_create_info, None)? })
+}
+
+fn create_image_views(
+    device: &Device,
+    images: &[vk::Image],
+    format: vk::Format,
+) -> RendererResult<Vec<vk::ImageView>> {
+    images
+        .iter()
+        .map(|image| {
+            let subresource_range = vk::ImageSubresourceRange::builder()
+                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                .base_mip_level(0)
+                .level_count(1)
+                .base_array_layer(0)
+                .layer_count(1);
+            let imageview_create_info = vk::ImageViewCreateInfo::builder()
+                .image(*image)
+                .view_type(vk::ImageViewType::TYPE_2D)
+                .format(format)
+                .subresource_range(*subresource_range);
+            Ok(unsafe { device.create_image_view(&imageview_create_info, None) }?)
+        })
+        .collect()
+}
+
+impl Swapchain {
+    pub fn new(
+        instance: &Instance,
+        device: &Device,
+        surface: vk::SurfaceKHR,
+        surface_fn: &khr::Surface,
+        physical_device: vk::PhysicalDevice,
+        queue_family_indices: &QueueFamilyIndices,
+        desc: SwapchainDesc,
+    ) -> RendererResult<Swapchain> {
+        let support = query_surface_support(surface, surface_fn, physical_device, desc)?;
+
+        let loader = khr::Swapchain::new(instance, device);
+        let handle = create_swapchain_handle(
+            &loader,
+            surface,
+            &support,
+            queue_family_indices,
+            vk::SwapchainKHR::null(),
+        )?;
+        let images = unsafe { loader.get_swapchain_images(handle)? };
+        let image_views = create_image_views(device, &images, support.format.format)?;
+
+        Ok(Swapchain {
+            surface,
+            loader,
+            handle,
+            images,
+            image_views,
+            format: support.format,
+            present_mode: support.present_mode,
+            extent: support.extent,
+            image_usage: support.image_usage,
+        })
+    }
+
+    /// Rebuilds the swapchain and its image views for the current surface size.
+    ///
+    /// Returns `false` without touching the swapchain if the surface currently has a zero-sized
+    /// extent (e.g. the window is minimized), in which case the caller should retry later.
+    /// The caller must make sure the device is idle.
+    pub fn recreate(
+        &mut self,
+        device: &Device,
+        surface_fn: &khr::Surface,
+        physical_device: vk::PhysicalDevice,
+        queue_family_indices: &QueueFamilyIndices,
+        desc: SwapchainDesc,
+    ) -> RendererResult<bool> {
+        let support = query_surface_support(self.surface, surface_fn, physical_device, desc)?;
+        if support.extent.width == 0 || support.extent.height == 0 {
+            return Ok(false);
+        }
+
+        let handle = create_swapchain_handle(
+            &self.loader,
+            self.surface,
+            &support,
+            queue_family_indices,
+            self.handle,
+        )?;
+
+        unsafe { self.destroy(device) };
+
+        self.handle = handle;
+        self.images = unsafe { self.loader.get_swapchain_images(handle)? };
+        self.image_views = create_image_views(device, &self.images, support.format.format)?;
+        self.format = support.format;
+        self.present_mode = support.present_mode;
+        self.extent = support.extent;
+        self.image_usage = support.image_usage;
+        Ok(true)
+    }
+
+    /// Destroys the image views and the swapchain handle.
+    ///
+    /// # Safety
+    /// The caller must make sure the device is idle.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        self.image_views
+            .drain(..)
+            .for_each(|image_view| device.destroy_image_view(image_view, None));
+        self.loader.destroy_swapchain(self.handle, None);
+        self.handle = vk::SwapchainKHR::null();
+        self.images.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn surface_format(format: vk::Format, color_space: vk::ColorSpaceKHR) -> vk::SurfaceFormatKHR {
+        vk::SurfaceFormatKHR {
+            format,
+            color_space,
+        }
+    }
+
+    fn sdr(format: vk::Format) -> vk::SurfaceFormatKHR {
+        surface_format(format, vk::ColorSpaceKHR::SRGB_NONLINEAR)
+    }
+
+    #[test]
+    fn undefined_surface_format_means_no_preference() {
+        let available = [sdr(vk::Format::UNDEFINED)];
+        let policy = SurfaceFormatPolicy::default();
+        assert_eq!(
+            select_surface_format(&available, &policy),
+            Some(sdr(vk::Format::B8G8R8A8_SRGB))
+        );
+
+        let policy = SurfaceFormatPolicy {
+            prefer_srgb: false,
+            ..SurfaceFormatPolicy::default()
+        };
+        assert_eq!(
+            select_surface_format(&available, &policy),
+            Some(sdr(vk::Format::B8G8R8A8_UNORM))
+        );
+    }
+
+    #[test]
+    fn srgb_surface_format_is_preferred() {
+        let available = [
+            sdr(vk::Format::B8G8R8A8_UNORM),
+            surface_format(
+                vk::Format::R8G8B8A8_SRGB,
+                vk::ColorSpaceKHR::DISPLAY_P3_NONLINEAR_EXT,
+            ),
+            sdr(vk::Format::R8G8B8A8_SRGB),
+        ];
+        let policy = SurfaceFormatPolicy::default();
+        assert_eq!(
+            select_surface_format(&available, &policy),
+            Some(sdr(vk::Format::R8G8B8A8_SRGB))
+        );
+
+        let policy = SurfaceFormatPolicy {
+            prefer_srgb: false,
+            ..SurfaceFormatPolicy::default()
+        };
+        assert_eq!(
+            select_surface_format(&available, &policy),
+            Some(sdr(vk::Format::B8G8R8A8_UNORM))
+        );
+    }
+
+    #[test]
+    fn hdr_surface_format_is_used_when_requested_and_available() {
+        let hdr10 = surface_format(
+            vk::Format::A2B10G10R10_UNORM_PACK32,
+            vk::ColorSpaceKHR::HDR10_ST2084_EXT,
+        );
+        let scrgb = surface_format(
+            vk::Format::R16G16B16A16_SFLOAT,
+            vk::ColorSpaceKHR::EXTENDED_SRGB_LINEAR_EXT,
+        );
+        let available = [sdr(vk::Format::B8G8R8A8_SRGB), hdr10, scrgb];
+        let policy = |hdr| SurfaceFormatPolicy {
+            hdr,
+            ..SurfaceFormatPolicy::default()
+        };
+        assert_eq!(
+            select_surface_format(&available, &policy(Some(HdrMode::Hdr10))),
+            Some(hdr10)
+        );
+        assert_eq!(
+            select_surface_format(&available, &policy(Some(HdrMode::ScRgb))),
+            Some(scrgb)
+        );
+        assert_eq!(
+            select_surface_format(&available, &policy(None)),
+            Some(sdr(vk::Format::B8G8R8A8_SRGB))
+        );
+
+        // Without the HDR format, SDR is used as usual
+        assert_eq!(
+            select_surface_format(&available[..2], &policy(Some(HdrMode::ScRgb))),
+            Some(sdr(vk::Format::B8G8R8A8_SRGB))
+        );
+    }
+
+    #[test]
+    fn output_encoding_matches_the_surface_format() {
+        let encoding =
+            |format, color_space| OutputEncoding::of(&surface_format(format, color_space));
+        assert_eq!(
+            OutputEncoding::of(&sdr(vk::Format::B8G8R8A8_SRGB)),
+            OutputEncoding::Linear
+        );
+        assert_eq!(
+            OutputEncoding::of(&sdr(vk::Format::B8G8R8A8_UNORM)),
+            OutputEncoding::Srgb
+        );
+        assert_eq!(
+            encoding(
+                vk::Format::A2B10G10R10_UNORM_PACK32,
+                vk::ColorSpaceKHR::HDR10_ST2084_EXT
+            ),
+            OutputEncoding::Pq
+        );
+        assert_eq!(
+            encoding(
+                vk::Format::R16G16B16A16_SFLOAT,
+                vk::ColorSpaceKHR::EXTENDED_SRGB_LINEAR_EXT
+            ),
+            OutputEncoding::Linear
+        );
+    }
+
+    #[test]
+    fn surface_format_falls_back_to_the_first_one() {
+        let available = [
+            sdr(vk::Format::A2B10G10R10_UNORM_PACK32),
+            sdr(vk::Format::R8G8B8A8_UNORM),
+        ];
+        let policy = SurfaceFormatPolicy::default();
+        assert_eq!(
+            select_surface_format(&available, &policy),
+            Some(sdr(vk::Format::R8G8B8A8_UNORM))
+        );
+
+        let policy = SurfaceFormatPolicy {
+            fallbacks: Vec::new(),
+            ..SurfaceFormatPolicy::default()
+        };
+        assert_eq!(
+            select_surface_format(&available, &policy),
+            Some(sdr(vk::Format::A2B10G10R10_UNORM_PACK32))
+        );
+        assert_eq!(select_surface_format(&[], &policy), None);
+    }
+
+    #[test]
+    fn present_policies_fall_back_to_fifo() {
+        let policies = [
+            PresentPolicy::Vsync,
+            PresentPolicy::AdaptiveVsync,
+            PresentPolicy::LowLatency,
+            PresentPolicy::Uncapped,
+        ];
+        for &policy in &policies {
+            assert_eq!(
+                select_present_mode(&[vk::PresentModeKHR::FIFO], policy),
+                vk::PresentModeKHR::FIFO,
+                "{:?}",
+                policy
+            );
+            assert_eq!(
+                select_present_mode(&[], policy),
+                vk::PresentModeKHR::FIFO,
+                "{:?}",
+                policy
+            );
+        }
+    }
+
+    #[test]
+    fn present_policies_use_their_preferred_mode() {
+        let all = [
+            vk::PresentModeKHR::IMMEDIATE,
+            vk::PresentModeKHR::MAILBOX,
+            vk::PresentModeKHR::FIFO,
+            vk::PresentModeKHR::FIFO_RELAXED,
+        ];
+        assert_eq!(
+            select_present_mode(&all, PresentPolicy::AdaptiveVsync),
+            vk::PresentModeKHR::FIFO_RELAXED
+        );
+        assert_eq!(
+            select_present_mode(&all, PresentPolicy::LowLatency),
+            vk::PresentModeKHR::MAILBOX
+        );
+        assert_eq!(
+            select_present_mode(&all, PresentPolicy::Uncapped),
+            vk::PresentModeKHR::IMMEDIATE
+        );
+
+        // The second choice is used before falling back to FIFO
+        let no_mailbox = [vk::PresentModeKHR::FIFO, vk::PresentModeKHR::IMMEDIATE];
+        assert_eq!(
+            select_present_mode(&no_mailbox, PresentPolicy::LowLatency),
+            vk::PresentModeKHR::IMMEDIATE
+        );
+        let no_immediate = [vk::PresentModeKHR::FIFO, vk::PresentModeKHR::MAILBOX];
+        assert_eq!(
+            select_present_mode(&no_immediate, PresentPolicy::Uncapped),
+            vk::PresentModeKHR::MAILBOX
+        );
+    }
+
+    fn capabilities(current: vk::Extent2D) -> vk::SurfaceCapabilitiesKHR {
+        vk::SurfaceCapabilitiesKHR {
+            current_extent: current,
+            min_image_extent: vk::Extent2D {
+                width: 64,
+                height: 32,
+            },
+            max_image_extent: vk::Extent2D {
+                width: 4096,
+                height: 2048,
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn swapchain_extent_follows_the_current_extent() {
+        let current = vk::Extent2D {
+            width: 800,
+            height: 600,
+        };
+        let window = vk::Extent2D {
+            width: 1024,
+            height: 768,
+        };
+        assert_eq!(
+            choose_swapchain_extent(&capabilities(current), window),
+            current
+        );
+    }
+
+    #[test]
+    fn swapchain_extent_is_clamped_without_current_extent() {
+        let capabilities = capabilities(vk::Extent2D {
+            width: u32::MAX,
+            height: u32::MAX,
+        });
+        let extent = |width, height| vk::Extent2D { width, height };
+        assert_eq!(
+            choose_swapchain_extent(&capabilities, extent(1024, 768)),
+            extent(1024, 768)
+        );
+        assert_eq!(
+            choose_swapchain_extent(&capabilities, extent(10, 8000)),
+            extent(64, 2048)
+        );
+        assert_eq!(
+            choose_swapchain_extent(&capabilities, extent(5000, 0)),
+            extent(4096, 32)
+        );
+    }
+}
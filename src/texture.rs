@@ -0,0 +1,208 @@
+use ash::{vk, Device};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::error::{RendererError, RendererResult};
+use crate::resource::{Image, ImageView, ResourceManager, Sampler};
+
+/// What a texture holds, which decides whether it is stored as sRGB
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TextureUsage {
+    /// Colors such as base color or emissive, which image files store as sRGB
+    Color,
+    /// Normals, roughness, occlusion and other values that must not be converted
+    Data,
+}
+
+impl TextureUsage {
+    /// The 8-bit RGBA format for textures with this usage
+    pub fn rgba8_format(self) -> vk::Format {
+        match self {
+            TextureUsage::Color => vk::Format::R8G8B8A8_SRGB,
+            TextureUsage::Data => vk::Format::R8G8B8A8_UNORM,
+        }
+    }
+}
+
+/// An 8-bit RGBA image in host memory, to create a texture from
+#[derive(Clone, Debug)]
+pub struct TextureData {
+    pub extent: vk::Extent2D,
+    /// Tightly packed rows, starting at the top
+    pub pixels: Vec<u8>,
+}
+
+impl TextureData {
+    /// Decodes a PNG, JPEG or TGA file. The format is guessed from the contents, or from the
+    /// extension for TGA files.
+    pub fn load(path: &Path) -> RendererResult<TextureData> {
+        let decode = || -> image::ImageResult<TextureData> {
+            let image = image::ImageReader::open(path)?
+                .with_guessed_format()?
+                .decode()?
+                .into_rgba8();
+            Ok(TextureData {
+                extent: vk::Extent2D {
+                    width: image.width(),
+                    height: image.height(),
+                },
+                pixels: image.into_raw(),
+            })
+        };
+        decode()
+            .map_err(|err| RendererError::Image(format!("Can't load {}: {}", path.display(), err)))
+    }
+}
+
+/// How a texture is filtered and addressed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SamplerDesc {
+    pub mag_filter: vk::Filter,
+    pub min_filter: vk::Filter,
+    /// `None` only samples the first mip level
+    pub mipmap_mode: Option<vk::SamplerMipmapMode>,
+    pub address_mode_u: vk::SamplerAddressMode,
+    pub address_mode_v: vk::SamplerAddressMode,
+    /// `None` disables anisotropic filtering
+    pub max_anisotropy: Option<u32>,
+}
+
+impl Default for SamplerDesc {
+    /// Trilinear filtering with repeating texture coordinates
+    fn default() -> Self {
+        SamplerDesc {
+            mag_filter: vk::Filter::LINEAR,
+            min_filter: vk::Filter::LINEAR,
+            mipmap_mode: Some(vk::SamplerMipmapMode::LINEAR),
+            address_mode_u: vk::SamplerAddressMode::REPEAT,
+            address_mode_v: vk::SamplerAddressMode::REPEAT,
+            max_anisotropy: None,
+        }
+    }
+}
+
+impl SamplerDesc {
+    pub fn create_info(&self) -> vk::SamplerCreateInfo {
+        vk::SamplerCreateInfo::builder()
+            .mag_filter(self.mag_filter)
+            .min_filter(self.min_filter)
+            .mipmap_mode(self.mipmap_mode.unwrap_or(vk::SamplerMipmapMode::NEAREST))
+            .address_mode_u(self.address_mode_u)
+            .address_mode_v(self.address_mode_v)
+            .address_mode_w(vk::SamplerAddressMode::REPEAT)
+            .anisotropy_enable(self.max_anisotropy.is_some())
+            .max_anisotropy(self.max_anisotropy.unwrap_or(1) as f32)
+            .min_lod(0.0)
+            // Clamping the level of detail to 0.25 is how Vulkan turns off mipmapping
+            .max_lod(if self.mipmap_mode.is_some() {
+                vk::LOD_CLAMP_NONE
+            } else {
+                0.25
+            })
+            .build()
+    }
+}
+
+/// Samplers for `SamplerDesc`s, created on first use and kept until the renderer is dropped
+pub struct SamplerCache {
+    samplers: HashMap<SamplerDesc, Sampler>,
+    /// The device limit, `None` if anisotropic filtering isn't enabled
+    max_anisotropy: Option<f32>,
+}
+
+impl SamplerCache {
+    pub fn new(max_anisotropy: Option<f32>) -> SamplerCache {
+        SamplerCache {
+            samplers: HashMap::new(),
+            max_anisotropy,
+        }
+    }
+
+    /// The sampler for `desc`. Anisotropy is clamped to what the device supports.
+    pub fn get(
+        &mut self,
+        device: &Device,
+        resources: &mut ResourceManager,
+        desc: &SamplerDesc,
+    ) -> RendererResult<vk::Sampler> {
+        if let Some(sampler) = self.samplers.get(desc) {
+            return Ok(sampler.handle());
+        }
+        let mut create_info = desc.create_info();
+        match self.max_anisotropy {
+            Some(max_anisotropy) => {
+                create_info.max_anisotropy = create_info.max_anisotropy.min(max_anisotropy)
+            }
+            None => {
+                create_info.anisotropy_enable = vk::FALSE;
+                create_info.max_anisotropy = 1.0;
+            }
+        }
+        let sampler = resources.create_sampler(device, &create_info, &format!("{:?}", desc))?;
+        let handle = sampler.handle();
+        self.samplers.insert(*desc, sampler);
+        Ok(handle)
+    }
+
+    pub fn len(&self) -> usize {
+        self.samplers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samplers.is_empty()
+    }
+
+    /// Drops all samplers, they are destroyed once no frame in flight uses them anymore.
+    pub fn clear(&mut self) {
+        self.samplers.clear();
+    }
+}
+
+struct TextureImage {
+    image: Image,
+    image_view: ImageView,
+}
+
+/// A sampled 2D image with a view of all of its mip levels, in `SHADER_READ_ONLY_OPTIMAL`
+/// layout.
+///
+/// Cloning a texture is cheap and shares the image, which is destroyed once the last clone is
+/// dropped and no frame in flight uses it anymore.
+#[derive(Clone)]
+pub struct Texture {
+    inner: Rc<TextureImage>,
+}
+
+impl Texture {
+    pub fn new(image: Image, image_view: ImageView) -> Texture {
+        Texture {
+            inner: Rc::new(TextureImage { image, image_view }),
+        }
+    }
+
+    pub fn image(&self) -> &Image {
+        &self.inner.image
+    }
+
+    pub fn image_view(&self) -> &ImageView {
+        &self.inner.image_view
+    }
+
+    pub fn name(&self) -> &str {
+        self.inner.image.name()
+    }
+}
+
+impl fmt::Debug for Texture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let image = self.image();
+        f.debug_struct("Texture")
+            .field("name", &image.name())
+            .field("extent", &image.extent())
+            .field("format", &image.format())
+            .field("mip_levels", &image.mip_levels())
+            .finish()
+    }
+}
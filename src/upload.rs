@@ -0,0 +1,674 @@
+use ash::{vk, Device};
+use std::collections::VecDeque;
+
+use crate::allocator::{AllocationStrategy, MemoryLocation};
+use crate::device::QueueFamilyIndices;
+use crate::error::RendererResult;
+use crate::resource::{Buffer, BufferDesc, Image, ResourceManager};
+
+/// Stage in which the graphics queue waits for uploads and acquires their resources
+const ACQUIRE_STAGE: vk::PipelineStageFlags = vk::PipelineStageFlags::ALL_COMMANDS;
+
+/// `alignment` must be a power of two
+fn align_up(value: vk::DeviceSize, alignment: vk::DeviceSize) -> vk::DeviceSize {
+    (value + alignment - 1) & !(alignment - 1)
+}
+
+/// The space of a buffer that is used as a ring. Space is handed out at the head and reclaimed
+/// at the tail once the batches that used it have completed, in submission order.
+struct RingSpace {
+    size: vk::DeviceSize,
+    alignment: vk::DeviceSize,
+    head: vk::DeviceSize,
+    tail: vk::DeviceSize,
+    /// Distinguishes a full ring from an empty one when `head == tail`
+    empty: bool,
+}
+
+impl RingSpace {
+    fn new(size: vk::DeviceSize, alignment: vk::DeviceSize) -> Self {
+        RingSpace {
+            size,
+            alignment,
+            head: 0,
+            tail: 0,
+            empty: true,
+        }
+    }
+
+    fn allocate(&mut self, size: vk::DeviceSize) -> Option<vk::DeviceSize> {
+        if self.empty {
+            self.head = 0;
+            self.tail = 0;
+        }
+        let start = align_up(self.head, self.alignment);
+        let offset = if self.empty || self.tail < self.head {
+            if start + size <= self.size {
+                start
+            } else if size <= self.tail {
+                // Wrap around, wasting the end of the buffer
+                0
+            } else {
+                return None;
+            }
+        } else if start + size <= self.tail {
+            start
+        } else {
+            return None;
+        };
+        self.head = offset + size;
+        self.empty = false;
+        Some(offset)
+    }
+
+    /// Reclaims the space up to `end`, the ring head after the last allocation of a batch.
+    fn release(&mut self, end: vk::DeviceSize, is_last: bool) {
+        self.tail = end;
+        if is_last {
+            self.empty = true;
+        }
+    }
+}
+
+/// A host-visible buffer that staging data is written to
+struct StagingRing {
+    buffer: Buffer,
+    space: RingSpace,
+}
+
+impl StagingRing {
+    unsafe fn write(&self, offset: vk::DeviceSize, data: &[u8]) {
+        let mapped = self.buffer.mapped_ptr().expect("Staging memory is mapped");
+        std::ptr::copy_nonoverlapping(
+            data.as_ptr(),
+            mapped.as_ptr().add(offset as usize),
+            data.len(),
+        );
+    }
+}
+
+/// A command buffer of copies on the transfer queue
+struct Batch {
+    command_buffer: vk::CommandBuffer,
+    fence: vk::Fence,
+    semaphore: vk::Semaphore,
+    /// The ring head after the batch's last staging allocation, if it made any
+    staging_end: Option<vk::DeviceSize>,
+    /// Staging buffers for uploads that don't fit into the ring
+    temporary_buffers: Vec<Buffer>,
+    /// Barriers the graphics queue must record to take ownership of the uploaded resources
+    buffer_acquires: Vec<vk::BufferMemoryBarrier>,
+    image_acquires: Vec<vk::ImageMemoryBarrier>,
+}
+
+/// Copies data into device-local buffers and images through a staging ring on the transfer
+/// queue, so that uploads overlap with rendering.
+///
+/// Uploads are recorded into a batch that is submitted by `submit`. The graphics queue then has
+/// to record the ownership acquire barriers with `record_acquires` and wait for the semaphores
+/// from `take_wait_semaphores` before using the uploaded resources. If the transfer and graphics
+/// queue families are the same, no ownership transfer is needed and only the semaphores remain.
+pub struct Uploader {
+    transfer_family: u32,
+    graphics_family: u32,
+    transfer_queue: vk::Queue,
+    command_pool: vk::CommandPool,
+    /// Only `None` once destroyed
+    ring: Option<StagingRing>,
+    recording: Option<Batch>,
+    in_flight: VecDeque<Batch>,
+    /// Reusable command buffers and fences of completed batches
+    free_batches: Vec<(vk::CommandBuffer, vk::Fence)>,
+    /// Signaled by submitted batches, not yet waited for by the graphics queue
+    unwaited_semaphores: Vec<vk::Semaphore>,
+    /// Waited for by the frame with the given number
+    waited_semaphores: VecDeque<(u64, vk::Semaphore)>,
+    free_semaphores: Vec<vk::Semaphore>,
+    pending_buffer_acquires: Vec<vk::BufferMemoryBarrier>,
+    pending_image_acquires: Vec<vk::ImageMemoryBarrier>,
+}
+
+impl Uploader {
+    pub fn new(
+        device: &Device,
+        resources: &mut ResourceManager,
+        queue_family_indices: &QueueFamilyIndices,
+        transfer_queue: vk::Queue,
+        limits: &vk::PhysicalDeviceLimits,
+        staging_buffer_size: vk::DeviceSize,
+    ) -> RendererResult<Uploader> {
+        let command_pool_create_info = vk::CommandPoolCreateInfo::builder()
+            .queue_family_index(queue_family_indices.transfer)
+            .flags(
+                vk::CommandPoolCreateFlags::TRANSIENT
+                    | vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER,
+            );
+        let command_pool = unsafe { device.create_command_pool(&command_pool_create_info, None)? };
+
+        let buffer = resources.create_buffer(
+            device,
+            &BufferDesc {
+                name: "staging ring",
+                size: staging_buffer_size,
+                usage: vk::BufferUsageFlags::TRANSFER_SRC,
+                location: MemoryLocation::CpuToGpu,
+                strategy: AllocationStrategy::Buddy,
+            },
+        )?;
+        // Image copies need offsets that are a multiple of the texel block size, which is at
+        // most 16 bytes
+        let alignment = limits.optimal_buffer_copy_offset_alignment.max(16);
+
+        Ok(Uploader {
+            transfer_family: queue_family_indices.transfer,
+            graphics_family: queue_family_indices.graphics,
+            transfer_queue,
+            command_pool,
+            ring: Some(StagingRing {
+                space: RingSpace::new(buffer.size(), alignment),
+                buffer,
+            }),
+            recording: None,
+            in_flight: VecDeque::new(),
+            free_batches: Vec::new(),
+            unwaited_semaphores: Vec::new(),
+            waited_semaphores: VecDeque::new(),
+            free_semaphores: Vec::new(),
+            pending_buffer_acquires: Vec::new(),
+            pending_image_acquires: Vec::new(),
+        })
+    }
+
+    fn needs_ownership_transfer(&self) -> bool {
+        self.transfer_family != self.graphics_family
+    }
+
+    fn ring(&mut self) -> &mut StagingRing {
+        self.ring.as_mut().expect("The uploader was destroyed")
+    }
+
+    /// Retires the batches whose fences have been signaled, or waits for the oldest one if
+    /// `wait` is set.
+    fn retire_batches(&mut self, device: &Device, wait: bool) -> RendererResult<()> {
+        while let Some(batch) = self.in_flight.front() {
+            let fence = [batch.fence];
+            if wait {
+                unsafe { device.wait_for_fences(&fence, true, u64::MAX)? };
+            } else if !unsafe { device.get_fence_status(batch.fence)? } {
+                break;
+            }
+            let batch = self.in_flight.pop_front().unwrap();
+            let is_last = self.in_flight.is_empty()
+                && self
+                    .recording
+                    .as_ref()
+                    .and_then(|batch| batch.staging_end)
+                    .is_none();
+            if let Some(end) = batch.staging_end {
+                self.ring().space.release(end, is_last);
+            } else if is_last {
+                self.ring().space.empty = true;
+            }
+            unsafe {
+                device.reset_fences(&fence)?;
+                device.reset_command_buffer(
+                    batch.command_buffer,
+                    vk::CommandBufferResetFlags::empty(),
+                )?;
+            }
+            self.free_batches.push((batch.command_buffer, batch.fence));
+            // The temporary staging buffers are dropped here
+            if wait {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// The batch that uploads are currently recorded into
+    fn recording_batch(&mut self, device: &Device) -> RendererResult<&mut Batch> {
+        if self.recording.is_none() {
+            let (command_buffer, fence) = match self.free_batches.pop() {
+                Some(free) => free,
+                None => {
+                    let command_buffer_allocate_info = vk::CommandBufferAllocateInfo::builder()
+                        .command_pool(self.command_pool)
+                        .command_buffer_count(1)
+                        .level(vk::CommandBufferLevel::PRIMARY);
+                    let command_buffer =
+                        unsafe { device.allocate_command_buffers(&command_buffer_allocate_info)? }
+                            [0];
+                    let fence =
+                        unsafe { device.create_fence(&vk::FenceCreateInfo::default(), None)? };
+                    (command_buffer, fence)
+                }
+            };
+            let semaphore = match self.free_semaphores.pop() {
+                Some(semaphore) => semaphore,
+                None => unsafe {
+                    device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None)?
+                },
+            };
+            let begin_info = vk::CommandBufferBeginInfo::builder()
+                .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+            unsafe { device.begin_command_buffer(command_buffer, &begin_info)? };
+            self.recording = Some(Batch {
+                command_buffer,
+                fence,
+                semaphore,
+                staging_end: None,
+                temporary_buffers: Vec::new(),
+                buffer_acquires: Vec::new(),
+                image_acquires: Vec::new(),
+            });
+        }
+        Ok(self.recording.as_mut().unwrap())
+    }
+
+    /// Copies `data` into staging memory and returns the buffer and offset it is in.
+    fn stage(
+        &mut self,
+        device: &Device,
+        resources: &mut ResourceManager,
+        data: &[u8],
+    ) -> RendererResult<(vk::Buffer, vk::DeviceSize)> {
+        let size = data.len() as vk::DeviceSize;
+        if size > self.ring().space.size {
+            let buffer = resources.create_buffer(
+                device,
+                &BufferDesc {
+                    name: "temporary staging buffer",
+                    size,
+                    usage: vk::BufferUsageFlags::TRANSFER_SRC,
+                    location: MemoryLocation::CpuToGpu,
+                    strategy: AllocationStrategy::Linear,
+                },
+            )?;
+            let mapped = buffer.mapped_ptr().expect("Staging memory is mapped");
+            unsafe {
+                std::ptr::copy_nonoverlapping(data.as_ptr(), mapped.as_ptr(), data.len());
+            }
+            let handle = buffer.handle();
+            self.recording_batch(device)?.temporary_buffers.push(buffer);
+            return Ok((handle, 0));
+        }
+
+        self.retire_batches(device, false)?;
+        let offset = loop {
+            if let Some(offset) = self.ring().space.allocate(size) {
+                break offset;
+            }
+            // Make room by waiting for the oldest batch, submitting the current one first if
+            // it is the one holding the space
+            if self.in_flight.is_empty() {
+                self.submit(device)?;
+            }
+            self.retire_batches(device, true)?;
+        };
+        let ring = self.ring();
+        unsafe { ring.write(offset, data) };
+        let (end, buffer) = (ring.space.head, ring.buffer.handle());
+        self.recording_batch(device)?.staging_end = Some(end);
+        Ok((buffer, offset))
+    }
+
+    /// Records a copy of `data` into `buffer` at `offset`. The buffer must have been created
+    /// with `TRANSFER_DST` usage and may not be in use by the GPU.
+    pub fn upload_buffer(
+        &mut self,
+        device: &Device,
+        resources: &mut ResourceManager,
+        buffer: &Buffer,
+        offset: vk::DeviceSize,
+        data: &[u8],
+    ) -> RendererResult<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let (staging_buffer, staging_offset) = self.stage(device, resources, data)?;
+        let needs_ownership_transfer = self.needs_ownership_transfer();
+        let (transfer_family, graphics_family) = (self.transfer_family, self.graphics_family);
+        let batch = self.recording_batch(device)?;
+
+        let region = vk::BufferCopy {
+            src_offset: staging_offset,
+            dst_offset: offset,
+            size: data.len() as vk::DeviceSize,
+        };
+        unsafe {
+            device.cmd_copy_buffer(
+                batch.command_buffer,
+                staging_buffer,
+                buffer.handle(),
+                &[region],
+            )
+        };
+
+        if needs_ownership_transfer {
+            let barrier = |src_access, dst_access| {
+                vk::BufferMemoryBarrier::builder()
+                    .src_access_mask(src_access)
+                    .dst_access_mask(dst_access)
+                    .src_queue_family_index(transfer_family)
+                    .dst_queue_family_index(graphics_family)
+                    .buffer(buffer.handle())
+                    .offset(offset)
+                    .size(data.len() as vk::DeviceSize)
+                    .build()
+            };
+            let release = barrier(vk::AccessFlags::TRANSFER_WRITE, vk::AccessFlags::empty());
+            unsafe {
+                device.cmd_pipeline_barrier(
+                    batch.command_buffer,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[release],
+                    &[],
+                )
+            };
+            batch.buffer_acquires.push(barrier(
+                vk::AccessFlags::empty(),
+                vk::AccessFlags::MEMORY_READ | vk::AccessFlags::MEMORY_WRITE,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Records copies of tightly packed mip levels, starting at level 0, into `image`. The image
+    /// must have been created with `TRANSFER_DST` usage and may not be in use by the GPU. Its
+    /// previous contents are discarded and it ends up in `final_layout`.
+    ///
+    /// Levels are staged at offsets that are multiples of 16 bytes, so the texel block size of
+    /// the image's format has to divide 16. That rules out formats with three 8, 16 or 32-bit
+    /// channels such as `R8G8B8_UNORM`.
+    pub fn upload_image(
+        &mut self,
+        device: &Device,
+        resources: &mut ResourceManager,
+        image: &Image,
+        levels: &[&[u8]],
+        final_layout: vk::ImageLayout,
+    ) -> RendererResult<()> {
+        let needs_ownership_transfer = self.needs_ownership_transfer();
+        let (transfer_family, graphics_family) = (self.transfer_family, self.graphics_family);
+        // All levels are transitioned, so that the whole image ends up in `final_layout` even if
+        // only some levels are uploaded
+        let subresource_range = image.subresource_range();
+        let to_transfer_dst = vk::ImageMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::empty())
+            .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .old_layout(vk::ImageLayout::UNDEFINED)
+            .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image.handle())
+            .subresource_range(subresource_range)
+            .build();
+        let command_buffer = self.recording_batch(device)?.command_buffer;
+        unsafe {
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[to_transfer_dst],
+            )
+        };
+
+        for (level, data) in levels.iter().enumerate() {
+            // Staging may submit the batch to make room. The transition stays in effect for the
+            // following batches since they are submitted to the same queue.
+            let (staging_buffer, staging_offset) = self.stage(device, resources, data)?;
+            let extent = image.extent();
+            let region = vk::BufferImageCopy {
+                buffer_offset: staging_offset,
+                buffer_row_length: 0,
+                buffer_image_height: 0,
+                image_subresource: vk::ImageSubresourceLayers {
+                    aspect_mask: image.aspect_mask(),
+                    mip_level: level as u32,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                },
+                image_offset: vk::Offset3D::default(),
+                image_extent: vk::Extent3D {
+                    width: (extent.width >> level).max(1),
+                    height: (extent.height >> level).max(1),
+                    depth: 1,
+                },
+            };
+            let command_buffer = self.recording_batch(device)?.command_buffer;
+            unsafe {
+                device.cmd_copy_buffer_to_image(
+                    command_buffer,
+                    staging_buffer,
+                    image.handle(),
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &[region],
+                )
+            };
+        }
+
+        let batch = self.recording_batch(device)?;
+        // The release and the acquire barrier must perform the same layout transition
+        let barrier = |src_access, dst_access, src_family, dst_family| {
+            vk::ImageMemoryBarrier::builder()
+                .src_access_mask(src_access)
+                .dst_access_mask(dst_access)
+                .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                .new_layout(final_layout)
+                .src_queue_family_index(src_family)
+                .dst_queue_family_index(dst_family)
+                .image(image.handle())
+                .subresource_range(subresource_range)
+                .build()
+        };
+        let graphics_access = vk::AccessFlags::MEMORY_READ | vk::AccessFlags::MEMORY_WRITE;
+        if needs_ownership_transfer {
+            let release = barrier(
+                vk::AccessFlags::TRANSFER_WRITE,
+                vk::AccessFlags::empty(),
+                transfer_family,
+                graphics_family,
+            );
+            unsafe {
+                device.cmd_pipeline_barrier(
+                    batch.command_buffer,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    &[release],
+                )
+            };
+            batch.image_acquires.push(barrier(
+                vk::AccessFlags::empty(),
+                graphics_access,
+                transfer_family,
+                graphics_family,
+            ));
+        } else {
+            // The semaphore makes the result visible to the graphics queue
+            let transition = barrier(
+                vk::AccessFlags::TRANSFER_WRITE,
+                vk::AccessFlags::empty(),
+                vk::QUEUE_FAMILY_IGNORED,
+                vk::QUEUE_FAMILY_IGNORED,
+            );
+            unsafe {
+                device.cmd_pipeline_barrier(
+                    batch.command_buffer,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    &[transition],
+                )
+            };
+        }
+        Ok(())
+    }
+
+    /// Submits the recorded uploads to the transfer queue.
+    pub fn submit(&mut self, device: &Device) -> RendererResult<()> {
+        let mut batch = match self.recording.take() {
+            Some(batch) => batch,
+            None => return Ok(()),
+        };
+        let command_buffers = [batch.command_buffer];
+        let signal_semaphores = [batch.semaphore];
+        let submit_info = vk::SubmitInfo::builder()
+            .command_buffers(&command_buffers)
+            .signal_semaphores(&signal_semaphores)
+            .build();
+        unsafe {
+            device.end_command_buffer(batch.command_buffer)?;
+            device.queue_submit(self.transfer_queue, &[submit_info], batch.fence)?;
+        }
+        self.unwaited_semaphores.push(batch.semaphore);
+        self.pending_buffer_acquires
+            .append(&mut batch.buffer_acquires);
+        self.pending_image_acquires
+            .append(&mut batch.image_acquires);
+        self.in_flight.push_back(batch);
+        Ok(())
+    }
+
+    /// Records the barriers that transfer ownership of the submitted uploads to the graphics
+    /// queue. The command buffer must also wait for `take_wait_semaphores`.
+    ///
+    /// # Safety
+    /// `command_buffer` must be recording, outside of a rendering scope, and be submitted to the
+    /// graphics queue.
+    pub unsafe fn record_acquires(&mut self, device: &Device, command_buffer: vk::CommandBuffer) {
+        if self.pending_buffer_acquires.is_empty() && self.pending_image_acquires.is_empty() {
+            return;
+        }
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            ACQUIRE_STAGE,
+            vk::PipelineStageFlags::ALL_COMMANDS,
+            vk::DependencyFlags::empty(),
+            &[],
+            &self.pending_buffer_acquires,
+            &self.pending_image_acquires,
+        );
+        self.pending_buffer_acquires.clear();
+        self.pending_image_acquires.clear();
+    }
+
+    /// The semaphores of the submitted uploads, with the stage to wait for them in. They must be
+    /// waited for by the graphics submission of `frame_number`.
+    pub fn take_wait_semaphores(
+        &mut self,
+        frame_number: u64,
+    ) -> Vec<(vk::Semaphore, vk::PipelineStageFlags)> {
+        let waited_semaphores = &mut self.waited_semaphores;
+        self.unwaited_semaphores
+            .drain(..)
+            .map(|semaphore| {
+                waited_semaphores.push_back((frame_number, semaphore));
+                (semaphore, ACQUIRE_STAGE)
+            })
+            .collect()
+    }
+
+    /// Recycles what the completed transfer batches and graphics frames used.
+    ///
+    /// # Safety
+    /// The GPU must be done with `completed_frame` and all frames before it.
+    pub unsafe fn collect(&mut self, device: &Device, completed_frame: u64) -> RendererResult<()> {
+        self.retire_batches(device, false)?;
+        while let Some((frame_number, _)) = self.waited_semaphores.front() {
+            if *frame_number > completed_frame {
+                break;
+            }
+            let (_, semaphore) = self.waited_semaphores.pop_front().unwrap();
+            self.free_semaphores.push(semaphore);
+        }
+        Ok(())
+    }
+
+    /// Must be called before the resource manager is destroyed.
+    ///
+    /// # Safety
+    /// The device must be idle.
+    pub unsafe fn destroy(&mut self, device: &Device) {
+        // Submitted batches no longer own their semaphore, it is in one of the semaphore lists
+        if let Some(batch) = self.recording.take() {
+            device.destroy_semaphore(batch.semaphore, None);
+            device.destroy_fence(batch.fence, None);
+        }
+        for batch in self.in_flight.drain(..) {
+            device.destroy_fence(batch.fence, None);
+        }
+        for (_, fence) in self.free_batches.drain(..) {
+            device.destroy_fence(fence, None);
+        }
+        let semaphores = self
+            .unwaited_semaphores
+            .drain(..)
+            .chain(
+                self.waited_semaphores
+                    .drain(..)
+                    .map(|(_, semaphore)| semaphore),
+            )
+            .chain(self.free_semaphores.drain(..));
+        for semaphore in semaphores {
+            device.destroy_semaphore(semaphore, None);
+        }
+        device.destroy_command_pool(self.command_pool, None);
+        // The staging buffer is destroyed by the resource manager
+        self.ring = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ring_space_is_aligned() {
+        let mut space = RingSpace::new(1024, 16);
+        assert_eq!(space.allocate(10), Some(0));
+        assert_eq!(space.allocate(100), Some(16));
+        assert_eq!(space.allocate(1), Some(128));
+        assert_eq!(space.head, 129);
+    }
+
+    #[test]
+    fn ring_space_wraps_around_once_the_tail_is_released() {
+        let mut space = RingSpace::new(1024, 16);
+        assert_eq!(space.allocate(400), Some(0));
+        let first_batch_end = space.head;
+        assert_eq!(space.allocate(400), Some(400));
+        // Neither the end of the buffer nor the start are free yet
+        assert_eq!(space.allocate(300), None);
+
+        space.release(first_batch_end, false);
+        assert_eq!(space.allocate(300), Some(0));
+        // The head may not overtake the tail
+        assert_eq!(space.allocate(100), None);
+        assert_eq!(space.allocate(96), Some(304));
+    }
+
+    #[test]
+    fn ring_space_starts_over_when_everything_is_released() {
+        let mut space = RingSpace::new(1024, 16);
+        assert_eq!(space.allocate(1000), Some(0));
+        let end = space.head;
+        assert_eq!(space.allocate(100), None);
+
+        space.release(end, true);
+        assert!(space.empty);
+        assert_eq!(space.allocate(1024), Some(0));
+        // A full ring isn't mistaken for an empty one
+        assert_eq!(space.allocate(1), None);
+    }
+}
@@ -0,0 +1,179 @@
+use ash::{vk, Entry};
+use std::ffi::CStr;
+use std::str::FromStr;
+
+use crate::error::RendererResult;
+
+pub const VALIDATION_LAYER_NAME: &str = "VK_LAYER_KHRONOS_validation\0";
+
+/// Which parts of the Khronos validation layer to enable.
+///
+/// Validation is off by default. When it is requested but the layer isn't installed, the
+/// renderer runs without it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ValidationConfig {
+    pub enabled: bool,
+    /// Instruments shaders to catch out-of-bounds accesses. Slow.
+    pub gpu_assisted: bool,
+    pub best_practices: bool,
+    /// Reports missing or incorrect barriers and other synchronization hazards
+    pub synchronization: bool,
+    /// Makes `Renderer::render` fail when validation errors were reported
+    pub strict: bool,
+}
+
+impl ValidationConfig {
+    fn wants_validation_features(&self) -> bool {
+        self.gpu_assisted || self.best_practices || self.synchronization
+    }
+
+    /// The `VK_EXT_validation_features` values for the requested toggles
+    pub fn enabled_features(&self) -> Vec<vk::ValidationFeatureEnableEXT> {
+        let mut features = Vec::new();
+        if self.gpu_assisted {
+            features.push(vk::ValidationFeatureEnableEXT::GPU_ASSISTED);
+            features.push(vk::ValidationFeatureEnableEXT::GPU_ASSISTED_RESERVE_BINDING_SLOT);
+        }
+        if self.best_practices {
+            features.push(vk::ValidationFeatureEnableEXT::BEST_PRACTICES);
+        }
+        if self.synchronization {
+            features.push(vk::ValidationFeatureEnableEXT::SYNCHRONIZATION_VALIDATION);
+        }
+        features
+    }
+}
+
+/// Parses the value of the `CHARLIE_VALIDATION` environment variable or the `--validation` flag.
+///
+/// `0`/`off` disables validation and `1`/`on` enables the default checks. Otherwise the value
+/// is a comma-separated list of extra features (`gpu-assisted`, `best-practices`, `sync`) and
+/// `strict`, which also enables validation.
+impl FromStr for ValidationConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = ValidationConfig::default();
+        match s.trim().to_lowercase().as_str() {
+            "0" | "off" | "false" => return Ok(config),
+            "" | "1" | "on" | "true" => {
+                config.enabled = true;
+                return Ok(config);
+            }
+            features => {
+                config.enabled = true;
+                for feature in features.split(',').map(str::trim) {
+                    match feature {
+                        "gpu-assisted" | "gpu" => config.gpu_assisted = true,
+                        "best-practices" => config.best_practices = true,
+                        "sync" | "synchronization" => config.synchronization = true,
+                        "strict" => config.strict = true,
+                        _ => return Err(format!("Unknown validation feature `{}`", feature)),
+                    }
+                }
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// What could actually be enabled out of a `ValidationConfig` on this machine
+pub struct ValidationSupport {
+    pub layer: bool,
+    pub validation_features: bool,
+}
+
+fn layer_extension_properties(
+    entry: &Entry,
+    layer_name: &CStr,
+) -> RendererResult<Vec<vk::ExtensionProperties>> {
+    // ash only exposes the query for extensions that are not provided by a layer
+    let enumerate = entry.fp_v1_0().enumerate_instance_extension_properties;
+    let mut count = 0;
+    unsafe {
+        enumerate(layer_name.as_ptr(), &mut count, std::ptr::null_mut()).result()?;
+        let mut properties = Vec::with_capacity(count as usize);
+        enumerate(layer_name.as_ptr(), &mut count, properties.as_mut_ptr()).result()?;
+        properties.set_len(count as usize);
+        Ok(properties)
+    }
+}
+
+pub fn query_validation_support(
+    entry: &Entry,
+    config: &ValidationConfig,
+) -> RendererResult<ValidationSupport> {
+    let mut support = ValidationSupport {
+        layer: false,
+        validation_features: false,
+    };
+    if !config.enabled {
+        return Ok(support);
+    }
+
+    let layer_name = CStr::from_bytes_with_nul(VALIDATION_LAYER_NAME.as_bytes()).unwrap();
+    support.layer = entry
+        .enumerate_instance_layer_properties()?
+        .iter()
+        .any(|layer| unsafe { CStr::from_ptr(layer.layer_name.as_ptr()) } == layer_name);
+    if !support.layer {
+        log::warn!(
+            "Validation was requested, but {:?} is not installed",
+            layer_name
+        );
+        return Ok(support);
+    }
+
+    if config.wants_validation_features() {
+        support.validation_features =
+            layer_extension_properties(entry, layer_name)?
+                .iter()
+                .any(|extension| {
+                    let name = unsafe { CStr::from_ptr(extension.extension_name.as_ptr()) };
+                    name == vk::ExtValidationFeaturesFn::name()
+                });
+        if !support.validation_features {
+            log::warn!("The validation layer doesn't support VK_EXT_validation_features");
+        }
+    }
+    Ok(support)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn on_and_off_values_toggle_the_default_checks() {
+        let enabled = ValidationConfig {
+            enabled: true,
+            ..Default::default()
+        };
+        assert_eq!("".parse(), Ok(enabled));
+        assert_eq!(" ON ".parse(), Ok(enabled));
+        assert_eq!("0".parse(), Ok(ValidationConfig::default()));
+        assert_eq!("off".parse(), Ok(ValidationConfig::default()));
+    }
+
+    #[test]
+    fn feature_lists_enable_validation() {
+        let config: ValidationConfig = "sync, strict".parse().unwrap();
+        assert_eq!(
+            config,
+            ValidationConfig {
+                enabled: true,
+                synchronization: true,
+                strict: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            config.enabled_features(),
+            [vk::ValidationFeatureEnableEXT::SYNCHRONIZATION_VALIDATION]
+        );
+        assert_eq!(
+            "gpu,typo".parse::<ValidationConfig>(),
+            Err("Unknown validation feature `typo`".to_string())
+        );
+    }
+}
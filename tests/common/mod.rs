@@ -0,0 +1,168 @@
+//! Golden-image testing: scenes are rendered headlessly through `Renderer::render` and compared
+//! against reference PNGs in `tests/references`.
+//!
+//! Run with `CHARLIE_BLESS=1` to (re)generate the references from the current output. On
+//! failure, the actual image and a diff image are written to the Cargo target directory.
+
+use charlie_renderer::config::RendererConfig;
+use charlie_renderer::error::RendererResult;
+use charlie_renderer::readback::Screenshot;
+use charlie_renderer::renderer::Renderer;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// A named setup of the renderer that is rendered at a fixed resolution
+pub struct Scene {
+    pub name: &'static str,
+    pub width: u32,
+    pub height: u32,
+    /// Adjusts the configuration the renderer is created with
+    pub configure: fn(&mut RendererConfig),
+    /// Called on the fresh renderer before the frame that is compared gets rendered
+    pub prepare: fn(&mut Renderer) -> RendererResult<()>,
+}
+
+/// How much the rendered image may deviate from the reference
+#[derive(Clone, Copy, Debug)]
+pub struct Tolerance {
+    /// Pixels whose channels all differ by at most this much count as equal
+    pub max_channel_difference: u8,
+    /// Fraction of pixels that may differ, to allow for rasterization differences at edges
+    pub max_differing_pixel_ratio: f64,
+}
+
+impl Default for Tolerance {
+    fn default() -> Self {
+        Tolerance {
+            max_channel_difference: 2,
+            max_differing_pixel_ratio: 0.001,
+        }
+    }
+}
+
+struct Comparison {
+    differing_pixels: usize,
+    diff_image: Screenshot,
+}
+
+fn load_png(path: &Path) -> Result<Screenshot, Box<dyn std::error::Error>> {
+    let mut decoder = png::Decoder::new(File::open(path)?);
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let mut reader = decoder.read_info()?;
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels)?;
+    if info.color_type != png::ColorType::Rgba || info.bit_depth != png::BitDepth::Eight {
+        return Err(format!("{} is not an 8-bit RGBA image", path.display()).into());
+    }
+    pixels.truncate(info.buffer_size());
+    Ok(Screenshot {
+        width: info.width,
+        height: info.height,
+        pixels,
+    })
+}
+
+/// Compares two images of the same size. Differing pixels are marked red in the diff image, the
+/// rest is a dimmed copy of the actual image.
+fn compare(actual: &Screenshot, reference: &Screenshot, tolerance: Tolerance) -> Comparison {
+    let mut differing_pixels = 0;
+    let mut diff_pixels = Vec::with_capacity(actual.pixels.len());
+    for (actual, reference) in actual
+        .pixels
+        .chunks_exact(4)
+        .zip(reference.pixels.chunks_exact(4))
+    {
+        let differs = actual
+            .iter()
+            .zip(reference)
+            .any(|(a, r)| a.abs_diff(*r) > tolerance.max_channel_difference);
+        if differs {
+            differing_pixels += 1;
+            diff_pixels.extend_from_slice(&[255, 0, 0, 255]);
+        } else {
+            diff_pixels.extend_from_slice(&[actual[0] / 4, actual[1] / 4, actual[2] / 4, 255]);
+        }
+    }
+    Comparison {
+        differing_pixels,
+        diff_image: Screenshot {
+            width: actual.width,
+            height: actual.height,
+            pixels: diff_pixels,
+        },
+    }
+}
+
+fn reference_path(scene: &Scene) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("references")
+        .join(format!("{}.png", scene.name))
+}
+
+fn output_dir() -> PathBuf {
+    Path::new(env!("CARGO_TARGET_TMPDIR")).join("golden")
+}
+
+/// Validation is enabled through `CHARLIE_VALIDATION`, always in strict mode so that any API
+/// misuse fails the test.
+pub fn render_scene(scene: &Scene) -> RendererResult<Screenshot> {
+    let _ = env_logger::builder().is_test(true).try_init();
+
+    let mut config = RendererConfig::default();
+    if let Ok(validation) = std::env::var("CHARLIE_VALIDATION") {
+        config.validation = validation.parse().expect("Invalid CHARLIE_VALIDATION");
+        config.validation.strict = true;
+    }
+    (scene.configure)(&mut config);
+    let mut renderer = Renderer::new_headless(scene.width, scene.height, config)?;
+    (scene.prepare)(&mut renderer)?;
+    renderer.capture_frame()
+}
+
+/// Renders `scene` and panics if it doesn't match its reference image within `tolerance`.
+pub fn check_scene(scene: &Scene, tolerance: Tolerance) {
+    let actual = render_scene(scene)
+        .unwrap_or_else(|err| panic!("Failed to render scene `{}`: {}", scene.name, err));
+
+    let reference_path = reference_path(scene);
+    if std::env::var_os("CHARLIE_BLESS").is_some() {
+        actual.save_png(&reference_path).unwrap();
+        println!("Blessed {}", reference_path.display());
+        return;
+    }
+
+    let reference = load_png(&reference_path).unwrap_or_else(|err| {
+        panic!(
+            "Can't load the reference image {} ({}). Run with CHARLIE_BLESS=1 to create it.",
+            reference_path.display(),
+            err
+        )
+    });
+    assert_eq!(
+        (actual.width, actual.height),
+        (reference.width, reference.height),
+        "Scene `{}` was rendered at a different size than its reference image",
+        scene.name
+    );
+
+    let comparison = compare(&actual, &reference, tolerance);
+    let pixel_count = (actual.width * actual.height) as usize;
+    let differing_ratio = comparison.differing_pixels as f64 / pixel_count as f64;
+    if differing_ratio > tolerance.max_differing_pixel_ratio {
+        let output_dir = output_dir();
+        std::fs::create_dir_all(&output_dir).unwrap();
+        let actual_path = output_dir.join(format!("{}-actual.png", scene.name));
+        let diff_path = output_dir.join(format!("{}-diff.png", scene.name));
+        actual.save_png(&actual_path).unwrap();
+        comparison.diff_image.save_png(&diff_path).unwrap();
+        panic!(
+            "Scene `{}` differs from its reference in {} of {} pixels. See {} and {}",
+            scene.name,
+            comparison.differing_pixels,
+            pixel_count,
+            actual_path.display(),
+            diff_path.display()
+        );
+    }
+}
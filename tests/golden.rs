@@ -0,0 +1,112 @@
+mod common;
+
+use charlie_renderer::mesh::{MeshData, Vertex};
+use common::{check_scene, Scene, Tolerance};
+use glam::{Mat4, Vec3, Vec4};
+
+/// A unit cube around the origin with a normal per face
+fn cube(color: Vec4) -> MeshData {
+    let mut data = MeshData::default();
+    for &normal in &[Vec3::X, -Vec3::X, Vec3::Y, -Vec3::Y, Vec3::Z, -Vec3::Z] {
+        let tangent = normal.any_orthonormal_vector();
+        let bitangent = normal.cross(tangent);
+        let first = data.vertices.len() as u32;
+        for &(u, v) in &[(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)] {
+            data.vertices.push(Vertex {
+                position: normal + tangent * u + bitangent * v,
+                normal,
+                color,
+                ..Default::default()
+            });
+        }
+        data.indices
+            .extend_from_slice(&[first, first + 1, first + 2, first, first + 2, first + 3]);
+    }
+    data
+}
+
+#[test]
+fn triangle() {
+    check_scene(
+        &Scene {
+            name: "triangle",
+            width: 64,
+            height: 64,
+            configure: |_| {},
+            prepare: |_| Ok(()),
+        },
+        Tolerance::default(),
+    );
+}
+
+#[test]
+fn triangle_non_square() {
+    check_scene(
+        &Scene {
+            name: "triangle_non_square",
+            width: 96,
+            height: 64,
+            configure: |_| {},
+            prepare: |_| Ok(()),
+        },
+        Tolerance::default(),
+    );
+}
+
+#[test]
+fn triangle_after_resize() {
+    check_scene(
+        &Scene {
+            name: "triangle_non_square",
+            width: 64,
+            height: 64,
+            configure: |_| {},
+            // The first frame is rendered after the offscreen target was recreated
+            prepare: |renderer| {
+                renderer.resize(96, 64);
+                Ok(())
+            },
+        },
+        Tolerance::default(),
+    );
+}
+
+#[test]
+fn triangle_render_pass() {
+    check_scene(
+        &Scene {
+            name: "triangle",
+            width: 64,
+            height: 64,
+            configure: |config| config.disable_dynamic_rendering = true,
+            prepare: |_| Ok(()),
+        },
+        Tolerance::default(),
+    );
+}
+
+#[test]
+fn cube_mesh() {
+    check_scene(
+        &Scene {
+            name: "cube_mesh",
+            width: 64,
+            height: 64,
+            configure: |_| {},
+            // Two faces of the cube turned by 45 degrees are visible, their edges are vertical and
+            // horizontal in an orthographic view
+            prepare: |renderer| {
+                let mesh = renderer.create_mesh(&cube(Vec4::new(1.0, 0.5, 0.2, 1.0)), "cube")?;
+                let projection = Mat4::orthographic_rh(-2.0, 2.0, -2.0, 2.0, 0.1, 10.0);
+                // Vulkan's clip space has y pointing down
+                renderer
+                    .set_view_projection(Mat4::from_scale(Vec3::new(1.0, -1.0, 1.0)) * projection);
+                let model = Mat4::from_translation(Vec3::new(0.0, 0.0, -5.0))
+                    * Mat4::from_rotation_y(45f32.to_radians());
+                renderer.draw_mesh(&mesh, &[], model);
+                Ok(())
+            },
+        },
+        Tolerance::default(),
+    );
+}